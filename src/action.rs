@@ -1,25 +1,110 @@
-use crate::widget_diff_tree::DiffTreeWidget;
+use std::{path::PathBuf, time::Duration};
+
+use tuinix::TerminalColor;
+
+use crate::widget_diff_tree::{DiffTreeWidget, StartupExpand};
 
 #[derive(Debug, Clone)]
 pub enum Action {
     Quit,
     Recenter,
+    ForceRedraw,
     MoveUp,
     MoveDown,
     MoveLeft,
     MoveRight,
-    ToggleExpand,
+    ToggleExpand {
+        // Run instead when the cursor is on a line, which has nothing to expand.
+        leaf_action: Option<Box<Action>>,
+    },
+    ToggleCompact,
+    ToggleChunkContext,
+    ToggleStagedContext,
+    ToggleReconcileFile,
+    ToggleFocus,
+    TogglePathStyle,
+    ToggleModeLabels,
+    ToggleIgnored,
+    ToggleStagedBase,
+    ToggleDeletedContent,
+    ToggleCombinedView,
+    ToggleSidebar,
+    ToggleBinarySizes,
+    CycleSort,
+    CollapseAll,
+    ToggleFileChunks,
+    ExpandAll,
+    RestoreExpansion,
+    ToggleOutline,
     Stage,
-    Discard,
+    StageFileHunks,
+    RestageFile,
+    Discard {
+        // Whether to show the changes to be discarded and ask for confirmation first.
+        confirm: bool,
+    },
     Unstage,
+    SplitChunk,
+    StageAll {
+        confirm: bool,
+    },
+    DiscardAll,
+    Undiscard,
+    YankNewLine,
+    YankOldLine,
+    CopyLineRef,
+    CopyAsMarkdown,
+    CopyBeforeAfter,
+    ExportStaged {
+        path: PathBuf,
+    },
     ToggleLegend,
+    ShowHelp,
+    ShowHunkList,
+    GotoFile,
+    PreviewContext,
+    DumpTree,
     InitLegend {
         hide: bool,
         label_show: String,
         label_hide: String,
         highlight_active_binding: bool,
     },
+    InitContext {
+        idle_timeout: Option<Duration>,
+    },
+    InitTree {
+        wrap_navigation: bool,
+        phase_separator: bool,
+        focus_lines: usize,
+        show_child_counts: bool,
+        elide_path_components: usize,
+        show_deleted_content: bool,
+        mark_reload_changes: bool,
+        unstaged_header_color: TerminalColor,
+        staged_header_color: TerminalColor,
+        ruler_column: Option<usize>,
+        max_content_cols: Option<usize>,
+        center_content: bool,
+        show_mtimes: bool,
+        show_binary_sizes: bool,
+        startup_expand: StartupExpand,
+        fold_context_lines: Option<usize>,
+    },
+    InitPostStageCommand(Option<mame::command::ExternalCommand>),
     ExecuteCommand(mame::command::ExternalCommand),
+    GitCommand,
+    SetGlobFilter,
+    FilterByAuthor,
+    CommitWithTemplate {
+        // Initial text of the commit message prompt (e.g., "feat: ").
+        template: String,
+    },
+    FormatStaged {
+        command: mame::command::ExternalCommand,
+        staged_files_only: bool,
+    },
+    Batch(Vec<Action>),
 }
 
 impl Action {
@@ -27,19 +112,154 @@ impl Action {
         match self {
             Self::Quit => true,
             Self::Recenter => tree.cursor_row() != 0,
+            Self::ForceRedraw => true,
             Self::MoveUp => tree.can_cursor_up(),
             Self::MoveDown => tree.can_cursor_down(),
             Self::MoveLeft => tree.can_cursor_left(),
             Self::MoveRight => tree.can_cursor_right(),
-            Self::ToggleExpand => tree.can_toggle(),
+            Self::ToggleExpand { .. } => match self.leaf_fallback(tree) {
+                Some(action) => action.is_applicable(tree),
+                None => tree.can_toggle(),
+            },
+            Self::ToggleCompact => true,
+            Self::ToggleChunkContext => tree.can_toggle_chunk_context(),
+            Self::ToggleStagedContext => true,
+            Self::ToggleReconcileFile => tree.can_toggle_reconcile_file(),
+            Self::ToggleFocus => true,
+            Self::TogglePathStyle => true,
+            Self::ToggleModeLabels => true,
+            Self::ToggleIgnored => true,
+            Self::ToggleStagedBase => tree.can_toggle_staged_base(),
+            Self::ToggleDeletedContent => true,
+            Self::ToggleCombinedView => true,
+            Self::ToggleSidebar => tree.can_toggle_sidebar(),
+            Self::ToggleBinarySizes => true,
+            Self::CycleSort => true,
+            Self::CollapseAll => true,
+            Self::ToggleFileChunks => tree.can_toggle_file_chunks(),
+            Self::ExpandAll => true,
+            Self::RestoreExpansion => tree.can_restore_expansion(),
+            Self::ToggleOutline => true,
             Self::Stage => tree.can_stage_or_discard(),
-            Self::Discard => tree.can_stage_or_discard(),
+            Self::StageFileHunks => tree.can_stage_file_hunks(),
+            Self::RestageFile => tree.can_restage_file(),
+            Self::Discard { .. } => tree.can_stage_or_discard(),
             Self::Unstage => tree.can_unstage(),
+            Self::SplitChunk => tree.can_split_chunk(),
+            Self::StageAll { .. } | Self::DiscardAll => {
+                !tree.is_read_only() && tree.unstaged_summary().0 > 0
+            }
+            Self::Undiscard => tree.can_undiscard(),
+            Self::YankNewLine => tree.cursor_line_text(true).is_some(),
+            Self::YankOldLine => tree.cursor_line_text(false).is_some(),
+            Self::CopyLineRef => tree.cursor_line_ref().is_some(),
+            Self::CopyAsMarkdown => tree.can_copy_patch(),
+            Self::CopyBeforeAfter => tree.can_copy_before_after(),
+            Self::ExportStaged { .. } => tree.change_summary().staged_files > 0,
             Self::ToggleLegend => true,
+            Self::ShowHelp => true,
+            Self::ShowHunkList => tree.can_show_hunks(),
+            Self::GotoFile => tree.can_goto_file(),
+            Self::PreviewContext => tree.cursor_unstaged_chunk().is_some(),
+            Self::DumpTree => true,
             Self::InitLegend { .. } => true,
+            Self::InitContext { .. } => true,
+            Self::InitTree { .. } => true,
+            Self::InitPostStageCommand(_) => true,
             Self::ExecuteCommand(_) => true,
+            Self::GitCommand => true,
+            Self::SetGlobFilter => true,
+            Self::FilterByAuthor => tree.can_filter_by_author(),
+            Self::CommitWithTemplate { .. } => tree.has_staged_changes(),
+            Self::FormatStaged {
+                staged_files_only, ..
+            } => {
+                !tree.is_read_only() && (!staged_files_only || tree.staged_files().next().is_some())
+            }
+            Self::Batch(actions) => actions.iter().any(|a| a.is_applicable(tree)),
+        }
+    }
+
+    /// Returns the action to run instead of this one for the node under the cursor, if any.
+    pub fn leaf_fallback(&self, tree: &DiffTreeWidget) -> Option<&Action> {
+        match self {
+            Self::ToggleExpand {
+                leaf_action: Some(action),
+            } if tree.is_cursor_on_line() => Some(action),
+            _ => None,
         }
     }
+
+    /// Returns the `type` name used for this action in configuration files.
+    ///
+    /// A batch has no `type`, so it is named after its actions as in `[recenter, force-redraw]`.
+    pub fn type_name(&self) -> String {
+        let name = match self {
+            Self::Quit => "quit",
+            Self::Recenter => "recenter",
+            Self::ForceRedraw => "force-redraw",
+            Self::MoveUp => "move-up",
+            Self::MoveDown => "move-down",
+            Self::MoveLeft => "move-left",
+            Self::MoveRight => "move-right",
+            Self::ToggleExpand { .. } => "toggle-expand",
+            Self::ToggleCompact => "toggle-compact",
+            Self::ToggleChunkContext => "toggle-chunk-context",
+            Self::ToggleStagedContext => "toggle-staged-context",
+            Self::ToggleReconcileFile => "toggle-reconcile-file",
+            Self::ToggleFocus => "toggle-focus",
+            Self::TogglePathStyle => "toggle-path-style",
+            Self::ToggleModeLabels => "toggle-mode-labels",
+            Self::ToggleIgnored => "toggle-ignored",
+            Self::ToggleStagedBase => "toggle-staged-base",
+            Self::ToggleDeletedContent => "toggle-deleted-content",
+            Self::ToggleCombinedView => "toggle-combined-view",
+            Self::ToggleSidebar => "toggle-sidebar",
+            Self::ToggleBinarySizes => "toggle-binary-sizes",
+            Self::CycleSort => "cycle-sort",
+            Self::CollapseAll => "collapse-all",
+            Self::ToggleFileChunks => "toggle-file-chunks",
+            Self::ExpandAll => "expand-all",
+            Self::RestoreExpansion => "restore-expansion",
+            Self::ToggleOutline => "toggle-outline",
+            Self::Stage => "stage",
+            Self::StageFileHunks => "stage-file-hunks",
+            Self::RestageFile => "restage-file",
+            Self::Discard { .. } => "discard",
+            Self::Unstage => "unstage",
+            Self::SplitChunk => "split-chunk",
+            Self::StageAll { .. } => "stage-all",
+            Self::DiscardAll => "discard-all",
+            Self::Undiscard => "undiscard",
+            Self::YankNewLine => "yank-new-line",
+            Self::YankOldLine => "yank-old-line",
+            Self::CopyLineRef => "copy-line-ref",
+            Self::CopyAsMarkdown => "copy-as-markdown",
+            Self::CopyBeforeAfter => "copy-before-after",
+            Self::ExportStaged { .. } => "export-staged",
+            Self::ToggleLegend => "toggle-legend",
+            Self::ShowHelp => "show-help",
+            Self::ShowHunkList => "show-hunk-list",
+            Self::GotoFile => "goto-file",
+            Self::PreviewContext => "preview-context",
+            Self::DumpTree => "dump-tree",
+            Self::InitLegend { .. } => "init-legend",
+            Self::InitContext { .. } => "init-context",
+            Self::InitTree { .. } => "init-tree",
+            Self::InitPostStageCommand(_) => "init-post-stage-command",
+            Self::ExecuteCommand(_) => "execute-command",
+            Self::GitCommand => "git-command",
+            Self::SetGlobFilter => "set-glob-filter",
+            Self::FilterByAuthor => "filter-by-author",
+            Self::CommitWithTemplate { .. } => "commit-with-template",
+            Self::FormatStaged { .. } => "format-staged",
+            Self::Batch(actions) => {
+                let names = actions.iter().map(|a| a.type_name()).collect::<Vec<_>>();
+                return format!("[{}]", names.join(", "));
+            }
+        };
+        name.to_owned()
+    }
 }
 
 impl mame::action::Action for Action {}
@@ -48,20 +268,85 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for Action {
     type Error = nojson::JsonParseError;
 
     fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        if let Ok(actions) = value.to_array() {
+            return Ok(Self::Batch(
+                actions.map(Self::try_from).collect::<Result<_, _>>()?,
+            ));
+        }
+
         let ty = value.to_member("type")?.required()?;
 
         match ty.to_unquoted_string_str()?.as_ref() {
             "quit" => Ok(Self::Quit),
             "recenter" => Ok(Self::Recenter),
+            "force-redraw" => Ok(Self::ForceRedraw),
             "move-up" => Ok(Self::MoveUp),
             "move-down" => Ok(Self::MoveDown),
             "move-left" => Ok(Self::MoveLeft),
             "move-right" => Ok(Self::MoveRight),
-            "toggle-expand" => Ok(Self::ToggleExpand),
+            "toggle-expand" => {
+                let leaf_action = value
+                    .to_member("leaf_action")?
+                    .map(Self::try_from)?
+                    .map(Box::new);
+                Ok(Self::ToggleExpand { leaf_action })
+            }
+            "toggle-compact" => Ok(Self::ToggleCompact),
+            "toggle-chunk-context" => Ok(Self::ToggleChunkContext),
+            "toggle-staged-context" => Ok(Self::ToggleStagedContext),
+            "toggle-reconcile-file" => Ok(Self::ToggleReconcileFile),
+            "toggle-focus" => Ok(Self::ToggleFocus),
+            "toggle-path-style" => Ok(Self::TogglePathStyle),
+            "toggle-mode-labels" => Ok(Self::ToggleModeLabels),
+            "toggle-ignored" => Ok(Self::ToggleIgnored),
+            "toggle-staged-base" => Ok(Self::ToggleStagedBase),
+            "toggle-deleted-content" => Ok(Self::ToggleDeletedContent),
+            "toggle-combined-view" => Ok(Self::ToggleCombinedView),
+            "toggle-sidebar" => Ok(Self::ToggleSidebar),
+            "toggle-binary-sizes" => Ok(Self::ToggleBinarySizes),
+            "cycle-sort" => Ok(Self::CycleSort),
+            "collapse-all" => Ok(Self::CollapseAll),
+            "toggle-file-chunks" => Ok(Self::ToggleFileChunks),
+            "expand-all" => Ok(Self::ExpandAll),
+            "restore-expansion" => Ok(Self::RestoreExpansion),
+            "toggle-outline" => Ok(Self::ToggleOutline),
             "stage" => Ok(Self::Stage),
-            "discard" => Ok(Self::Discard),
+            "stage-file-hunks" => Ok(Self::StageFileHunks),
+            "restage-file" => Ok(Self::RestageFile),
+            "discard" => Ok(Self::Discard {
+                confirm: value
+                    .to_member("confirm")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default(),
+            }),
             "unstage" => Ok(Self::Unstage),
+            "split-chunk" => Ok(Self::SplitChunk),
+            "stage-all" => Ok(Self::StageAll {
+                confirm: value
+                    .to_member("confirm")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default(),
+            }),
+            "discard-all" => Ok(Self::DiscardAll),
+            "undiscard" => Ok(Self::Undiscard),
+            "yank-new-line" => Ok(Self::YankNewLine),
+            "yank-old-line" => Ok(Self::YankOldLine),
+            "copy-line-ref" => Ok(Self::CopyLineRef),
+            "copy-as-markdown" => Ok(Self::CopyAsMarkdown),
+            "copy-before-after" => Ok(Self::CopyBeforeAfter),
+            "export-staged" => Ok(Self::ExportStaged {
+                path: value
+                    .to_member("path")?
+                    .map(String::try_from)?
+                    .unwrap_or_else(|| "mamediff-staged.patch".to_owned())
+                    .into(),
+            }),
             "toggle-legend" => Ok(Self::ToggleLegend),
+            "show-help" => Ok(Self::ShowHelp),
+            "show-hunk-list" => Ok(Self::ShowHunkList),
+            "goto-file" => Ok(Self::GotoFile),
+            "preview-context" => Ok(Self::PreviewContext),
+            "dump-tree" => Ok(Self::DumpTree),
             "init-legend" => {
                 let hide = value
                     .to_member("hide")?
@@ -81,8 +366,206 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for Action {
                     highlight_active_binding,
                 })
             }
+            "init-context" => {
+                // Zero (the default) disables the timeout.
+                let idle_timeout = value
+                    .to_member("idle_timeout_ms")?
+                    .map(u64::try_from)?
+                    .filter(|&ms| ms > 0)
+                    .map(Duration::from_millis);
+                Ok(Self::InitContext { idle_timeout })
+            }
+            "init-tree" => {
+                let wrap_navigation = value
+                    .to_member("wrap_navigation")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let phase_separator = value
+                    .to_member("phase_separator")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let focus_lines = value
+                    .to_member("focus_lines")?
+                    .map(usize::try_from)?
+                    .unwrap_or(DiffTreeWidget::DEFAULT_FOCUS_LINES);
+                let show_child_counts = value
+                    .to_member("show_child_counts")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let elide_path_components = value
+                    .to_member("elide_path_components")?
+                    .map(usize::try_from)?
+                    .unwrap_or_default();
+                let show_deleted_content = value
+                    .to_member("show_deleted_content")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let mark_reload_changes = value
+                    .to_member("mark_reload_changes")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let unstaged_header_color = value
+                    .to_member("unstaged_header_color")?
+                    .map(parse_color)?
+                    .unwrap_or(DiffTreeWidget::DEFAULT_UNSTAGED_HEADER_COLOR);
+                let staged_header_color = value
+                    .to_member("staged_header_color")?
+                    .map(parse_color)?
+                    .unwrap_or(DiffTreeWidget::DEFAULT_STAGED_HEADER_COLOR);
+                let show_mtimes = value
+                    .to_member("show_mtimes")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let show_binary_sizes = value
+                    .to_member("show_binary_sizes")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let ruler_column = value.to_member("ruler_column")?.map(usize::try_from)?;
+                let max_content_cols = value.to_member("max_content_cols")?.map(usize::try_from)?;
+                let center_content = value
+                    .to_member("center_content")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default();
+                let fold_context_lines = value
+                    .to_member("fold_context_lines")?
+                    .map(usize::try_from)?;
+                let startup_expand = value
+                    .to_member("startup_expand")?
+                    .map(parse_startup_expand)?
+                    .unwrap_or_default();
+                Ok(Self::InitTree {
+                    wrap_navigation,
+                    phase_separator,
+                    focus_lines,
+                    show_child_counts,
+                    elide_path_components,
+                    show_deleted_content,
+                    mark_reload_changes,
+                    unstaged_header_color,
+                    staged_header_color,
+                    ruler_column,
+                    max_content_cols,
+                    center_content,
+                    show_mtimes,
+                    show_binary_sizes,
+                    startup_expand,
+                    fold_context_lines,
+                })
+            }
+            "init-post-stage-command" => {
+                let command = value.try_into()?;
+                let enabled = value
+                    .to_member("enabled")?
+                    .map(bool::try_from)?
+                    .unwrap_or(true);
+                Ok(Self::InitPostStageCommand(enabled.then_some(command)))
+            }
             "execute-command" => Ok(Self::ExecuteCommand(value.try_into()?)),
+            "git-command" => Ok(Self::GitCommand),
+            "set-glob-filter" => Ok(Self::SetGlobFilter),
+            "filter-by-author" => Ok(Self::FilterByAuthor),
+            "commit-with-template" => Ok(Self::CommitWithTemplate {
+                template: value
+                    .to_member("template")?
+                    .map(String::try_from)?
+                    .unwrap_or_default(),
+            }),
+            "format-staged" => Ok(Self::FormatStaged {
+                command: value.try_into()?,
+                staged_files_only: value
+                    .to_member("staged_files_only")?
+                    .map(bool::try_from)?
+                    .unwrap_or_default(),
+            }),
             type_name => Err(ty.invalid(format!("unknown action type: {type_name:?}"))),
         }
     }
 }
+
+// Parses a color name (e.g., "red") or an RGB hex code (e.g., "#ff8800").
+fn parse_color(
+    value: nojson::RawJsonValue<'_, '_>,
+) -> Result<TerminalColor, nojson::JsonParseError> {
+    let s = value.to_unquoted_string_str()?;
+    let color = match s.as_ref() {
+        "black" => TerminalColor::BLACK,
+        "red" => TerminalColor::RED,
+        "green" => TerminalColor::GREEN,
+        "yellow" => TerminalColor::YELLOW,
+        "blue" => TerminalColor::BLUE,
+        "magenta" => TerminalColor::MAGENTA,
+        "cyan" => TerminalColor::CYAN,
+        "white" => TerminalColor::WHITE,
+        hex => {
+            let rgb = hex
+                .strip_prefix('#')
+                .filter(|h| h.len() == 6)
+                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                .ok_or_else(|| value.invalid(format!("unknown color: {hex:?}")))?;
+            TerminalColor::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+        }
+    };
+    Ok(color)
+}
+
+fn parse_startup_expand(
+    value: nojson::RawJsonValue<'_, '_>,
+) -> Result<StartupExpand, nojson::JsonParseError> {
+    match value.to_unquoted_string_str()?.as_ref() {
+        "fill" => Ok(StartupExpand::Fill),
+        "first-file" => Ok(StartupExpand::FirstFile),
+        s => Err(value.invalid(format!("unknown startup_expand: {s:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mame::action::BindingConfig;
+
+    use super::*;
+
+    #[test]
+    fn unknown_action_location() {
+        let text = r#"{
+  "setup": {"context": "@main"},
+  "bindings": {
+    "@main": [
+      {"triggers": ["q"], "action": {"type": "quitt"}}
+    ]
+  }
+}"#;
+        let e = BindingConfig::<Action>::load_from_str("test.jsonc", text)
+            .expect_err("unknown action type");
+        let message = e.to_string();
+        assert!(
+            message.contains(r#"unknown action type: "quitt""#),
+            "{message}"
+        );
+        assert!(message.contains("test.jsonc:5:"), "{message}");
+    }
+
+    #[test]
+    fn header_colors() -> Result<(), nojson::JsonParseError> {
+        let action = |text: &str| {
+            let json = nojson::RawJson::parse(text)?;
+            Action::try_from(json.value())
+        };
+        let Action::InitTree {
+            unstaged_header_color,
+            staged_header_color,
+            ..
+        } = action(r##"{"type": "init-tree", "staged_header_color": "#0080ff"}"##)?
+        else {
+            panic!("not init-tree");
+        };
+        assert_eq!(
+            unstaged_header_color,
+            DiffTreeWidget::DEFAULT_UNSTAGED_HEADER_COLOR
+        );
+        assert_eq!(staged_header_color, TerminalColor::new(0, 128, 255));
+
+        assert!(action(r#"{"type": "init-tree", "staged_header_color": "pink"}"#).is_err());
+        assert!(action(r##"{"type": "init-tree", "staged_header_color": "#12345"}"##).is_err());
+        Ok(())
+    }
+}
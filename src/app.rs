@@ -1,11 +1,33 @@
+use std::{
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
 use mame::action::{BindingConfig, BindingContextName};
 use orfail::OrFail;
-use tuinix::{Terminal, TerminalEvent};
+use tuinix::{
+    KeyCode, KeyInput, Terminal, TerminalEvent, TerminalInput, TerminalPosition, TerminalSize,
+    TerminalStyle,
+};
 
 use crate::{
-    action::Action, canvas::Canvas, widget_diff_tree::DiffTreeWidget, widget_legend::LegendWidget,
+    action::Action,
+    canvas::{Canvas, Charset, Token},
+    clipboard,
+    diff::Diff,
+    event_log::EventLog,
+    git,
+    widget_chunk_preview::{ChunkPreviewInput, ChunkPreviewWidget},
+    widget_diff_tree::{DiffTreeWidget, StartupExpand},
+    widget_help::HelpWidget,
+    widget_hunk_list::{HunkListInput, HunkListWidget},
+    widget_legend::LegendWidget,
 };
 
+// Below this size, a message is shown instead of the tree.
+const MIN_TERMINAL_SIZE: TerminalSize = TerminalSize::rows_cols(4, 30);
+
 #[derive(Debug)]
 pub struct App {
     terminal: Terminal,
@@ -17,6 +39,37 @@ pub struct App {
     tree: DiffTreeWidget,
     legend: LegendWidget,
     preview: Option<mame::preview::TextPreview>,
+    // Command run with the paths of the staged files after each stage operation.
+    post_stage_command: Option<mame::command::ExternalCommand>,
+    status: Option<String>,
+    // Summary of the HEAD commit shown in the status line (if enabled).
+    head_summary: Option<String>,
+    // Operation waiting for confirmation, and the preview replaced by the changes to be discarded.
+    confirmation: Option<(ConfirmedOperation, Option<mame::preview::TextPreview>)>,
+    // Line being typed in the status line, and what it is for.
+    prompt: Option<(PromptKind, String)>,
+    help: Option<HelpWidget>,
+    hunk_list: Option<HunkListWidget>,
+    chunk_preview: Option<ChunkPreviewWidget>,
+    charset: Charset,
+    // Idle time after which a non-initial context falls back to the initial one.
+    context_timeout: Option<Duration>,
+    last_input_time: Instant,
+    // Interval of the periodic reload of the diffs.
+    refresh_interval: Option<Duration>,
+    last_refresh_time: Instant,
+    event_log: Option<EventLog>,
+    // Load count of the tree when the last event was logged.
+    logged_load_count: usize,
+}
+
+// Operations that can be run after confirmation.
+#[derive(Debug, Clone, Copy)]
+enum ConfirmedOperation {
+    StageAll,
+    DiscardAll,
+    // Discards the changes under the cursor.
+    Discard,
 }
 
 impl App {
@@ -33,34 +86,232 @@ impl App {
             tree,
             legend: LegendWidget::default(),
             preview: None,
+            post_stage_command: None,
+            status: None,
+            head_summary: None,
+            confirmation: None,
+            prompt: None,
+            help: None,
+            hunk_list: None,
+            chunk_preview: None,
+            charset: Charset::detect(),
+            context_timeout: None,
+            last_input_time: Instant::now(),
+            refresh_interval: None,
+            last_refresh_time: Instant::now(),
+            event_log: None,
+            logged_load_count: 0,
         })
     }
 
-    pub fn run(mut self) -> orfail::Result<()> {
+    pub fn run(&mut self) -> orfail::Result<()> {
         if let Some(action) = self.config.setup_action().cloned() {
-            self.handle_action(action).or_fail()?;
+            self.handle_logged_action(action).or_fail()?;
         }
         self.render().or_fail()?;
 
         while !self.exit {
-            let Some(event) = self.terminal.poll_event(&[], &[], None).or_fail()? else {
+            let context_timeout = self
+                .context_timeout
+                .filter(|_| self.context != *self.config.initial_context())
+                .map(|t| idle_time_left(t, self.last_input_time));
+            let refresh_timeout = self
+                .refresh_interval
+                .map(|t| t.saturating_sub(self.last_refresh_time.elapsed()));
+            let timeout = poll_timeout(context_timeout, refresh_timeout);
+            let Some(event) = self.terminal.poll_event(&[], &[], timeout).or_fail()? else {
+                if self.is_context_expired() {
+                    self.context = self.config.initial_context().clone();
+                    self.render().or_fail()?;
+                }
+                if self.is_refresh_due() {
+                    self.refresh().or_fail()?;
+                }
                 continue;
             };
+            if matches!(event, TerminalEvent::Input(_)) {
+                self.last_input_time = Instant::now();
+            }
             self.handle_event(event).or_fail()?;
         }
 
         Ok(())
     }
 
-    fn render(&mut self) -> orfail::Result<()> {
-        if self.terminal.size().is_empty() {
+    fn is_context_expired(&self) -> bool {
+        self.context != *self.config.initial_context()
+            && self
+                .context_timeout
+                .is_some_and(|t| idle_time_left(t, self.last_input_time).is_zero())
+    }
+
+    fn is_refresh_due(&self) -> bool {
+        self.refresh_interval
+            .is_some_and(|t| self.last_refresh_time.elapsed() >= t)
+    }
+
+    // Reloads the diffs unless the user is looking at something on top of the tree.
+    fn refresh(&mut self) -> orfail::Result<()> {
+        self.last_refresh_time = Instant::now();
+        if self.preview.is_some()
+            || self.confirmation.is_some()
+            || self.prompt.is_some()
+            || self.help.is_some()
+            || self.hunk_list.is_some()
+            || self.chunk_preview.is_some()
+        {
             return Ok(());
         }
+        self.tree.refresh().or_fail()?;
+        self.update_author_mismatches();
+        self.log_reload_if_need().or_fail()?;
+        self.scroll_if_need();
+        self.render().or_fail()
+    }
+
+    // Appends the handled actions and reloads to the given file as JSON lines.
+    pub fn set_event_log(&mut self, path: &Path) -> orfail::Result<()> {
+        self.event_log = Some(EventLog::open(path).or_fail()?);
+        self.logged_load_count = self.tree.load_count();
+        Ok(())
+    }
+
+    fn handle_logged_action(&mut self, action: Action) -> orfail::Result<()> {
+        let name = action.type_name();
+        self.handle_action(action).or_fail()?;
+        if let Some(log) = &mut self.event_log {
+            log.action(&name, self.tree.cursor_path()).or_fail()?;
+        }
+        self.log_reload_if_need().or_fail()
+    }
+
+    fn log_reload_if_need(&mut self) -> orfail::Result<()> {
+        let Some(log) = &mut self.event_log else {
+            return Ok(());
+        };
+        if self.tree.load_count() != self.logged_load_count {
+            self.logged_load_count = self.tree.load_count();
+            log.reload(self.tree.cursor_path()).or_fail()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_refresh_interval(&mut self, interval: Duration) {
+        self.refresh_interval = Some(interval);
+    }
+
+    pub fn set_dry_run(&mut self) {
+        self.tree.dry_run = true;
+    }
+
+    pub fn staged_patch(&self) -> orfail::Result<String> {
+        self.tree.staged_patch().or_fail()
+    }
+
+    // Moves the cursor to the given file (relative to the current directory, or absolute).
+    pub fn select_file(&mut self, path: &Path) -> orfail::Result<()> {
+        let root_dir = std::env::current_dir().or_fail()?;
+        let path = match path.strip_prefix(&root_dir) {
+            Ok(path) => path.to_path_buf(),
+            Err(_) => normalize_path(&git::cwd_prefix().join(path)),
+        };
+        if self.tree.select_file(&path).or_fail()? {
+            self.scroll_if_need();
+        } else {
+            self.status = Some(format!("No changes in {}", path.display()));
+        }
+        Ok(())
+    }
+
+    pub fn set_charset(&mut self, charset: Charset) {
+        self.charset = charset;
+    }
+
+    // Shows the short hash and subject of the HEAD commit in the status line.
+    pub fn show_head_summary(&mut self) {
+        self.head_summary = Some(match git::head_summary() {
+            Some((hash, subject)) => format!("HEAD: {hash} {subject}"),
+            None => "HEAD: no commits yet".to_owned(),
+        });
+    }
+
+    // Runs the setup action and returns the first screen as text with ANSI styles,
+    // without entering the event loop.
+    // The legend is not included because it is drawn directly onto the terminal frame.
+    pub fn render_once(mut self) -> orfail::Result<String> {
+        if let Some(action) = self.config.setup_action().cloned() {
+            self.handle_action(action).or_fail()?;
+        }
+        Ok(self.render_canvas().to_styled_text())
+    }
 
+    fn render_canvas(&self) -> Canvas {
         let mut canvas = Canvas::new(self.frame_row_start, self.terminal.size());
+        canvas.set_charset(self.charset);
         self.tree.render(&mut canvas);
+        let prompt = self
+            .prompt
+            .as_ref()
+            .map(|(kind, input)| format!("{}{input}_", kind.label()));
+        let filters = [
+            self.tree
+                .glob_filter()
+                .map(|glob| format!("filter: {glob}")),
+            self.tree
+                .author_filter()
+                .map(|author| format!("author: {author}")),
+        ];
+        let filter = Some(filters.into_iter().flatten().collect::<Vec<_>>().join("  "))
+            .filter(|f| !f.is_empty());
+        let staged_base = self
+            .tree
+            .staged_against_worktree()
+            .then(|| "staged: index vs worktree (not what will be committed)".to_owned());
+        if let Some(status) = prompt
+            .as_ref()
+            .or(self.status.as_ref())
+            .or(filter.as_ref())
+            .or(staged_base.as_ref())
+            .or(self.head_summary.as_ref())
+        {
+            let size = canvas.frame_size();
+            let position = TerminalPosition::row_col(canvas.frame_row_range().end - 1, 0);
+            let text = format!("{status:<0$}", size.cols);
+            canvas.draw_at(
+                position,
+                Token::with_style(text, TerminalStyle::new().reverse()),
+            );
+        }
+        canvas
+    }
+
+    fn render(&mut self) -> orfail::Result<()> {
+        if self.terminal.size().is_empty() {
+            return Ok(());
+        }
+
+        if let Some(canvas) = too_small_canvas(self.terminal.size(), self.charset) {
+            self.terminal.draw(canvas.into_frame()).or_fail()?;
+            return Ok(());
+        }
+
+        if let Some(help) = &self.help {
+            let frame = help.render(self.terminal.size(), self.charset);
+            self.terminal.draw(frame).or_fail()?;
+            return Ok(());
+        }
+        if let Some(hunk_list) = &self.hunk_list {
+            let frame = hunk_list.render(self.terminal.size(), self.charset);
+            self.terminal.draw(frame).or_fail()?;
+            return Ok(());
+        }
+        if let Some(chunk_preview) = &self.chunk_preview {
+            let frame = chunk_preview.render(self.terminal.size(), self.charset);
+            self.terminal.draw(frame).or_fail()?;
+            return Ok(());
+        }
 
-        let mut frame = canvas.into_frame();
+        let mut frame = self.render_canvas().into_frame();
         if let Some(preview) = &mut self.preview {
             preview.render(&mut frame).or_fail()?;
         }
@@ -84,6 +335,63 @@ impl App {
                 self.render().or_fail()
             }
             TerminalEvent::Input(input) => {
+                self.status = None;
+                if let Some(help) = &mut self.help {
+                    let size = self.terminal.size();
+                    if !matches!(input, TerminalInput::Key(key) if help.handle_key(key, size)) {
+                        self.help = None;
+                    }
+                    return self.render().or_fail();
+                }
+                if let Some(hunk_list) = &mut self.hunk_list {
+                    if let TerminalInput::Key(key) = input {
+                        match hunk_list.handle_key(key, self.terminal.size()) {
+                            HunkListInput::Continue => {}
+                            HunkListInput::Close => self.hunk_list = None,
+                            HunkListInput::Jump(path) => {
+                                self.hunk_list = None;
+                                if self.tree.jump_to(&path).or_fail()? {
+                                    self.scroll_if_need();
+                                }
+                            }
+                        }
+                    }
+                    return self.render().or_fail();
+                }
+                if let Some(chunk_preview) = &mut self.chunk_preview {
+                    if let TerminalInput::Key(key) = input {
+                        match chunk_preview.handle_key(key, self.terminal.size()) {
+                            ChunkPreviewInput::Continue => {}
+                            ChunkPreviewInput::Close => self.chunk_preview = None,
+                            ChunkPreviewInput::Stage(range) => {
+                                self.chunk_preview = None;
+                                self.stage_chunk_lines(range).or_fail()?;
+                            }
+                        }
+                    }
+                    return self.render().or_fail();
+                }
+                if let Some((kind, input_line)) = self.prompt.take() {
+                    if let TerminalInput::Key(key) = input {
+                        self.handle_prompt_input(kind, input_line, key).or_fail()?;
+                    }
+                    return self.render().or_fail();
+                }
+                if let Some((operation, previous_preview)) = self.confirmation.take() {
+                    if !matches!(operation, ConfirmedOperation::StageAll) {
+                        // Brings back the preview (e.g., of a dry run) shown before the question.
+                        self.preview = previous_preview;
+                    }
+                    if let TerminalInput::Key(key) = input
+                        && key.code == KeyCode::Char('y')
+                        && !key.ctrl
+                        && !key.alt
+                    {
+                        self.run_confirmed_operation(operation).or_fail()?;
+                    }
+                    return self.render().or_fail();
+                }
+
                 let bindings = self.config.get_bindings(&self.context).or_fail()?;
                 if let Some((index, binding)) =
                     bindings.iter().enumerate().find(|(_, b)| b.matches(input))
@@ -96,7 +404,8 @@ impl App {
                         if self.legend.highlight_active_binding {
                             self.render().or_fail()?;
                         }
-                        self.handle_action(action).or_fail()?;
+                        self.handle_logged_action(action).or_fail()?;
+                        self.update_author_mismatches();
                         self.current_binding_index = None;
                     }
 
@@ -119,6 +428,9 @@ impl App {
             Action::Recenter => {
                 self.recenter();
             }
+            Action::ForceRedraw => {
+                self.invalidate_screen().or_fail()?;
+            }
             Action::MoveUp => {
                 if self.tree.cursor_up().or_fail()? {
                     self.scroll_if_need();
@@ -139,27 +451,201 @@ impl App {
                     self.scroll_if_need();
                 }
             }
-            Action::ToggleExpand => {
+            Action::ToggleExpand { .. } => {
+                if let Some(leaf_action) = action.leaf_fallback(&self.tree) {
+                    return self.handle_action(leaf_action.clone()).or_fail();
+                }
                 self.tree.toggle().or_fail()?;
             }
+            Action::ToggleCompact => {
+                self.tree.toggle_compact().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleChunkContext => {
+                self.tree.toggle_chunk_context().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleStagedContext => {
+                self.tree.toggle_staged_context().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleReconcileFile => {
+                self.tree.toggle_reconcile_file().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleFocus => {
+                self.tree.toggle_focus();
+                self.scroll_if_need();
+            }
+            Action::TogglePathStyle => {
+                self.tree.toggle_path_style();
+            }
+            Action::ToggleModeLabels => {
+                self.tree.toggle_mode_labels();
+            }
+            Action::ToggleIgnored => {
+                self.tree.toggle_ignored().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleStagedBase => {
+                self.tree.toggle_staged_base().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleDeletedContent => {
+                self.tree.toggle_deleted_content().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleBinarySizes => {
+                self.tree.toggle_binary_sizes();
+            }
+            Action::ToggleCombinedView => {
+                self.tree.toggle_combined_view().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleSidebar => {
+                self.tree.toggle_sidebar().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::CycleSort => {
+                self.tree.cycle_file_order().or_fail()?;
+                self.status = Some(format!("Sorted by {}", self.tree.file_order().label()));
+                self.scroll_if_need();
+            }
+            Action::CollapseAll => {
+                self.tree.collapse_all().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleFileChunks => {
+                self.tree.toggle_file_chunks().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ExpandAll => {
+                self.tree.expand_all().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::RestoreExpansion => {
+                self.tree.restore_expansion().or_fail()?;
+                self.scroll_if_need();
+            }
+            Action::ToggleOutline => {
+                self.tree.toggle_outline().or_fail()?;
+                self.scroll_if_need();
+            }
             Action::Stage => {
+                let paths = self.tree.cursor_unstaged_paths();
                 if self.tree.stage().or_fail()? {
                     self.scroll_if_need();
+                    self.warn_staged_conflict_markers();
+                    self.run_post_stage_command(paths).or_fail()?;
                 }
+                self.show_dry_run_patch();
             }
-            Action::Discard => {
-                if self.tree.discard().or_fail()? {
+            Action::StageFileHunks => {
+                let paths = self.tree.cursor_unstaged_paths();
+                if self.tree.stage_file_hunks().or_fail()? {
+                    self.scroll_if_need();
+                    self.warn_staged_conflict_markers();
+                    self.run_post_stage_command(paths).or_fail()?;
+                }
+                self.show_dry_run_patch();
+            }
+            Action::RestageFile => {
+                let paths = self.tree.cursor_restage_path().into_iter().collect();
+                if self.tree.restage_file().or_fail()? {
                     self.scroll_if_need();
+                    self.run_post_stage_command(paths).or_fail()?;
+                }
+                self.show_dry_run_patch();
+            }
+            Action::Discard { confirm } => {
+                if confirm {
+                    self.confirm(ConfirmedOperation::Discard).or_fail()?;
+                } else {
+                    self.run_confirmed_operation(ConfirmedOperation::Discard)
+                        .or_fail()?;
                 }
             }
             Action::Unstage => {
                 if self.tree.unstage().or_fail()? {
                     self.scroll_if_need();
                 }
+                self.show_dry_run_patch();
+            }
+            Action::SplitChunk => {
+                if self.tree.split_chunk().or_fail()? {
+                    self.scroll_if_need();
+                }
+            }
+            Action::StageAll { confirm } => {
+                if confirm {
+                    self.confirm(ConfirmedOperation::StageAll).or_fail()?;
+                } else {
+                    self.run_confirmed_operation(ConfirmedOperation::StageAll)
+                        .or_fail()?;
+                }
+            }
+            Action::DiscardAll => {
+                // Always confirm as this cannot be undone.
+                self.confirm(ConfirmedOperation::DiscardAll).or_fail()?;
+            }
+            Action::Undiscard => {
+                if let Err(e) = self.tree.undiscard() {
+                    self.show_error(&format!("Failed to undiscard:\n{}", e.message));
+                } else if let Err(e) = self.tree.reload() {
+                    // The changes are back in the working tree even if they are not shown yet.
+                    self.show_error(&format!(
+                        "Restored the discarded changes, but failed to reload:\n{}",
+                        e.message
+                    ));
+                } else {
+                    self.scroll_if_need();
+                }
+            }
+            Action::YankNewLine => {
+                self.yank_line(true).or_fail()?;
+            }
+            Action::YankOldLine => {
+                self.yank_line(false).or_fail()?;
+            }
+            Action::CopyLineRef => {
+                self.copy_line_ref().or_fail()?;
+            }
+            Action::CopyAsMarkdown => {
+                self.copy_as_markdown().or_fail()?;
+            }
+            Action::CopyBeforeAfter => {
+                self.copy_before_after().or_fail()?;
+            }
+            Action::ExportStaged { path } => {
+                self.export_staged(&path).or_fail()?;
             }
             Action::ToggleLegend => {
                 self.legend.toggle_hide();
             }
+            Action::ShowHelp => {
+                self.help = Some(HelpWidget::new(&self.config, &self.context));
+            }
+            Action::ShowHunkList => {
+                self.hunk_list = Some(HunkListWidget::new(self.tree.hunks()));
+            }
+            Action::GotoFile => {
+                self.prompt = Some((PromptKind::GotoFile, String::new()));
+            }
+            Action::PreviewContext => {
+                if let Some((file, chunk)) = self.tree.cursor_unstaged_chunk() {
+                    let content = std::fs::read_to_string(file.path()).ok();
+                    self.chunk_preview = Some(ChunkPreviewWidget::new(
+                        file.path().display().to_string(),
+                        chunk,
+                        content.as_deref(),
+                        self.terminal.size(),
+                    ));
+                }
+            }
+            Action::DumpTree => {
+                let pane = mame::preview::TextPreviewPane::new("dump-tree", &self.tree.dump_tree());
+                self.preview = Some(mame::preview::TextPreview::new(Some(pane), None));
+            }
             Action::InitLegend {
                 hide,
                 label_show,
@@ -171,14 +657,177 @@ impl App {
                 self.legend.hide = hide;
                 self.legend.highlight_active_binding = highlight_active_binding;
             }
+            Action::InitContext { idle_timeout } => {
+                self.context_timeout = idle_timeout;
+            }
+            Action::InitTree {
+                wrap_navigation,
+                phase_separator,
+                focus_lines,
+                show_child_counts,
+                elide_path_components,
+                show_deleted_content,
+                mark_reload_changes,
+                unstaged_header_color,
+                staged_header_color,
+                ruler_column,
+                max_content_cols,
+                center_content,
+                show_mtimes,
+                show_binary_sizes,
+                startup_expand,
+                fold_context_lines,
+            } => {
+                self.tree.wrap_navigation = wrap_navigation;
+                self.tree.phase_separator = phase_separator;
+                self.tree.focus_lines = focus_lines;
+                self.tree.show_child_counts = show_child_counts;
+                self.tree.elide_path_components = elide_path_components;
+                self.tree.mark_reload_changes = mark_reload_changes;
+                self.tree.header_colors = [unstaged_header_color, staged_header_color];
+                self.tree.ruler_column = ruler_column;
+                self.tree.max_content_cols = max_content_cols;
+                self.tree.center_content = center_content;
+                self.tree.set_show_mtimes(show_mtimes);
+                self.tree.set_show_binary_sizes(show_binary_sizes);
+                self.tree
+                    .set_fold_context_lines(fold_context_lines)
+                    .or_fail()?;
+                self.tree
+                    .set_show_deleted_content(show_deleted_content)
+                    .or_fail()?;
+                if startup_expand == StartupExpand::FirstFile {
+                    self.tree.expand_first_file_only().or_fail()?;
+                }
+            }
+            Action::InitPostStageCommand(command) => {
+                self.post_stage_command = command;
+            }
             Action::ExecuteCommand(a) => {
-                self.execute_command(&a).or_fail()?;
+                let Some(command) = self.expand_env_vars(a) else {
+                    return Ok(());
+                };
+                self.execute_command(&command).or_fail()?;
+            }
+            Action::GitCommand => {
+                self.prompt = Some((PromptKind::GitCommand, String::new()));
+            }
+            Action::SetGlobFilter => {
+                let glob = self.tree.glob_filter().unwrap_or_default().to_owned();
+                self.prompt = Some((PromptKind::Glob, glob));
+            }
+            Action::FilterByAuthor => {
+                let author = self.tree.author_filter().unwrap_or_default().to_owned();
+                self.prompt = Some((PromptKind::Author, author));
+            }
+            Action::CommitWithTemplate { template } => {
+                let kind = PromptKind::Commit {
+                    template: template.clone(),
+                };
+                self.prompt = Some((kind, template));
+            }
+            Action::FormatStaged {
+                command,
+                staged_files_only,
+            } => {
+                let Some(mut command) = self.expand_env_vars(command) else {
+                    return Ok(());
+                };
+                if staged_files_only {
+                    let files = self.tree.staged_files();
+                    command.args.extend(files.map(|f| f.display().to_string()));
+                }
+                self.execute_command(&command).or_fail()?;
+            }
+            Action::Batch(actions) => {
+                for action in actions {
+                    self.handle_action(action).or_fail()?;
+                }
             }
         }
         Ok(())
     }
 
-    fn execute_command(&mut self, command: &mame::command::ExternalCommand) -> orfail::Result<()> {
+    // If expanding environment variables fails, the error is shown in the preview.
+    fn expand_env_vars(
+        &mut self,
+        command: mame::command::ExternalCommand,
+    ) -> Option<mame::command::ExternalCommand> {
+        match expand_command_env_vars(command) {
+            Ok(command) => Some(command),
+            Err(e) => {
+                self.show_error(&e);
+                None
+            }
+        }
+    }
+
+    // Stages the lines selected in the chunk preview, like the `stage` action does for a chunk.
+    fn stage_chunk_lines(&mut self, range: Range<usize>) -> orfail::Result<()> {
+        let paths = self.tree.cursor_unstaged_paths();
+        if self.tree.stage_chunk_lines(range).or_fail()? {
+            self.scroll_if_need();
+            self.warn_staged_conflict_markers();
+            self.run_post_stage_command(paths).or_fail()?;
+        }
+        self.show_dry_run_patch();
+        Ok(())
+    }
+
+    // Warns in the status line if the last staging added conflict markers to the index.
+    fn warn_staged_conflict_markers(&mut self) {
+        if self.tree.take_staged_conflict_markers() {
+            self.status = Some("Warning: staged lines contain conflict markers".to_owned());
+        }
+    }
+
+    // Shows the patch that the last operation would have applied if not in dry-run mode.
+    fn show_dry_run_patch(&mut self) {
+        if let Some((title, patch)) = self.tree.take_dry_run_patch() {
+            let pane = mame::preview::TextPreviewPane::new(title, &patch);
+            self.preview = Some(mame::preview::TextPreview::new(Some(pane), None));
+        }
+    }
+
+    // Blames the staged files expanded since the last update, if the author filter is enabled.
+    // Files that cannot be blamed are left undimmed and reported in the status line.
+    fn update_author_mismatches(&mut self) {
+        if let Err(e) = self.tree.update_author_mismatches() {
+            let reason = e.message.lines().next().unwrap_or_default();
+            self.status = Some(format!("Failed to blame: {reason}"));
+        }
+    }
+
+    fn show_error(&mut self, message: &str) {
+        let error_pane = mame::preview::TextPreviewPane::new("error", message);
+        self.preview = Some(mame::preview::TextPreview::new(None, Some(error_pane)));
+    }
+
+    // Failures are reported in the status line; the staged changes are kept as they are.
+    fn run_post_stage_command(&mut self, paths: Vec<PathBuf>) -> orfail::Result<()> {
+        let Some(command) = self.post_stage_command.clone() else {
+            return Ok(());
+        };
+        let Some(mut command) = self.expand_env_vars(command) else {
+            return Ok(());
+        };
+        command
+            .args
+            .extend(paths.iter().map(|p| p.display().to_string()));
+        if let Some(status) = self.execute_command(&command).or_fail()?
+            && !status.success()
+        {
+            self.status = Some(format!("Post-stage command failed ({status})"));
+        }
+        Ok(())
+    }
+
+    // Returns `None` if the command could not be run (e.g., not found).
+    // The error is shown in the preview instead of terminating the app.
+    fn execute_command(
+        &mut self,
+        command: &mame::command::ExternalCommand,
+    ) -> orfail::Result<Option<std::process::ExitStatus>> {
         let executing_pane = mame::preview::TextPreviewPane::new(
             "executing",
             &format!("$ {}", command.command_line()),
@@ -186,10 +835,20 @@ impl App {
         self.preview = Some(mame::preview::TextPreview::new(Some(executing_pane), None));
         self.render().or_fail()?;
 
-        let output = command.execute().or_fail()?;
+        let output = match command.execute() {
+            Ok(output) => output,
+            Err(e) => {
+                self.show_error(&command_error_message(command, &e));
+                return Ok(None);
+            }
+        };
 
         if output.status.success() {
             self.tree.reload().or_fail()?;
+            if self.head_summary.is_some() {
+                // The command may have created a commit.
+                self.show_head_summary();
+            }
         }
 
         let stdout_pane =
@@ -200,6 +859,243 @@ impl App {
             Some(stdout_pane),
             Some(stderr_pane),
         ));
+        Ok(Some(output.status))
+    }
+
+    // Edits the line in the status line; Enter submits it and Esc (or C-c) cancels it.
+    fn handle_prompt_input(
+        &mut self,
+        kind: PromptKind,
+        mut line: String,
+        key: KeyInput,
+    ) -> orfail::Result<()> {
+        match edit_input_line(&mut line, key) {
+            InputLineEdit::Submit => self.submit_prompt(kind, line).or_fail(),
+            InputLineEdit::Cancel => Ok(()),
+            InputLineEdit::Continue => {
+                self.prompt = Some((kind, line));
+                Ok(())
+            }
+        }
+    }
+
+    fn submit_prompt(&mut self, kind: PromptKind, line: String) -> orfail::Result<()> {
+        let input = line.trim();
+        match kind {
+            PromptKind::GitCommand => self.run_git_command(&line).or_fail(),
+            PromptKind::Glob => {
+                let glob = Some(input.to_owned()).filter(|g| !g.is_empty());
+                self.tree.set_glob_filter(glob).or_fail()?;
+                self.scroll_if_need();
+                Ok(())
+            }
+            PromptKind::Author => {
+                let author = Some(input.to_owned()).filter(|a| !a.is_empty());
+                self.tree.set_author_filter(author);
+                self.update_author_mismatches();
+                Ok(())
+            }
+            PromptKind::GotoFile => {
+                let moved = match input.parse() {
+                    Ok(number) => self.tree.goto_file(number).or_fail()?,
+                    Err(_) => false,
+                };
+                if moved {
+                    self.scroll_if_need();
+                } else if !input.is_empty() {
+                    self.status = Some(format!("No such file: {input}"));
+                }
+                Ok(())
+            }
+            PromptKind::Commit { template } => {
+                // Submitting the template as it is (e.g., `feat:`) means no message was entered.
+                let message = if input == template.trim() { "" } else { input };
+                self.commit(message).or_fail()
+            }
+        }
+    }
+
+    // Failures (e.g., a rejecting hook) are shown in the preview.
+    fn commit(&mut self, message: &str) -> orfail::Result<()> {
+        if message.is_empty() {
+            self.status = Some("Commit aborted due to empty message".to_owned());
+            return Ok(());
+        }
+        let output = match git::commit(message) {
+            Ok(output) => output,
+            Err(e) => {
+                self.show_error(&e.message);
+                return Ok(());
+            }
+        };
+        if !output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            self.show_error(&format!("{stdout}{stderr}"));
+            return Ok(());
+        }
+
+        self.tree.reload().or_fail()?;
+        if self.head_summary.is_some() {
+            self.show_head_summary();
+        }
+        self.scroll_if_need();
+        self.status =
+            git::head_summary().map(|(hash, subject)| format!("Committed {hash} {subject}"));
+        Ok(())
+    }
+
+    // The diffs are reloaded even if the command fails, as it may have changed something.
+    fn run_git_command(&mut self, line: &str) -> orfail::Result<()> {
+        let args = match split_command_line(line) {
+            Ok(args) if args.is_empty() => return Ok(()),
+            Ok(args) => args,
+            Err(e) => {
+                self.show_error(&e);
+                return Ok(());
+            }
+        };
+        let output = match git::run(&args) {
+            Ok(output) => output,
+            Err(e) => {
+                self.show_error(&e.message);
+                return Ok(());
+            }
+        };
+
+        self.tree.reload().or_fail()?;
+        if self.head_summary.is_some() {
+            self.show_head_summary();
+        }
+        self.scroll_if_need();
+        if !output.status.success() {
+            self.status = Some(format!("git {} failed ({})", args[0], output.status));
+        }
+
+        let stdout_pane =
+            mame::preview::TextPreviewPane::new("stdout", &String::from_utf8_lossy(&output.stdout));
+        let stderr_pane =
+            mame::preview::TextPreviewPane::new("stderr", &String::from_utf8_lossy(&output.stderr));
+        self.preview = Some(mame::preview::TextPreview::new(
+            Some(stdout_pane),
+            Some(stderr_pane),
+        ));
+        Ok(())
+    }
+
+    // Asks for confirmation in the status line; the operation runs if the next key is 'y'.
+    // The changes to be discarded are shown in the preview so that they can be reviewed first.
+    fn confirm(&mut self, operation: ConfirmedOperation) -> orfail::Result<()> {
+        let (files, chunks) = self.tree.unstaged_summary();
+        let (status, patch) = match operation {
+            ConfirmedOperation::StageAll => (
+                format!("Stage all {files} files ({chunks} chunks)? [y/N]"),
+                None,
+            ),
+            ConfirmedOperation::DiscardAll => (
+                format!("Discard all {files} files ({chunks} chunks)? [y/N]"),
+                Some(self.tree.discard_all_patch().or_fail()?),
+            ),
+            ConfirmedOperation::Discard => {
+                let Some(patch) = self.tree.discard_patch().or_fail()? else {
+                    return Ok(());
+                };
+                (
+                    "Discard the previewed changes? [y/N]".to_owned(),
+                    Some(patch),
+                )
+            }
+        };
+        let previous_preview = patch.and_then(|patch| {
+            let pane = mame::preview::TextPreviewPane::new("to be discarded", &patch);
+            self.preview
+                .replace(mame::preview::TextPreview::new(Some(pane), None))
+        });
+        self.status = Some(status);
+        self.confirmation = Some((operation, previous_preview));
+        Ok(())
+    }
+
+    fn run_confirmed_operation(&mut self, operation: ConfirmedOperation) -> orfail::Result<()> {
+        match operation {
+            ConfirmedOperation::StageAll => {
+                let paths = self.tree.unstaged_paths();
+                if self.tree.stage_all().or_fail()? {
+                    self.scroll_if_need();
+                    self.warn_staged_conflict_markers();
+                    self.run_post_stage_command(paths).or_fail()?;
+                }
+            }
+            ConfirmedOperation::DiscardAll => {
+                if self.tree.discard_all().or_fail()? {
+                    self.scroll_if_need();
+                }
+            }
+            ConfirmedOperation::Discard => {
+                if self.tree.discard().or_fail()? {
+                    self.scroll_if_need();
+                }
+            }
+        }
+        self.show_dry_run_patch();
+        Ok(())
+    }
+
+    fn yank_line(&mut self, new: bool) -> orfail::Result<()> {
+        let Some(text) = self.tree.cursor_line_text(new) else {
+            return Ok(());
+        };
+        clipboard::copy(text).or_fail()?;
+        self.status = Some(format!("Yanked: {text}"));
+        Ok(())
+    }
+
+    fn copy_line_ref(&mut self) -> orfail::Result<()> {
+        let Some(line_ref) = self.tree.cursor_line_ref() else {
+            return Ok(());
+        };
+        clipboard::copy(&line_ref).or_fail()?;
+        self.status = Some(format!("Copied: {line_ref}"));
+        Ok(())
+    }
+
+    fn copy_as_markdown(&mut self) -> orfail::Result<()> {
+        let Some(patch) = self.tree.cursor_patch().or_fail()? else {
+            return Ok(());
+        };
+        let text = markdown_diff_block(&patch);
+        clipboard::copy(&text).or_fail()?;
+        self.status = Some(format!(
+            "Copied {} lines as a Markdown diff block",
+            text.lines().count()
+        ));
+        Ok(())
+    }
+
+    fn copy_before_after(&mut self) -> orfail::Result<()> {
+        let Some(text) = self.tree.cursor_before_after() else {
+            return Ok(());
+        };
+        clipboard::copy(&text).or_fail()?;
+        self.status = Some(format!(
+            "Copied {} lines as a before/after block",
+            text.lines().count()
+        ));
+        Ok(())
+    }
+
+    // The result is shown in a preview pane since the file outlives the session.
+    fn export_staged(&mut self, path: &Path) -> orfail::Result<()> {
+        let patch = self.tree.staged_patch().or_fail()?;
+        let files = self.tree.change_summary().staged_files;
+        match write_patch(path, &patch, files) {
+            Ok(()) => {
+                let message = format!("Wrote {files} staged files to {}\n", path.display());
+                let pane = mame::preview::TextPreviewPane::new("export-staged", &message);
+                self.preview = Some(mame::preview::TextPreview::new(Some(pane), None));
+            }
+            Err(e) => self.show_error(&e),
+        }
         Ok(())
     }
 
@@ -213,6 +1109,14 @@ impl App {
         }
     }
 
+    // Makes the next `render()` repaint every cell, not only the ones that differ from the
+    // previous frame, to recover from output that has been corrupted outside mamediff.
+    // The terminal redraws everything when the frame size changes, so an empty frame is drawn.
+    fn invalidate_screen(&mut self) -> orfail::Result<()> {
+        let frame = mame::terminal::UnicodeTerminalFrame::new(TerminalSize::default());
+        self.terminal.draw(frame).or_fail()
+    }
+
     fn recenter(&mut self) {
         if self.terminal.size().is_empty() {
             return;
@@ -232,3 +1136,371 @@ impl App {
         };
     }
 }
+
+// Returns how long to wait for the next event, or `None` to block until one arrives.
+//
+// Only a pending context timeout or refresh wakes the loop up, so nothing is polled while idle.
+fn poll_timeout(
+    context_timeout: Option<Duration>,
+    refresh_timeout: Option<Duration>,
+) -> Option<Duration> {
+    match (context_timeout, refresh_timeout) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Returns how much of `timeout` is left since the last input, saturating at zero once it expires.
+fn idle_time_left(timeout: Duration, last_input_time: Instant) -> Duration {
+    timeout.saturating_sub(last_input_time.elapsed())
+}
+
+// Resolves `.` and `..` components without accessing the file system.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn expand_command_env_vars(
+    mut command: mame::command::ExternalCommand,
+) -> Result<mame::command::ExternalCommand, String> {
+    if let Some(s) = command.command.to_str() {
+        command.command = expand_env_vars(s)?.into();
+    }
+    for arg in &mut command.args {
+        *arg = expand_env_vars(arg)?;
+    }
+    Ok(command)
+}
+
+// Expands `$VAR` and `${VAR}` with the values of environment variables (`$$` yields `$`).
+fn expand_env_vars(s: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        let (name, next) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable reference in {s:?}"))?;
+            (&braced[..end], &braced[end + 1..])
+        } else if let Some(next) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = next;
+            continue;
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            // Not a variable reference (e.g., a trailing `$`).
+            expanded.push('$');
+            continue;
+        }
+
+        let value = std::env::var(name)
+            .map_err(|_| format!("environment variable {name:?} is not set (in {s:?})"))?;
+        expanded.push_str(&value);
+        rest = next;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn command_error_message(command: &mame::command::ExternalCommand, e: &std::io::Error) -> String {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        format!(
+            "command '{}' not found; check $PATH or the command in the key bindings configuration",
+            command.command.display()
+        )
+    } else {
+        e.to_string()
+    }
+}
+
+// Returns a canvas with a message if the terminal is too small to show the tree.
+fn too_small_canvas(size: TerminalSize, charset: Charset) -> Option<Canvas> {
+    if size.rows >= MIN_TERMINAL_SIZE.rows && size.cols >= MIN_TERMINAL_SIZE.cols {
+        return None;
+    }
+    let mut canvas = Canvas::new(0, size);
+    canvas.set_charset(charset);
+    canvas.drawln(Token::new(format!(
+        "terminal too small (need at least {}x{})",
+        MIN_TERMINAL_SIZE.rows, MIN_TERMINAL_SIZE.cols
+    )));
+    Some(canvas)
+}
+
+// Writes the patch after checking that it can be parsed back into the same number of files.
+fn write_patch(path: &Path, patch: &str, files: usize) -> Result<(), String> {
+    let diff = patch
+        .parse::<Diff>()
+        .map_err(|e| format!("generated patch cannot be parsed: {e}"))?;
+    if diff.files.len() != files {
+        return Err(format!(
+            "generated patch has {} files, but {files} are staged",
+            diff.files.len()
+        ));
+    }
+    std::fs::write(path, patch).map_err(|e| format!("failed to write {}: {e}", path.display()))
+}
+
+// Wraps the patch in a fenced code block with `diff` syntax highlighting.
+// The fence is made longer than any backtick run in the patch.
+fn markdown_diff_block(patch: &str) -> String {
+    let backticks = patch.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(backticks.max(2) + 1);
+    // Generated patches end with an empty line that is not needed in the block.
+    let patch = patch.trim_end_matches('\n');
+    format!("{fence}diff\n{patch}\n{fence}\n")
+}
+
+// What the line typed in the status line is for, i.e., the action that started it.
+#[derive(Debug, Clone)]
+enum PromptKind {
+    GitCommand,
+    Glob,
+    Author,
+    GotoFile,
+    Commit { template: String },
+}
+
+impl PromptKind {
+    // Text shown before the typed line.
+    fn label(&self) -> &'static str {
+        match self {
+            Self::GitCommand => "$ git ",
+            Self::Glob => "filter (glob, empty to clear): ",
+            Self::Author => "author (empty to clear): ",
+            Self::GotoFile => "go to file number: ",
+            Self::Commit { .. } => "commit message (empty to abort): ",
+        }
+    }
+}
+
+// Result of a key typed into a line in the status line.
+enum InputLineEdit {
+    Submit,
+    Cancel,
+    Continue,
+}
+
+fn edit_input_line(line: &mut String, key: KeyInput) -> InputLineEdit {
+    match key.code {
+        KeyCode::Enter => return InputLineEdit::Submit,
+        KeyCode::Escape => return InputLineEdit::Cancel,
+        KeyCode::Char('c') if key.ctrl => return InputLineEdit::Cancel,
+        KeyCode::Backspace => {
+            line.pop();
+        }
+        KeyCode::Char(c) if !key.ctrl && !key.alt => line.push(c),
+        _ => {}
+    }
+    InputLineEdit::Continue
+}
+
+// Splits a command line into arguments separated by whitespace.
+// Single or double quotes group characters including whitespace (no escape sequences).
+fn split_command_line(s: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    for c in s.chars() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => arg.get_or_insert_default().push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_default();
+            }
+            (None, _) if c.is_whitespace() => args.extend(arg.take()),
+            (None, _) => arg.get_or_insert_default().push(c),
+        }
+    }
+    if let Some(q) = quote {
+        return Err(format!("unterminated {q} in {s:?}"));
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_poll_blocks() {
+        assert_eq!(poll_timeout(None, None), None);
+
+        let (short, long) = (Duration::from_millis(500), Duration::from_secs(2));
+        assert_eq!(poll_timeout(Some(short), None), Some(short));
+        assert_eq!(poll_timeout(None, Some(long)), Some(long));
+        assert_eq!(poll_timeout(Some(long), Some(short)), Some(short));
+    }
+
+    #[test]
+    fn idle_context_timeout() {
+        let timeout = Duration::from_secs(2);
+
+        // The context expires once the timeout has passed without input.
+        let mut last_input_time = Instant::now() - Duration::from_secs(3);
+        assert!(idle_time_left(timeout, last_input_time).is_zero());
+        assert_eq!(
+            poll_timeout(Some(idle_time_left(timeout, last_input_time)), None),
+            Some(Duration::ZERO)
+        );
+
+        // An input restarts the timeout, and the loop waits for the rest of it.
+        last_input_time = Instant::now();
+        let left = idle_time_left(timeout, last_input_time);
+        assert!(Duration::from_secs(1) < left && left <= timeout);
+        assert_eq!(poll_timeout(Some(left), None), Some(left));
+    }
+
+    #[test]
+    fn path_normalization() {
+        assert_eq!(normalize_path(Path::new("a/./b")), Path::new("a/b"));
+        assert_eq!(normalize_path(Path::new("a/b/../c")), Path::new("a/c"));
+        assert_eq!(normalize_path(Path::new("a/../../c")), Path::new("c"));
+    }
+
+    #[test]
+    fn command_not_found() {
+        let command = mame::command::ExternalCommand {
+            command: PathBuf::from("mamediff-no-such-editor"),
+            args: vec!["foo".to_owned()],
+            envs: Default::default(),
+            stdin: Default::default(),
+            stdout: Default::default(),
+            stderr: Default::default(),
+        };
+        let e = command.execute().expect_err("should fail");
+        assert_eq!(
+            command_error_message(&command, &e),
+            "command 'mamediff-no-such-editor' not found; \
+             check $PATH or the command in the key bindings configuration"
+        );
+    }
+
+    #[test]
+    fn too_small_terminal() {
+        let lines = |size| {
+            too_small_canvas(size, Charset::Ascii).map(|canvas| {
+                let lines = canvas.frame_lines();
+                lines.iter().map(|l| l.text()).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            lines(TerminalSize::rows_cols(1, 1)),
+            Some(vec!["t".to_owned()])
+        );
+        assert_eq!(
+            lines(TerminalSize::rows_cols(2, 80)),
+            Some(vec![
+                "terminal too small (need at least 4x30)".to_owned(),
+                String::new()
+            ])
+        );
+        assert_eq!(lines(MIN_TERMINAL_SIZE), None);
+    }
+
+    #[test]
+    fn patch_export() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let path = dir.path().join("staged.patch");
+        let patch = concat!(
+            "diff --git a/foo b/foo\n",
+            "index e3bdb24..dd04db5 100644\n",
+            "--- a/foo\n",
+            "+++ b/foo\n",
+            "@@ -1 +1 @@\n",
+            "-a\n",
+            "+b\n"
+        );
+        assert_eq!(write_patch(&path, patch, 1), Ok(()));
+        assert_eq!(std::fs::read_to_string(&path).or_fail()?, patch);
+
+        // A patch that does not round-trip is not written.
+        let path = dir.path().join("broken.patch");
+        assert!(write_patch(&path, patch, 2).is_err());
+        assert!(write_patch(&path, "diff --git a/foo\n", 1).is_err());
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn markdown_fence() {
+        let patch = "diff --git a/foo b/foo\n--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-a\n+b\n";
+        assert_eq!(markdown_diff_block(patch), format!("```diff\n{patch}```\n"));
+        assert_eq!(
+            markdown_diff_block(&format!("{patch}\n")),
+            format!("```diff\n{patch}```\n")
+        );
+
+        // A fence in the patch (e.g., in a Markdown file) does not close the block.
+        let patch = "@@ -1 +1 @@\n-```\n+````rust";
+        assert_eq!(
+            markdown_diff_block(patch),
+            format!("`````diff\n{patch}\n`````\n")
+        );
+
+        // Also an indented one or a run in the middle of a line.
+        let patch = "@@ -1 +1 @@\n-  ````\n+x ``````";
+        assert_eq!(
+            markdown_diff_block(patch),
+            format!("```````diff\n{patch}\n```````\n")
+        );
+    }
+
+    #[test]
+    fn command_line_splitting() {
+        let split = |s| split_command_line(s);
+        assert_eq!(split("  "), Ok(vec![]));
+        assert_eq!(
+            split("reset  --soft HEAD~1"),
+            Ok(vec![
+                "reset".to_owned(),
+                "--soft".to_owned(),
+                "HEAD~1".to_owned()
+            ])
+        );
+        assert_eq!(
+            split(r#"commit -m "fix: a 'b'" -m '' x"y z""#),
+            Ok(vec![
+                "commit".to_owned(),
+                "-m".to_owned(),
+                "fix: a 'b'".to_owned(),
+                "-m".to_owned(),
+                String::new(),
+                "xy z".to_owned(),
+            ])
+        );
+        assert!(split("commit -m 'oops").is_err());
+    }
+
+    #[test]
+    fn env_var_expansion() {
+        let home = std::env::var("HOME").expect("HOME is set in tests");
+        assert_eq!(expand_env_vars("plain").as_deref(), Ok("plain"));
+        assert_eq!(expand_env_vars("$HOME/x"), Ok(format!("{home}/x")));
+        assert_eq!(expand_env_vars("${HOME}x"), Ok(format!("{home}x")));
+        assert_eq!(expand_env_vars("$$HOME $ 1$").as_deref(), Ok("$HOME $ 1$"));
+        assert_eq!(expand_env_vars("$1").as_deref(), Ok("$1"));
+        assert!(expand_env_vars("$MAMEDIFF_UNDEFINED_VAR").is_err());
+        assert!(expand_env_vars("${HOME").is_err());
+    }
+}
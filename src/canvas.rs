@@ -2,11 +2,74 @@ use std::{fmt::Write, num::NonZeroUsize, ops::Range};
 
 use tuinix::{TerminalPosition, TerminalSize, TerminalStyle};
 
+// Characters available for decorations such as ellipses.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    #[default]
+    Unicode,
+    Ascii,
+}
+
+impl Charset {
+    // Guesses the charset from the locale environment variables.
+    pub fn detect() -> Self {
+        // As with setlocale(3), the first non-empty variable takes precedence.
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .into_iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|value| !value.is_empty());
+        Self::from_locale(locale.as_deref())
+    }
+
+    fn from_locale(locale: Option<&str>) -> Self {
+        let Some(locale) = locale else {
+            // Most terminals support Unicode even if no locale is set.
+            return Self::Unicode;
+        };
+        let locale = locale.to_ascii_lowercase();
+        if locale.contains("utf-8") || locale.contains("utf8") {
+            Self::Unicode
+        } else {
+            Self::Ascii
+        }
+    }
+
+    pub fn ellipsis(self) -> &'static str {
+        match self {
+            Self::Unicode => "…",
+            Self::Ascii => "...",
+        }
+    }
+
+    pub fn ruler(self) -> &'static str {
+        match self {
+            Self::Unicode => "│",
+            Self::Ascii => "|",
+        }
+    }
+
+    pub fn horizontal_line(self) -> &'static str {
+        match self {
+            Self::Unicode => "─",
+            Self::Ascii => "-",
+        }
+    }
+
+    // Used to fill the columns of a partially visible wide char.
+    fn fill_char(self) -> char {
+        match self {
+            Self::Unicode => '…',
+            Self::Ascii => '.',
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Canvas {
     frame: Frame,
     frame_row_offset: usize,
     cursor: TerminalPosition,
+    charset: Charset,
 }
 
 impl Canvas {
@@ -15,9 +78,18 @@ impl Canvas {
             frame: Frame::new(frame_size),
             frame_row_offset,
             cursor: TerminalPosition::ZERO,
+            charset: Charset::default(),
         }
     }
 
+    pub fn charset(&self) -> Charset {
+        self.charset
+    }
+
+    pub fn set_charset(&mut self, charset: Charset) {
+        self.charset = charset;
+    }
+
     pub fn frame_row_range(&self) -> Range<usize> {
         Range {
             start: self.frame_row_offset,
@@ -29,6 +101,10 @@ impl Canvas {
         self.frame.size
     }
 
+    pub fn frame_lines(&self) -> &[FrameLine] {
+        &self.frame.lines
+    }
+
     pub fn is_frame_exceeded(&self) -> bool {
         self.cursor.row >= self.frame_row_range().end
     }
@@ -64,8 +140,54 @@ impl Canvas {
 
         let i = position.row - self.frame_row_offset;
         let line = &mut self.frame.lines[i];
-        line.draw_token(position.col, token);
-        line.split_off(self.frame.size.cols);
+        line.draw_token(position.col, token, self.charset);
+        line.split_off(self.frame.size.cols, self.charset);
+    }
+
+    // Copies the visible lines of `other` into the visible rows of this canvas at the given column.
+    pub fn draw_canvas(&mut self, col: usize, other: Canvas) {
+        let row_start = self.frame_row_range().start;
+        for (i, line) in other.frame.lines.into_iter().enumerate() {
+            let mut position = TerminalPosition::row_col(row_start + i, col);
+            for token in line.tokens {
+                let cols = token.cols();
+                self.draw_at(position, token);
+                position.col += cols;
+            }
+        }
+    }
+
+    // Draws a dim horizontal line across the frame width and moves to the next row.
+    pub fn draw_separator(&mut self) {
+        let line = self.charset.horizontal_line().repeat(self.frame.size.cols);
+        self.drawln(Token::with_style(line, TerminalStyle::new().dim()));
+    }
+
+    // Draws a dim vertical line at the given column of each non-empty row.
+    // Rows whose content reaches the column are left as is.
+    pub fn draw_ruler(&mut self, col: usize) {
+        if col >= self.frame.size.cols {
+            return;
+        }
+        let token = Token::with_style(self.charset.ruler(), TerminalStyle::new().dim());
+        for line in &mut self.frame.lines {
+            let cols = line.cols();
+            if cols > 0 && cols <= col {
+                line.draw_token(col, token.clone(), self.charset);
+            }
+        }
+    }
+
+    // Returns the frame lines as text, with each style change written as an ANSI escape sequence.
+    pub fn to_styled_text(&self) -> String {
+        let mut text = String::new();
+        for line in self.frame_lines() {
+            for token in line.tokens() {
+                let _ = write!(text, "{}{}", token.style, token.text);
+            }
+            let _ = writeln!(text, "{}", TerminalStyle::RESET);
+        }
+        text
     }
 
     pub fn into_frame(self) -> mame::terminal::UnicodeTerminalFrame {
@@ -81,13 +203,13 @@ impl Canvas {
 }
 
 #[derive(Debug, Clone)]
-pub struct Frame {
+struct Frame {
     size: TerminalSize,
     lines: Vec<FrameLine>,
 }
 
 impl Frame {
-    pub fn new(size: TerminalSize) -> Self {
+    fn new(size: TerminalSize) -> Self {
         Self {
             size,
             lines: vec![FrameLine::new(); size.rows],
@@ -113,19 +235,19 @@ impl FrameLine {
         self.tokens.iter().map(|t| t.text.clone()).collect()
     }
 
-    pub fn draw_token(&mut self, col: usize, token: Token) {
+    pub fn draw_token(&mut self, col: usize, token: Token, charset: Charset) {
         if let Some(n) = col.checked_sub(self.cols()).and_then(NonZeroUsize::new) {
             let s: String = std::iter::repeat_n(' ', n.get()).collect();
             self.tokens.push(Token::new(s));
         }
 
-        let mut suffix = self.split_off(col);
-        let suffix = suffix.split_off(token.cols());
+        let mut suffix = self.split_off(col, charset);
+        let suffix = suffix.split_off(token.cols(), charset);
         self.tokens.push(token);
         self.tokens.extend(suffix.tokens);
     }
 
-    fn split_off(&mut self, col: usize) -> Self {
+    fn split_off(&mut self, col: usize, charset: Charset) -> Self {
         let mut acc_cols = 0;
         for i in 0..self.tokens.len() {
             if acc_cols == col {
@@ -140,7 +262,7 @@ impl FrameLine {
             } else if let Some(n) = acc_cols.checked_sub(col) {
                 let mut suffix = self.tokens.split_off(i);
                 let token_prefix_cols = token_cols - n;
-                let token_prefix = suffix[0].split_prefix_off(token_prefix_cols);
+                let token_prefix = suffix[0].split_prefix_off(token_prefix_cols, charset);
                 self.tokens.push(token_prefix);
                 return Self { tokens: suffix };
             }
@@ -190,7 +312,7 @@ impl Token {
         Self { text, style }
     }
 
-    pub fn split_prefix_off(&mut self, col: usize) -> Self {
+    pub fn split_prefix_off(&mut self, col: usize, charset: Charset) -> Self {
         let mut acc_cols = 0;
         for (i, c) in self.text.char_indices() {
             if acc_cols == col {
@@ -200,12 +322,16 @@ impl Token {
 
             let next_acc_cols = acc_cols + mame::terminal::char_cols(c);
             if next_acc_cols > col {
-                // Not a char boundary.
-                let suffix = self.text.split_off(i + c.len_utf8());
+                // Not a char boundary: both halves of the wide char are filled
+                // so that the prefix and the suffix keep their exact widths.
+                let rest = self.text.split_off(i + c.len_utf8());
+                let mut suffix = std::iter::repeat_n(charset.fill_char(), next_acc_cols - col)
+                    .collect::<String>();
+                suffix.push_str(&rest);
                 let suffix = Self::with_style(suffix, self.style);
                 let _ = self.text.pop();
                 for _ in acc_cols..col {
-                    self.text.push('…');
+                    self.text.push(charset.fill_char());
                 }
                 return std::mem::replace(self, suffix);
             }
@@ -215,8 +341,10 @@ impl Token {
         std::mem::replace(self, Self::with_style(String::new(), self.style))
     }
 
+    // Sums the widths of the chars, as the terminal frame does, rather than using
+    // the width of the whole string (which may differ for emoji sequences).
     pub fn cols(&self) -> usize {
-        mame::terminal::str_cols(&self.text)
+        self.text.chars().map(mame::terminal::char_cols).sum()
     }
 }
 
@@ -228,22 +356,118 @@ mod tests {
     fn frame_line() -> orfail::Result<()> {
         let mut line = FrameLine::new();
 
-        line.draw_token(2, Token::new("foo"));
+        line.draw_token(2, Token::new("foo"), Charset::Unicode);
         assert_eq!(line.text(), "  foo");
 
-        line.draw_token(4, Token::new("bar"));
+        line.draw_token(4, Token::new("bar"), Charset::Unicode);
         assert_eq!(line.text(), "  fobar");
 
-        line.draw_token(7, Token::new("baz"));
+        line.draw_token(7, Token::new("baz"), Charset::Unicode);
         assert_eq!(line.text(), "  fobarbaz");
 
-        line.draw_token(6, Token::new("qux"));
+        line.draw_token(6, Token::new("qux"), Charset::Unicode);
         assert_eq!(line.text(), "  fobaquxz");
 
         // Control chars are escaped.
-        line.draw_token(0, Token::new("0\n1"));
+        line.draw_token(0, Token::new("0\n1"), Charset::Unicode);
         assert_eq!(line.text(), "0\\n1baquxz");
 
         Ok(())
     }
+
+    #[test]
+    fn canvas_clipping() {
+        let mut canvas = Canvas::new(1, TerminalSize::rows_cols(3, 6));
+        for i in 0..5 {
+            canvas.drawln(Token::new(format!("line{i}:abc")));
+        }
+        assert!(canvas.is_frame_exceeded());
+
+        // Rows outside the frame and columns beyond the frame width are clipped.
+        let lines = canvas
+            .frame_lines()
+            .iter()
+            .map(|l| l.text())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["line1:", "line2:", "line3:"]);
+
+        // A wide char straddling the right edge is replaced with an ellipsis.
+        let mut canvas = Canvas::new(0, TerminalSize::rows_cols(1, 4));
+        canvas.draw(Token::new("abあい"));
+        assert_eq!(canvas.frame_lines()[0].text(), "abあ");
+        let mut canvas = Canvas::new(0, TerminalSize::rows_cols(1, 3));
+        canvas.draw(Token::new("abあい"));
+        assert_eq!(canvas.frame_lines()[0].text(), "ab…");
+        canvas = Canvas::new(0, TerminalSize::rows_cols(1, 3));
+        canvas.set_charset(Charset::Ascii);
+        canvas.draw(Token::new("abあい"));
+        assert_eq!(canvas.frame_lines()[0].text(), "ab.");
+    }
+
+    #[test]
+    fn wide_chars() {
+        // Truncating at odd columns keeps the line exactly as wide as the frame.
+        for text in ["あいうえお", "😀😀😀😀😀", "aあ😀い❤\u{fe0f}b"] {
+            for cols in 1..10 {
+                let mut canvas = Canvas::new(0, TerminalSize::rows_cols(1, cols));
+                canvas.draw(Token::new(text));
+                let line = &canvas.frame_lines()[0];
+                assert_eq!(line.cols(), cols.min(Token::new(text).cols()), "{text:?}");
+            }
+        }
+
+        // Overwriting the middle of a wide char does not shift the following chars.
+        let mut line = FrameLine::new();
+        line.draw_token(0, Token::new("あいう"), Charset::Unicode);
+        line.draw_token(1, Token::new("x"), Charset::Unicode);
+        assert_eq!(line.text(), "…xいう");
+        assert_eq!(line.cols(), 6);
+
+        line.draw_token(3, Token::new("😀"), Charset::Ascii);
+        assert_eq!(line.text(), "…x.😀.");
+        assert_eq!(line.cols(), 6);
+    }
+
+    #[test]
+    fn styled_text() {
+        let mut canvas = Canvas::new(0, TerminalSize::rows_cols(2, 10));
+        canvas.draw(Token::new("foo "));
+        canvas.drawln(Token::with_style("bar", TerminalStyle::new().bold()));
+        assert_eq!(
+            canvas.to_styled_text(),
+            "\x1b[0mfoo \x1b[0;1mbar\x1b[0m\n\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn ruler() {
+        let mut canvas = Canvas::new(0, TerminalSize::rows_cols(4, 10));
+        canvas.drawln(Token::new("abc"));
+        canvas.drawln(Token::new("abcdefgh"));
+        canvas.drawln(Token::new("abcde"));
+        canvas.draw_ruler(5);
+        canvas.draw_ruler(10);
+        let lines = canvas
+            .frame_lines()
+            .iter()
+            .map(|l| l.text())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["abc  │", "abcdefgh", "abcde│", ""]);
+        assert_eq!(
+            canvas.frame_lines()[0].tokens().last().map(|t| t.style()),
+            Some(TerminalStyle::new().dim())
+        );
+    }
+
+    #[test]
+    fn charset_detection() {
+        assert_eq!(Charset::from_locale(None), Charset::Unicode);
+        assert_eq!(Charset::from_locale(Some("en_US.UTF-8")), Charset::Unicode);
+        assert_eq!(Charset::from_locale(Some("ja_JP.utf8")), Charset::Unicode);
+        assert_eq!(Charset::from_locale(Some("C")), Charset::Ascii);
+        assert_eq!(
+            Charset::from_locale(Some("en_US.ISO-8859-1")),
+            Charset::Ascii
+        );
+    }
 }
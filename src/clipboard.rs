@@ -0,0 +1,48 @@
+use std::io::Write;
+
+use orfail::OrFail;
+
+// Copies the text to the system clipboard via the OSC 52 escape sequence,
+// which most terminal emulators (and tmux with `set-clipboard on`) support.
+pub fn copy(text: &str) -> orfail::Result<()> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", base64_encode(text.as_bytes())).or_fail()?;
+    stdout.flush().or_fail()?;
+    Ok(())
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - i * 8)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (n >> (18 - i * 6)) & 0x3f;
+                encoded.push(char::from(TABLE[index as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode("あ".as_bytes()), "44GC");
+    }
+}
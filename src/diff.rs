@@ -1,5 +1,7 @@
 use std::{
+    fmt::Display,
     iter::Peekable,
+    ops::Range,
     path::{Path, PathBuf},
     str::{FromStr, Lines},
 };
@@ -8,7 +10,7 @@ use orfail::OrFail;
 
 use crate::git;
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Diff {
     pub files: Vec<FileDiff>,
 }
@@ -21,22 +23,156 @@ impl Diff {
         }
         Ok(patch)
     }
+
+    /// Returns `true` if any added line looks like a conflict marker.
+    pub fn adds_conflict_markers(&self) -> bool {
+        self.files
+            .iter()
+            .flat_map(|f| f.chunks())
+            .flat_map(|c| &c.lines)
+            .any(|l| matches!(l, LineDiff::New(_)) && l.is_conflict_marker())
+    }
 }
 
 impl FromStr for Diff {
-    type Err = orfail::Failure;
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut lines = s.lines().peekable();
+        let mut lines = DiffLines::new(s);
         let mut file_diffs = Vec::new();
-        while let Some(file_diff) = FileDiff::parse(&mut lines).or_fail()? {
+        while let Some(file_diff) = FileDiff::parse(&mut lines)? {
             file_diffs.push(file_diff);
         }
         Ok(Self { files: file_diffs })
     }
 }
 
-#[derive(Debug, Clone)]
+/// Error returned when parsing a diff fails.
+///
+/// Line numbers are 1-based and refer to the offending line of the parsed text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A file diff does not start with a `diff --git` line.
+    UnexpectedDiffLine { line: usize },
+
+    /// An unknown extended header line follows the `diff --git` line.
+    UnexpectedHeader { line: usize },
+
+    /// A chunk header (`@@ -l,s +l,s @@`) was expected but the line is malformed.
+    BadHunkHeader { line: usize },
+
+    /// The text ended in the middle of a file diff.
+    UnexpectedEof,
+
+    /// Any other malformed line (e.g., an invalid `index` header).
+    Malformed { line: usize, message: String },
+}
+
+impl ParseError {
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::UnexpectedDiffLine { line }
+            | Self::UnexpectedHeader { line }
+            | Self::BadHunkHeader { line }
+            | Self::Malformed { line, .. } => Some(*line),
+            Self::UnexpectedEof => None,
+        }
+    }
+
+    /// Returns the error message followed by the lines around the offending line of `text`.
+    pub fn to_string_with_context(&self, text: &str) -> String {
+        const CONTEXT_LINES: usize = 2;
+
+        let line_number = self.line().unwrap_or_else(|| text.lines().count());
+        let mut s = format!("{self}\n(at line {line_number} of the diff)\n");
+        let start = line_number.saturating_sub(CONTEXT_LINES + 1);
+        for (i, line) in text
+            .lines()
+            .enumerate()
+            .skip(start)
+            .take(CONTEXT_LINES * 2 + 1)
+        {
+            let marker = if i + 1 == line_number { '>' } else { ' ' };
+            s.push_str(&format!("{marker}{:5} | {line}\n", i + 1));
+        }
+        s
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedDiffLine { line } => {
+                write!(f, "line {line}: expected a `diff --git` line")
+            }
+            Self::UnexpectedHeader { line } => {
+                write!(f, "line {line}: unexpected diff header line")
+            }
+            Self::BadHunkHeader { line } => write!(f, "line {line}: malformed chunk header"),
+            Self::UnexpectedEof => write!(f, "unexpected end of diff"),
+            Self::Malformed { line, message } => write!(f, "line {line}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for orfail::Failure {
+    fn from(e: ParseError) -> Self {
+        orfail::Failure::new(e)
+    }
+}
+
+// Line iterator that tracks the number of consumed lines for error reporting.
+#[derive(Debug)]
+struct DiffLines<'a> {
+    inner: Peekable<Lines<'a>>,
+    line_number: usize,
+}
+
+impl<'a> DiffLines<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            inner: text.lines().peekable(),
+            line_number: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&'a str> {
+        self.inner.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let line = self.inner.next()?;
+        self.line_number += 1;
+        Some(line)
+    }
+
+    fn next_or_eof(&mut self) -> Result<&'a str, ParseError> {
+        self.next().ok_or(ParseError::UnexpectedEof)
+    }
+
+    // Error for the last consumed line.
+    fn malformed<E: Display>(&self, e: E) -> ParseError {
+        ParseError::Malformed {
+            line: self.line_number,
+            message: e.to_string(),
+        }
+    }
+
+    fn check<F>(&self, condition: bool, f: F) -> Result<(), ParseError>
+    where
+        F: FnOnce() -> String,
+    {
+        if condition {
+            Ok(())
+        } else {
+            Err(self.malformed(f()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LineDiff {
     Old(String),
     New(String),
@@ -44,6 +180,22 @@ pub enum LineDiff {
     NoNewlineAtEndOfFile,
 }
 
+impl LineDiff {
+    const CONFLICT_MARKERS: [&str; 4] = ["<<<<<<<", "|||||||", "=======", ">>>>>>>"];
+
+    /// Returns `true` if this is an added or context line starting with a conflict marker
+    /// (e.g., `<<<<<<< HEAD` or `=======`).
+    pub fn is_conflict_marker(&self) -> bool {
+        let (Self::New(s) | Self::Both(s)) = self else {
+            return false;
+        };
+        Self::CONFLICT_MARKERS.iter().any(|marker| {
+            s.strip_prefix(marker)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+        })
+    }
+}
+
 impl FromStr for LineDiff {
     type Err = orfail::Failure;
 
@@ -69,7 +221,7 @@ impl std::fmt::Display for LineDiff {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ChunkDiff {
     pub old_start_line_number: usize,
     pub new_start_line_number: usize,
@@ -78,6 +230,22 @@ pub struct ChunkDiff {
 }
 
 impl ChunkDiff {
+    /// Formats the removed lines under a "Before:" heading and the added lines under "After:".
+    ///
+    /// A section is left empty if the chunk has only additions or only deletions.
+    pub fn to_before_after(&self) -> String {
+        let mut before = String::new();
+        let mut after = String::new();
+        for line in &self.lines {
+            match line {
+                LineDiff::Old(s) => before.push_str(&format!("{s}\n")),
+                LineDiff::New(s) => after.push_str(&format!("{s}\n")),
+                LineDiff::Both(_) | LineDiff::NoNewlineAtEndOfFile => {}
+            }
+        }
+        format!("Before:\n{before}\nAfter:\n{after}")
+    }
+
     fn added_lines(&self) -> usize {
         self.lines
             .iter()
@@ -93,36 +261,111 @@ impl ChunkDiff {
     }
 
     pub fn get_line_chunk(&self, index: usize, reverse: bool) -> Option<Self> {
-        if index >= self.lines.len() {
+        self.get_lines_chunk(index..index + 1, reverse)
+    }
+
+    /// Returns a chunk that keeps only the changes of the lines in `range`.
+    ///
+    /// The other lines are turned into context lines (or dropped), as in [`Self::get_line_chunk()`].
+    pub fn get_lines_chunk(&self, range: Range<usize>, reverse: bool) -> Option<Self> {
+        if range.is_empty() || range.end > self.lines.len() {
             return None;
         }
 
         let mut lines = Vec::new();
+        // Positions of the first and last target lines in `lines`.
+        let mut first = 0;
+        let mut last = 0;
+        // Whether the previous line was kept, and whether it was turned into a context line.
+        let mut prev = None;
         for (i, line) in self.lines.iter().enumerate() {
-            if i == index {
+            if range.contains(&i) {
+                if i == range.start {
+                    first = lines.len();
+                }
+                last = lines.len();
                 lines.push(line.clone());
+                prev = Some(false);
                 continue;
             }
 
             match line {
                 LineDiff::Old(s) if !reverse => {
                     lines.push(LineDiff::Both(s.clone()));
+                    prev = Some(true);
                 }
                 LineDiff::New(s) if reverse => {
                     lines.push(LineDiff::Both(s.clone()));
+                    prev = Some(true);
                 }
                 LineDiff::Both(_) => {
                     lines.push(line.clone());
+                    prev = Some(false);
+                }
+                LineDiff::NoNewlineAtEndOfFile => match prev {
+                    // The converted line is the last one on only one side.
+                    // If the target lines follow it, the line gets a newline on the other side.
+                    Some(true) if range.start > i => {
+                        let Some(LineDiff::Both(s)) = lines.pop() else {
+                            unreachable!();
+                        };
+                        lines.push(LineDiff::Old(s.clone()));
+                        if !reverse {
+                            lines.push(line.clone());
+                        }
+                        lines.push(LineDiff::New(s));
+                        if reverse {
+                            lines.push(line.clone());
+                        }
+                    }
+                    Some(_) => lines.push(line.clone()),
+                    None => {}
+                },
+                _ => {
+                    prev = None;
                 }
-                _ => {}
             }
         }
 
+        // If the last target line was the last one on its side but context lines follow it now,
+        // it needs a newline.
+        if matches!(lines.get(last + 1), Some(LineDiff::NoNewlineAtEndOfFile))
+            && lines.len() > last + 2
+        {
+            lines.remove(last + 1);
+        }
+
+        // Other than the target lines, all lines are context lines here.
+        // Keep only a few of them around the targets, as chunks can be very large
+        // (e.g., when the diff is generated with `--function-context`).
+        // Lines that belong to the preceding one (e.g., "\ No newline at end of file") are not
+        // separated from it.
+        const CONTEXT_LINES: usize = 3;
+        let is_continuation = |line: &LineDiff| match line {
+            LineDiff::Old(_) => reverse,
+            LineDiff::New(_) => !reverse,
+            LineDiff::Both(_) => false,
+            LineDiff::NoNewlineAtEndOfFile => true,
+        };
+        let mut skip = first.saturating_sub(CONTEXT_LINES);
+        while skip > 0 && is_continuation(&lines[skip]) {
+            skip -= 1;
+        }
+        let mut end = last + CONTEXT_LINES + 1;
+        while lines.get(end).is_some_and(is_continuation) {
+            end += 1;
+        }
+        lines.truncate(end);
+        let skipped = lines
+            .drain(..skip)
+            .filter(|line| !is_continuation(line))
+            .count();
+
         let start = if !reverse {
             self.old_start_line_number
         } else {
             self.new_start_line_number
-        };
+        } + skipped;
         Some(Self {
             old_start_line_number: start,
             new_start_line_number: start,
@@ -131,13 +374,103 @@ impl ChunkDiff {
         })
     }
 
-    pub fn to_diff(&self, path: &Path) -> Diff {
+    /// Returns the line number of the line at `index` in the new file
+    /// (or in the old file if the line has been removed).
+    pub fn line_number(&self, index: usize) -> Option<usize> {
+        let before = self.lines.get(..index)?;
+        let count = |f: fn(&LineDiff) -> bool| before.iter().filter(|l| f(l)).count();
+        match self.lines.get(index)? {
+            LineDiff::Old(_) => Some(
+                self.old_start_line_number
+                    + count(|l| matches!(l, LineDiff::Both(_) | LineDiff::Old(_))),
+            ),
+            LineDiff::New(_) | LineDiff::Both(_) => Some(
+                self.new_start_line_number
+                    + count(|l| matches!(l, LineDiff::Both(_) | LineDiff::New(_))),
+            ),
+            LineDiff::NoNewlineAtEndOfFile => None,
+        }
+    }
+
+    /// Returns the line number in the new file at which the line at `index` is located.
+    ///
+    /// A removed line is located before the next line in the new file.
+    /// Unlike [`ChunkDiff::line_number()`], this does not change when the index is updated.
+    pub fn new_file_position(&self, index: usize) -> Option<usize> {
+        let before = self.lines.get(..index)?;
+        self.lines.get(index)?;
+        Some(
+            self.new_start_line_number
+                + before
+                    .iter()
+                    .filter(|l| matches!(l, LineDiff::Both(_) | LineDiff::New(_)))
+                    .count(),
+        )
+    }
+
+    /// Returns whether the new side of this chunk overlaps or touches the old side of `other`.
+    ///
+    /// This is meaningful when both sides refer to the same version of the file,
+    /// e.g., this is a staged chunk and `other` is an unstaged one (both refer to the index).
+    pub fn is_intersect(&self, other: &Self) -> bool {
+        let start = self.new_start_line_number;
+        let end = start + self.new_rows();
+        let other_start = other.old_start_line_number;
+        let other_end = other_start + other.old_rows();
+        start <= other_end && other_start <= end
+    }
+
+    /// Splits this chunk into two at `index`, which must be inside a run of context lines
+    /// separating changes.
+    ///
+    /// Both chunks keep at least one context line at the boundary, so that each can be applied
+    /// independently.
+    pub fn split_at(&self, index: usize) -> Option<(Self, Self)> {
+        let is_context = |i: usize| matches!(self.lines.get(i), Some(LineDiff::Both(_)));
+        if index == 0 || !is_context(index - 1) || !is_context(index) {
+            return None;
+        }
+
+        let is_change = |line: &LineDiff| !matches!(line, LineDiff::Both(_));
+        let (before, after) = self.lines.split_at(index);
+        if !before.iter().any(is_change) || !after.iter().any(is_change) {
+            return None;
+        }
+
+        let first = Self {
+            old_start_line_number: self.old_start_line_number,
+            new_start_line_number: self.new_start_line_number,
+            start_line: self.start_line.clone(),
+            lines: before.to_vec(),
+        };
+        let second = Self {
+            old_start_line_number: self.old_start_line_number + first.old_rows(),
+            new_start_line_number: self.new_start_line_number + first.new_rows(),
+            start_line: self.start_line.clone(),
+            lines: after.to_vec(),
+        };
+        Some((first, second))
+    }
+
+    /// Converts this chunk into a diff that updates `file` with only this chunk.
+    ///
+    /// The hashes of `file` describe the whole change, so the diff has null hashes
+    /// and only carries the mode of `file`.
+    /// Mode changes of `file` are not included, so applying the diff keeps the current mode.
+    pub fn to_diff(&self, file: &FileDiff) -> Diff {
+        let mode = match file {
+            FileDiff::Update { new_mode, .. } => *new_mode,
+            FileDiff::New { mode, .. } | FileDiff::Delete { mode, .. } => *mode,
+            // Only renames without content lack the mode, and they have no chunks.
+            FileDiff::Rename { mode, .. } => mode.unwrap_or(Mode::REGULAR),
+            FileDiff::Chmod { new_mode, .. } => *new_mode,
+        };
         let file_diff = FileDiff::Update {
-            path: path.to_path_buf(),
-            old_hash: "0000000".to_owned(), // dummy
-            new_hash: "0000000".to_owned(), // dummy
+            path: file.path().clone(),
+            old_hash: NULL_HASH.to_owned(),
+            new_hash: NULL_HASH.to_owned(),
             old_mode: None,
-            new_mode: Mode(0), // dummy
+            new_mode: mode,
             content: ContentDiff::Text {
                 chunks: vec![self.clone()],
             },
@@ -147,6 +480,59 @@ impl ChunkDiff {
         }
     }
 
+    /// Converts this chunk into a diff that renames a file and updates it with only this chunk.
+    ///
+    /// A rename cannot be split, so applying the diff always stages the whole rename
+    /// together with this chunk.
+    pub fn to_rename_diff(
+        &self,
+        old_path: &Path,
+        new_path: &Path,
+        similarity_index: &SimilarityIndexHeaderLine,
+    ) -> Diff {
+        let file_diff = FileDiff::Rename {
+            old_path: old_path.to_path_buf(),
+            new_path: new_path.to_path_buf(),
+            similarity_index: similarity_index.clone(),
+            mode: None,
+            content: Some(ContentDiff::Text {
+                chunks: vec![self.clone()],
+            }),
+        };
+        Diff {
+            files: vec![file_diff],
+        }
+    }
+
+    /// Converts this chunk into a diff that creates `path` with only the chunk's new lines.
+    ///
+    /// Unlike [`ChunkDiff::to_diff()`], the resulting patch can be applied to the index
+    /// even if `path` is not tracked yet (e.g., when staging a part of an untracked file).
+    pub fn to_new_file_diff(&self, path: &Path, mode: Mode) -> Diff {
+        let chunk = Self {
+            old_start_line_number: 0,
+            new_start_line_number: 1,
+            start_line: None,
+            lines: self
+                .lines
+                .iter()
+                .filter(|line| matches!(line, LineDiff::New(_)))
+                .cloned()
+                .collect(),
+        };
+        let file_diff = FileDiff::New {
+            path: path.to_path_buf(),
+            hash: "0000000".to_owned(), // dummy
+            mode,
+            content: ContentDiff::Text {
+                chunks: vec![chunk],
+            },
+        };
+        Diff {
+            files: vec![file_diff],
+        }
+    }
+
     pub fn head_line(&self) -> String {
         let mut s = String::new();
         s.push_str(&format!(
@@ -177,30 +563,36 @@ impl ChunkDiff {
             .count()
     }
 
-    fn parse(lines: &mut Peekable<Lines>) -> orfail::Result<Option<Self>> {
+    fn parse(lines: &mut DiffLines) -> Result<Option<Self>, ParseError> {
         let Some(line) = lines.peek() else {
             return Ok(None);
         };
         if line.starts_with("diff ") {
             return Ok(None);
         }
-        let line = lines.next().expect("infallible");
-
-        line.starts_with("@@ -")
-            .or_fail_with(|()| format!("unexpected diff line: {line}"))?;
+        let line = lines.next_or_eof()?;
+        let bad_hunk_header = || ParseError::BadHunkHeader {
+            line: lines.line_number,
+        };
 
-        let (range_end, start_line) = if line.ends_with(" @@") {
-            (line.len() - 3, None)
+        let line = line.strip_prefix("@@ -").ok_or_else(bad_hunk_header)?;
+        let (range_end, start_line) = if let Some(line) = line.strip_suffix(" @@") {
+            (line.len(), None)
         } else {
-            let range_end = line.find(" @@ ").or_fail()?;
+            let range_end = line.find(" @@ ").ok_or_else(bad_hunk_header)?;
             let start_line = line[range_end + " @@ ".len()..].to_owned();
             (range_end, Some(start_line))
         };
 
-        let line = &line["@@ -".len()..range_end];
-        let mut tokens = line.splitn(2, " +");
-        let old_range = LineRange::from_str(tokens.next().or_fail()?).or_fail()?;
-        let new_range = LineRange::from_str(tokens.next().or_fail()?).or_fail()?;
+        let mut tokens = line[..range_end].splitn(2, " +");
+        let mut next_range = || {
+            tokens
+                .next()
+                .and_then(|s| LineRange::from_str(s).ok())
+                .ok_or_else(bad_hunk_header)
+        };
+        let old_range = next_range()?;
+        let new_range = next_range()?;
 
         let mut line_diffs = Vec::new();
         while lines
@@ -208,8 +600,8 @@ impl ChunkDiff {
             .and_then(|line| line.chars().next())
             .is_some_and(|c| matches!(c, ' ' | '-' | '+' | '\\'))
         {
-            let line = lines.next().or_fail()?;
-            let diff = LineDiff::from_str(line).or_fail()?;
+            let line = lines.next_or_eof()?;
+            let diff = LineDiff::from_str(line).map_err(|e| lines.malformed(e.message))?;
             line_diffs.push(diff);
         }
 
@@ -245,7 +637,7 @@ impl std::fmt::Display for ChunkDiff {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ContentDiff {
     Text { chunks: Vec<ChunkDiff> },
     Binary,
@@ -260,23 +652,34 @@ impl ContentDiff {
         }
     }
 
-    fn parse(lines: &mut Peekable<Lines>) -> orfail::Result<Self> {
+    fn parse(lines: &mut DiffLines) -> Result<Self, ParseError> {
         if lines.peek().is_none_or(|line| line.starts_with("diff ")) {
             return Ok(Self::Empty);
         }
 
-        let line = lines.next().or_fail()?;
+        let line = lines.next_or_eof()?;
         if line.starts_with("Binary files ") {
             return Ok(Self::Binary);
         }
+        if line == "GIT binary patch" {
+            // Generated by `git diff --binary`; the encoded data is not needed.
+            while lines.peek().is_some_and(|line| !line.starts_with("diff ")) {
+                lines.next();
+            }
+            return Ok(Self::Binary);
+        }
 
-        line.starts_with("--- ").or_fail()?;
+        lines.check(line.starts_with("--- "), || {
+            format!("expected a `---` line: {line:?}")
+        })?;
 
-        let line = lines.next().or_fail()?;
-        line.starts_with("+++ ").or_fail()?;
+        let line = lines.next_or_eof()?;
+        lines.check(line.starts_with("+++ "), || {
+            format!("expected a `+++` line: {line:?}")
+        })?;
 
         let mut chunks = Vec::new();
-        while let Some(chunk) = ChunkDiff::parse(lines).or_fail()? {
+        while let Some(chunk) = ChunkDiff::parse(lines)? {
             chunks.push(chunk);
         }
 
@@ -298,7 +701,7 @@ impl std::fmt::Display for ContentDiff {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FileDiff {
     New {
         path: PathBuf,
@@ -324,6 +727,8 @@ pub enum FileDiff {
         old_path: PathBuf,
         new_path: PathBuf,
         similarity_index: SimilarityIndexHeaderLine,
+        // The mode in the index line, which only renames with content changes have.
+        mode: Option<Mode>,
         content: Option<ContentDiff>,
     },
     Chmod {
@@ -348,6 +753,23 @@ impl FileDiff {
         }
     }
 
+    /// Converts a chunk (or a part of a chunk) of this file into a diff that applies only that chunk.
+    ///
+    /// Staging a chunk of a new or renamed file also creates the file in the index or stages the rename.
+    /// If `reverse` is `true` (i.e., the diff is to be reverted), only the new path is touched instead.
+    pub fn chunk_diff(&self, chunk: &ChunkDiff, reverse: bool) -> Diff {
+        match self {
+            FileDiff::New { path, mode, .. } if !reverse => chunk.to_new_file_diff(path, *mode),
+            FileDiff::Rename {
+                old_path,
+                new_path,
+                similarity_index,
+                ..
+            } if !reverse => chunk.to_rename_diff(old_path, new_path, similarity_index),
+            _ => chunk.to_diff(self),
+        }
+    }
+
     pub fn path(&self) -> &PathBuf {
         match self {
             FileDiff::New { path, .. }
@@ -358,6 +780,25 @@ impl FileDiff {
         }
     }
 
+    pub fn is_binary(&self) -> bool {
+        matches!(
+            self,
+            FileDiff::Update {
+                content: ContentDiff::Binary,
+                ..
+            } | FileDiff::New {
+                content: ContentDiff::Binary,
+                ..
+            } | FileDiff::Delete {
+                content: ContentDiff::Binary,
+                ..
+            } | FileDiff::Rename {
+                content: Some(ContentDiff::Binary),
+                ..
+            }
+        )
+    }
+
     pub fn chunks(&self) -> &[ChunkDiff] {
         match self {
             FileDiff::Update { content, .. }
@@ -371,89 +812,146 @@ impl FileDiff {
         }
     }
 
-    fn parse(lines: &mut Peekable<Lines>) -> orfail::Result<Option<Self>> {
+    /// Returns the old and new targets if this is an addition, deletion or change of a symbolic link.
+    ///
+    /// The content of a symbolic link is its target, so the targets are the removed and added lines.
+    pub fn symlink_targets(&self) -> Option<(Option<&str>, Option<&str>)> {
+        let is_symlink = match self {
+            FileDiff::New { mode, .. } | FileDiff::Delete { mode, .. } => *mode == Mode::SYMLINK,
+            FileDiff::Update {
+                old_mode, new_mode, ..
+            } => *new_mode == Mode::SYMLINK && old_mode.is_none_or(|m| m == Mode::SYMLINK),
+            FileDiff::Rename { .. } | FileDiff::Chmod { .. } => false,
+        };
+        if !is_symlink {
+            return None;
+        }
+        let lines = self.chunks().iter().flat_map(|c| &c.lines);
+        let mut old = None;
+        let mut new = None;
+        for line in lines {
+            match line {
+                LineDiff::Old(s) => old = Some(s.as_str()),
+                LineDiff::New(s) => new = Some(s.as_str()),
+                LineDiff::Both(_) | LineDiff::NoNewlineAtEndOfFile => {}
+            }
+        }
+        Some((old, new))
+    }
+
+    /// Splits a chunk of this file at a line (see [`ChunkDiff::split_at()`]).
+    ///
+    /// Returns `false` if the chunk cannot be split there.
+    pub fn split_chunk(&mut self, chunk_index: usize, line_index: usize) -> bool {
+        let (FileDiff::Update { content, .. }
+        | FileDiff::New { content, .. }
+        | FileDiff::Delete { content, .. }
+        | FileDiff::Rename {
+            content: Some(content),
+            ..
+        }) = self
+        else {
+            return false;
+        };
+        let ContentDiff::Text { chunks } = content else {
+            return false;
+        };
+        let Some((first, second)) = chunks
+            .get(chunk_index)
+            .and_then(|chunk| chunk.split_at(line_index))
+        else {
+            return false;
+        };
+        chunks.splice(chunk_index..=chunk_index, [first, second]);
+        true
+    }
+
+    fn parse(lines: &mut DiffLines) -> Result<Option<Self>, ParseError> {
         let Some(line) = lines.next() else {
             return Ok(None);
         };
 
-        let path = if let Some(line) = line.strip_prefix("diff --git a/") {
-            let path = line.split(' ').next().or_fail()?;
-            PathBuf::from(path)
-        } else if let Some(line) = line.strip_prefix("diff --git \"a/") {
-            let path = line.split("\" ").next().or_fail()?;
-            git::parse_escaped_path(path).or_fail()?
-        } else {
-            return Err(orfail::Failure::new(format!(
-                "unexpected git diff line: {line}"
-            )));
+        let Some(paths) = line.strip_prefix("diff --git ") else {
+            return Err(ParseError::UnexpectedDiffLine {
+                line: lines.line_number,
+            });
         };
+        // The old path differs only for renames (which have their own header lines)
+        // and `--no-index` diffs, where the new path is the one to show.
+        let (_old_path, path) = parse_diff_git_paths(paths)
+            .map_err(|e| lines.malformed(format!("{}: {line:?}", e.message)))?;
 
-        let line = lines.next().or_fail()?;
+        let line = lines.next_or_eof()?;
         let this = if line.starts_with(IndexHeaderLine::PREFIX) {
-            let index = IndexHeaderLine::from_str(line).or_fail()?;
-            Self::parse_with_index(lines, path, index, None).or_fail()?
+            let index = parse_header_line(lines, line)?;
+            Self::parse_with_index(lines, path, index, None)?
         } else if line.starts_with(NewFileModeHeaderLine::PREFIX) {
-            let new_file_mode = NewFileModeHeaderLine::from_str(line).or_fail()?;
-            Self::parse_with_new_file_mode(lines, path, new_file_mode).or_fail()?
+            let new_file_mode = parse_header_line(lines, line)?;
+            Self::parse_with_new_file_mode(lines, path, new_file_mode)?
         } else if line.starts_with(DeletedFileModeHeaderLine::PREFIX) {
-            let deleted_file_mode = DeletedFileModeHeaderLine::from_str(line).or_fail()?;
-            Self::parse_with_deleted_file_mode(lines, path, deleted_file_mode).or_fail()?
+            let deleted_file_mode = parse_header_line(lines, line)?;
+            Self::parse_with_deleted_file_mode(lines, path, deleted_file_mode)?
         } else if line.starts_with(OldModeHeaderLine::PREFIX) {
-            let old_mode = OldModeHeaderLine::from_str(line).or_fail()?;
-            Self::parse_with_old_mode(lines, path, old_mode).or_fail()?
+            let old_mode = parse_header_line(lines, line)?;
+            Self::parse_with_old_mode(lines, path, old_mode)?
         } else if line.starts_with(SimilarityIndexHeaderLine::PREFIX) {
-            let similarity_index = SimilarityIndexHeaderLine::from_str(line).or_fail()?;
-            Self::parse_with_similarity_index(lines, path, similarity_index).or_fail()?
+            let similarity_index = parse_header_line(lines, line)?;
+            Self::parse_with_similarity_index(lines, path, similarity_index)?
         } else {
-            return Err(orfail::Failure::new(format!(
-                "Unexpected diff header line: {line:?}"
-            )));
+            return Err(ParseError::UnexpectedHeader {
+                line: lines.line_number,
+            });
         };
         Ok(Some(this))
     }
 
     fn parse_with_similarity_index(
-        lines: &mut Peekable<Lines>,
+        lines: &mut DiffLines,
         path: PathBuf,
         similarity_index: SimilarityIndexHeaderLine,
-    ) -> orfail::Result<Self> {
-        let line = lines.next().or_fail()?;
-        let rename_from = RenameFromHeaderLine::from_str(line).or_fail()?;
+    ) -> Result<Self, ParseError> {
+        let line = lines.next_or_eof()?;
+        let rename_from: RenameFromHeaderLine = parse_header_line(lines, line)?;
 
-        let line = lines.next().or_fail()?;
-        let rename_to = RenameToHeaderLine::from_str(line).or_fail()?;
+        let line = lines.next_or_eof()?;
+        let rename_to: RenameToHeaderLine = parse_header_line(lines, line)?;
 
-        let content = if lines
+        let (mode, content) = if lines
             .peek()
             .is_some_and(|l| l.starts_with(IndexHeaderLine::PREFIX))
         {
-            let line = lines.next().or_fail()?;
-            let index = IndexHeaderLine::from_str(line).or_fail()?;
-            let Self::Update { content, .. } =
-                Self::parse_with_index(lines, path, index, None).or_fail()?
+            let line = lines.next_or_eof()?;
+            let index = parse_header_line(lines, line)?;
+            let Self::Update {
+                new_mode, content, ..
+            } = Self::parse_with_index(lines, path, index, None)?
             else {
-                return Err(orfail::Failure::new("unexpected diff line"));
+                unreachable!("parse_with_index() always returns FileDiff::Update");
             };
-            Some(content)
+            (Some(new_mode), Some(content))
+        } else if lines.peek().is_some_and(|l| l.starts_with("--- ")) {
+            // Patches generated by `ChunkDiff::to_rename_diff()` have no index line.
+            (None, Some(ContentDiff::parse(lines)?))
         } else {
-            None
+            (None, None)
         };
 
         Ok(Self::Rename {
             old_path: rename_from.path,
             new_path: rename_to.path,
             similarity_index,
+            mode,
             content,
         })
     }
 
     fn parse_with_old_mode(
-        lines: &mut Peekable<Lines>,
+        lines: &mut DiffLines,
         path: PathBuf,
         old_mode: OldModeHeaderLine,
-    ) -> orfail::Result<Self> {
-        let line = lines.next().or_fail()?;
-        let new_mode = NewModeHeaderLine::from_str(line).or_fail()?;
+    ) -> Result<Self, ParseError> {
+        let line = lines.next_or_eof()?;
+        let new_mode: NewModeHeaderLine = parse_header_line(lines, line)?;
 
         if lines.peek().is_some_and(|line| line.starts_with("diff")) {
             return Ok(Self::Chmod {
@@ -470,25 +968,30 @@ impl FileDiff {
                 new_mode: new_mode.mode,
             });
         };
-        let mut index = IndexHeaderLine::from_str(line).or_fail()?;
-        index.mode.is_none().or_fail()?;
+        let mut index: IndexHeaderLine = parse_header_line(lines, line)?;
+        lines.check(index.mode.is_none(), || {
+            format!("unexpected mode in index line: {line:?}")
+        })?;
         index.mode = Some(new_mode.mode);
 
-        Self::parse_with_index(lines, path, index, Some(old_mode.mode)).or_fail()
+        Self::parse_with_index(lines, path, index, Some(old_mode.mode))
     }
 
     fn parse_with_new_file_mode(
-        lines: &mut Peekable<Lines>,
+        lines: &mut DiffLines,
         path: PathBuf,
         new_file_mode: NewFileModeHeaderLine,
-    ) -> orfail::Result<Self> {
-        let line = lines.next().or_fail()?;
-        let index = IndexHeaderLine::from_str(line).or_fail()?;
-        index.mode.is_none().or_fail()?;
-        (index.old_hash.parse::<u32>() == Ok(0))
-            .or_fail_with(|()| format!("unexpected added file's old hash: {}", index.old_hash))?;
-
-        let content = ContentDiff::parse(lines).or_fail()?;
+    ) -> Result<Self, ParseError> {
+        let line = lines.next_or_eof()?;
+        let index: IndexHeaderLine = parse_header_line(lines, line)?;
+        lines.check(index.mode.is_none(), || {
+            format!("unexpected mode in index line: {line:?}")
+        })?;
+        lines.check(index.old_hash.parse::<u32>() == Ok(0), || {
+            format!("unexpected added file's old hash: {}", index.old_hash)
+        })?;
+
+        let content = ContentDiff::parse(lines)?;
         Ok(Self::New {
             path,
             hash: index.new_hash,
@@ -498,17 +1001,20 @@ impl FileDiff {
     }
 
     fn parse_with_deleted_file_mode(
-        lines: &mut Peekable<Lines>,
+        lines: &mut DiffLines,
         path: PathBuf,
         deleted_file_mode: DeletedFileModeHeaderLine,
-    ) -> orfail::Result<Self> {
-        let line = lines.next().or_fail()?;
-        let index = IndexHeaderLine::from_str(line).or_fail()?;
-        index.mode.is_none().or_fail()?;
-        (index.new_hash.parse::<u32>() == Ok(0))
-            .or_fail_with(|()| format!("unexpected deleted file's new hash: {}", index.new_hash))?;
-
-        let content = ContentDiff::parse(lines).or_fail()?;
+    ) -> Result<Self, ParseError> {
+        let line = lines.next_or_eof()?;
+        let index: IndexHeaderLine = parse_header_line(lines, line)?;
+        lines.check(index.mode.is_none(), || {
+            format!("unexpected mode in index line: {line:?}")
+        })?;
+        lines.check(index.new_hash.parse::<u32>() == Ok(0), || {
+            format!("unexpected deleted file's new hash: {}", index.new_hash)
+        })?;
+
+        let content = ContentDiff::parse(lines)?;
         Ok(Self::Delete {
             path,
             hash: index.old_hash,
@@ -518,18 +1024,21 @@ impl FileDiff {
     }
 
     fn parse_with_index(
-        lines: &mut Peekable<Lines>,
+        lines: &mut DiffLines,
         path: PathBuf,
         index: IndexHeaderLine,
         old_mode: Option<Mode>,
-    ) -> orfail::Result<Self> {
-        let content = ContentDiff::parse(lines).or_fail()?;
+    ) -> Result<Self, ParseError> {
+        let new_mode = index
+            .mode
+            .ok_or_else(|| lines.malformed("missing mode in index line"))?;
+        let content = ContentDiff::parse(lines)?;
         Ok(Self::Update {
             path,
             old_hash: index.old_hash,
             new_hash: index.new_hash,
             old_mode,
-            new_mode: index.mode.or_fail()?,
+            new_mode,
             content,
         })
     }
@@ -539,68 +1048,115 @@ impl FileDiff {
         match self {
             FileDiff::New {
                 path,
+                hash,
                 mode,
                 content,
-                ..
             } => {
                 if let ContentDiff::Binary = content {
                     let diff = git::new_file_diff(path, true).or_fail()?;
                     patch.push_str(&diff);
                 } else {
+                    let index = IndexHeaderLine {
+                        old_hash: NULL_HASH.to_owned(),
+                        new_hash: hash.clone(),
+                        mode: None,
+                    };
                     let path = path.display();
                     patch.push_str(&format!("diff --git a/{path} b/{path}\n"));
                     patch.push_str(&format!("new file mode {mode}\n"));
+                    patch.push_str(&format!("{index}\n"));
                     if !matches!(content, ContentDiff::Empty) {
-                        patch.push_str(&format!("{content}\n"));
+                        patch.push_str("--- /dev/null\n");
+                        patch.push_str(&format!("+++ b/{path}\n"));
+                        patch.push_str(&content.to_string());
                     }
                 }
             }
             FileDiff::Delete {
                 path,
+                hash,
                 mode,
                 content,
-                ..
             } => {
                 if let ContentDiff::Binary = content {
                     let diff = git::binary_file_diff(path).or_fail()?;
                     patch.push_str(&diff);
                 } else {
+                    let index = IndexHeaderLine {
+                        old_hash: hash.clone(),
+                        new_hash: NULL_HASH.to_owned(),
+                        mode: None,
+                    };
                     let path = path.display();
                     patch.push_str(&format!("diff --git a/{path} b/{path}\n"));
                     patch.push_str(&format!("deleted file mode {mode}\n"));
-                    patch.push_str(&format!("{content}\n"));
+                    patch.push_str(&format!("{index}\n"));
+                    if !matches!(content, ContentDiff::Empty) {
+                        patch.push_str(&format!("--- a/{path}\n"));
+                        patch.push_str("+++ /dev/null\n");
+                        patch.push_str(&content.to_string());
+                    }
                 }
             }
             FileDiff::Update {
                 path,
+                old_hash,
+                new_hash,
                 old_mode,
                 new_mode,
                 content,
-                ..
             } => {
                 if let ContentDiff::Binary = content {
                     let diff = git::binary_file_diff(path).or_fail()?;
                     patch.push_str(&diff);
                 } else {
+                    // The mode is in the index line only if it is unchanged.
+                    let index = IndexHeaderLine {
+                        old_hash: old_hash.clone(),
+                        new_hash: new_hash.clone(),
+                        mode: old_mode.is_none().then_some(*new_mode),
+                    };
                     let path = path.display();
                     patch.push_str(&format!("diff --git a/{path} b/{path}\n"));
                     if let Some(old_mode) = old_mode {
                         patch.push_str(&format!("old mode {old_mode}\n"));
                         patch.push_str(&format!("new mode {new_mode}\n"));
                     }
-                    patch.push_str(&format!("--- a/{path}\n"));
-                    patch.push_str(&format!("+++ b/{path}\n"));
-                    patch.push_str(&format!("{content}\n"));
+                    patch.push_str(&format!("{index}\n"));
+                    if !matches!(content, ContentDiff::Empty) {
+                        patch.push_str(&format!("--- a/{path}\n"));
+                        patch.push_str(&format!("+++ b/{path}\n"));
+                        patch.push_str(&content.to_string());
+                    }
                 }
             }
             FileDiff::Rename {
-                old_path, new_path, ..
+                old_path,
+                new_path,
+                similarity_index,
+                mode,
+                content,
             } => {
                 let old_path = old_path.display();
                 let new_path = new_path.display();
                 patch.push_str(&format!("diff --git a/{old_path} b/{new_path}\n"));
+                patch.push_str(&format!("{similarity_index}\n"));
                 patch.push_str(&format!("rename from {old_path}\n"));
                 patch.push_str(&format!("rename to {new_path}\n"));
+                if let Some(mode) = mode {
+                    // The hashes are not kept, but the mode is needed to apply the content diff.
+                    let index = IndexHeaderLine {
+                        old_hash: NULL_HASH.to_owned(),
+                        new_hash: NULL_HASH.to_owned(),
+                        mode: Some(*mode),
+                    };
+                    patch.push_str(&format!("{index}\n"));
+                }
+                if let Some(content @ ContentDiff::Text { .. }) = content {
+                    patch.push_str(&format!("--- a/{old_path}\n"));
+                    patch.push_str(&format!("+++ b/{new_path}\n"));
+                    patch.push_str(&content.to_string());
+                }
             }
             FileDiff::Chmod {
                 path,
@@ -618,13 +1174,67 @@ impl FileDiff {
 }
 
 impl FromStr for FileDiff {
-    type Err = orfail::Failure;
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Self::parse(&mut s.lines().peekable()).or_fail()?.or_fail()
+        Self::parse(&mut DiffLines::new(s))?.ok_or(ParseError::UnexpectedEof)
     }
 }
 
+fn parse_header_line<T>(lines: &DiffLines, line: &str) -> Result<T, ParseError>
+where
+    T: FromStr<Err = orfail::Failure>,
+{
+    T::from_str(line).map_err(|e| lines.malformed(format!("{}: {line:?}", e.message)))
+}
+
+// Parses the `a/OLD b/NEW` part of a `diff --git` line.
+// Unquoted paths may contain spaces, so the part is split in the middle if both paths are the same.
+fn parse_diff_git_paths(s: &str) -> orfail::Result<(PathBuf, PathBuf)> {
+    let (old, new) = if s.starts_with('"') {
+        let end = quoted_len(s).or_fail()?;
+        (&s[..end], s[end..].strip_prefix(' ').or_fail()?)
+    } else if let Some(i) = s.find(" \"b/") {
+        (&s[..i], &s[i + 1..])
+    } else {
+        let half = s.len().saturating_sub(1) / 2;
+        match (s.get(..half), s.get(half..)) {
+            (Some(old), Some(new)) if new.starts_with(" b/") && old.get(2..) == new.get(3..) => {
+                (old, &new[1..])
+            }
+            _ => {
+                let i = s.find(" b/").or_fail()?;
+                (&s[..i], &s[i + 1..])
+            }
+        }
+    };
+
+    let unquote = |s: &str| match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(s) => git::parse_escaped_path(s),
+        None => Ok(PathBuf::from(s)),
+    };
+    let old = unquote(old).or_fail()?;
+    let new = unquote(new).or_fail()?;
+    Ok((
+        old.strip_prefix("a/").or_fail()?.to_path_buf(),
+        new.strip_prefix("b/").or_fail()?.to_path_buf(),
+    ))
+}
+
+// Returns the length of the quoted string at the beginning of `s` (including the quotes).
+fn quoted_len(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
 #[derive(Debug)]
 struct LineRange {
     start: usize,
@@ -834,6 +1444,9 @@ impl std::fmt::Display for DeletedFileModeHeaderLine {
     }
 }
 
+// Abbreviated hash of a missing blob (e.g., the old content of an added file).
+const NULL_HASH: &str = "0000000";
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct IndexHeaderLine {
     old_hash: String,
@@ -896,6 +1509,31 @@ impl std::fmt::Display for Mode {
     }
 }
 
+impl Mode {
+    pub const REGULAR: Self = Self(0o100644);
+    pub const SYMLINK: Self = Self(0o120000);
+
+    /// Returns the kind of file that this mode stands for, if it is one that Git records.
+    pub fn label(self) -> Option<&'static str> {
+        match self.0 {
+            0o100644 => Some("file"),
+            0o100755 => Some("executable"),
+            0o120000 => Some("symlink"),
+            0o160000 => Some("submodule"),
+            _ => None,
+        }
+    }
+
+    /// Formats the mode as its label if `labeled` is `true` (and the mode is known),
+    /// or in octal otherwise.
+    pub fn format(self, labeled: bool) -> String {
+        match self.label() {
+            Some(label) if labeled => label.to_owned(),
+            _ => self.to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -954,6 +1592,23 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn mode_format() {
+        for (mode, label) in [
+            (0o100644, "file"),
+            (0o100755, "executable"),
+            (0o120000, "symlink"),
+            (0o160000, "submodule"),
+        ] {
+            assert_eq!(Mode(mode).format(false), format!("{mode:06o}"));
+            assert_eq!(Mode(mode).format(true), label);
+        }
+
+        // Unknown modes are always shown in octal.
+        assert_eq!(Mode(0o100664).label(), None);
+        assert_eq!(Mode(0o100664).format(true), "100664");
+    }
+
     #[test]
     fn chunks() -> orfail::Result<()> {
         let text = r#"diff --git a/src/git.rs b/src/git.rs
@@ -1099,4 +1754,712 @@ index 315f0d6..04f0902 100644
 
         Ok(())
     }
+
+    #[test]
+    fn parse_error_context() {
+        let text = r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,2 +1,2 @@
+ foo
+-bar
++baz
+diff --git a/bar b/bar
+unknown header line
+index e3bdb24..dd04db5 100644"#;
+
+        let e = Diff::from_str(text).expect_err("should fail");
+        assert_eq!(e, ParseError::UnexpectedHeader { line: 10 });
+
+        let message = e.to_string_with_context(text);
+        assert!(message.contains("(at line 10 of the diff)"));
+        assert!(message.contains(">   10 | unknown header line"));
+        assert!(message.contains("     9 | diff --git a/bar b/bar"));
+    }
+
+    #[test]
+    fn parse_error_kinds() {
+        let header =
+            "diff --git a/foo b/foo\nindex e3bdb24..dd04db5 100644\n--- a/foo\n+++ b/foo\n";
+        let parse = |text: &str| Diff::from_str(text).expect_err("should fail");
+
+        assert_eq!(
+            parse("--- a/foo"),
+            ParseError::UnexpectedDiffLine { line: 1 }
+        );
+        assert_eq!(
+            parse(&format!("{header}@@ -1,2 +1,2 @@\n foo\nbar")),
+            ParseError::BadHunkHeader { line: 7 }
+        );
+        assert_eq!(
+            parse(&format!("{header}@@ -x +1 @@\n foo")),
+            ParseError::BadHunkHeader { line: 5 }
+        );
+        assert_eq!(parse("diff --git a/foo b/foo"), ParseError::UnexpectedEof);
+        assert!(matches!(
+            parse("diff --git a/foo b/foo\nindex zzz 100644"),
+            ParseError::Malformed { line: 2, .. }
+        ));
+        assert!(matches!(
+            parse(&format!("{header}@@ -1 +1 @@\n\\ unknown")),
+            ParseError::Malformed { line: 6, .. }
+        ));
+    }
+
+    fn git_in(
+        dir: &Path,
+        args: &[&str],
+        input: &str,
+        check_status: bool,
+    ) -> orfail::Result<String> {
+        let mut child = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .or_fail()?;
+        std::io::Write::write_all(&mut child.stdin.take().or_fail()?, input.as_bytes())
+            .or_fail()?;
+        let output = child.wait_with_output().or_fail()?;
+        (!check_status || output.status.success()).or_fail()?;
+        String::from_utf8(output.stdout).or_fail()
+    }
+
+    #[test]
+    fn chunk_intersection() -> orfail::Result<()> {
+        let chunk = |old_start: usize, new_start: usize, lines: &str| {
+            let rows = |c| lines.lines().filter(|l| !l.starts_with(c)).count();
+            let header = format!(
+                "@@ -{old_start},{} +{new_start},{} @@\n",
+                rows('+'),
+                rows('-')
+            );
+            let text = format!(
+                "diff --git a/foo b/foo\nindex e3bdb24..dd04db5 100644\n--- a/foo\n+++ b/foo\n{header}{lines}"
+            );
+            let diff = Diff::from_str(&text).or_fail()?;
+            Ok::<_, orfail::Failure>(diff.files[0].chunks()[0].clone())
+        };
+        // The staged chunk puts lines 10-12 in the index.
+        let staged = chunk(10, 10, "-a\n+A\n+B\n+C\n")?;
+        assert!(staged.is_intersect(&chunk(12, 12, "-C\n+c\n")?));
+        assert!(staged.is_intersect(&chunk(13, 13, "-d\n+D\n")?));
+        assert!(!staged.is_intersect(&chunk(14, 14, "-e\n+E\n")?));
+        assert!(!staged.is_intersect(&chunk(5, 5, "-x\n+X\n")?));
+        Ok(())
+    }
+
+    #[test]
+    fn before_after() -> orfail::Result<()> {
+        let text = "diff --git a/foo b/foo\nindex e3bdb24..dd04db5 100644\n--- a/foo\n+++ b/foo\n@@ -1,4 +1,4 @@\n a\n-b\n-c\n+B\n+C\n d\n@@ -10,1 +10,2 @@\n x\n+y\n";
+        let diff = Diff::from_str(text).or_fail()?;
+        let chunks = diff.files[0].chunks();
+        assert_eq!(
+            chunks[0].to_before_after(),
+            "Before:\nb\nc\n\nAfter:\nB\nC\n"
+        );
+        assert_eq!(chunks[1].to_before_after(), "Before:\n\nAfter:\ny\n");
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_markers() -> orfail::Result<()> {
+        let line = |s: &str| LineDiff::from_str(s).or_fail();
+        assert!(line("+<<<<<<< HEAD")?.is_conflict_marker());
+        assert!(line("+=======")?.is_conflict_marker());
+        assert!(line(" >>>>>>> topic")?.is_conflict_marker());
+        assert!(line("+||||||| merged common ancestors")?.is_conflict_marker());
+        assert!(!line("-<<<<<<< HEAD")?.is_conflict_marker());
+        assert!(!line("+========")?.is_conflict_marker());
+        assert!(!line("+ =======")?.is_conflict_marker());
+
+        let text = "diff --git a/foo b/foo\nindex e3bdb24..dd04db5 100644\n--- a/foo\n+++ b/foo\n@@ -1,2 +1,4 @@\n a\n+<<<<<<< HEAD\n b\n+=======\n";
+        assert!(Diff::from_str(text).or_fail()?.adds_conflict_markers());
+        let text = text
+            .replace("+<<<<<<< HEAD", "+x")
+            .replace("+=======", "+y");
+        assert!(!Diff::from_str(&text).or_fail()?.adds_conflict_markers());
+        Ok(())
+    }
+
+    #[test]
+    fn diff_git_header_paths() -> orfail::Result<()> {
+        // `git diff --no-index a/x b/y`
+        let text = r#"diff --git a/a/x b/b/y
+index 1961029..12ecda3 100644
+--- a/a/x
++++ b/b/y
+@@ -1 +1 @@
+-x
++y"#;
+        let diff = Diff::from_str(text).or_fail()?;
+        assert_eq!(diff.files[0].path(), Path::new("b/y"));
+
+        let paths = |s| {
+            parse_diff_git_paths(s)
+                .map(|(old, new)| (old.display().to_string(), new.display().to_string()))
+                .ok()
+        };
+        let pair = |old: &str, new: &str| Some((old.to_owned(), new.to_owned()));
+        assert_eq!(paths("a/foo b/foo"), pair("foo", "foo"));
+        assert_eq!(paths("a/foo b/bar"), pair("foo", "bar"));
+        assert_eq!(
+            paths("a/with b/space b/with b/space"),
+            pair("with b/space", "with b/space")
+        );
+        assert_eq!(paths(r#""a/t\"x" "b/t\"y""#), pair("t\"x", "t\"y"));
+        assert_eq!(paths(r#"a/cafe "b/caf\303\251""#), pair("cafe", "café"));
+        assert_eq!(paths("foo bar"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn patch_round_trip() -> orfail::Result<()> {
+        let text = r#"diff --git a/Cargo.toml b/C.toml
+similarity index 90%
+rename from Cargo.toml
+rename to C.toml
+index e3bdb24..dd04db5 100644
+--- a/Cargo.toml
++++ b/C.toml
+@@ -1,2 +1,2 @@
+ [package]
+-name = "a"
++name = "b"
+diff --git a/Cargo.lock b/Cargo.lock
+old mode 100644
+new mode 100755
+diff --git a/README.md b/README.md
+deleted file mode 100644
+index 977a212..0000000
+--- a/README.md
++++ /dev/null
+@@ -1,2 +0,0 @@
+-mamediff
+-========
+diff --git a/foo b/foo
+new file mode 100644
+index 0000000..e69de29
+diff --git a/lib.rs b/lib.rs
+new file mode 100644
+index 0000000..c2bf1c3
+--- /dev/null
++++ b/lib.rs
+@@ -0,0 +1,1 @@
++pub mod git;
+\ No newline at end of file
+diff --git a/main.rs b/main.rs
+old mode 100644
+new mode 100755
+index 1961029..12ecda3
+--- a/main.rs
++++ b/main.rs
+@@ -1,1 +1,1 @@
+-fn main() {}
++fn main() { }
+diff --git a/git.rs b/git.rs
+index 1961029..12ecda3 100644
+--- a/git.rs
++++ b/git.rs
+@@ -1,2 +1,3 @@ mod foo
+ a
++b
+ c"#;
+        let diff = Diff::from_str(text).or_fail()?;
+        let patch = diff.to_patch().or_fail()?;
+        assert_eq!(Diff::from_str(&patch).or_fail()?, diff);
+
+        // Except for the hashes of the renamed file, the patch is the same as the original.
+        assert_eq!(
+            patch,
+            format!(
+                "{}\n",
+                text.replace(
+                    "index e3bdb24..dd04db5 100644\n",
+                    "index 0000000..0000000 100644\n"
+                )
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stage_chunk_keeps_mode() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str], input: &str| git_in(dir.path(), args, input, true);
+        git(&["init", "--quiet"], "").or_fail()?;
+        git(&["config", "core.fileMode", "true"], "").or_fail()?;
+
+        let lines = (1..=20).map(|i| format!("line{i}\n")).collect::<Vec<_>>();
+        std::fs::write(dir.path().join("foo"), lines.concat()).or_fail()?;
+        git(&["add", "foo"], "").or_fail()?;
+        git(&["update-index", "--chmod=+x", "foo"], "").or_fail()?;
+
+        // Change the content in two places and drop the exec bit in the working tree.
+        let mut new_lines = lines.clone();
+        new_lines[0] = "LINE1\n".to_owned();
+        new_lines[19] = "LINE20\n".to_owned();
+        std::fs::write(dir.path().join("foo"), new_lines.concat()).or_fail()?;
+
+        let text = git(&["diff", "--src-prefix=a/", "--dst-prefix=b/"], "").or_fail()?;
+        let diff = Diff::from_str(&text).or_fail()?;
+        let file = &diff.files[0];
+        assert!(matches!(
+            file,
+            FileDiff::Update {
+                old_mode: Some(_),
+                ..
+            }
+        ));
+        assert_eq!(file.chunks().len(), 2);
+
+        // Stage only the first chunk.
+        let patch = file.chunks()[0].to_diff(file).to_patch().or_fail()?;
+        assert!(patch.contains("\nindex 0000000..0000000 100644\n"));
+        git(&["apply", "--cached"], &patch).or_fail()?;
+        assert!(
+            git(&["ls-files", "--stage", "foo"], "")
+                .or_fail()?
+                .starts_with("100755 ")
+        );
+        assert!(
+            git(&["show", ":foo"], "")
+                .or_fail()?
+                .starts_with("LINE1\nline2\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stage_part_of_new_file() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str], input: &str, check_status: bool| {
+            git_in(dir.path(), args, input, check_status)
+        };
+        git(&["init", "--quiet"], "", true).or_fail()?;
+        std::fs::write(dir.path().join("ita"), "a\nb\nc\n").or_fail()?;
+        std::fs::write(dir.path().join("untracked"), "d\ne\n").or_fail()?;
+        git(&["add", "--intent-to-add", "ita"], "", true).or_fail()?;
+
+        let diffs = [
+            git(&["diff", "--src-prefix=a/", "--dst-prefix=b/"], "", true).or_fail()?,
+            // This command exits with code 1 even upon success.
+            git(
+                &[
+                    "diff",
+                    "--no-index",
+                    "--src-prefix=a/",
+                    "--dst-prefix=b/",
+                    "/dev/null",
+                    "untracked",
+                ],
+                "",
+                false,
+            )
+            .or_fail()?,
+        ];
+        for (text, (path, expected)) in diffs.iter().zip([("ita", "b\n"), ("untracked", "e\n")]) {
+            let diff = Diff::from_str(text).or_fail()?;
+            let file = &diff.files[0];
+            let FileDiff::New { mode, .. } = file else {
+                return Err(orfail::Failure::new(format!("unexpected diff: {text}")));
+            };
+
+            // Stage only the second line of the new file.
+            let line_chunk = file.chunks()[0].get_line_chunk(1, false).or_fail()?;
+            let patch = line_chunk
+                .to_new_file_diff(file.path(), *mode)
+                .to_patch()
+                .or_fail()?;
+            git(&["apply", "--cached"], &patch, true).or_fail()?;
+            assert_eq!(
+                git(&["show", &format!(":{path}")], "", true).or_fail()?,
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stage_last_line_without_newline() -> orfail::Result<()> {
+        // (committed content, working tree content, line to stage, expected index content)
+        let cases = [
+            ("a\n", "a\nb", LineDiff::New("b".to_owned()), "a\nb"),
+            ("a\nb", "a\nB", LineDiff::New("B".to_owned()), "a\nb\nB"),
+            ("a\nb", "a\nB", LineDiff::Old("b".to_owned()), "a\n"),
+            ("a\nb", "a\nb\n", LineDiff::New("b".to_owned()), "a\nb\nb\n"),
+        ];
+        for (old, new, line, expected) in cases {
+            let dir = tempfile::tempdir().or_fail()?;
+            let git = |args: &[&str], input: &str| git_in(dir.path(), args, input, true);
+            git(&["init", "--quiet"], "").or_fail()?;
+            std::fs::write(dir.path().join("foo"), old).or_fail()?;
+            git(&["add", "foo"], "").or_fail()?;
+            std::fs::write(dir.path().join("foo"), new).or_fail()?;
+
+            let text = git(&["diff", "--src-prefix=a/", "--dst-prefix=b/"], "").or_fail()?;
+            let diff = Diff::from_str(&text).or_fail()?;
+            let file = &diff.files[0];
+            let chunk = &file.chunks()[0];
+            let index = chunk.lines.iter().position(|l| *l == line).or_fail()?;
+            let patch = chunk
+                .get_line_chunk(index, false)
+                .or_fail()?
+                .to_diff(file)
+                .to_patch()
+                .or_fail()?;
+            git(&["apply", "--cached"], &patch).or_fail()?;
+            assert_eq!(git(&["show", ":foo"], "").or_fail()?, expected, "{patch}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stage_symlink() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str], input: &str| git_in(dir.path(), args, input, true);
+        let worktree_diff = || -> orfail::Result<Diff> {
+            let text = git(&["diff", "--src-prefix=a/", "--dst-prefix=b/"], "").or_fail()?;
+            Diff::from_str(&text).or_fail()
+        };
+        git(&["init", "--quiet"], "").or_fail()?;
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("old-target", &link).or_fail()?;
+        git(&["add", "link"], "").or_fail()?;
+        std::fs::remove_file(&link).or_fail()?;
+        std::os::unix::fs::symlink("new-target", &link).or_fail()?;
+
+        let diff = worktree_diff().or_fail()?;
+        let file = &diff.files[0];
+        assert_eq!(
+            file.symlink_targets(),
+            Some((Some("old-target"), Some("new-target")))
+        );
+
+        // The change is staged as is.
+        git(&["apply", "--cached"], &diff.to_patch().or_fail()?).or_fail()?;
+        assert_eq!(git(&["show", ":link"], "").or_fail()?, "new-target");
+
+        // Regular files are not symlinks.
+        std::fs::write(dir.path().join("foo"), "old-target\n").or_fail()?;
+        git(&["add", "foo"], "").or_fail()?;
+        std::fs::write(dir.path().join("foo"), "new-target\n").or_fail()?;
+        let diff = worktree_diff().or_fail()?;
+        assert_eq!(diff.files[0].symlink_targets(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn stage_line_range() -> orfail::Result<()> {
+        let old = "a\nb\nc\nd\n";
+        let new = "a\nB\nC\nd\ne\n";
+        let targets = [LineDiff::Old("c".to_owned()), LineDiff::New("B".to_owned())];
+        let mut results = Vec::new();
+        for at_once in [true, false] {
+            let dir = tempfile::tempdir().or_fail()?;
+            let git = |args: &[&str], input: &str| git_in(dir.path(), args, input, true);
+            git(&["init", "--quiet"], "").or_fail()?;
+            std::fs::write(dir.path().join("foo"), old).or_fail()?;
+            git(&["add", "foo"], "").or_fail()?;
+            std::fs::write(dir.path().join("foo"), new).or_fail()?;
+
+            // Staging the range at once (from the preview) is the same as staging
+            // its lines one by one (from the tree, which reloads the diff after each).
+            let stages = if at_once { 1 } else { targets.len() };
+            for i in 0..stages {
+                let text = git(&["diff", "--src-prefix=a/", "--dst-prefix=b/"], "").or_fail()?;
+                let diff = Diff::from_str(&text).or_fail()?;
+                let file = &diff.files[0];
+                let chunk = &file.chunks()[0];
+                let position = |line| chunk.lines.iter().position(|l| l == line).or_fail();
+                let range = if at_once {
+                    position(&targets[0])?..position(&targets[1])? + 1
+                } else {
+                    let index = position(&targets[i])?;
+                    index..index + 1
+                };
+                let patch = chunk
+                    .get_lines_chunk(range, false)
+                    .or_fail()?
+                    .to_diff(file)
+                    .to_patch()
+                    .or_fail()?;
+                git(&["apply", "--cached"], &patch).or_fail()?;
+            }
+            results.push(git(&["show", ":foo"], "").or_fail()?);
+        }
+        assert_eq!(results, ["a\nb\nB\nd\n", "a\nb\nB\nd\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn stage_renamed_file_with_changes() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str], input: &str| git_in(dir.path(), args, input, true);
+        git(&["init", "--quiet"], "").or_fail()?;
+        git(&["config", "user.name", "test"], "").or_fail()?;
+        git(&["config", "user.email", "test@example.com"], "").or_fail()?;
+
+        let mut lines = (1..=30).map(|i| format!("line{i}\n")).collect::<Vec<_>>();
+        std::fs::write(dir.path().join("old"), lines.concat()).or_fail()?;
+        git(&["add", "old"], "").or_fail()?;
+        git(&["commit", "--quiet", "-m", "init"], "").or_fail()?;
+
+        git(&["mv", "old", "new"], "").or_fail()?;
+        git(&["reset", "--quiet"], "").or_fail()?;
+        git(&["add", "--intent-to-add", "new"], "").or_fail()?;
+        lines[1] = "LINE2\n".to_owned();
+        lines[27] = "LINE28\n".to_owned();
+        std::fs::write(dir.path().join("new"), lines.concat()).or_fail()?;
+
+        let unstaged = || -> orfail::Result<Diff> {
+            let text = git(&["diff", "--src-prefix=a/", "--dst-prefix=b/"], "").or_fail()?;
+            Diff::from_str(&text).or_fail()
+        };
+        let staged = || -> orfail::Result<Diff> {
+            let text = git(
+                &[
+                    "diff",
+                    "--cached",
+                    "-M",
+                    "--src-prefix=a/",
+                    "--dst-prefix=b/",
+                ],
+                "",
+            )
+            .or_fail()?;
+            Diff::from_str(&text).or_fail()
+        };
+        let diff = unstaged().or_fail()?;
+        let file = &diff.files[0];
+        let FileDiff::Rename {
+            old_path,
+            new_path,
+            similarity_index,
+            ..
+        } = file
+        else {
+            return Err(orfail::Failure::new(format!("unexpected diff: {diff:?}")));
+        };
+        assert_eq!(file.chunks().len(), 2);
+
+        // Reverting a chunk only touches the new path, with the mode of the renamed file.
+        let patch = file
+            .chunk_diff(&file.chunks()[1], true)
+            .to_patch()
+            .or_fail()?;
+        assert!(patch.contains("\nindex 0000000..0000000 100644\n"));
+
+        // Stage only the first chunk (together with the rename).
+        let patch = file.chunks()[0]
+            .to_rename_diff(old_path, new_path, similarity_index)
+            .to_patch()
+            .or_fail()?;
+        git(&["apply", "--cached"], &patch).or_fail()?;
+        let diff = staged().or_fail()?;
+        assert!(matches!(diff.files[0], FileDiff::Rename { .. }));
+        assert_eq!(diff.files[0].chunks().len(), 1);
+        let diff = unstaged().or_fail()?;
+        assert!(matches!(diff.files[0], FileDiff::Update { .. }));
+        assert_eq!(diff.files[0].chunks().len(), 1);
+
+        // Stage the whole renamed file.
+        git(&["reset", "--quiet"], "").or_fail()?;
+        git(&["add", "--intent-to-add", "new"], "").or_fail()?;
+        let patch = unstaged().or_fail()?.to_patch().or_fail()?;
+        git(&["apply", "--cached"], &patch).or_fail()?;
+        let diff = staged().or_fail()?;
+        assert!(matches!(diff.files[0], FileDiff::Rename { .. }));
+        assert_eq!(diff.files[0].chunks().len(), 2);
+        assert!(unstaged().or_fail()?.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn unstage_line_of_staged_chunk() -> orfail::Result<()> {
+        // The last lines of the files lack a trailing newline in some cases.
+        let cases = [
+            ("a\nb\nc\nd\n", "a\nB\nC\nd\nE\n"),
+            ("a\nb\nc", "a\nB\nc\nD"),
+            ("a\nb\nc", "a\nB\nC\n"),
+            ("a\nb\nc\n", "a\nX\nb\nY"),
+        ];
+        for (old, new) in cases {
+            let dir = tempfile::tempdir().or_fail()?;
+            let git = |args: &[&str], input: &str| git_in(dir.path(), args, input, true);
+            git(&["init", "--quiet"], "").or_fail()?;
+            git(&["config", "user.name", "test"], "").or_fail()?;
+            git(&["config", "user.email", "test@example.com"], "").or_fail()?;
+            std::fs::write(dir.path().join("foo"), old).or_fail()?;
+            git(&["add", "foo"], "").or_fail()?;
+            git(&["commit", "--quiet", "-m", "init"], "").or_fail()?;
+            std::fs::write(dir.path().join("foo"), new).or_fail()?;
+            git(&["add", "foo"], "").or_fail()?;
+
+            let text = git(
+                &["diff", "--cached", "--src-prefix=a/", "--dst-prefix=b/"],
+                "",
+            )
+            .or_fail()?;
+            let diff = Diff::from_str(&text).or_fail()?;
+            let file = &diff.files[0];
+            let chunk = &file.chunks()[0];
+            for (i, line) in chunk.lines.iter().enumerate() {
+                if !matches!(line, LineDiff::Old(_) | LineDiff::New(_)) {
+                    continue;
+                }
+
+                // Unstage only this line from the fully staged chunk.
+                git(&["add", "foo"], "").or_fail()?;
+                let patch = chunk
+                    .get_line_chunk(i, true)
+                    .or_fail()?
+                    .to_diff(file)
+                    .to_patch()
+                    .or_fail()?;
+                git(&["apply", "--cached", "--reverse"], &patch)
+                    .or_fail_with(|e| format!("{e}\n{text}\n{patch}"))?;
+                let unstaged = git(&["diff", "--numstat"], "").or_fail()?;
+                assert!(
+                    unstaged.starts_with("1\t0\t") || unstaged.starts_with("0\t1\t"),
+                    "line {i} of {text:?}: {unstaged:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn split_chunk() -> orfail::Result<()> {
+        let text = r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,7 +1,7 @@ fn foo() {
+ a
+-b
++B
+ c
+ d
+ e
+-f
++F
+ g
+"#;
+        let diff = Diff::from_str(text).or_fail()?;
+        let chunk = &diff.files[0].chunks()[0];
+
+        // Not inside a run of context lines separating changes.
+        for i in [0, 1, 3, 8, 9] {
+            assert!(chunk.split_at(i).is_none(), "{i}");
+        }
+
+        let (first, second) = chunk.split_at(4).or_fail()?;
+        assert_eq!(first.head_line(), "@@ -1,3 +1,3 @@ fn foo() {");
+        assert_eq!(second.head_line(), "@@ -4,4 +4,4 @@ fn foo() {");
+        assert_eq!(
+            first
+                .to_string()
+                .lines()
+                .skip(1)
+                .chain(second.to_string().lines().skip(1))
+                .collect::<Vec<_>>(),
+            chunk.to_string().lines().skip(1).collect::<Vec<_>>()
+        );
+
+        let mut file = diff.files[0].clone();
+        assert!(!file.split_chunk(0, 3));
+        assert!(file.split_chunk(0, 4));
+        assert_eq!(file.chunks().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn line_number() -> orfail::Result<()> {
+        let diff = Diff::from_str(
+            r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -10,4 +20,5 @@
+ a
+-b
++B
++C
+ c
+ d
+"#,
+        )
+        .or_fail()?;
+        let chunk = &diff.files[0].chunks()[0];
+        let numbers = (0..=chunk.lines.len())
+            .map(|i| chunk.line_number(i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            numbers,
+            [
+                Some(20),
+                Some(11),
+                Some(21),
+                Some(22),
+                Some(23),
+                Some(24),
+                None
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn line_chunk_with_function_context() -> orfail::Result<()> {
+        // A chunk generated with `--function-context` (`-W`).
+        let old_lines = (1..=20).map(|i| format!("line{i}")).collect::<Vec<_>>();
+        let mut text = r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,20 +1,21 @@ fn foo() {
+"#
+        .to_owned();
+        for (i, line) in old_lines.iter().enumerate() {
+            match i {
+                9 => text.push_str(&format!("-{line}\n+LINE10\n")),
+                14 => text.push_str(&format!(" {line}\n+line15.5\n")),
+                _ => text.push_str(&format!(" {line}\n")),
+            }
+        }
+        let diff = Diff::from_str(&text).or_fail()?;
+        let chunk = &diff.files[0].chunks()[0];
+
+        // Stage only the removal of `line10`.
+        let line_chunk = chunk.get_line_chunk(9, false).or_fail()?;
+        assert_eq!(
+            line_chunk.to_string(),
+            "@@ -7,7 +7,6 @@ fn foo() {\n line7\n line8\n line9\n-line10\n line11\n line12\n line13\n"
+        );
+
+        // Stage only the addition of `line15.5`.
+        let line_chunk = chunk.get_line_chunk(16, false).or_fail()?;
+        assert_eq!(
+            line_chunk.to_string(),
+            "@@ -13,6 +13,7 @@ fn foo() {\n line13\n line14\n line15\n+line15.5\n line16\n line17\n line18\n"
+        );
+
+        // The trimmed patch applies to the original content.
+        let dir = tempfile::tempdir().or_fail()?;
+        std::fs::write(dir.path().join("foo"), old_lines.join("\n") + "\n").or_fail()?;
+        let patch = line_chunk.to_diff(&diff.files[0]).to_patch().or_fail()?;
+        let mut child = std::process::Command::new("git")
+            .args(["apply", "--check", "-"])
+            .current_dir(dir.path())
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .or_fail()?;
+        std::io::Write::write_all(&mut child.stdin.take().or_fail()?, patch.as_bytes())
+            .or_fail()?;
+        assert!(child.wait().or_fail()?.success());
+        Ok(())
+    }
 }
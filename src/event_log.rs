@@ -0,0 +1,96 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use orfail::OrFail;
+
+// Appends a JSON line for each handled action and reload (see `--log-events`).
+//
+// Record examples:
+// - {"event":"action","action":"move-down","cursor":[0,0,1]}
+// - {"event":"reload","cursor":[0,0,1]}
+#[derive(Debug)]
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> orfail::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .or_fail_with(|e| format!("failed to open {}: {e}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    pub fn action(&mut self, action: &str, cursor: &[usize]) -> orfail::Result<()> {
+        self.write(Event {
+            kind: "action",
+            action: Some(action),
+            cursor,
+        })
+    }
+
+    pub fn reload(&mut self, cursor: &[usize]) -> orfail::Result<()> {
+        self.write(Event {
+            kind: "reload",
+            action: None,
+            cursor,
+        })
+    }
+
+    fn write(&mut self, event: Event) -> orfail::Result<()> {
+        writeln!(self.file, "{}", nojson::Json(event)).or_fail()
+    }
+}
+
+struct Event<'a> {
+    kind: &'static str,
+    action: Option<&'a str>,
+    cursor: &'a [usize],
+}
+
+impl nojson::DisplayJson for Event<'_> {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.object(|f| {
+            f.member("event", self.kind)?;
+            if let Some(action) = self.action {
+                f.member("action", action)?;
+            }
+            f.member("cursor", self.cursor)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let path = dir.path().join("events.jsonl");
+        let mut log = EventLog::open(&path).or_fail()?;
+        log.action("move-down", &[0, 0, 1]).or_fail()?;
+        log.reload(&[]).or_fail()?;
+
+        // Records are appended to an existing file.
+        let mut log = EventLog::open(&path).or_fail()?;
+        log.action("quit", &[0]).or_fail()?;
+        assert_eq!(
+            std::fs::read_to_string(&path).or_fail()?,
+            concat!(
+                r#"{"event":"action","action":"move-down","cursor":[0,0,1]}"#,
+                "\n",
+                r#"{"event":"reload","cursor":[]}"#,
+                "\n",
+                r#"{"event":"action","action":"quit","cursor":[0]}"#,
+                "\n",
+            )
+        );
+        Ok(())
+    }
+}
@@ -1,60 +1,428 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     io::Write,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     process::{Command, Stdio},
     str::FromStr,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
 };
 
 use orfail::OrFail;
 
 use crate::diff::{ContentDiff, Diff, FileDiff, Mode};
 
+static DEBUG_LOG: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+static CWD_PREFIX: OnceLock<PathBuf> = OnceLock::new();
+
+static UNTRACKED_DIFF_JOBS: OnceLock<NonZeroUsize> = OnceLock::new();
+
+static STAGED_DIFF_BASE: OnceLock<String> = OnceLock::new();
+
+static STAGED_DIFF_FILTER: OnceLock<String> = OnceLock::new();
+
+static COLLAPSE_UNTRACKED_DIRS: OnceLock<bool> = OnceLock::new();
+
+static UNTRACKED_GROUP_THRESHOLD: OnceLock<NonZeroUsize> = OnceLock::new();
+
+static SHOWN_REV: OnceLock<String> = OnceLock::new();
+
+// Whether ignored files were included when the untracked files were last listed,
+// so that a collapsed directory is expanded into the files that were counted in it.
+static UNTRACKED_INCLUDES_IGNORED: AtomicBool = AtomicBool::new(false);
+
+/// Starts recording executed `git` commands and diff parse errors.
+pub fn enable_debug_log() {
+    *DEBUG_LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(Vec::new());
+}
+
+/// Returns the recorded debug log entries (empty if the log is disabled).
+pub fn take_debug_log() -> Vec<String> {
+    DEBUG_LOG
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+        .map(std::mem::take)
+        .unwrap_or_default()
+}
+
+fn debug_log<F>(f: F)
+where
+    F: FnOnce() -> String,
+{
+    if let Some(log) = DEBUG_LOG.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        log.push(f());
+    }
+}
+
 pub fn is_available() -> bool {
     // Check if `git` is accessible and we are within a Git directory.
     let Ok(root_dir) = call(&["rev-parse", "--show-toplevel"], true) else {
         return false;
     };
+    if let Ok(prefix) = call(&["rev-parse", "--show-prefix"], true) {
+        let _ = CWD_PREFIX.set(PathBuf::from(prefix.trim()));
+    }
     std::env::set_current_dir(root_dir.trim()).is_ok()
 }
 
-pub fn stage(diff: &Diff) -> orfail::Result<()> {
-    let patch = diff.to_patch().or_fail()?;
-    call_with_input(&["apply", "--cached"], &patch).or_fail()?;
+/// Returns the directory where mamediff was started, relative to the repository root.
+pub fn cwd_prefix() -> PathBuf {
+    CWD_PREFIX.get().cloned().unwrap_or_default()
+}
+
+/// Sets the number of threads used to diff untracked files (the default is the number of CPUs).
+pub fn set_untracked_diff_jobs(jobs: NonZeroUsize) {
+    let _ = UNTRACKED_DIFF_JOBS.set(jobs);
+}
+
+fn untracked_diff_jobs() -> NonZeroUsize {
+    *UNTRACKED_DIFF_JOBS
+        .get_or_init(|| std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN))
+}
+
+/// Makes each wholly untracked directory appear as a single entry instead of its files.
+///
+/// Staging or discarding such an entry applies to all the untracked files in the directory.
+pub fn set_collapse_untracked_dirs() {
+    let _ = COLLAPSE_UNTRACKED_DIRS.set(true);
+}
+
+fn collapse_untracked_dirs() -> bool {
+    COLLAPSE_UNTRACKED_DIRS.get().copied().unwrap_or_default()
+}
+
+/// Makes the untracked files in a directory appear as a single `dir/` entry
+/// if there are more than `threshold` of them.
+///
+/// As with [`set_collapse_untracked_dirs()`], staging or discarding the entry applies to
+/// all the untracked files under the directory.
+pub fn set_untracked_group_threshold(threshold: NonZeroUsize) {
+    let _ = UNTRACKED_GROUP_THRESHOLD.set(threshold);
+}
+
+/// Sets the commit that the staged diff is compared against (the default is `HEAD`).
+pub fn set_staged_diff_base(base: String) {
+    let _ = STAGED_DIFF_BASE.set(base);
+}
+
+/// Returns the base commit of the staged diff if it is not `HEAD`.
+pub fn staged_diff_base() -> Option<&'static str> {
+    STAGED_DIFF_BASE.get().map(|s| s.as_str())
+}
+
+/// Shows the changes introduced by the given commit instead of the working tree and the index.
+pub fn set_shown_rev(rev: String) {
+    let _ = SHOWN_REV.set(rev);
+}
+
+/// Returns the commit whose changes are shown, if any.
+pub fn shown_rev() -> Option<&'static str> {
+    SHOWN_REV.get().map(|s| s.as_str())
+}
+
+// For a merge commit, the changes relative to the first parent are shown,
+// i.e., what the merge brought into the branch it was made on.
+fn show_commit_args(rev: &str) -> Vec<&str> {
+    let mut args = [
+        &["show", "--format=", "--diff-merges=first-parent"],
+        &DIFF_ARGS[1..],
+    ]
+    .concat();
+    // `--` prevents the revision from being interpreted as a path.
+    args.extend([rev, "--"]);
+    args
+}
+
+/// Returns the changes introduced by the given commit.
+pub fn show_commit(rev: &str) -> orfail::Result<Diff> {
+    call_and_parse_diff(&show_commit_args(rev)).or_fail()
+}
+
+/// Limits the staged diff to the given change types (the `--diff-filter` value, e.g., `AD`).
+///
+/// The index itself is not affected, so staging and unstaging work as usual.
+pub fn set_staged_diff_filter(filter: String) {
+    let _ = STAGED_DIFF_FILTER.set(format!("--diff-filter={filter}"));
+}
+
+// If `against_worktree` is `true`, the index is compared with the working tree instead of
+// the base, i.e., the diff is the reverse of the unstaged one and `base` is ignored.
+fn staged_diff_args<'a>(
+    base: Option<&'a str>,
+    filter: Option<&'a str>,
+    against_worktree: bool,
+) -> Vec<&'a str> {
+    if against_worktree {
+        // `-R` also swaps the prefixes, so they are given swapped to keep `a/` as the old side.
+        let mut args = [DIFF_ARGS, &["-R", "--src-prefix=b/", "--dst-prefix=a/"]].concat();
+        args.extend(filter);
+        return args;
+    }
+    let mut args = [DIFF_ARGS, &["--cached"]].concat();
+    args.extend(filter);
+    if let Some(base) = base {
+        // `--` prevents the base from being interpreted as a path.
+        args.extend([base, "--"]);
+    }
+    args
+}
+
+// Options that keep the output parseable regardless of the user's configuration
+// (e.g., `color.diff=always`, `diff.external` or `diff.noprefix`).
+const DIFF_ARGS: &[&str] = &["diff", "--no-color", "--no-ext-diff", "--default-prefix"];
+
+const HEAD_SUMMARY_ARGS: &[&str] = &["log", "-1", "--format=%h%n%s"];
+
+/// Returns the short hash and subject of the `HEAD` commit.
+///
+/// Returns `None` if there is no commit yet (i.e., the current branch is unborn).
+pub fn head_summary() -> Option<(String, String)> {
+    let output = call(HEAD_SUMMARY_ARGS, true).ok()?;
+    parse_head_summary(&output)
+}
+
+fn parse_head_summary(output: &str) -> Option<(String, String)> {
+    let (hash, subject) = output.split_once('\n')?;
+    Some((hash.to_owned(), subject.trim_end().to_owned()))
+}
+
+/// Returns the author (`name <email>`) of each line of the index version of `path`.
+///
+/// Lines that are not committed yet are attributed to "Not Committed Yet".
+pub fn blame_index_authors(path: &Path) -> orfail::Result<Vec<String>> {
+    let path = path.to_str().or_fail()?;
+    let content = call(&["show", &format!(":{path}")], true).or_fail()?;
+    let args = ["blame", "--line-porcelain", "--contents", "-", "--", path];
+    let output = output_with_input(&args, &content).or_fail()?;
+    output
+        .status
+        .success()
+        .or_fail_with(|()| String::from_utf8_lossy(&output.stderr).into_owned())?;
+    let output = String::from_utf8(output.stdout).or_fail()?;
+    Ok(parse_blame_authors(&output))
+}
+
+fn parse_blame_authors(output: &str) -> Vec<String> {
+    let mut authors = Vec::new();
+    let mut name = "";
+    for line in output.lines() {
+        // Content lines start with a tab, so they are never taken as headers.
+        if let Some(value) = line.strip_prefix("author ") {
+            name = value;
+        } else if let Some(mail) = line.strip_prefix("author-mail ") {
+            authors.push(format!("{name} {mail}"));
+        }
+    }
+    authors
+}
+
+/// Returns the sizes in bytes of the blobs of the paths at the revs (the index if a rev is empty).
+///
+/// They are read by a single `git cat-file` process; a blob that is not found has no size.
+pub fn blob_sizes(objects: &[(&str, &Path)]) -> Vec<Option<u64>> {
+    if objects.is_empty() {
+        return Vec::new();
+    }
+    let input = objects
+        .iter()
+        .map(|(rev, path)| format!("{rev}:{}\n", path.display()))
+        .collect::<String>();
+    let output = output_with_input(&["cat-file", "--batch-check=%(objectsize)"], &input)
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+    // A missing object is reported as `<object> missing` instead of its size.
+    let mut sizes = output
+        .lines()
+        .map(|line| line.parse().ok())
+        .collect::<Vec<_>>();
+    sizes.resize(objects.len(), None);
+    sizes
+}
+
+// `--all` also stages the removal of a deleted file.
+const ADD_ARGS: &[&str] = &["add", "--all", "--"];
+
+/// Stages the working tree version of the file as a whole (`git add`).
+pub fn add(path: &Path) -> orfail::Result<()> {
+    let path = path.to_str().or_fail()?;
+    call(&[ADD_ARGS, &[path]].concat(), true).or_fail()?;
     Ok(())
 }
 
+pub fn stage(diff: &Diff) -> orfail::Result<()> {
+    apply_to_index(&["apply", "--cached"], diff).or_fail()
+}
+
 pub fn unstage(diff: &Diff) -> orfail::Result<()> {
+    apply_to_index(&["apply", "--cached", "--reverse"], diff).or_fail()
+}
+
+// The line endings of the parsed diff are always LF, so a patch for a file stored with CRLF
+// in the index (e.g., committed before `text=auto` was set) does not apply as it is.
+// If applying fails, it is retried with CRs restored in the patches of such files.
+// Only the files whose content did not match are checked (other failures are not retried).
+fn apply_to_index(args: &[&str], diff: &Diff) -> orfail::Result<()> {
     let patch = diff.to_patch().or_fail()?;
-    call_with_input(&["apply", "--cached", "--reverse"], &patch).or_fail()?;
+    let output = output_with_input(args, &patch).or_fail()?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mismatched_paths = stderr
+        .lines()
+        .filter_map(|line| {
+            let line = line.strip_prefix("error: ")?;
+            line.strip_suffix(": patch does not apply").map(Path::new)
+        })
+        .collect::<BTreeSet<_>>();
+    let mut crlf_patch = String::new();
+    let mut has_crlf = false;
+    for file in &diff.files {
+        let patch = file.to_diff().to_patch().or_fail()?;
+        if mismatched_paths.contains(file.path().as_path()) && index_uses_crlf(file.path()) {
+            crlf_patch.push_str(&restore_crlf(&patch));
+            has_crlf = true;
+        } else {
+            crlf_patch.push_str(&patch);
+        }
+    }
+    let patch = if has_crlf { crlf_patch } else { patch };
+    call_with_input(args, &patch).or_fail()?;
     Ok(())
 }
 
-pub fn discard(diff: &Diff) -> orfail::Result<()> {
+fn index_uses_crlf(path: &Path) -> bool {
+    let object = format!(":{}", path.display());
+    call(&["cat-file", "-p", &object], true).is_ok_and(|content| content.contains("\r\n"))
+}
+
+// Appends CR to the lines in the chunks of the patch.
+fn restore_crlf(patch: &str) -> String {
+    let mut restored = String::new();
+    let mut in_chunk = false;
+    for line in patch.lines() {
+        if line.starts_with("@@") {
+            in_chunk = true;
+        } else if line.starts_with("diff --git ") {
+            in_chunk = false;
+        } else if in_chunk && line.starts_with([' ', '-', '+']) {
+            restored.push_str(line);
+            restored.push_str("\r\n");
+            continue;
+        }
+        restored.push_str(line);
+        restored.push('\n');
+    }
+    restored
+}
+
+/// Reverts the diff in the working tree and returns the reverted patch.
+pub fn discard(diff: &Diff) -> orfail::Result<String> {
     let patch = diff.to_patch().or_fail()?;
     call_with_input(&["apply", "--reverse"], &patch).or_fail()?;
+    Ok(patch)
+}
+
+/// Runs `git` with arguments given by the user and returns its output even if it fails.
+///
+/// The command cannot read from the terminal, and commands that would open an editor fail
+/// instead of blocking (e.g., `git commit` without `-m`).
+pub fn run(args: &[String]) -> orfail::Result<std::process::Output> {
+    debug_log(|| format!("$ git {}", args.join(" ")));
+    Command::new("git")
+        .arg("--no-pager")
+        .args(args)
+        .env("GIT_EDITOR", "false")
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .output()
+        .or_fail_with(|e| format!("Failed to execute `$ git {}`: {e}", args.join(" ")))
+}
+
+/// Commits the staged changes with the given one-line message (`git commit -m`).
+///
+/// As with [`run`], the output is returned even if the command fails (e.g., rejected by a hook).
+pub fn commit(message: &str) -> orfail::Result<std::process::Output> {
+    let args = ["commit", "-m", message].map(str::to_owned);
+    run(&args).or_fail()
+}
+
+/// Returns whether the index differs from `HEAD`, i.e., whether there is anything to commit.
+pub fn has_staged_changes() -> orfail::Result<bool> {
+    let args = ["diff", "--cached", "--quiet"];
+    debug_log(|| format!("$ git {}", args.join(" ")));
+    let output = git_command()
+        .args(args)
+        .output()
+        .or_fail_with(|e| format!("Failed to execute `$ git {}`: {e}", args.join(" ")))?;
+
+    // `--quiet` implies `--exit-code`, so the status is 1 if there are differences.
+    let changed = output.status.code() == Some(1);
+    (changed || output.status.success())
+        .or_fail_with(|()| String::from_utf8_lossy(&output.stderr).into_owned())?;
+    Ok(changed)
+}
+
+/// Returns the changes between `HEAD` and the index as a patch.
+pub fn cached_patch() -> orfail::Result<String> {
+    call(&[DIFF_ARGS, &["--cached", "--binary"]].concat(), true).or_fail()
+}
+
+/// Applies the patch to the working tree.
+pub fn apply(patch: &str) -> orfail::Result<()> {
+    call_with_input(&["apply"], patch).or_fail()?;
     Ok(())
 }
 
-pub fn unstaged_and_staged_diffs() -> orfail::Result<(Diff, Diff)> {
+/// Returns the unstaged (including untracked files) and staged diffs,
+/// and the number of files in each group of untracked files.
+///
+/// If `include_ignored` is `true`, ignored files are also included as untracked files.
+/// If `staged_against_worktree` is `true`, the staged diff goes from the working tree to the index
+/// instead of from `HEAD` (or the staged diff base) to the index.
+/// The untracked files in `expanded_groups` are not grouped
+/// (see [`set_untracked_group_threshold()`]).
+///
+/// If a commit is shown (see [`set_shown_rev()`]), its changes are returned as the unstaged diff
+/// and the staged diff is empty.
+pub fn unstaged_and_staged_diffs(
+    include_ignored: bool,
+    staged_against_worktree: bool,
+    expanded_groups: &BTreeSet<PathBuf>,
+) -> orfail::Result<(Diff, Diff, BTreeMap<PathBuf, usize>)> {
+    if let Some(rev) = shown_rev() {
+        return Ok((
+            show_commit(rev).or_fail()?,
+            Diff::default(),
+            BTreeMap::new(),
+        ));
+    }
     let (mut unstaged_diff, staged_diff, untracked_files) =
         std::thread::scope(|s| -> orfail::Result<_> {
-            let unstaged_diff_handle = s.spawn(|| {
-                let output = call(&["diff", "--default-prefix"], true).or_fail()?;
-                Diff::from_str(&output).or_fail()
-            });
+            let unstaged_diff_handle = s.spawn(|| call_and_parse_diff(DIFF_ARGS).or_fail());
             let staged_diff_handle = s.spawn(|| {
-                let output = call(&["diff", "--cached", "--default-prefix"], true).or_fail()?;
-                Diff::from_str(&output).or_fail()
+                let filter = STAGED_DIFF_FILTER.get().map(|s| s.as_str());
+                let args = staged_diff_args(staged_diff_base(), filter, staged_against_worktree);
+                call_and_parse_diff(&args).or_fail()
             });
             let untracked_files_handle = s.spawn(|| {
-                call(&["ls-files", "--others", "--exclude-standard"], true)
-                    .or_fail()
-                    .and_then(|output| {
-                        output
-                            .lines()
-                            .map(parse_maybe_escaped_path)
-                            .collect::<orfail::Result<Vec<_>>>()
-                    })
+                UNTRACKED_INCLUDES_IGNORED.store(include_ignored, Ordering::Relaxed);
+                let args = untracked_files_args(include_ignored, collapse_untracked_dirs());
+                call(&args, true).or_fail().and_then(|output| {
+                    output
+                        .lines()
+                        .map(parse_maybe_escaped_path)
+                        .collect::<orfail::Result<Vec<_>>>()
+                })
             });
 
             let unstaged_diff = unstaged_diff_handle
@@ -74,54 +442,142 @@ pub fn unstaged_and_staged_diffs() -> orfail::Result<(Diff, Diff)> {
         })
         .or_fail()?;
 
-    std::thread::scope(|s| -> orfail::Result<_> {
-        let mut handles = Vec::new();
-        for path in &untracked_files {
-            handles.push(s.spawn(move || {
-                let content = std::fs::read(path).ok();
-                if content.is_some_and(|c| std::str::from_utf8(&c).is_ok()) {
-                    let diff = new_file_diff(path, false).or_fail()?;
-                    FileDiff::from_str(&diff).or_fail()
-                } else {
-                    Ok(FileDiff::New {
-                        path: PathBuf::from(path),
-                        hash: "0000000".to_string(), // dummy
-                        mode: Mode(0),               // dummy
-                        content: ContentDiff::Binary,
-                    })
-                }
-            }));
+    let (untracked_files, groups) = match UNTRACKED_GROUP_THRESHOLD.get() {
+        Some(threshold) => group_untracked_files(untracked_files, threshold.get(), expanded_groups),
+        None => (untracked_files, BTreeMap::new()),
+    };
+    let mut diffs = untracked_file_diffs(untracked_files).or_fail()?;
+    diffs.append(&mut unstaged_diff.files);
+    unstaged_diff.files = diffs;
+
+    Ok((unstaged_diff, staged_diff, groups))
+}
+
+// Replaces the files in each directory that directly contains more than `threshold` files
+// with a single `dir/` entry, and returns the entries along with the numbers of the files.
+// A group also takes the files in the subdirectories, because staging `dir/` covers them too.
+// The files directly in the repository root and those in `expanded` groups are left as they are.
+fn group_untracked_files(
+    paths: Vec<PathBuf>,
+    threshold: usize,
+    expanded: &BTreeSet<PathBuf>,
+) -> (Vec<PathBuf>, BTreeMap<PathBuf, usize>) {
+    let mut counts = BTreeMap::<&Path, usize>::new();
+    for path in &paths {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            *counts.entry(dir).or_default() += 1;
         }
+    }
+    let group_dirs = counts
+        .into_iter()
+        .filter(|(dir, n)| *n > threshold && !expanded.contains(*dir))
+        .map(|(dir, _)| dir.to_path_buf())
+        .collect::<BTreeSet<_>>();
 
-        let mut diffs = handles
-            .into_iter()
-            .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
-            .collect::<orfail::Result<Vec<_>>>()
-            .or_fail()?;
+    let mut files = Vec::new();
+    let mut groups = BTreeMap::new();
+    for path in paths {
+        // The outermost group wins.
+        let Some(dir) = path
+            .ancestors()
+            .skip(1)
+            .filter(|a| group_dirs.contains(*a))
+            .last()
+        else {
+            files.push(path);
+            continue;
+        };
+        let mut dir = dir.as_os_str().to_owned();
+        dir.push("/");
+        // The entry takes the place of the first file in the group to keep the path order.
+        *groups.entry(PathBuf::from(dir)).or_insert_with_key(|dir| {
+            files.push(dir.clone());
+            0
+        }) += 1;
+    }
+    (files, groups)
+}
 
-        diffs.append(&mut unstaged_diff.files);
-        unstaged_diff.files = diffs;
+fn untracked_files_args(include_ignored: bool, directories: bool) -> Vec<&'static str> {
+    let mut args = vec!["ls-files", "--others"];
+    if !include_ignored {
+        args.push("--exclude-standard");
+    }
+    if directories {
+        // Listed directories end with `/`; empty ones are omitted as in `git status`.
+        args.extend(["--directory", "--no-empty-directory"]);
+    }
+    args
+}
 
-        Ok(())
+// Diffs untracked files using a bounded number of worker threads.
+fn untracked_file_diffs(paths: Vec<PathBuf>) -> orfail::Result<Vec<FileDiff>> {
+    let jobs = untracked_diff_jobs().get().min(paths.len());
+    let (path_tx, path_rx) = mpsc::channel();
+    for job in paths.into_iter().enumerate() {
+        let _ = path_tx.send(job);
+    }
+    std::mem::drop(path_tx);
+    let path_rx = Mutex::new(path_rx);
+
+    let mut diffs = std::thread::scope(|s| -> orfail::Result<_> {
+        let handles = (0..jobs)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut diffs = Vec::new();
+                    loop {
+                        let job = path_rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                        let Ok((i, path)) = job else {
+                            return Ok(diffs);
+                        };
+                        diffs.push((i, untracked_file_diff(&path).or_fail()?));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut diffs = Vec::new();
+        for handle in handles {
+            let result: orfail::Result<Vec<_>> = handle
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+            diffs.extend(result.or_fail()?);
+        }
+        Ok(diffs)
     })
     .or_fail()?;
 
-    Ok((unstaged_diff, staged_diff))
+    // Workers finish in arbitrary order, so restore the order of `paths`.
+    diffs.sort_by_key(|(i, _)| *i);
+    Ok(diffs.into_iter().map(|(_, diff)| diff).collect())
+}
+
+// A collapsed untracked directory cannot be read, so it is treated as a binary file.
+fn untracked_file_diff(path: &Path) -> orfail::Result<FileDiff> {
+    let content = std::fs::read(path).ok();
+    if content.is_some_and(|c| std::str::from_utf8(&c).is_ok()) {
+        let diff = new_file_diff(path, false).or_fail()?;
+        FileDiff::from_str(&diff).or_fail_with(|e| {
+            let e = e.to_string_with_context(&diff);
+            debug_log(|| format!("Failed to parse the diff of untracked file {path:?}:\n{e}"));
+            e
+        })
+    } else {
+        Ok(FileDiff::New {
+            path: path.to_path_buf(),
+            hash: "0000000".to_string(), // dummy
+            mode: Mode(0),               // dummy
+            content: ContentDiff::Binary,
+        })
+    }
 }
 
 pub fn binary_file_diff<P: AsRef<Path>>(path: P) -> orfail::Result<String> {
     let path = &path.as_ref().display().to_string();
-    let diff = call(&["diff", "--binary", "--default-prefix", "--", path], true).or_fail()?;
+    let diff = call(&[DIFF_ARGS, &["--binary", "--", path]].concat(), true).or_fail()?;
     if diff.is_empty() {
         call(
-            &[
-                "diff",
-                "--binary",
-                "--cached",
-                "--default-prefix",
-                "--",
-                path,
-            ],
+            &[DIFF_ARGS, &["--binary", "--cached", "--", path]].concat(),
             true,
         )
         .or_fail()
@@ -133,31 +589,67 @@ pub fn binary_file_diff<P: AsRef<Path>>(path: P) -> orfail::Result<String> {
 pub fn new_file_diff<P: AsRef<Path>>(path: P, binary: bool) -> orfail::Result<String> {
     // This command exits with code 1 even upon success.
     // Therefore, specify `check_status=false` here.
+    if path.as_ref().is_dir() {
+        let include_ignored = UNTRACKED_INCLUDES_IGNORED.load(Ordering::Relaxed);
+        return untracked_dir_diff(path.as_ref(), include_ignored).or_fail();
+    }
     let path = &path.as_ref().display().to_string();
     if binary {
         call(
-            &[
-                "diff",
-                "--no-index",
-                "--binary",
-                "--default-prefix",
-                "/dev/null",
-                path,
-            ],
+            &[DIFF_ARGS, &["--no-index", "--binary", "/dev/null", path]].concat(),
             false,
         )
         .or_fail()
     } else {
         call(
-            &["diff", "--no-index", "--default-prefix", "/dev/null", path],
+            &[DIFF_ARGS, &["--no-index", "/dev/null", path]].concat(),
             false,
         )
         .or_fail()
     }
 }
 
+// Concatenates the diffs of the untracked files in a collapsed directory.
+fn untracked_dir_diff(dir: &Path, include_ignored: bool) -> orfail::Result<String> {
+    let dir = dir.display().to_string();
+    let args = [
+        &untracked_files_args(include_ignored, false)[..],
+        &["--", &dir],
+    ]
+    .concat();
+    let mut diff = String::new();
+    for line in call(&args, true).or_fail()?.lines() {
+        let path = parse_maybe_escaped_path(line).or_fail()?;
+        diff.push_str(&new_file_diff(&path, true).or_fail()?);
+    }
+    Ok(diff)
+}
+
+fn call_and_parse_diff(args: &[&str]) -> orfail::Result<Diff> {
+    let output = call(args, true).or_fail()?;
+    Diff::from_str(&output).or_fail_with(|e| {
+        let e = e.to_string_with_context(&output);
+        debug_log(|| {
+            format!(
+                "Failed to parse the output of `$ git {}`:\n{e}",
+                args.join(" ")
+            )
+        });
+        e
+    })
+}
+
+// Returns a `git` command whose output does not depend on the user's locale or pager.
+// Messages are also in English so that they read the same in bug reports.
+fn git_command() -> Command {
+    let mut command = Command::new("git");
+    command.env("LC_ALL", "C").env("GIT_PAGER", "cat");
+    command
+}
+
 fn call(args: &[&str], check_status: bool) -> orfail::Result<String> {
-    let output = Command::new("git")
+    debug_log(|| format!("$ git {}", args.join(" ")));
+    let output = git_command()
         .args(args)
         .output()
         .or_fail_with(|e| format!("Failed to execute `$ git {}`: {e}", args.join(" ")))?;
@@ -176,7 +668,23 @@ fn call(args: &[&str], check_status: bool) -> orfail::Result<String> {
 }
 
 fn call_with_input(args: &[&str], input: &str) -> orfail::Result<String> {
-    let mut child = Command::new("git")
+    let output = output_with_input(args, input).or_fail()?;
+    output.status.success().or_fail_with(|()| {
+        let _ = std::fs::write(".mamediff.error.input", input.as_bytes());
+        format!(
+            "Failed to execute `$ cat .mamediff.error.input | git {}`:\n{}\n",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        )
+    })?;
+
+    String::from_utf8(output.stdout).or_fail()
+}
+
+// Unlike `call_with_input()`, the output is returned even if the command fails.
+fn output_with_input(args: &[&str], input: &str) -> orfail::Result<std::process::Output> {
+    debug_log(|| format!("$ git {} < ({} bytes)", args.join(" "), input.len()));
+    let mut child = git_command()
         .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -188,20 +696,9 @@ fn call_with_input(args: &[&str], input: &str) -> orfail::Result<String> {
     stdin.write_all(input.as_bytes()).or_fail()?;
     std::mem::drop(stdin);
 
-    let output = child
+    child
         .wait_with_output()
-        .or_fail_with(|e| format!("Failed to execute `$ git {}`: {e}", args.join(" ")))?;
-
-    output.status.success().or_fail_with(|()| {
-        let _ = std::fs::write(".mamediff.error.input", input.as_bytes());
-        format!(
-            "Failed to execute `$ cat .mamediff.error.input | git {}`:\n{}\n",
-            args.join(" "),
-            String::from_utf8_lossy(&output.stderr)
-        )
-    })?;
-
-    String::from_utf8(output.stdout).or_fail()
+        .or_fail_with(|e| format!("Failed to execute `$ git {}`: {e}", args.join(" ")))
 }
 
 pub fn parse_escaped_path(s: &str) -> orfail::Result<PathBuf> {
@@ -241,23 +738,446 @@ fn parse_maybe_escaped_path(s: &str) -> orfail::Result<PathBuf> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    // Runs `git` in `dir` and returns its output if it succeeds.
+    // A user is set for commands that need one (e.g., `commit`).
+    pub(crate) fn git_in(dir: &Path, args: &[&str]) -> orfail::Result<String> {
+        git_as(dir, "test", args)
+    }
+
+    // Same as `git_in()`, but as the user `name <name@example.com>`.
+    pub(crate) fn git_as(dir: &Path, name: &str, args: &[&str]) -> orfail::Result<String> {
+        let output = Command::new("git")
+            .args(["-c", &format!("user.name={name}")])
+            .args(["-c", &format!("user.email={name}@example.com")])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .or_fail()?;
+        output
+            .status
+            .success()
+            .or_fail_with(|()| String::from_utf8_lossy(&output.stderr).into_owned())?;
+        String::from_utf8(output.stdout).or_fail()
+    }
+
+    // Runs `f` in `dir`, as the functions above run `git` in the current directory.
+    // Tests doing so take turns, and the previous directory is restored afterwards.
+    pub(crate) fn with_current_dir<T>(
+        dir: &Path,
+        f: impl FnOnce() -> orfail::Result<T>,
+    ) -> orfail::Result<T> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let previous_dir = std::env::current_dir().or_fail()?;
+        std::env::set_current_dir(dir).or_fail()?;
+        let result = f();
+        std::env::set_current_dir(previous_dir).or_fail()?;
+        result
+    }
+
     #[test]
     fn git_new() -> orfail::Result<()> {
         let dir = tempfile::tempdir().or_fail()?;
-        std::env::set_current_dir(&dir).or_fail()?;
+        with_current_dir(dir.path(), || {
+            // `dir` is not a Git directory yet.
+            assert!(!is_available());
+
+            // Directly create a `Git` instance to bypass the check.
+            call(&["init"], true).or_fail()?;
+
+            // Now, `dir` is a Git directory.
+            assert!(is_available());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn blob_sizes_at_revs() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        git_in(dir.path(), &["init", "--quiet"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "foo\n").or_fail()?;
+        git_in(dir.path(), &["add", "foo"]).or_fail()?;
+        git_in(dir.path(), &["commit", "--quiet", "-m", "Add foo"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "foobar\n").or_fail()?;
+        git_in(dir.path(), &["add", "foo"]).or_fail()?;
 
-        // `dir` is not a Git directory yet.
-        assert!(!is_available());
+        let sizes = with_current_dir(dir.path(), || {
+            let (foo, bar) = (Path::new("foo"), Path::new("bar"));
+            Ok(blob_sizes(&[
+                ("HEAD", foo),
+                ("", foo),
+                ("", bar),
+                ("HEAD", foo),
+            ]))
+        })
+        .or_fail()?;
+        assert_eq!(sizes, [Some(4), Some(7), None, Some(4)]);
+        Ok(())
+    }
+
+    #[test]
+    fn git_command_ignores_locale() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| -> orfail::Result<std::process::Output> {
+            git_command()
+                .args(args)
+                .env("LANG", "de_DE.UTF-8")
+                .env("LC_MESSAGES", "ja_JP.UTF-8")
+                .current_dir(dir.path())
+                .output()
+                .or_fail()
+        };
+        let envs = git_command()
+            .get_envs()
+            .map(|(k, v)| (k.to_owned(), v.map(|v| v.to_owned())))
+            .collect::<Vec<_>>();
+        assert!(envs.contains(&("LC_ALL".into(), Some("C".into()))));
+
+        // Not a Git directory yet.
+        let output = git(&["status"]).or_fail()?;
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).or_fail()?;
+        assert!(stderr.contains("not a git repository"), "{stderr}");
+
+        git(&["init", "--quiet"]).or_fail()?;
+        let output = git(&["rev-parse", "--is-inside-work-tree"]).or_fail()?;
+        assert_eq!(String::from_utf8(output.stdout).or_fail()?.trim(), "true");
+        Ok(())
+    }
+
+    #[test]
+    fn untracked_files_with_ignored() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        std::fs::write(dir.path().join(".gitignore"), "ignored.txt\n").or_fail()?;
+        std::fs::write(dir.path().join("ignored.txt"), "foo\n").or_fail()?;
+        std::fs::write(dir.path().join("other.txt"), "bar\n").or_fail()?;
+
+        // Run in `dir` without changing the current directory of the process.
+        git_in(dir.path(), &["init", "--quiet"]).or_fail()?;
+
+        let ls_files =
+            |include_ignored| git_in(dir.path(), &untracked_files_args(include_ignored, false));
+        assert_eq!(ls_files(false).or_fail()?, ".gitignore\nother.txt\n");
+        assert_eq!(
+            ls_files(true).or_fail()?,
+            ".gitignore\nignored.txt\nother.txt\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn untracked_dirs() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        std::fs::create_dir_all(dir.path().join("new/sub")).or_fail()?;
+        std::fs::create_dir(dir.path().join("empty")).or_fail()?;
+        std::fs::write(dir.path().join("new/foo"), "foo\n").or_fail()?;
+        std::fs::write(dir.path().join("new/sub/bar"), "bar\n").or_fail()?;
+        std::fs::write(dir.path().join("baz"), "baz\n").or_fail()?;
+        git_in(dir.path(), &["init", "--quiet"]).or_fail()?;
+
+        let ls_files = |directories| git_in(dir.path(), &untracked_files_args(false, directories));
+        assert_eq!(ls_files(false).or_fail()?, "baz\nnew/foo\nnew/sub/bar\n");
+        assert_eq!(ls_files(true).or_fail()?, "baz\nnew/\n");
+
+        // A collapsed directory cannot be read as a file.
+        let diff = untracked_file_diff(&dir.path().join("new/")).or_fail()?;
+        assert!(diff.is_binary());
+
+        // A directory of ignored files is listed (and expanded) only when including them.
+        std::fs::write(dir.path().join(".gitignore"), "/ignored/\n").or_fail()?;
+        std::fs::create_dir(dir.path().join("ignored")).or_fail()?;
+        std::fs::write(dir.path().join("ignored/qux"), "qux\n").or_fail()?;
+        let ls_files = git_in(dir.path(), &untracked_files_args(true, true)).or_fail()?;
+        assert!(
+            ls_files.lines().any(|line| line == "ignored/"),
+            "{ls_files}"
+        );
+        let (without, with) = with_current_dir(dir.path(), || {
+            let ignored = Path::new("ignored/");
+            Ok((
+                untracked_dir_diff(ignored, false).or_fail()?,
+                untracked_dir_diff(ignored, true).or_fail()?,
+            ))
+        })
+        .or_fail()?;
+        assert_eq!(without, "");
+        assert!(with.contains("+++ b/ignored/qux"), "{with}");
+        Ok(())
+    }
+
+    #[test]
+    fn untracked_file_order() -> orfail::Result<()> {
+        // The byte order of `git ls-files` differs from the component order of `Path`.
+        // (Missing files are treated as binary without running Git.)
+        let paths = ["a-b", "a/b", "a0", "a/a"].map(PathBuf::from).to_vec();
+        let diffs = untracked_file_diffs(paths.clone()).or_fail()?;
+        let diff_paths = diffs.iter().map(|d| d.path().clone()).collect::<Vec<_>>();
+        assert_eq!(diff_paths, paths);
+        Ok(())
+    }
+
+    #[test]
+    fn untracked_groups() {
+        let paths = [
+            "a",
+            "build/1",
+            "build/2",
+            "build/3",
+            "build/sub/4",
+            "src/gen/1",
+            "src/gen/2",
+            "src/gen/3",
+            "src/x",
+            "src/y",
+            "z",
+        ]
+        .map(PathBuf::from)
+        .to_vec();
+
+        let (files, groups) = group_untracked_files(paths.clone(), 2, &BTreeSet::new());
+        assert_eq!(
+            files,
+            ["a", "build/", "src/gen/", "src/x", "src/y", "z"].map(PathBuf::from)
+        );
+        assert_eq!(
+            groups.into_iter().collect::<Vec<_>>(),
+            [(PathBuf::from("build/"), 4), (PathBuf::from("src/gen/"), 3)]
+        );
 
-        // Directly create a `Git` instance to bypass the check.
-        call(&["init"], true).or_fail()?;
+        // An expanded group shows its files, but its subdirectories may still be grouped.
+        let expanded = [PathBuf::from("build"), PathBuf::from("src/gen")].into();
+        let (files, groups) = group_untracked_files(paths.clone(), 2, &expanded);
+        assert_eq!(files, paths);
+        assert!(groups.is_empty());
+
+        let (files, _) = group_untracked_files(paths.clone(), 4, &BTreeSet::new());
+        assert_eq!(files, paths);
+    }
+
+    #[test]
+    fn staged_diff_with_filter() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git_in(dir.path(), args);
+        git(&["init", "--quiet"]).or_fail()?;
+        std::fs::write(dir.path().join("modified"), "foo\n").or_fail()?;
+        std::fs::write(dir.path().join("deleted"), "bar\n").or_fail()?;
+        git(&["add", "."]).or_fail()?;
+        git(&["commit", "--quiet", "-m", "init"]).or_fail()?;
+
+        std::fs::write(dir.path().join("modified"), "baz\n").or_fail()?;
+        std::fs::write(dir.path().join("added"), "qux\n").or_fail()?;
+        std::fs::remove_file(dir.path().join("deleted")).or_fail()?;
+        git(&["add", "--all"]).or_fail()?;
+
+        let staged_paths = |filter| -> orfail::Result<Vec<PathBuf>> {
+            let diff = git(&staged_diff_args(None, filter, false)).or_fail()?;
+            let diff = Diff::from_str(&diff).or_fail()?;
+            Ok(diff.files.iter().map(|f| f.path().clone()).collect())
+        };
+        assert_eq!(
+            staged_paths(None).or_fail()?,
+            [
+                PathBuf::from("added"),
+                PathBuf::from("deleted"),
+                PathBuf::from("modified")
+            ]
+        );
+        assert_eq!(
+            staged_paths(Some("--diff-filter=AD")).or_fail()?,
+            [PathBuf::from("added"), PathBuf::from("deleted")]
+        );
+
+        // Against the working tree, only the file modified after staging differs.
+        std::fs::write(dir.path().join("modified"), "quux\n").or_fail()?;
+        let diff = git(&staged_diff_args(Some("HEAD~"), None, true)).or_fail()?;
+        assert_eq!(
+            diff.lines()
+                .filter(|l| l.starts_with(['-', '+']))
+                .collect::<Vec<_>>(),
+            ["--- a/modified", "+++ b/modified", "-quux", "+baz"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn diff_ignores_user_config() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git_in(dir.path(), args);
+        git(&["init", "--quiet"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "foo\n").or_fail()?;
+        git(&["add", "foo"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "bar\n").or_fail()?;
+
+        git(&["config", "diff.external", "echo corrupted"]).or_fail()?;
+        git(&["config", "color.diff", "always"]).or_fail()?;
+        git(&["config", "diff.noprefix", "true"]).or_fail()?;
+        assert!(git(&["diff"]).or_fail()?.contains("corrupted"));
+
+        let diff = Diff::from_str(&git(DIFF_ARGS).or_fail()?).or_fail()?;
+        assert_eq!(diff.files.len(), 1);
+        assert_eq!(diff.files[0].path(), Path::new("foo"));
+        assert_eq!(diff.files[0].chunks()[0].lines.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn add_after_modifying_staged_file() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git_in(dir.path(), args);
+        git(&["init", "--quiet"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "foo\n").or_fail()?;
+        git(&["add", "foo"]).or_fail()?;
+
+        // The file now has both staged and unstaged changes.
+        std::fs::write(dir.path().join("foo"), "foo\nbar\n").or_fail()?;
+        assert_eq!(git(&["show", ":foo"]).or_fail()?, "foo\n");
+
+        with_current_dir(dir.path(), || add(Path::new("foo"))).or_fail()?;
+        assert_eq!(git(&["show", ":foo"]).or_fail()?, "foo\nbar\n");
+        assert_eq!(git(&["diff", "--name-only"]).or_fail()?, "");
+
+        // A deleted file is removed from the index.
+        std::fs::remove_file(dir.path().join("foo")).or_fail()?;
+        with_current_dir(dir.path(), || add(Path::new("foo"))).or_fail()?;
+        assert_eq!(git(&["ls-files"]).or_fail()?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn stage_crlf_file_with_text_auto() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git_in(dir.path(), args);
+        git(&["init", "--quiet"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "a\r\nb\r\nc\r\n").or_fail()?;
+        git(&["add", "foo"]).or_fail()?;
+        git(&["commit", "--quiet", "-m", "Add foo"]).or_fail()?;
+        std::fs::write(dir.path().join(".gitattributes"), "* text=auto\n").or_fail()?;
+        std::fs::write(dir.path().join("foo"), "a\r\nB\r\nc\r\n").or_fail()?;
+
+        // The patch itself does not apply, as its lines end with LF.
+        let diff = Diff::from_str(&git(DIFF_ARGS).or_fail()?).or_fail()?;
+        std::fs::write(
+            dir.path().join(".git/foo.patch"),
+            diff.to_patch().or_fail()?,
+        )
+        .or_fail()?;
+        assert!(git(&["apply", "--cached", "--check", ".git/foo.patch"]).is_err());
+
+        with_current_dir(dir.path(), || stage(&diff)).or_fail()?;
+        assert_eq!(git(&["show", ":foo"]).or_fail()?, "a\r\nB\r\nc\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn head_summary_of_unborn_branch() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        git_in(dir.path(), &["init", "--quiet"]).or_fail()?;
+
+        // `git log` fails if there are no commits yet.
+        assert!(git_in(dir.path(), HEAD_SUMMARY_ARGS).is_err());
+
+        std::fs::write(dir.path().join("foo"), "foo\n").or_fail()?;
+        git_in(dir.path(), &["add", "foo"]).or_fail()?;
+        git_in(dir.path(), &["commit", "--quiet", "-m", "Add foo"]).or_fail()?;
+
+        let output = git_in(dir.path(), HEAD_SUMMARY_ARGS).or_fail()?;
+        let (hash, subject) = parse_head_summary(&output).or_fail()?;
+        assert!(!hash.is_empty());
+        assert_eq!(subject, "Add foo");
+        Ok(())
+    }
+
+    #[test]
+    fn blame_authors() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |name: &str, args: &[&str]| git_as(dir.path(), name, args);
+        git("alice", &["init", "--quiet"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "a\nb\n").or_fail()?;
+        git("alice", &["add", "foo"]).or_fail()?;
+        git("alice", &["commit", "--quiet", "-m", "a"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "a\nB\nc\n").or_fail()?;
+        git("bob", &["commit", "--quiet", "-am", "b"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "a\nB\nc\nd\n").or_fail()?;
+        git("bob", &["add", "foo"]).or_fail()?;
+        // Unstaged lines are not blamed.
+        std::fs::write(dir.path().join("foo"), "a\nB\nc\nd\ne\n").or_fail()?;
+
+        let authors =
+            with_current_dir(dir.path(), || blame_index_authors(Path::new("foo"))).or_fail()?;
+        assert_eq!(
+            authors,
+            [
+                "alice <alice@example.com>",
+                "bob <bob@example.com>",
+                "bob <bob@example.com>",
+                "Not Committed Yet <not.committed.yet>",
+            ]
+        );
+
+        // A file that is not in the index cannot be blamed.
+        let result = with_current_dir(dir.path(), || blame_index_authors(Path::new("bar")));
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn commit_with_message() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git_in(dir.path(), args);
+        git(&["init", "--quiet"]).or_fail()?;
+        // `commit()` does not pass an identity on the command line.
+        git(&["config", "user.name", "test"]).or_fail()?;
+        git(&["config", "user.email", "test@example.com"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "foo\n").or_fail()?;
+        git(&["add", "foo"]).or_fail()?;
+        assert!(with_current_dir(dir.path(), has_staged_changes).or_fail()?);
+
+        let output = with_current_dir(dir.path(), || commit("feat: add foo")).or_fail()?;
+        assert!(output.status.success());
+        let (_, subject) = parse_head_summary(&git(HEAD_SUMMARY_ARGS).or_fail()?).or_fail()?;
+        assert_eq!(subject, "feat: add foo");
+
+        // Nothing to commit.
+        assert!(!with_current_dir(dir.path(), has_staged_changes).or_fail()?);
+        let output = with_current_dir(dir.path(), || commit("feat: add bar")).or_fail()?;
+        assert!(!output.status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn show_commit_diff() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git_in(dir.path(), args);
+        let changed_paths = |rev: &str| -> orfail::Result<Vec<PathBuf>> {
+            let diff = with_current_dir(dir.path(), || show_commit(rev)).or_fail()?;
+            Ok(diff.files.iter().map(|f| f.path().clone()).collect())
+        };
+        git(&["init", "--quiet", "--initial-branch=main"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "foo\n").or_fail()?;
+        std::fs::write(dir.path().join("bar"), "bar\n").or_fail()?;
+        git(&["add", "."]).or_fail()?;
+        git(&["commit", "--quiet", "-m", "init"]).or_fail()?;
+
+        // The root commit adds the files.
+        assert_eq!(
+            changed_paths("HEAD").or_fail()?,
+            ["bar", "foo"].map(PathBuf::from)
+        );
 
-        // Now, `dir` is a Git directory.
-        assert!(is_available());
+        git(&["switch", "--quiet", "-c", "topic"]).or_fail()?;
+        std::fs::write(dir.path().join("bar"), "BAR\n").or_fail()?;
+        git(&["commit", "--quiet", "-am", "bar"]).or_fail()?;
+        git(&["switch", "--quiet", "main"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "FOO\n").or_fail()?;
+        git(&["commit", "--quiet", "-am", "foo"]).or_fail()?;
+        git(&["merge", "--quiet", "--no-edit", "topic"]).or_fail()?;
 
+        // A merge shows the changes from the first parent.
+        assert_eq!(changed_paths("HEAD").or_fail()?, [PathBuf::from("bar")]);
+        assert_eq!(changed_paths("HEAD^").or_fail()?, [PathBuf::from("foo")]);
         Ok(())
     }
 
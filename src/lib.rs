@@ -1,7 +1,12 @@
 pub mod action;
 pub mod app;
 pub mod canvas;
+pub mod clipboard;
 pub mod diff;
+pub mod event_log;
 pub mod git;
+pub mod widget_chunk_preview;
 pub mod widget_diff_tree;
+pub mod widget_help;
+pub mod widget_hunk_list;
 pub mod widget_legend;
@@ -1,7 +1,14 @@
-use std::path::PathBuf;
+use std::{
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Write},
+    num::{NonZeroU64, NonZeroUsize},
+    os::fd::{AsFd, AsRawFd},
+    path::PathBuf,
+    time::Duration,
+};
 
 use mame::action::BindingConfig;
-use mamediff::{app::App, git};
+use mamediff::{app::App, canvas::Charset, git, widget_diff_tree::DiffTreeWidget};
 use orfail::OrFail;
 
 fn main() -> noargs::Result<()> {
@@ -15,7 +22,7 @@ fn main() -> noargs::Result<()> {
     }
     noargs::HELP_FLAG.take_help(&mut args);
 
-    let config_path: Option<PathBuf> = noargs::opt("config")
+    let config_opt = noargs::opt("config")
         .short('c')
         .ty("PATH")
         .doc(concat!(
@@ -25,26 +32,313 @@ fn main() -> noargs::Result<()> {
         ))
         .example("/path/to/config.jsonc")
         .env("MAMEDIFF_CONFIG_FILE")
+        .take(&mut args);
+    let config_from_env = matches!(config_opt, noargs::Opt::Env { .. });
+    let config_path: Option<PathBuf> = config_opt.present_and_then(|a| a.value().parse())?;
+
+    let print_config_source = noargs::flag("print-config-source")
+        .doc("Print which key bindings configuration is in effect and exit")
+        .take(&mut args)
+        .is_present();
+
+    let select_path: Option<PathBuf> = noargs::opt("select")
+        .ty("PATH")
+        .doc("Start with the cursor on the given file (if it has changes)")
         .take(&mut args)
         .present_and_then(|a| a.value().parse())?;
 
+    let staged_base: Option<String> = noargs::opt("staged-base")
+        .ty("REF")
+        .doc(concat!(
+            "Show staged changes relative to the given commit instead of HEAD\n",
+            "\n",
+            "The staged view becomes `git diff --cached REF` (e.g., the merge base with main).\n",
+            "Staging still updates the index as usual, but unstaging is disabled\n",
+            "because the displayed changes may include already committed ones"
+        ))
+        .example("main")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+
+    let shown_rev: Option<String> = noargs::opt("show")
+        .ty("REV")
+        .doc(concat!(
+            "Review the changes introduced by the given commit instead of the working tree\n",
+            "\n",
+            "The tree is read-only: staging, unstaging and discarding are disabled.\n",
+            "For a merge commit, the changes relative to its first parent are shown"
+        ))
+        .example("HEAD~1")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+
+    let staged_diff_filter: Option<String> = noargs::opt("staged-diff-filter")
+        .ty("FILTER")
+        .doc(concat!(
+            "Show only the staged changes of the given types (passed to `git diff --diff-filter`)\n",
+            "\n",
+            "E.g., `AD` shows only added and deleted files.\n",
+            "The unstaged changes are not affected"
+        ))
+        .example("AD")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+
+    let jobs: Option<NonZeroUsize> = noargs::opt("jobs")
+        .short('j')
+        .ty("INTEGER")
+        .doc(concat!(
+            "Number of threads used to diff untracked files\n",
+            "\n",
+            "Default: the number of CPUs"
+        ))
+        .env("MAMEDIFF_JOBS")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+
+    let untracked_dirs = noargs::flag("untracked-dirs")
+        .doc(concat!(
+            "Show each wholly untracked directory as a single entry instead of its files\n",
+            "\n",
+            "Speeds up startup in repositories with large untracked trees.\n",
+            "Staging or discarding the entry applies to all the files in the directory"
+        ))
+        .take(&mut args)
+        .is_present();
+
+    let group_untracked: Option<NonZeroUsize> = noargs::opt("group-untracked")
+        .ty("INTEGER")
+        .doc(concat!(
+            "Show the untracked files in a directory as a single entry if there are more than INTEGER\n",
+            "\n",
+            "The entry can be expanded to show the files, and staging it stages all of them"
+        ))
+        .env("MAMEDIFF_GROUP_UNTRACKED")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+
+    let refresh_interval: Option<NonZeroU64> = noargs::opt("refresh-interval")
+        .ty("SECS")
+        .doc(concat!(
+            "Reload the diffs every SECS seconds to catch changes made outside mamediff\n",
+            "\n",
+            "The reload is skipped while a preview or a prompt is shown"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+
+    let ascii = noargs::flag("ascii")
+        .doc(concat!(
+            "Use ASCII characters instead of Unicode ones (e.g., `...` instead of `…`)\n",
+            "\n",
+            "By default, ASCII is used only if the locale (LC_ALL, LC_CTYPE or LANG) is not UTF-8"
+        ))
+        .take(&mut args)
+        .is_present();
+
+    let once = noargs::flag("once")
+        .doc(concat!(
+            "Print the initial screen (with ANSI styles) to stdout and exit\n",
+            "\n",
+            "The key bindings legend is not included"
+        ))
+        .take(&mut args)
+        .is_present();
+
+    let print_staged_on_exit = noargs::flag("print-staged-on-exit")
+        .doc(concat!(
+            "Print the staged changes as a patch to stdout on exit\n",
+            "\n",
+            "If stdout is not a terminal, the TUI is drawn on /dev/tty instead,\n",
+            "so the output can be piped to other commands"
+        ))
+        .take(&mut args)
+        .is_present();
+
+    let summary = noargs::flag("summary")
+        .doc(concat!(
+            "Print the numbers of changed files and lines as JSON and exit\n",
+            "\n",
+            r#"Output example: {"staged_files":1,"unstaged_files":2,"added":12,"removed":3}"#
+        ))
+        .take(&mut args)
+        .is_present();
+
+    let show_head = noargs::flag("show-head")
+        .doc(concat!(
+            "Show the short hash and subject of the HEAD commit in the status line\n",
+            "\n",
+            "Useful to check which commit the staged changes will be based on"
+        ))
+        .take(&mut args)
+        .is_present();
+
+    let dry_run = noargs::flag("dry-run")
+        .doc(concat!(
+            "Show the patches of stage, unstage and discard operations instead of applying them\n",
+            "\n",
+            "Neither the index nor the working tree is modified"
+        ))
+        .take(&mut args)
+        .is_present();
+
+    let log_events: Option<PathBuf> = noargs::opt("log-events")
+        .ty("FILE")
+        .doc(concat!(
+            "Append each handled action and reload to FILE as a JSON line\n",
+            "\n",
+            "Each line includes the resulting cursor path, e.g.,\n",
+            r#"{"event":"action","action":"move-down","cursor":[0,0,1]}"#
+        ))
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+
+    let debug = noargs::flag("debug")
+        .doc(concat!(
+            "Log executed `git` commands and diff parse errors\n",
+            "\n",
+            "The log is written to stderr after the TUI exits"
+        ))
+        .take(&mut args)
+        .is_present();
+
     if let Some(help) = args.finish()? {
         print!("{help}");
         return Ok(());
     }
 
+    // `git::is_available()` changes the current directory to the repository root,
+    // so the config is loaded before it to resolve a relative path against the directory
+    // where mamediff was started.
+    let config_source = match config_path {
+        Some(path) if config_from_env => ConfigSource::Env(path),
+        Some(path) => ConfigSource::Option(path),
+        None => ConfigSource::Default,
+    };
+    let config = match &config_source {
+        ConfigSource::Option(path) | ConfigSource::Env(path) => {
+            BindingConfig::load_from_file(path)?
+        }
+        ConfigSource::Default => {
+            BindingConfig::load_from_str("<DEFAULT>", include_str!("../configs/default.jsonc"))?
+        }
+    };
+    if print_config_source {
+        println!("{config_source}");
+        return Ok(());
+    }
+
     if !git::is_available() {
         eprintln!("error: no `git` command found, or not a Git directory");
         std::process::exit(1);
     };
 
-    let config = if let Some(path) = config_path {
-        BindingConfig::load_from_file(path)?
+    if debug {
+        git::enable_debug_log();
+    }
+    if let Some(jobs) = jobs {
+        git::set_untracked_diff_jobs(jobs);
+    }
+    if untracked_dirs {
+        git::set_collapse_untracked_dirs();
+    }
+    if let Some(threshold) = group_untracked {
+        git::set_untracked_group_threshold(threshold);
+    }
+    if let Some(base) = staged_base {
+        git::set_staged_diff_base(base);
+    }
+    if let Some(filter) = staged_diff_filter {
+        git::set_staged_diff_filter(filter);
+    }
+    if let Some(rev) = shown_rev {
+        git::set_shown_rev(rev);
+    }
+    if summary {
+        let tree = DiffTreeWidget::load().or_fail()?;
+        println!("{}", nojson::Json(tree.change_summary()));
+        return Ok(());
+    }
+
+    let patch_output = if print_staged_on_exit {
+        Some(take_stdout().or_fail()?)
     } else {
-        BindingConfig::load_from_str("<DEFAULT>", include_str!("../configs/default.jsonc"))?
+        None
     };
 
-    let app = App::new(config).or_fail()?;
-    app.run().or_fail()?;
+    let result = App::new(config).and_then(|mut app| {
+        if ascii {
+            app.set_charset(Charset::Ascii);
+        }
+        if let Some(secs) = refresh_interval {
+            app.set_refresh_interval(Duration::from_secs(secs.get()));
+        }
+        if show_head {
+            app.show_head_summary();
+        }
+        if dry_run {
+            app.set_dry_run();
+        }
+        if let Some(path) = &log_events {
+            app.set_event_log(path).or_fail()?;
+        }
+        if let Some(path) = select_path {
+            app.select_file(&path).or_fail()?;
+        }
+        if once {
+            // The terminal is restored when `app` is dropped, so the text stays visible.
+            let text = app.render_once().or_fail()?;
+            print!("{text}");
+            return Ok(());
+        }
+        app.run().or_fail()?;
+        if let Some(mut output) = patch_output {
+            let patch = app.staged_patch().or_fail()?;
+            drop(app); // Restore the terminal before printing.
+            output.write_all(patch.as_bytes()).or_fail()?;
+        }
+        Ok(())
+    });
+    if debug {
+        eprintln!("[debug] config: {config_source}");
+    }
+    for entry in git::take_debug_log() {
+        eprintln!("[debug] {entry}");
+    }
+    result.or_fail()?;
     Ok(())
 }
+
+// Returns a handle to the original stdout.
+// If stdout is not a terminal, /dev/tty is put in its place so that the TUI output
+// does not mix with what is printed to the returned handle.
+fn take_stdout() -> std::io::Result<File> {
+    let stdout = std::io::stdout();
+    let original = File::from(stdout.as_fd().try_clone_to_owned()?);
+    if stdout.is_terminal() {
+        return Ok(original);
+    }
+
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(original)
+}
+
+// Where the key bindings configuration was loaded from.
+enum ConfigSource {
+    Option(PathBuf),
+    Env(PathBuf),
+    Default,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Option(path) => write!(f, "{} (from --config)", path.display()),
+            Self::Env(path) => write!(f, "{} (from $MAMEDIFF_CONFIG_FILE)", path.display()),
+            Self::Default => write!(f, "<DEFAULT> (embedded default)"),
+        }
+    }
+}
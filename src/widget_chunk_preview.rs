@@ -0,0 +1,274 @@
+use std::ops::Range;
+
+use tuinix::{KeyCode, KeyInput, TerminalPosition, TerminalSize, TerminalStyle};
+
+use crate::canvas::{Canvas, Charset, Token};
+use crate::diff::{ChunkDiff, LineDiff};
+
+// Full-screen view of an unstaged chunk within the whole file,
+// for staging a contiguous range of its lines.
+#[derive(Debug)]
+pub struct ChunkPreviewWidget {
+    title: String,
+    // Each line and, if it is a line of the chunk, its index in the chunk.
+    rows: Vec<(LineDiff, Option<usize>)>,
+    cursor: usize,
+    // Row where the selection started (the selection is the cursor row only if `None`).
+    anchor: Option<usize>,
+    row_start: usize,
+}
+
+// What to do after a key is handled by the preview.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChunkPreviewInput {
+    Continue,
+    Close,
+    // Stages the lines of the chunk in the range.
+    Stage(Range<usize>),
+}
+
+impl ChunkPreviewWidget {
+    // The title line precedes the rows, and the status line follows them.
+    const HEADER_ROWS: usize = 1;
+    const STATUS_ROWS: usize = 1;
+
+    // `content` is the working tree version of the file, which the new side of the chunk is in.
+    pub fn new(
+        title: String,
+        chunk: &ChunkDiff,
+        content: Option<&str>,
+        size: TerminalSize,
+    ) -> Self {
+        let file_lines = content.unwrap_or_default().lines().collect::<Vec<_>>();
+        let context = |lines: &[&str]| {
+            lines
+                .iter()
+                .map(|l| (LineDiff::Both((*l).to_owned()), None))
+                .collect::<Vec<_>>()
+        };
+        let chunk_new_lines = chunk
+            .lines
+            .iter()
+            .filter(|l| matches!(l, LineDiff::New(_) | LineDiff::Both(_)))
+            .count();
+        let before_end = chunk.new_start_line_number.saturating_sub(1);
+        let after_start = before_end + chunk_new_lines;
+
+        let mut rows = context(file_lines.get(..before_end).unwrap_or_default());
+        let cursor = rows.len();
+        rows.extend(chunk.lines.iter().cloned().zip((0..).map(Some)));
+        rows.extend(context(file_lines.get(after_start..).unwrap_or_default()));
+
+        let mut this = Self {
+            title,
+            rows,
+            cursor,
+            anchor: None,
+            row_start: 0,
+        };
+        // The chunk starts in the middle of the screen.
+        this.row_start = cursor.saturating_sub(this.visible_rows(size) / 2);
+        this
+    }
+
+    fn visible_rows(&self, size: TerminalSize) -> usize {
+        size.rows
+            .saturating_sub(Self::HEADER_ROWS + Self::STATUS_ROWS)
+            .max(1)
+    }
+
+    fn selection(&self) -> Range<usize> {
+        let anchor = self.anchor.unwrap_or(self.cursor);
+        anchor.min(self.cursor)..anchor.max(self.cursor) + 1
+    }
+
+    // Returns the range of the chunk lines in the selection.
+    fn selected_chunk_lines(&self) -> Option<Range<usize>> {
+        let mut indices = self.rows[self.selection()].iter().filter_map(|(_, i)| *i);
+        let start = indices.next()?;
+        let end = indices.next_back().unwrap_or(start) + 1;
+        Some(start..end)
+    }
+
+    // Shows how many chunk lines would be staged while selecting.
+    fn status(&self) -> Option<String> {
+        self.anchor?;
+        let lines = self.selected_chunk_lines().map_or(0, |range| range.len());
+        let unit = if lines == 1 { "line" } else { "lines" };
+        Some(format!("{lines} {unit} selected"))
+    }
+
+    pub fn handle_key(&mut self, key: KeyInput, size: TerminalSize) -> ChunkPreviewInput {
+        let last = self.rows.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Char('j') | KeyCode::Down => self.cursor = (self.cursor + 1).min(last),
+            KeyCode::Char('k') | KeyCode::Up => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::PageDown => self.cursor = (self.cursor + size.rows).min(last),
+            KeyCode::PageUp => self.cursor = self.cursor.saturating_sub(size.rows),
+            KeyCode::Char('v') | KeyCode::Char(' ') => {
+                self.anchor = match self.anchor {
+                    Some(_) => None,
+                    None => Some(self.cursor),
+                };
+            }
+            KeyCode::Char('s') => {
+                if let Some(range) = self.selected_chunk_lines() {
+                    return ChunkPreviewInput::Stage(range);
+                }
+            }
+            KeyCode::Escape | KeyCode::Char('q') => return ChunkPreviewInput::Close,
+            _ => {}
+        }
+
+        // Keep the cursor row visible.
+        let rows = self.visible_rows(size);
+        if self.cursor < self.row_start {
+            self.row_start = self.cursor;
+        } else if self.cursor >= self.row_start + rows {
+            self.row_start = self.cursor + 1 - rows;
+        }
+        ChunkPreviewInput::Continue
+    }
+
+    pub fn render(
+        &self,
+        size: TerminalSize,
+        charset: Charset,
+    ) -> mame::terminal::UnicodeTerminalFrame {
+        let mut canvas = Canvas::new(0, size);
+        canvas.set_charset(charset);
+        canvas.drawln(Token::with_style(
+            format!(
+                "{} (j/k to move, v to select, s to stage, ESC to close)",
+                self.title
+            ),
+            TerminalStyle::new().bold(),
+        ));
+        let selection = self.selection();
+        for (i, (line, index)) in self.rows.iter().enumerate().skip(self.row_start) {
+            if canvas.cursor().row + Self::STATUS_ROWS >= size.rows {
+                break;
+            }
+            let (marker, text) = match line {
+                LineDiff::Old(s) => ("-", s.as_str()),
+                LineDiff::New(s) => ("+", s.as_str()),
+                LineDiff::Both(s) => (" ", s.as_str()),
+                LineDiff::NoNewlineAtEndOfFile => ("\\", " No newline at end of file"),
+            };
+            // Lines outside the chunk are dimmed, and chunk lines are styled as in the tree.
+            let style = match (line, index) {
+                (_, None) | (LineDiff::Old(_), _) => TerminalStyle::new().dim(),
+                (LineDiff::New(_), _) => TerminalStyle::new().bold(),
+                _ => TerminalStyle::new(),
+            };
+            let style = if selection.contains(&i) && (self.anchor.is_some() || i == self.cursor) {
+                style.reverse()
+            } else {
+                style
+            };
+            let prefix = if i == self.cursor { "->" } else { "  " };
+            canvas.draw(Token::new(prefix));
+            canvas.drawln(Token::with_style(format!("{marker}{text}"), style));
+        }
+        if let Some(status) = self.status() {
+            let position = TerminalPosition::row_col(size.rows.saturating_sub(1), 0);
+            let text = format!("{status:<0$}", size.cols);
+            canvas.draw_at(
+                position,
+                Token::with_style(text, TerminalStyle::new().reverse()),
+            );
+        }
+        canvas.into_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use orfail::OrFail;
+
+    use super::*;
+    use crate::diff::Diff;
+
+    fn key(code: KeyCode) -> KeyInput {
+        KeyInput {
+            ctrl: false,
+            alt: false,
+            code,
+        }
+    }
+
+    #[test]
+    fn select_and_stage() -> orfail::Result<()> {
+        let text = r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -2,3 +2,3 @@
+ b
+-c
++C
+ d"#;
+        let diff = Diff::from_str(text).or_fail()?;
+        let chunk = &diff.files[0].chunks()[0];
+        let size = TerminalSize::rows_cols(10, 80);
+        let mut preview =
+            ChunkPreviewWidget::new("foo".to_owned(), chunk, Some("a\nb\nC\nd\ne\n"), size);
+
+        // The file lines around the chunk are shown as well.
+        let rows = preview
+            .rows
+            .iter()
+            .map(|(l, i)| (l.to_string(), *i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                (" a".to_owned(), None),
+                (" b".to_owned(), Some(0)),
+                ("-c".to_owned(), Some(1)),
+                ("+C".to_owned(), Some(2)),
+                (" d".to_owned(), Some(3)),
+                (" e".to_owned(), None),
+            ]
+        );
+        assert_eq!(preview.cursor, 1);
+
+        // Only the chunk lines in the selection are staged.
+        preview.handle_key(key(KeyCode::Char('k')), size);
+        preview.handle_key(key(KeyCode::Char('v')), size);
+        assert_eq!(preview.status().as_deref(), Some("0 lines selected"));
+        preview.handle_key(key(KeyCode::Char('j')), size);
+        assert_eq!(preview.status().as_deref(), Some("1 line selected"));
+        preview.handle_key(key(KeyCode::Char('j')), size);
+        assert_eq!(preview.status().as_deref(), Some("2 lines selected"));
+        assert_eq!(
+            preview.handle_key(key(KeyCode::Char('s')), size),
+            ChunkPreviewInput::Stage(0..2)
+        );
+
+        // Without a selection, the cursor line is staged.
+        preview.handle_key(key(KeyCode::Char('v')), size);
+        assert_eq!(preview.status(), None);
+        preview.handle_key(key(KeyCode::Char('j')), size);
+        assert_eq!(
+            preview.handle_key(key(KeyCode::Char('s')), size),
+            ChunkPreviewInput::Stage(2..3)
+        );
+
+        // Lines outside the chunk cannot be staged.
+        for _ in 0..3 {
+            preview.handle_key(key(KeyCode::Down), size);
+        }
+        assert_eq!(
+            preview.handle_key(key(KeyCode::Char('s')), size),
+            ChunkPreviewInput::Continue
+        );
+        assert_eq!(
+            preview.handle_key(key(KeyCode::Escape), size),
+            ChunkPreviewInput::Close
+        );
+        Ok(())
+    }
+}
@@ -1,10 +1,17 @@
-use std::cmp::Ordering;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use orfail::OrFail;
-use tuinix::{TerminalSize, TerminalStyle};
+use tuinix::{TerminalColor, TerminalPosition, TerminalSize, TerminalStyle};
 
 use crate::{
-    canvas::{Canvas, Token},
+    canvas::{Canvas, Charset, Token},
     diff::{ChunkDiff, ContentDiff, Diff, FileDiff, LineDiff},
     git,
 };
@@ -15,836 +22,6054 @@ pub struct DiffTreeWidget {
     staged_diff: PhasedDiff,
     root_node: DiffTreeNode,
     cursor: Cursor,
+    compact: bool,
+    // Per-chunk overrides of `compact`.
+    chunk_compact: HashMap<ChunkKey, bool>,
+    // Runs of more context lines than this are folded, except in the unfolded chunks.
+    fold_context_lines: Option<usize>,
+    unfolded_chunks: HashSet<ChunkKey>,
+    show_staged_context: bool,
+    // Glob that the paths of the shown files match, and the diffs before filtering.
+    glob_filter: Option<String>,
+    unfiltered_diffs: (Diff, Diff),
+    // Partially staged file whose overlapping staged and unstaged chunks are shown together.
+    reconciled_file: Option<PathBuf>,
+    focus: bool,
+    path_style: PathStyle,
+    cwd_prefix: PathBuf,
+    // Base commit of the staged diff if it is not `HEAD`.
+    staged_diff_base: Option<String>,
+    // Commit whose changes are shown (read-only) instead of the unstaged changes.
+    shown_rev: Option<String>,
+    // Author whose staged chunks are highlighted (the others are dimmed) in staged-base mode,
+    // the blamed authors of the lines of the staged files (`None` if the file could not be blamed),
+    // and the dimmed (file, chunk) indices.
+    author_filter: Option<String>,
+    blamed_authors: HashMap<PathBuf, Option<Vec<String>>>,
+    author_mismatches: HashSet<(usize, usize)>,
+    include_ignored: bool,
+    // Numbers of the files in the groups of untracked files, and the groups expanded by the user.
+    untracked_groups: BTreeMap<PathBuf, usize>,
+    expanded_untracked_groups: BTreeSet<PathBuf>,
+    // Whether the staged phase compares the index with the working tree instead of `HEAD`.
+    staged_against_worktree: bool,
+    // Whether the index differs from `HEAD` (the staged phase may not tell it, e.g., in the above mode).
+    has_staged_changes: bool,
+    show_deleted_content: bool,
+    // Whether the unstaged and staged files are shown as a single flat list.
+    combined: bool,
+    // Whether the files are listed in a left pane with the file under the cursor on the right.
+    sidebar: bool,
+    // Index of the selected file in the combined view.
+    combined_index: usize,
+    // Patches of recently discarded changes (the newest is at the back).
+    trash: VecDeque<String>,
+    // Expanded state saved before the last collapse-all, expand-all or outline.
+    saved_expansion: Option<DiffTreeNode>,
+    // Whether only the chunk headers of the files are shown (see `toggle_outline()`).
+    outline: bool,
+    // Title and patch of the last operation skipped in dry-run mode.
+    dry_run_patch: Option<(&'static str, String)>,
+    // Whether the last stage operation added lines that look like conflict markers.
+    staged_conflict_markers: bool,
+    // Whether stage, unstage and discard only show their patches without applying them.
+    pub dry_run: bool,
+    // Whether to show how long ago each file was modified in the working tree.
+    show_mtimes: bool,
+    show_binary_sizes: bool,
+    file_order: FileOrder,
+    pub wrap_navigation: bool,
+    // Whether to draw a horizontal line between the unstaged and staged phases.
+    pub phase_separator: bool,
+    pub focus_lines: usize,
+    pub show_child_counts: bool,
+    // Whether to show file modes as labels (e.g., "executable") instead of in octal.
+    mode_labels: bool,
+    pub elide_path_components: usize,
+    // Whether to mark files that have appeared or changed since the previous reload.
+    pub mark_reload_changes: bool,
+    // Number of times the diffs have been (re)loaded.
+    load_count: usize,
+    // Colors of the unstaged and staged phase headers.
+    pub header_colors: [TerminalColor; 2],
+    // Column of the line text after which a vertical ruler is drawn.
+    pub ruler_column: Option<usize>,
+    // Width of the area the tree is drawn in, if narrower than the terminal.
+    pub max_content_cols: Option<usize>,
+    // Whether to center the area limited by `max_content_cols` (instead of aligning it left).
+    pub center_content: bool,
 }
 
 impl DiffTreeWidget {
+    pub const DEFAULT_FOCUS_LINES: usize = 3;
+    pub const DEFAULT_UNSTAGED_HEADER_COLOR: TerminalColor = TerminalColor::YELLOW;
+    pub const DEFAULT_STAGED_HEADER_COLOR: TerminalColor = TerminalColor::CYAN;
+    const TRASH_CAPACITY: usize = 20;
+    // Column where the text of a diff line starts (after the cursor area and the `+`/`-` marker).
+    const LINE_TEXT_COL: usize = 11;
+    const MAX_SIDEBAR_COLS: usize = 40;
+
     pub fn new(terminal_size: TerminalSize) -> orfail::Result<Self> {
+        let mut this = Self::load().or_fail()?;
+        this.expand_if_possible(terminal_size).or_fail()?;
+        Ok(this)
+    }
+
+    // Loads the current diffs without expanding any nodes (no terminal is needed).
+    pub fn load() -> orfail::Result<Self> {
+        let (unstaged_diff, staged_diff, untracked_groups) =
+            git::unstaged_and_staged_diffs(false, false, &BTreeSet::new()).or_fail()?;
+        let mut this = Self::with_diffs(unstaged_diff, staged_diff).or_fail()?;
+        this.untracked_groups = untracked_groups;
+        this.has_staged_changes = git::has_staged_changes().or_fail()?;
+        Ok(this)
+    }
+
+    fn with_diffs(unstaged_diff: Diff, staged_diff: Diff) -> orfail::Result<Self> {
+        let has_staged_changes = !staged_diff.files.is_empty();
         let mut this = Self {
-            unstaged_diff: PhasedDiff {
-                phase: DiffPhase::Unstaged,
-                diff: Diff::default(),
-            },
-            staged_diff: PhasedDiff {
-                phase: DiffPhase::Staged,
-                diff: Diff::default(),
-            },
+            unstaged_diff: PhasedDiff::new(DiffPhase::Unstaged),
+            staged_diff: PhasedDiff::new(DiffPhase::Staged),
             root_node: DiffTreeNode::new_root_node(),
             cursor: Cursor::root(),
+            compact: false,
+            chunk_compact: HashMap::new(),
+            fold_context_lines: None,
+            unfolded_chunks: HashSet::new(),
+            show_staged_context: false,
+            glob_filter: None,
+            unfiltered_diffs: (Diff::default(), Diff::default()),
+            reconciled_file: None,
+            focus: false,
+            path_style: PathStyle::default(),
+            cwd_prefix: git::cwd_prefix(),
+            staged_diff_base: git::staged_diff_base().map(|s| s.to_owned()),
+            shown_rev: git::shown_rev().map(|s| s.to_owned()),
+            author_filter: None,
+            blamed_authors: HashMap::new(),
+            author_mismatches: HashSet::new(),
+            include_ignored: false,
+            untracked_groups: BTreeMap::new(),
+            expanded_untracked_groups: BTreeSet::new(),
+            staged_against_worktree: false,
+            has_staged_changes,
+            show_deleted_content: false,
+            combined: false,
+            sidebar: false,
+            combined_index: 0,
+            trash: VecDeque::new(),
+            saved_expansion: None,
+            outline: false,
+            dry_run_patch: None,
+            staged_conflict_markers: false,
+            dry_run: false,
+            show_mtimes: false,
+            show_binary_sizes: false,
+            file_order: FileOrder::default(),
+            wrap_navigation: false,
+            phase_separator: false,
+            focus_lines: Self::DEFAULT_FOCUS_LINES,
+            show_child_counts: false,
+            mode_labels: false,
+            elide_path_components: 0,
+            mark_reload_changes: false,
+            load_count: 0,
+            header_colors: [
+                Self::DEFAULT_UNSTAGED_HEADER_COLOR,
+                Self::DEFAULT_STAGED_HEADER_COLOR,
+            ],
+            ruler_column: None,
+            max_content_cols: None,
+            center_content: false,
         };
-        this.reload().or_fail()?;
-        this.expand_if_possible(terminal_size).or_fail()?;
+        this.update_diffs(unstaged_diff, staged_diff).or_fail()?;
         Ok(this)
     }
 
     pub fn render(&self, canvas: &mut Canvas) {
-        for (node, diff) in self.children_and_diffs() {
-            if !node.render_if_need(canvas, &self.cursor, diff) {
+        let size = canvas.frame_size();
+        let Some(cols) = self.max_content_cols.filter(|&cols| cols < size.cols) else {
+            self.render_content(canvas);
+            return;
+        };
+
+        // Lines are truncated at the width of the narrower canvas rather than of the terminal.
+        let mut content_canvas = Canvas::new(
+            canvas.frame_row_range().start,
+            TerminalSize::rows_cols(size.rows, cols),
+        );
+        content_canvas.set_charset(canvas.charset());
+        self.render_content(&mut content_canvas);
+        let col = if self.center_content {
+            (size.cols - cols) / 2
+        } else {
+            0
+        };
+        canvas.draw_canvas(col, content_canvas);
+    }
+
+    fn render_content(&self, canvas: &mut Canvas) {
+        let mut duplicate_basenames = HashSet::new();
+        if self.path_style == PathStyle::Basename {
+            let mut basenames = HashSet::new();
+            for file in self
+                .unstaged_diff
+                .diff
+                .files
+                .iter()
+                .chain(&self.staged_diff.diff.files)
+            {
+                if let Some(name) = file.path().file_name()
+                    && !basenames.insert(name)
+                {
+                    duplicate_basenames.insert(name);
+                }
+            }
+        }
+        let ctx = RenderContext {
+            cursor: &self.cursor,
+            staged_diff: &self.staged_diff.diff,
+            path_style: self.path_style,
+            cwd_prefix: &self.cwd_prefix,
+            duplicate_basenames,
+            show_child_counts: self.show_child_counts,
+            mode_labels: self.mode_labels,
+            include_ignored: self.include_ignored,
+            untracked_groups: &self.untracked_groups,
+            staged_against_worktree: self.staged_against_worktree,
+            staged_diff_base: self.staged_diff_base.as_deref(),
+            shown_rev: self.shown_rev.as_deref(),
+            author_mismatches: &self.author_mismatches,
+            elide_path_components: self.elide_path_components,
+            charset: canvas.charset(),
+            mark_reload_changes: self.mark_reload_changes,
+            file_order: self.file_order,
+            now: SystemTime::now(),
+            mtimes: [&self.unstaged_diff.mtimes, &self.staged_diff.mtimes],
+            binary_sizes: [
+                &self.unstaged_diff.binary_sizes,
+                &self.staged_diff.binary_sizes,
+            ],
+            header_colors: self.header_colors,
+            reload_changes: [
+                &self.unstaged_diff.reload_changes,
+                &self.staged_diff.reload_changes,
+            ],
+        };
+        if self.combined {
+            self.render_combined(canvas, &ctx);
+        } else if self.sidebar {
+            self.render_sidebar(canvas, &ctx);
+            return;
+        } else {
+            for (i, (node, diff)) in self.children_and_diffs().enumerate() {
+                if i > 0 && self.separator_rows() > 0 {
+                    if canvas.is_frame_exceeded() {
+                        break;
+                    }
+                    canvas.draw_separator();
+                }
+                if !node.render_if_need(canvas, &ctx, diff) {
+                    break;
+                }
+            }
+        }
+        if let Some(col) = self.ruler_column {
+            canvas.draw_ruler(Self::LINE_TEXT_COL + col);
+        }
+    }
+
+    // Lists the files of both phases in the left pane and draws the file under the cursor
+    // in the right pane. The frame row offset of `canvas` applies to the right pane only.
+    fn render_sidebar(&self, canvas: &mut Canvas, ctx: &RenderContext) {
+        let size = canvas.frame_size();
+        let list_cols = (size.cols / 3).min(Self::MAX_SIDEBAR_COLS);
+        let selected_phase = self.cursor.path.get(1);
+        let selected_file = self.cursor.path.get(2);
+
+        let mut list = Vec::new();
+        let mut selected_row = 0;
+        for (phase, (_, diff)) in self.children_and_diffs().enumerate() {
+            let selected = selected_phase == Some(phase) && selected_file.is_none();
+            list.push((selected, "", diff.head_line_tokens(ctx).collect::<Vec<_>>()));
+            for (i, file) in diff.diff.files.iter().enumerate() {
+                let selected = selected_phase == Some(phase) && selected_file == Some(i);
+                list.push((selected, "  ", file.head_line_tokens(ctx).collect()));
+            }
+        }
+        if let Some(row) = list.iter().position(|(selected, _, _)| *selected) {
+            selected_row = row;
+        }
+
+        // The list scrolls independently so that the selected entry stays visible.
+        let list_row_start = if selected_row < size.rows {
+            0
+        } else {
+            selected_row - size.rows / 2
+        };
+        let mut list_canvas = Canvas::new(
+            list_row_start,
+            TerminalSize::rows_cols(size.rows, list_cols),
+        );
+        list_canvas.set_charset(canvas.charset());
+        for (selected, indent, tokens) in list {
+            if list_canvas.is_frame_exceeded() {
+                break;
+            }
+            list_canvas.draw(Token::new(if selected { ">" } else { " " }));
+            list_canvas.draw(Token::new(indent));
+            for token in tokens {
+                list_canvas.draw(token);
+            }
+            list_canvas.newline();
+        }
+
+        let file_size = TerminalSize::rows_cols(size.rows, size.cols.saturating_sub(list_cols + 1));
+        let mut file_canvas = Canvas::new(canvas.frame_row_range().start, file_size);
+        file_canvas.set_charset(canvas.charset());
+        if let Some(file_cursor) = self.cursor_file()
+            && let Ok(node) = self.root_node.get_node(&file_cursor)
+            && let Some((_, diff)) = self.children_and_diffs().nth(file_cursor.path.0[1])
+            && let Some(file) = diff.diff.files.get(node.content_index)
+        {
+            node.render_if_need(&mut file_canvas, ctx, file);
+        }
+        if let Some(col) = self.ruler_column {
+            file_canvas.draw_ruler(Self::LINE_TEXT_COL + col);
+        }
+
+        let row_start = canvas.frame_row_range().start;
+        let separator = Token::with_style(canvas.charset().ruler(), TerminalStyle::new().dim());
+        for row in 0..size.rows {
+            canvas.draw_at(
+                TerminalPosition::row_col(row_start + row, list_cols),
+                separator.clone(),
+            );
+        }
+        canvas.draw_canvas(0, list_canvas);
+        canvas.draw_canvas(list_cols + 1, file_canvas);
+    }
+
+    fn render_combined(&self, canvas: &mut Canvas, ctx: &RenderContext) {
+        let files = self.combined_files();
+        let header_path = NodePath::root().join(0);
+        let cursor = Cursor {
+            path: header_path.join(self.combined_index),
+        };
+        cursor.render(canvas, &header_path, true);
+        canvas.drawln(Token::with_style(
+            format!("Changes ({} files)", files.len()),
+            TerminalStyle::new().bold(),
+        ));
+
+        let style = TerminalStyle::new();
+        for (i, file) in files.iter().enumerate() {
+            if canvas.is_frame_exceeded() {
                 break;
             }
+            cursor.render(canvas, &header_path.join(i), true);
+            let badge = |present: bool, text: &'static str, color: TerminalColor| {
+                Token::with_style(if present { text } else { " " }, style.fg_color(color))
+            };
+            canvas.draw(Token::new("["));
+            canvas.draw(badge(file.staged.is_some(), "S", TerminalColor::GREEN));
+            canvas.draw(badge(file.unstaged.is_some(), "U", TerminalColor::RED));
+            canvas.draw(Token::new("] "));
+            let diff = match (file.unstaged, file.staged) {
+                (Some(i), _) => self.unstaged_diff.diff.files.get(i),
+                (None, Some(i)) => self.staged_diff.diff.files.get(i),
+                (None, None) => None,
+            };
+            for token in diff.into_iter().flat_map(|d| d.head_line_tokens(ctx)) {
+                canvas.draw(token);
+            }
+            canvas.newline();
+        }
+    }
+
+    // Returns the files of both phases ordered by path.
+    fn combined_files(&self) -> Vec<CombinedFile<'_>> {
+        let mut files = BTreeMap::new();
+        for (phase, (_, diff)) in self.children_and_diffs().enumerate() {
+            for (i, file) in diff.diff.files.iter().enumerate() {
+                let entry = files.entry(file.path()).or_insert(CombinedFile {
+                    path: file.path(),
+                    unstaged: None,
+                    staged: None,
+                });
+                if phase == 0 {
+                    entry.unstaged = Some(i);
+                } else {
+                    entry.staged = Some(i);
+                }
+            }
+        }
+        files.into_values().collect()
+    }
+
+    pub fn can_toggle_sidebar(&self) -> bool {
+        !self.combined
+    }
+
+    // Shows or hides the file list pane. Showing it expands all files and moves the cursor
+    // from a phase to its first file so that the right pane has something to show.
+    pub fn toggle_sidebar(&mut self) -> orfail::Result<()> {
+        if !self.can_toggle_sidebar() {
+            return Ok(());
+        }
+        self.sidebar = !self.sidebar;
+        if !self.sidebar {
+            return Ok(());
+        }
+        self.show_files_in_sidebar().or_fail()
+    }
+
+    // Expands all files (including those that appeared on a reload) and keeps the cursor
+    // on a file, preferring the first file of the phase under the cursor.
+    fn show_files_in_sidebar(&mut self) -> orfail::Result<()> {
+        for phase in &mut self.root_node.children {
+            for file in &mut phase.children {
+                file.expanded = true;
+            }
+        }
+        if self.cursor.path.len() >= 3 {
+            return Ok(());
+        }
+        let phase = self.cursor.path.get(1).unwrap_or(0);
+        let Some(first_file) = [phase, 1 - phase]
+            .into_iter()
+            .map(|phase| Cursor {
+                path: NodePath::root().join(phase).join(0),
+            })
+            .find(|cursor| self.root_node.is_valid_cursor(cursor))
+        else {
+            return Ok(());
+        };
+        self.move_cursor(Some(first_file)).or_fail()?;
+        Ok(())
+    }
+
+    pub fn toggle_combined_view(&mut self) -> orfail::Result<()> {
+        self.combined = !self.combined;
+        if self.combined {
+            // Start from the file under the cursor.
+            let path = self
+                .cursor
+                .path
+                .get(1)
+                .zip(self.cursor.path.get(2))
+                .and_then(|(phase, file)| {
+                    let (_, diff) = self.children_and_diffs().nth(phase)?;
+                    Some(diff.diff.files.get(file)?.path().clone())
+                });
+            self.combined_index = self
+                .combined_files()
+                .iter()
+                .position(|f| Some(f.path) == path.as_ref())
+                .unwrap_or_default();
+            self.sync_combined_cursor().or_fail()?;
+        }
+        Ok(())
+    }
+
+    // Points the tree cursor at the selected file of the combined view
+    // (preferring its unstaged diff), so that cursor-based features keep working.
+    fn sync_combined_cursor(&mut self) -> orfail::Result<()> {
+        let files = self.combined_files();
+        let index = self.combined_index.min(files.len().saturating_sub(1));
+        let cursor = match files.get(index) {
+            Some(CombinedFile {
+                unstaged: Some(i), ..
+            }) => Cursor::root().join(*i),
+            Some(CombinedFile {
+                staged: Some(i), ..
+            }) => Cursor {
+                path: NodePath::root().join(1).join(*i),
+            },
+            _ => Cursor::root(),
+        };
+        self.combined_index = index;
+        self.move_cursor(Some(cursor)).or_fail()?;
+        Ok(())
+    }
+
+    // Returns the cursor that stage and discard (`phase == 0`) or unstage (`phase == 1`) work on.
+    // In the combined view, this is the selected file in the given phase.
+    fn operation_cursor(&self, phase: usize) -> Option<Cursor> {
+        if !self.combined {
+            return Some(self.cursor.clone());
+        }
+        let files = self.combined_files();
+        let file = files.get(self.combined_index)?;
+        let index = if phase == 0 {
+            file.unstaged
+        } else {
+            file.staged
+        }?;
+        Some(Cursor {
+            path: NodePath::root().join(phase).join(index),
+        })
+    }
+
+    fn move_combined_cursor(&mut self, up: bool) -> orfail::Result<bool> {
+        let Some(index) = self.combined_cursor_target(up) else {
+            return Ok(false);
+        };
+        self.combined_index = index;
+        self.sync_combined_cursor().or_fail()?;
+        Ok(true)
+    }
+
+    fn combined_cursor_target(&self, up: bool) -> Option<usize> {
+        let len = self.combined_files().len();
+        let index = self.combined_index;
+        match (up, self.wrap_navigation) {
+            (true, _) if index > 0 => Some(index - 1),
+            (false, _) if index + 1 < len => Some(index + 1),
+            (true, true) if len > 1 => Some(len - 1),
+            (false, true) if len > 1 => Some(0),
+            _ => None,
         }
     }
 
     pub fn can_cursor_up(&self) -> bool {
-        self.root_node.cursor_up(&self.cursor).is_some()
+        if self.combined {
+            return self.combined_cursor_target(true).is_some();
+        }
+        self.root_node.cursor_up(&self.cursor).is_some() || self.wrapped_cursor_up().is_some()
     }
 
     pub fn can_cursor_down(&self) -> bool {
-        self.root_node.cursor_down(&self.cursor).is_some()
+        if self.combined {
+            return self.combined_cursor_target(false).is_some();
+        }
+        self.root_node.cursor_down(&self.cursor).is_some() || self.wrapped_cursor_down().is_some()
     }
 
     pub fn can_cursor_right(&self) -> bool {
-        self.root_node.cursor_right(&self.cursor).is_some()
+        !self.combined && self.root_node.cursor_right(&self.cursor).is_some()
     }
 
     pub fn can_cursor_left(&self) -> bool {
-        self.cursor.parent().is_some()
+        !self.combined && self.cursor.parent().is_some()
+    }
+
+    // Returns `true` if the cursor is on a line node other than a fold placeholder.
+    pub fn is_cursor_on_line(&self) -> bool {
+        !self.combined
+            && self.cursor.path.len() == 5
+            && self
+                .root_node
+                .get_node(&self.cursor)
+                .is_ok_and(|n| n.folded_lines == 0)
     }
 
     pub fn can_toggle(&self) -> bool {
-        self.root_node
-            .get_node(&self.cursor)
-            .ok()
-            .is_some_and(|n| !n.children.is_empty())
+        !self.combined
+            && (self
+                .root_node
+                .get_node(&self.cursor)
+                .ok()
+                .is_some_and(|n| !n.children.is_empty())
+                || self.cursor_untracked_group().is_some())
+    }
+
+    // Returns the directory of the group of untracked files under the cursor.
+    fn cursor_untracked_group(&self) -> Option<&Path> {
+        if self.combined || self.cursor.path.len() != 3 || self.cursor.path.get(1) != Some(0) {
+            return None;
+        }
+        let file = self
+            .unstaged_diff
+            .diff
+            .files
+            .get(self.cursor.path.get(2)?)?;
+        self.untracked_groups
+            .contains_key(file.path())
+            .then(|| file.path().as_path())
+    }
+
+    // Whether the changes of a commit are shown, which cannot be staged or discarded.
+    pub fn is_read_only(&self) -> bool {
+        self.shown_rev.is_some()
     }
 
     pub fn can_stage_or_discard(&self) -> bool {
-        self.root_node.children[0]
-            .can_alter(&self.cursor, &self.unstaged_diff)
-            .ok()
-            .is_some_and(|b| b)
+        !self.is_read_only()
+            && !self.is_cursor_on_reconciled_file()
+            && self.operation_cursor(0).is_some_and(|cursor| {
+                self.root_node.children[0]
+                    .can_alter(&cursor, &self.unstaged_diff)
+                    .ok()
+                    .is_some_and(|b| b)
+            })
+    }
+
+    pub fn can_stage_file_hunks(&self) -> bool {
+        !self.is_read_only()
+            && self
+                .cursor_unstaged_file()
+                .is_some_and(|file| !file.chunks().is_empty())
+    }
+
+    // Whether the file under the cursor (in either phase) has unstaged changes.
+    pub fn can_restage_file(&self) -> bool {
+        self.cursor_restage_file().is_some()
+    }
+
+    pub fn cursor_restage_path(&self) -> Option<PathBuf> {
+        self.cursor_restage_file().map(|f| f.path().clone())
+    }
+
+    // Returns the unstaged file with the same path as the file under the cursor.
+    fn cursor_restage_file(&self) -> Option<&FileDiff> {
+        if self.is_read_only() {
+            return None;
+        }
+        let phase = self.cursor.path.get(1)?;
+        let file = self.cursor.path.get(2)?;
+        let (_, diff) = self.children_and_diffs().nth(phase)?;
+        let path = diff.diff.files.get(file)?.path();
+        self.unstaged_diff
+            .diff
+            .files
+            .iter()
+            .find(|f| f.path() == path)
+    }
+
+    // Stages the working tree version of the file under the cursor as a whole,
+    // replacing what has been staged for the file so far.
+    pub fn restage_file(&mut self) -> orfail::Result<bool> {
+        let Some(file) = self.cursor_restage_file() else {
+            return Ok(false);
+        };
+        if self.dry_run {
+            let diff = file.to_diff();
+            self.apply(Operation::Stage, &diff).or_fail()?;
+            return Ok(false);
+        }
+        git::add(file.path()).or_fail()?;
+        self.refresh().or_fail()?;
+        Ok(true)
+    }
+
+    // Returns the unstaged file under (or containing the node under) the cursor.
+    // The file being reconciled is excluded, as most of its chunks are hidden.
+    fn cursor_unstaged_file(&self) -> Option<&FileDiff> {
+        let cursor = self.operation_cursor(0)?;
+        if cursor.path.get(1) != Some(0) || self.is_cursor_on_reconciled_file() {
+            return None;
+        }
+        self.unstaged_diff.diff.files.get(cursor.path.get(2)?)
     }
 
     pub fn can_unstage(&self) -> bool {
-        self.root_node.children[1]
-            .can_alter(&self.cursor, &self.staged_diff)
-            .ok()
-            .is_some_and(|b| b)
+        // Reverting changes relative to another base would not be the inverse of staging.
+        // Against the working tree, reverting would even write the working tree content
+        // into the index, i.e., stage it.
+        self.staged_diff_base.is_none()
+            && !self.staged_against_worktree
+            && self.operation_cursor(1).is_some_and(|cursor| {
+                self.root_node.children[1]
+                    .can_alter(&cursor, &self.staged_diff)
+                    .ok()
+                    .is_some_and(|b| b)
+            })
     }
 
     pub fn cursor_up(&mut self) -> orfail::Result<bool> {
-        if let Some(new_cursor) = self.root_node.cursor_up(&self.cursor) {
-            self.cursor = new_cursor;
-            self.expand_parent().or_fail()?;
-            Ok(true)
-        } else {
-            Ok(false)
+        if self.combined {
+            return self.move_combined_cursor(true).or_fail();
         }
+        let new_cursor = self
+            .root_node
+            .cursor_up(&self.cursor)
+            .or_else(|| self.wrapped_cursor_up());
+        self.move_cursor(new_cursor).or_fail()
     }
 
     pub fn cursor_down(&mut self) -> orfail::Result<bool> {
-        if let Some(new_cursor) = self.root_node.cursor_down(&self.cursor) {
-            self.cursor = new_cursor;
-            self.expand_parent().or_fail()?;
-            Ok(true)
-        } else {
-            Ok(false)
+        if self.combined {
+            return self.move_combined_cursor(false).or_fail();
         }
+        let new_cursor = self
+            .root_node
+            .cursor_down(&self.cursor)
+            .or_else(|| self.wrapped_cursor_down());
+        self.move_cursor(new_cursor).or_fail()
     }
 
     pub fn cursor_right(&mut self) -> orfail::Result<bool> {
-        if let Some(new_cursor) = self.root_node.cursor_right(&self.cursor) {
+        if self.combined {
+            return Ok(false);
+        }
+        let new_cursor = self.root_node.cursor_right(&self.cursor);
+        self.move_cursor(new_cursor).or_fail()
+    }
+
+    fn move_cursor(&mut self, new_cursor: Option<Cursor>) -> orfail::Result<bool> {
+        if let Some(new_cursor) = new_cursor {
             self.cursor = new_cursor;
             self.expand_parent().or_fail()?;
+            self.update_focus();
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
+    fn wrapped_cursor_up(&self) -> Option<Cursor> {
+        if !self.wrap_navigation {
+            return None;
+        }
+
+        // Find the last node at the same depth as the current cursor.
+        let last_phase = self.root_node.children.len().checked_sub(1)?;
+        let mut cursor = Cursor {
+            path: NodePath::root().join(last_phase),
+        };
+        while cursor.path.len() < self.cursor.path.len() {
+            let index = self
+                .root_node
+                .get_node(&cursor)
+                .ok()
+                .map(|n| n.children.len().saturating_sub(1))
+                .unwrap_or_default();
+            cursor = cursor.join(index);
+        }
+        if !self.root_node.is_valid_cursor(&cursor) {
+            cursor = self.root_node.cursor_up(&cursor)?;
+        }
+        (cursor != self.cursor).then_some(cursor)
+    }
+
+    fn wrapped_cursor_down(&self) -> Option<Cursor> {
+        if !self.wrap_navigation {
+            return None;
+        }
+
+        // Find the first node at the same depth as the current cursor.
+        let mut cursor = Cursor::root();
+        while cursor.path.len() < self.cursor.path.len() {
+            cursor = cursor.first_child();
+        }
+        if !self.root_node.is_valid_cursor(&cursor) {
+            cursor = self.root_node.cursor_down(&cursor)?;
+        }
+        (cursor != self.cursor).then_some(cursor)
+    }
+
     pub fn cursor_left(&mut self) -> bool {
+        // The sidebar lists the files, so the cursor does not leave them.
+        if self.combined || (self.sidebar && self.cursor.path.len() <= 3) {
+            return false;
+        }
         if let Some(parent) = self.cursor.parent() {
             self.cursor = parent;
+            self.update_focus();
             true
         } else {
             false
         }
     }
 
-    pub fn cursor_row(&self) -> usize {
-        let root_node_offset = 1;
-        self.root_node.cursor_row(&self.cursor) - root_node_offset
+    // Returns the content of the line under the cursor as it appears in the new (or old) version.
+    pub fn cursor_line_text(&self, new: bool) -> Option<&str> {
+        let (_, chunk, line_index) = self.cursor_line()?;
+        match (chunk.lines.get(line_index)?, new) {
+            (LineDiff::Both(s), _) | (LineDiff::New(s), true) | (LineDiff::Old(s), false) => {
+                Some(s)
+            }
+            _ => None,
+        }
     }
 
-    pub fn toggle(&mut self) -> orfail::Result<()> {
-        self.root_node.toggle(&self.cursor).or_fail()
+    // Returns `path:line_number` for the line under the cursor.
+    pub fn cursor_line_ref(&self) -> Option<String> {
+        let (file, chunk, line_index) = self.cursor_line()?;
+        let line_number = chunk.line_number(line_index)?;
+        let path = match (file, &chunk.lines[line_index]) {
+            (FileDiff::Rename { old_path, .. }, LineDiff::Old(_)) => old_path,
+            _ => file.path(),
+        };
+        Some(format!("{}:{line_number}", path.display()))
     }
 
-    pub fn stage(&mut self) -> orfail::Result<bool> {
-        if !self.can_stage_or_discard() {
-            return Ok(false);
-        }
-        self.root_node.children[0]
-            .stage(&self.cursor, &self.unstaged_diff.diff)
-            .or_fail()?;
-        self.reload().or_fail()?;
-        Ok(true)
+    pub fn can_copy_patch(&self) -> bool {
+        self.patch_cursor().is_some()
     }
 
-    pub fn discard(&mut self) -> orfail::Result<bool> {
-        if !self.can_stage_or_discard() {
-            return Ok(false);
-        }
-        self.root_node.children[0]
-            .discard(&self.cursor, &self.unstaged_diff.diff)
-            .or_fail()?;
-        self.reload().or_fail()?;
-        Ok(true)
+    // Returns the patch of the node under the cursor (e.g., to share it).
+    pub fn cursor_patch(&self) -> orfail::Result<Option<String>> {
+        let Some((phase, cursor)) = self.patch_cursor() else {
+            return Ok(None);
+        };
+        let (node, diff) = self.children_and_diffs().nth(phase).or_fail()?;
+        let diff = node.get_diff(&cursor, &diff.diff, false).or_fail()?;
+        diff.to_patch().or_fail().map(Some)
     }
 
-    pub fn unstage(&mut self) -> orfail::Result<bool> {
-        if !self.can_unstage() {
-            return Ok(false);
+    // Returns the phase and the cursor of the node whose patch can be copied.
+    // Staged changes shown as context within unstaged files are not included.
+    fn patch_cursor(&self) -> Option<(usize, Cursor)> {
+        let (phase, cursor) = if self.combined {
+            (0..2).find_map(|phase| Some((phase, self.operation_cursor(phase)?)))?
+        } else {
+            (self.cursor.path.get(1)?, self.cursor.clone())
+        };
+        let (_, diff) = self.children_and_diffs().nth(phase)?;
+        if diff.diff.files.is_empty() {
+            return None;
         }
-        self.root_node.children[1]
-            .unstage(&self.cursor, &self.staged_diff.diff)
-            .or_fail()?;
-        self.reload().or_fail()?;
-        Ok(true)
+        if let Some(chunk_cursor) = (cursor.path.len() >= 4).then(|| Cursor {
+            path: NodePath(cursor.path.0[..4].to_vec()),
+        }) && self
+            .root_node
+            .get_node(&chunk_cursor)
+            .ok()?
+            .staged_context
+            .is_some()
+        {
+            return None;
+        }
+        Some((phase, cursor))
     }
 
-    fn expand_if_possible(&mut self, terminal_size: TerminalSize) -> orfail::Result<()> {
-        if !self.cursor_right().or_fail()? {
-            return Ok(());
+    // Returns the file, chunk and line index of the line under the cursor.
+    // A placeholder of folded lines is not a line.
+    fn cursor_line(&self) -> Option<(&FileDiff, &ChunkDiff, usize)> {
+        if self.cursor.path.len() != 5 {
+            return None;
         }
-
-        loop {
-            self.root_node.toggle(&self.cursor).or_fail()?;
-            if self.rows() > terminal_size.rows {
-                self.root_node.toggle(&self.cursor).or_fail()?;
-                break;
-            }
-            if !self.cursor_down().or_fail()? {
-                break;
-            }
+        let (file, chunk) = self.cursor_file_chunk()?;
+        let line_node = self.root_node.get_node(&self.cursor).ok()?;
+        if line_node.folded_lines > 0 {
+            return None;
         }
-
-        self.cursor = Cursor::root();
-        Ok(())
+        Some((file, chunk, line_node.content_index))
     }
 
-    fn expand_parent(&mut self) -> orfail::Result<()> {
-        if let Some(parent) = self.cursor.parent() {
-            self.root_node.get_node_mut(&parent).or_fail()?.expanded = true;
-        }
-        Ok(())
+    // Returns the file and chunk of the chunk or line under the cursor.
+    fn cursor_file_chunk(&self) -> Option<(&FileDiff, &ChunkDiff)> {
+        let chunk_cursor = Cursor {
+            path: NodePath(self.cursor.path.0.get(..4)?.to_vec()),
+        };
+        let chunk_node = self.root_node.get_node(&chunk_cursor).ok()?;
+        let files = match (chunk_node.staged_context, self.cursor.path.get(1)?) {
+            (Some(_), _) | (None, 1) => &self.staged_diff.diff.files,
+            (None, _) => &self.unstaged_diff.diff.files,
+        };
+        let file_index = chunk_node
+            .staged_context
+            .unwrap_or(self.cursor.path.get(2)?);
+        let file = files.get(file_index)?;
+        let chunk = file.chunks().get(chunk_node.content_index)?;
+        Some((file, chunk))
     }
 
-    fn rows(&self) -> usize {
-        let root_node_offset = 1;
-        self.root_node.rows() - root_node_offset
+    pub fn can_copy_before_after(&self) -> bool {
+        self.cursor_file_chunk().is_some()
     }
 
-    pub fn reload(&mut self) -> orfail::Result<()> {
-        let old = self.clone();
-        let (unstaged_diff, staged_diff) = git::unstaged_and_staged_diffs().or_fail()?;
-
-        self.unstaged_diff.diff = unstaged_diff;
-        self.staged_diff.diff = staged_diff;
-        for (node, diff) in self.children_and_diffs_mut() {
-            node.children.clear();
-            for (i, file) in diff.diff.files.iter().enumerate() {
-                let path = node.path.join(i);
-                let child = DiffTreeNode::new_file_diff_node(path, file);
-                node.children.push(child);
-            }
+    // Returns the removed and added lines of the chunk under the cursor as a before/after block.
+    pub fn cursor_before_after(&self) -> Option<String> {
+        let (_, chunk) = self.cursor_file_chunk()?;
+        Some(chunk.to_before_after())
+    }
 
-            node.restore_expanded_state(
-                &diff.diff,
-                &old.children_and_diffs()
-                    .map(|x| (x.0, &x.1.diff))
-                    .collect::<Vec<_>>(),
-            );
+    // Returns the file and chunk of the unstaged chunk under (or containing the line under) the cursor.
+    pub fn cursor_unstaged_chunk(&self) -> Option<(&FileDiff, &ChunkDiff)> {
+        if self.is_read_only()
+            || !matches!(self.cursor.path.len(), 4 | 5)
+            || self.cursor.path.get(1) != Some(0)
+        {
+            return None;
         }
-
-        while !self.root_node.is_valid_cursor(&self.cursor) {
-            if let Some(sibling_cursor) = self.cursor.prev_sibling() {
-                self.cursor = sibling_cursor;
-            } else if let Some(parent_cursor) = self.cursor.parent() {
-                self.cursor = parent_cursor;
-            } else {
-                self.cursor = Cursor::root();
-                break;
-            }
+        let chunk_cursor = Cursor {
+            path: NodePath(self.cursor.path.0[..4].to_vec()),
+        };
+        let chunk_node = self.root_node.get_node(&chunk_cursor).ok()?;
+        if chunk_node.staged_context.is_some() {
+            return None;
         }
-
-        self.expand_parent().or_fail()?;
-
-        Ok(())
+        self.cursor_file_chunk()
     }
 
-    fn children_and_diffs(&self) -> impl '_ + Iterator<Item = (&DiffTreeNode, &PhasedDiff)> {
-        self.root_node
-            .children
-            .iter()
-            .zip([&self.unstaged_diff, &self.staged_diff])
+    // Stages the lines in `range` of the unstaged chunk under the cursor.
+    pub fn stage_chunk_lines(&mut self, range: Range<usize>) -> orfail::Result<bool> {
+        let Some((file, chunk)) = self.cursor_unstaged_chunk() else {
+            return Ok(false);
+        };
+        let Some(lines_chunk) = chunk.get_lines_chunk(range, false) else {
+            return Ok(false);
+        };
+        let diff = file.chunk_diff(&lines_chunk, false);
+        if !self.apply(Operation::Stage, &diff).or_fail()? {
+            return Ok(false);
+        }
+        self.reload().or_fail()?;
+        Ok(true)
     }
 
-    fn children_and_diffs_mut(
-        &mut self,
-    ) -> impl '_ + Iterator<Item = (&mut DiffTreeNode, &mut PhasedDiff)> {
-        self.root_node
-            .children
-            .iter_mut()
-            .zip([&mut self.unstaged_diff, &mut self.staged_diff])
+    pub fn can_split_chunk(&self) -> bool {
+        self.cursor_chunk_line()
+            .is_some_and(|(phase, file, chunk, line)| {
+                let diff = if phase == 0 {
+                    &self.unstaged_diff
+                } else {
+                    &self.staged_diff
+                };
+                diff.diff
+                    .files
+                    .get(file)
+                    .and_then(|f| f.chunks().get(chunk))
+                    .and_then(|c| c.split_at(line))
+                    .is_some()
+            })
     }
-}
 
-#[derive(Debug, Clone)]
-struct DiffTreeNode {
-    path: NodePath,
-    expanded: bool,
-    children: Vec<Self>,
-}
+    // Splits the chunk under the cursor at the cursor line and moves the cursor to the second chunk.
+    pub fn split_chunk(&mut self) -> orfail::Result<bool> {
+        let Some((phase, file, chunk, line)) = self.cursor_chunk_line() else {
+            return Ok(false);
+        };
+        let diff = if phase == 0 {
+            &mut self.unstaged_diff
+        } else {
+            &mut self.staged_diff
+        };
+        if !diff.diff.files[file].split_chunk(chunk, line) {
+            return Ok(false);
+        }
 
-impl DiffTreeNode {
-    fn new_root_node() -> Self {
-        let root_path = NodePath::root();
-        Self {
-            path: root_path.clone(),
-            expanded: true,
-            children: vec![
-                Self::new_diff_node(root_path.join(0)),
-                Self::new_diff_node(root_path.join(1)),
-            ],
+        let mut cursor_keys = self.root_node.node_keys(&self.cursor).or_fail()?;
+        cursor_keys.truncate(2);
+        cursor_keys.extend([(None, chunk + 1), (None, 0)]);
+        self.rebuild_nodes().or_fail()?;
+        self.cursor = self.root_node.find_cursor(&cursor_keys);
+        self.update_focus();
+        Ok(true)
+    }
+
+    // Returns the phase, file, chunk, and line indices of the line under the cursor.
+    fn cursor_chunk_line(&self) -> Option<(usize, usize, usize, usize)> {
+        let chunk_cursor = self
+            .cursor
+            .parent()
+            .filter(|_| self.cursor.path.len() == 5)?;
+        let chunk_node = self.root_node.get_node(&chunk_cursor).ok()?;
+        if chunk_node.staged_context.is_some() {
+            return None;
         }
+        let line_node = self.root_node.get_node(&self.cursor).ok()?;
+        Some((
+            self.cursor.path.get(1)?,
+            self.cursor.path.get(2)?,
+            chunk_node.content_index,
+            line_node.content_index,
+        ))
     }
 
-    fn new_diff_node(path: NodePath) -> Self {
-        Self {
-            path,
-            expanded: true,
-            children: Vec::new(),
+    // Outline of the internal node tree for diagnosing navigation issues.
+    pub fn dump_tree(&self) -> String {
+        let mut out = String::new();
+        self.root_node.dump(&mut out, &self.cursor);
+        out
+    }
+
+    // Files hidden by the glob filter are included, as they are committed all the same.
+    pub fn staged_patch(&self) -> orfail::Result<String> {
+        if self.staged_against_worktree {
+            // The staged phase does not show what will be committed in this mode.
+            return git::cached_patch().or_fail();
         }
+        self.unfiltered_diffs.1.to_patch().or_fail()
     }
 
-    fn new_file_diff_node(path: NodePath, diff: &FileDiff) -> Self {
-        let children = diff
-            .chunks()
+    // Files in the staged diff that still exist in the working tree (including hidden ones).
+    pub fn staged_files(&self) -> impl '_ + Iterator<Item = &PathBuf> {
+        self.unfiltered_diffs
+            .1
+            .files
             .iter()
-            .enumerate()
-            .map(|(i, c)| DiffTreeNode::new_chunk_diff_node(path.join(i), c))
-            .collect();
-        Self {
-            path,
-            expanded: false,
-            children,
-        }
+            .filter(|f| !matches!(f, FileDiff::Delete { .. }))
+            .map(|f| f.path())
     }
 
-    fn new_chunk_diff_node(path: NodePath, diff: &ChunkDiff) -> Self {
-        let children = (0..diff.lines.len())
-            .map(|i| DiffTreeNode::new_line_diff_node(path.join(i)))
-            .collect();
-        Self {
-            path,
-            expanded: true,
-            children,
+    // Returns the tree path and a label of every chunk in both phases (excluding staged context).
+    pub fn hunks(&self) -> Vec<(Vec<usize>, String)> {
+        let mut hunks = Vec::new();
+        for (phase_node, diff) in self.children_and_diffs() {
+            let phase = match diff.phase {
+                DiffPhase::Unstaged => "unstaged",
+                DiffPhase::Staged => "staged",
+            };
+            for (file_node, file) in phase_node.children.iter().zip(&diff.diff.files) {
+                for chunk_node in &file_node.children {
+                    if chunk_node.staged_context.is_some() {
+                        continue;
+                    }
+                    let Some(chunk) = file.chunks().get(chunk_node.content_index) else {
+                        continue;
+                    };
+                    let label = format!("{phase} {} {}", file.path().display(), chunk.head_line());
+                    hunks.push((chunk_node.path.0.clone(), label));
+                }
+            }
         }
+        hunks
     }
 
-    fn new_line_diff_node(path: NodePath) -> Self {
-        Self {
-            path,
-            expanded: false,
-            children: Vec::new(),
-        }
+    pub fn can_show_hunks(&self) -> bool {
+        !self.combined
     }
 
-    fn restore_expanded_state(&mut self, diff: &Diff, old: &[(&Self, &Diff)]) {
-        if old.is_empty() {
-            return;
+    // Moves the cursor to the node at `path` (e.g., returned by `hunks()`), expanding its parents.
+    pub fn jump_to(&mut self, path: &[usize]) -> orfail::Result<bool> {
+        let cursor = Cursor {
+            path: NodePath(path.to_vec()),
+        };
+        if self.combined || !self.root_node.is_valid_cursor(&cursor) {
+            return Ok(false);
         }
+        self.move_cursor(Some(cursor)).or_fail()
+    }
 
-        self.expanded = old.iter().any(|x| x.0.expanded);
+    pub fn can_goto_file(&self) -> bool {
+        !self.combined && self.cursor_phase_files() > 0
+    }
 
-        for (c, d) in self.children.iter_mut().zip(diff.files.iter()) {
-            let expanded = old
-                .iter()
-                .flat_map(|x| x.0.children.iter().zip(x.1.files.iter()))
-                .filter(|x| x.1.path() == d.path())
-                .any(|x| x.0.expanded);
-            c.expanded = expanded;
-        }
+    fn cursor_phase_files(&self) -> usize {
+        self.cursor
+            .path
+            .get(1)
+            .and_then(|phase| self.root_node.children.get(phase))
+            .map_or(0, |node| node.children.len())
     }
 
-    fn render<T>(&self, canvas: &mut Canvas, cursor: &Cursor, content: &T)
-    where
-        T: DiffTreeNodeContent,
-    {
-        cursor.render(canvas, &self.path);
-        for token in content.head_line_tokens() {
-            canvas.draw(token);
-        }
-        if !self.expanded && !self.children.is_empty() {
-            canvas.draw(Token::new("…"));
+    // Moves the cursor to the `number`-th (1-based, as in the phase header count) file
+    // of the phase under the cursor.
+    pub fn goto_file(&mut self, number: usize) -> orfail::Result<bool> {
+        if !self.can_goto_file() || !(1..=self.cursor_phase_files()).contains(&number) {
+            return Ok(false);
         }
-        canvas.newline();
+        let phase = self.cursor.path.get(1).or_fail()?;
+        let cursor = Cursor {
+            path: NodePath::root().join(phase).join(number - 1),
+        };
+        self.move_cursor(Some(cursor)).or_fail()
+    }
 
-        if self.expanded {
-            for child in self.children.iter().zip(content.children().iter()) {
-                if !child.0.render_if_need(canvas, cursor, child.1) {
-                    break;
-                }
-            }
+    // Moves the cursor to the file node with the given repository-relative path and expands it.
+    pub fn select_file(&mut self, path: &Path) -> orfail::Result<bool> {
+        if self.combined {
+            let files = self.combined_files();
+            let Some(index) = files.iter().position(|f| *f.path == *path) else {
+                return Ok(false);
+            };
+            self.combined_index = index;
+            self.sync_combined_cursor().or_fail()?;
+            return Ok(true);
         }
+        let Some(cursor) = self
+            .children_and_diffs()
+            .flat_map(|(node, diff)| node.children.iter().zip(&diff.diff.files))
+            .find(|(_, file)| file.path() == path)
+            .map(|(node, _)| Cursor {
+                path: node.path.clone(),
+            })
+        else {
+            return Ok(false);
+        };
+        self.root_node.get_node_mut(&cursor).or_fail()?.expanded = true;
+        self.move_cursor(Some(cursor)).or_fail()
     }
 
-    fn render_if_need<T>(&self, canvas: &mut Canvas, cursor: &Cursor, content: &T) -> bool
-    where
-        T: DiffTreeNodeContent,
-    {
-        if canvas.is_frame_exceeded() {
-            return false;
+    pub fn cursor_row(&self) -> usize {
+        if self.combined {
+            let header_offset = 1;
+            return self.combined_index + header_offset;
         }
-
-        let mut canvas_cursor = canvas.cursor();
-        let drawn_rows = self.rows();
-        if canvas
-            .frame_row_range()
-            .start
-            .checked_sub(canvas_cursor.row)
-            .is_some_and(|n| n >= drawn_rows)
-        {
-            canvas_cursor.row += drawn_rows;
-            canvas.set_cursor(canvas_cursor);
-        } else {
-            self.render(canvas, cursor, content);
+        if self.sidebar {
+            // Only the file under the cursor is drawn in the right pane.
+            return self.cursor_file().map_or(0, |file_cursor| {
+                self.root_node.cursor_row(&self.cursor) - self.root_node.cursor_row(&file_cursor)
+            });
         }
-        true
+        let root_node_offset = 1;
+        let separator_offset = if self.cursor.path.get(1) == Some(1) {
+            self.separator_rows()
+        } else {
+            0
+        };
+        self.root_node.cursor_row(&self.cursor) - root_node_offset + separator_offset
     }
 
-    fn rows(&self) -> usize {
-        if self.expanded {
-            1 + self.children.iter().map(|c| c.rows()).sum::<usize>()
-        } else {
-            1
-        }
+    // Number of rows between the two phases.
+    fn separator_rows(&self) -> usize {
+        usize::from(self.phase_separator && !self.combined)
     }
 
-    fn cursor_row(&self, cursor: &Cursor) -> usize {
-        match cursor.path.0[..self.path.len()].cmp(&self.path.0) {
-            Ordering::Less => 0,
-            Ordering::Equal if cursor.path.len() == self.path.len() => 0,
-            Ordering::Equal => {
-                1 + self
-                    .children
-                    .iter()
-                    .map(|c| c.cursor_row(cursor))
-                    .sum::<usize>()
+    pub fn toggle(&mut self) -> orfail::Result<()> {
+        if self.combined {
+            return Ok(());
+        }
+        if let Some(dir) = self.cursor_untracked_group().map(|d| d.to_path_buf()) {
+            // Expands the group into its files; the cursor moves to the first of them.
+            self.expanded_untracked_groups.insert(dir.clone());
+            self.reload().or_fail()?;
+            let files = &self.unstaged_diff.diff.files;
+            if let Some(i) = files.iter().position(|f| f.path().starts_with(&dir)) {
+                let cursor = Cursor {
+                    path: NodePath(vec![0, 0, i]),
+                };
+                self.move_cursor(Some(cursor)).or_fail()?;
             }
-            Ordering::Greater => self.rows(),
+            return Ok(());
+        }
+        if self
+            .root_node
+            .get_node(&self.cursor)
+            .or_fail()?
+            .folded_lines
+            > 0
+        {
+            // Unfolds the context lines of the chunk; the cursor moves to the first of them.
+            let key = self.cursor_chunk_key().or_fail()?;
+            self.unfolded_chunks.insert(key);
+            return self.rebuild_nodes().or_fail();
         }
+        self.root_node.toggle(&self.cursor).or_fail()
     }
 
-    fn check_cursor(&self, cursor: &Cursor) -> orfail::Result<()> {
-        cursor.path.starts_with(&self.path).or_fail_with(|()| {
-            format!(
-                "invalid cursor: path={:?}, cursor={:?}",
-                self.path, cursor.path
-            )
-        })?;
-        Ok(())
+    pub fn toggle_compact(&mut self) -> orfail::Result<()> {
+        self.compact = !self.compact;
+        self.rebuild_nodes().or_fail()
     }
 
-    fn can_alter<T>(&self, cursor: &Cursor, content: &T) -> orfail::Result<bool>
-    where
-        T: DiffTreeNodeContent,
-    {
-        self.check_cursor(cursor).or_fail()?;
+    pub fn can_toggle_chunk_context(&self) -> bool {
+        !self.combined && self.cursor.path.len() >= 4
+    }
 
-        if let Some(i) = cursor.path.get(self.path.len()) {
-            let child_node = self.children.get(i).or_fail()?;
-            let child_content = content.children().get(i).or_fail()?;
-            child_node.can_alter(cursor, child_content).or_fail()
+    // Shows or hides the unchanged lines of the chunk under the cursor, overriding `compact`.
+    pub fn toggle_chunk_context(&mut self) -> orfail::Result<()> {
+        if !self.can_toggle_chunk_context() {
+            return Ok(());
+        }
+        let key = self.cursor_chunk_key().or_fail()?;
+        let compact = !self
+            .chunk_compact
+            .get(&key)
+            .copied()
+            .unwrap_or(self.compact);
+        if compact == self.compact {
+            self.chunk_compact.remove(&key);
         } else {
-            Ok(content.can_alter())
+            self.chunk_compact.insert(key, compact);
         }
+        self.rebuild_nodes().or_fail()
     }
 
-    fn is_valid_cursor(&self, cursor: &Cursor) -> bool {
-        self.get_node(cursor).is_ok()
+    // Returns the key of the chunk under the cursor, which is kept across rebuilds.
+    fn cursor_chunk_key(&self) -> orfail::Result<ChunkKey> {
+        let chunk_cursor = Cursor {
+            path: NodePath(self.cursor.path.0.get(..4).or_fail()?.to_vec()),
+        };
+        let (phase, file) = (chunk_cursor.path.0[1], chunk_cursor.path.0[2]);
+        let chunk_node = self.root_node.get_node(&chunk_cursor).or_fail()?;
+        let (_, diff) = self.children_and_diffs().nth(phase).or_fail()?;
+        let path = diff.diff.files.get(file).or_fail()?.path().clone();
+        Ok((
+            phase,
+            path,
+            chunk_node.staged_context.is_some(),
+            chunk_node.content_index,
+        ))
     }
 
-    fn toggle(&mut self, cursor: &Cursor) -> orfail::Result<()> {
-        let node = self.get_node_mut(cursor).or_fail()?;
-        node.expanded = !node.expanded;
-        Ok(())
+    pub fn set_fold_context_lines(&mut self, lines: Option<usize>) -> orfail::Result<()> {
+        self.fold_context_lines = lines;
+        self.rebuild_nodes().or_fail()
     }
 
-    fn get_node(&self, cursor: &Cursor) -> orfail::Result<&Self> {
-        if let Some((_, child)) = self.get_maybe_child(cursor).or_fail()? {
-            child.get_node(cursor).or_fail()
-        } else {
-            Ok(self)
-        }
+    pub fn toggle_staged_context(&mut self) -> orfail::Result<()> {
+        self.show_staged_context = !self.show_staged_context;
+        self.rebuild_nodes().or_fail()
     }
 
-    fn get_node_mut(&mut self, cursor: &Cursor) -> orfail::Result<&mut Self> {
-        cursor.path.starts_with(&self.path).or_fail()?;
+    // Whether the file under the cursor has overlapping unstaged and staged changes,
+    // or a file is being reconciled (to end it).
+    pub fn can_toggle_reconcile_file(&self) -> bool {
+        self.reconciled_file.is_some() || self.cursor_partially_staged_file().is_some()
+    }
 
-        if let Some(i) = cursor.path.get(self.path.len()) {
-            let child = self.children.get_mut(i).or_fail()?;
-            child.get_node_mut(cursor).or_fail()
-        } else {
-            Ok(self)
-        }
+    // Whether the cursor is on the node of the file being reconciled (not on its chunks).
+    // The file cannot be staged or discarded as a whole there, since only some chunks are shown.
+    fn is_cursor_on_reconciled_file(&self) -> bool {
+        self.cursor.path.len() == 3
+            && self.cursor.path.get(1) == Some(0)
+            && self.reconciled_file.as_ref().is_some_and(|path| {
+                self.cursor
+                    .path
+                    .get(2)
+                    .and_then(|i| self.unstaged_diff.diff.files.get(i))
+                    .is_some_and(|f| f.path() == path)
+            })
     }
 
-    fn get_maybe_child(&self, cursor: &Cursor) -> orfail::Result<Option<(usize, &Self)>> {
-        cursor.path.starts_with(&self.path).or_fail()?;
+    fn cursor_partially_staged_file(&self) -> Option<PathBuf> {
+        let (_, diff) = self.children_and_diffs().nth(self.cursor.path.get(1)?)?;
+        let path = diff.diff.files.get(self.cursor.path.get(2)?)?.path();
+        self.has_intersecting_chunks(path).then(|| path.clone())
+    }
 
-        if let Some(i) = cursor.path.get(self.path.len()) {
-            let child = self.children.get(i).or_fail()?;
-            Ok(Some((i, child)))
-        } else {
-            Ok(None)
+    fn has_intersecting_chunks(&self, path: &Path) -> bool {
+        if self.staged_against_worktree {
+            return false;
         }
+        let (Some(unstaged), Some(staged)) = (
+            self.unstaged_diff
+                .diff
+                .files
+                .iter()
+                .find(|f| f.path() == path),
+            self.staged_diff
+                .diff
+                .files
+                .iter()
+                .find(|f| f.path() == path),
+        ) else {
+            return false;
+        };
+        staged
+            .chunks()
+            .iter()
+            .any(|s| unstaged.chunks().iter().any(|u| s.is_intersect(u)))
     }
 
-    fn stage(&self, cursor: &Cursor, diff: &Diff) -> orfail::Result<()> {
-        let diff = self.get_diff(cursor, diff, false).or_fail()?;
-        git::stage(&diff).or_fail()?;
+    // Shows only the chunks of the file under the cursor where the staged and unstaged changes
+    // overlap, interleaving them within the unstaged file, or ends that view.
+    pub fn toggle_reconcile_file(&mut self) -> orfail::Result<()> {
+        if self.reconciled_file.take().is_some() {
+            return self.rebuild_nodes().or_fail();
+        }
+        let Some(path) = self.cursor_partially_staged_file() else {
+            return Ok(());
+        };
+        self.reconciled_file = Some(path.clone());
+        self.rebuild_nodes().or_fail()?;
+        let file_index = self
+            .unstaged_diff
+            .diff
+            .files
+            .iter()
+            .position(|f| *f.path() == path)
+            .or_fail()?;
+        let cursor = Cursor {
+            path: NodePath::root().join(0).join(file_index),
+        };
+        self.root_node.get_node_mut(&cursor).or_fail()?.expanded = true;
+        self.move_cursor(Some(cursor)).or_fail()?;
         Ok(())
     }
 
-    fn discard(&self, cursor: &Cursor, diff: &Diff) -> orfail::Result<()> {
-        let diff = self.get_diff(cursor, diff, true).or_fail()?;
-        git::discard(&diff).or_fail()?;
-        Ok(())
+    pub fn glob_filter(&self) -> Option<&str> {
+        self.glob_filter.as_deref()
     }
 
-    fn unstage(&self, cursor: &Cursor, diff: &Diff) -> orfail::Result<()> {
-        let diff = self.get_diff(cursor, diff, true).or_fail()?;
-        git::unstage(&diff).or_fail()?;
-        Ok(())
+    // Shows only the files whose paths match the glob (all files if `None`) without reloading.
+    pub fn set_glob_filter(&mut self, glob: Option<String>) -> orfail::Result<()> {
+        self.glob_filter = glob;
+        let (unstaged_diff, staged_diff) = self.unfiltered_diffs.clone();
+        self.update_diffs_keeping_cursor_file(unstaged_diff, staged_diff)
+            .or_fail()
     }
 
-    fn get_diff(&self, cursor: &Cursor, diff: &Diff, reverse: bool) -> orfail::Result<Diff> {
-        let Some((i, node)) = self.get_maybe_child(cursor).or_fail()? else {
-            return Ok(diff.clone());
-        };
-        let file = diff.files.get(i).or_fail()?;
-        let path = file.path();
+    pub fn staged_against_worktree(&self) -> bool {
+        self.staged_against_worktree
+    }
 
-        let Some((i, node)) = node.get_maybe_child(cursor).or_fail()? else {
-            return Ok(file.to_diff());
-        };
-        let chunk = file.chunks().get(i).or_fail()?;
+    // Whether there is anything to commit (as of the last reload).
+    pub fn has_staged_changes(&self) -> bool {
+        self.has_staged_changes
+    }
 
-        let Some((i, _node)) = node.get_maybe_child(cursor).or_fail()? else {
-            return Ok(chunk.to_diff(path));
-        };
+    // Blame is only meaningful for reviewing the changes since a base commit.
+    pub fn can_filter_by_author(&self) -> bool {
+        self.staged_diff_base.is_some() && !self.combined
+    }
 
-        Ok(chunk.get_line_chunk(i, reverse).or_fail()?.to_diff(path))
+    pub fn author_filter(&self) -> Option<&str> {
+        self.author_filter.as_deref()
     }
 
-    fn cursor_right(&self, cursor: &Cursor) -> Option<Cursor> {
-        let mut cursor = cursor.clone();
+    // Dims the staged chunks that have no added lines last touched by the author
+    // (matched case-insensitively against `name <email>`), or none if `None`.
+    // The chunks are dimmed by `update_author_mismatches()`.
+    pub fn set_author_filter(&mut self, author: Option<String>) {
+        self.author_filter = author.map(|a| a.to_lowercase());
+        self.author_mismatches.clear();
+    }
 
-        while cursor.path.len() >= self.path.len() {
-            let child_cursor = cursor.first_child();
-            if self.is_valid_cursor(&child_cursor) {
-                return Some(child_cursor);
+    // Files are blamed only when they are expanded, and the results are kept until
+    // the staged diff changes, as blaming a file is slow.
+    // The chunks of a file that cannot be blamed are not dimmed, and the first such error is
+    // returned after the other files are checked.
+    pub fn update_author_mismatches(&mut self) -> orfail::Result<()> {
+        self.author_mismatches.clear();
+        let Some(author) = &self.author_filter else {
+            return Ok(());
+        };
+        let mut error = None;
+        let file_nodes = &self.root_node.children[1].children;
+        for (i, file) in self.staged_diff.diff.files.iter().enumerate() {
+            if !file_nodes.get(i).is_some_and(|n| n.expanded) {
+                continue;
             }
-
-            let sibling_cursor = cursor.next_sibling();
-            if self.is_valid_cursor(&sibling_cursor) {
-                cursor = sibling_cursor;
-            } else {
-                break;
+            let authors = self
+                .blamed_authors
+                .entry(file.path().clone())
+                .or_insert_with(|| {
+                    if matches!(file, FileDiff::Delete { .. }) {
+                        // A deleted file has no lines, so none of its chunks match.
+                        return Some(Vec::new());
+                    }
+                    match git::blame_index_authors(file.path()) {
+                        Ok(authors) => Some(authors),
+                        Err(e) => {
+                            error.get_or_insert(e);
+                            None
+                        }
+                    }
+                });
+            let Some(authors) = authors else {
+                continue;
+            };
+            for (j, chunk) in file.chunks().iter().enumerate() {
+                let mut line_number = chunk.new_start_line_number;
+                let mut matched = false;
+                for line in &chunk.lines {
+                    match line {
+                        LineDiff::New(_) => {
+                            matched |= authors
+                                .get(line_number.wrapping_sub(1))
+                                .is_some_and(|a| a.to_lowercase().contains(author.as_str()));
+                            line_number += 1;
+                        }
+                        LineDiff::Both(_) => line_number += 1,
+                        LineDiff::Old(_) | LineDiff::NoNewlineAtEndOfFile => {}
+                    }
+                }
+                if !matched {
+                    self.author_mismatches.insert((i, j));
+                }
             }
         }
+        error.map_or(Ok(()), Err)
+    }
 
-        None
+    pub fn can_toggle_staged_base(&self) -> bool {
+        self.staged_diff_base.is_none()
     }
 
-    fn cursor_down(&self, cursor: &Cursor) -> Option<Cursor> {
-        let sibling_cursor = cursor.next_sibling();
-        if self.is_valid_cursor(&sibling_cursor) {
-            return Some(sibling_cursor);
+    // Switches the staged phase between index-vs-HEAD and index-vs-worktree.
+    // Unstaging is disabled in the latter mode (see `can_unstage()`).
+    pub fn toggle_staged_base(&mut self) -> orfail::Result<()> {
+        if !self.can_toggle_staged_base() {
+            return Ok(());
         }
+        self.staged_against_worktree = !self.staged_against_worktree;
+        self.reload().or_fail()
+    }
 
-        let mut base_cursor = cursor.clone();
-        loop {
-            base_cursor = base_cursor.parent()?;
+    pub fn toggle_ignored(&mut self) -> orfail::Result<()> {
+        self.include_ignored = !self.include_ignored;
+        self.reload().or_fail()
+    }
 
-            let mut next_cursor = base_cursor.next_sibling();
-            while next_cursor.path.len() < cursor.path.len() {
-                next_cursor = next_cursor.first_child();
-            }
+    pub fn toggle_deleted_content(&mut self) -> orfail::Result<()> {
+        self.set_show_deleted_content(!self.show_deleted_content)
+            .or_fail()
+    }
 
-            if self.is_valid_cursor(&next_cursor) {
-                return Some(next_cursor);
-            }
-        }
+    pub fn file_order(&self) -> FileOrder {
+        self.file_order
     }
 
-    fn cursor_up(&self, cursor: &Cursor) -> Option<Cursor> {
-        if let Some(sibling_cursor) = cursor.prev_sibling() {
-            return Some(sibling_cursor);
+    // Sorts the files of each phase in the next order, keeping the expanded state and the cursor file.
+    pub fn cycle_file_order(&mut self) -> orfail::Result<()> {
+        self.file_order = self.file_order.next();
+        let old_root_node = self.root_node.clone();
+        let old_indices = [
+            self.unstaged_diff.sort_files(self.file_order),
+            self.staged_diff.sort_files(self.file_order),
+        ];
+        self.build_file_nodes();
+        for ((node, old_node), indices) in self
+            .root_node
+            .children
+            .iter_mut()
+            .zip(&old_root_node.children)
+            .zip(&old_indices)
+        {
+            for (child, &i) in node.children.iter_mut().zip(indices) {
+                if let Some(old_child) = old_node.children.get(i) {
+                    child.copy_expanded_state(old_child);
+                }
+            }
         }
 
-        let mut base_cursor = cursor.clone();
-        loop {
-            base_cursor = base_cursor.parent()?;
+        if let (Some(phase), Some(file)) = (self.cursor.path.get(1), self.cursor.path.get(2))
+            && let Some(i) = old_indices[phase].iter().position(|&i| i == file)
+        {
+            self.cursor.path.0[2] = i;
+        }
+        self.update_focus();
+        if self.combined {
+            self.sync_combined_cursor().or_fail()?;
+        }
+        Ok(())
+    }
 
-            let Some(mut next_cursor) = base_cursor.prev_sibling() else {
-                continue;
-            };
-            while next_cursor.path.len() < cursor.path.len() {
-                let index = self
-                    .get_node(&next_cursor)
-                    .ok()
-                    .map(|n| n.children.len().saturating_sub(1))
-                    .unwrap_or_default();
-                next_cursor = next_cursor.join(index);
+    pub fn set_show_mtimes(&mut self, show: bool) {
+        self.show_mtimes = show;
+        for diff in [&mut self.unstaged_diff, &mut self.staged_diff] {
+            if show {
+                diff.read_mtimes();
+            } else {
+                diff.mtimes.clear();
             }
-            if self.is_valid_cursor(&next_cursor) {
-                return Some(next_cursor);
+        }
+    }
+
+    pub fn set_show_binary_sizes(&mut self, show: bool) {
+        self.show_binary_sizes = show;
+        for diff in [&mut self.unstaged_diff, &mut self.staged_diff] {
+            if show {
+                diff.read_binary_sizes();
+            } else {
+                diff.binary_sizes.clear();
             }
         }
     }
-}
 
-pub trait DiffTreeNodeContent {
-    type Child: DiffTreeNodeContent;
+    pub fn toggle_binary_sizes(&mut self) {
+        self.set_show_binary_sizes(!self.show_binary_sizes);
+    }
 
-    fn head_line_tokens(&self) -> impl Iterator<Item = Token>;
-    fn can_alter(&self) -> bool;
-    fn children(&self) -> &[Self::Child];
-}
+    // Expands or collapses all deleted files at once.
+    pub fn set_show_deleted_content(&mut self, show: bool) -> orfail::Result<()> {
+        self.show_deleted_content = show;
+        for (node, diff) in self.children_and_diffs_mut() {
+            for (file_node, file) in node.children.iter_mut().zip(&diff.diff.files) {
+                if matches!(file, FileDiff::Delete { .. }) {
+                    file_node.expanded = show;
+                }
+            }
+        }
+        self.move_cursor_out_of_collapsed().or_fail()
+    }
 
-impl DiffTreeNodeContent for PhasedDiff {
-    type Child = FileDiff;
+    // Expands only the first file to its chunk headers and collapses the others.
+    // The file selected by `--select` is kept expanded.
+    pub fn expand_first_file_only(&mut self) -> orfail::Result<()> {
+        let selected = self.cursor_file();
+        let mut first = true;
+        for phase_node in &mut self.root_node.children {
+            for file_node in &mut phase_node.children {
+                file_node.expanded = first;
+                if first {
+                    for chunk_node in &mut file_node.children {
+                        chunk_node.expanded = false;
+                    }
+                }
+                first = false;
+            }
+        }
+        if let Some(cursor) = selected {
+            self.root_node.get_node_mut(&cursor).or_fail()?.expanded = true;
+        }
+        self.update_focus();
+        Ok(())
+    }
 
-    fn head_line_tokens(&self) -> impl Iterator<Item = Token> {
-        std::iter::once(Token::with_style(
-            format!("{:?} changes ({} files)", self.phase, self.diff.files.len()),
-            TerminalStyle::new().bold(),
-        ))
+    // Collapses all files, leaving only the phase nodes and the file list visible.
+    pub fn collapse_all(&mut self) -> orfail::Result<()> {
+        self.saved_expansion = Some(self.root_node.clone());
+        self.outline = false;
+        for phase_node in &mut self.root_node.children {
+            for file_node in &mut phase_node.children {
+                file_node.set_expanded_recursively(false);
+            }
+        }
+        self.move_cursor_out_of_collapsed().or_fail()
     }
 
-    fn can_alter(&self) -> bool {
-        !self.diff.files.is_empty()
+    pub fn expand_all(&mut self) -> orfail::Result<()> {
+        self.saved_expansion = Some(self.root_node.clone());
+        self.outline = false;
+        self.root_node.set_expanded_recursively(true);
+        self.update_focus();
+        Ok(())
     }
 
-    fn children(&self) -> &[Self::Child] {
-        &self.diff.files
+    pub fn can_restore_expansion(&self) -> bool {
+        self.saved_expansion.is_some()
     }
-}
 
-impl DiffTreeNodeContent for FileDiff {
-    type Child = ChunkDiff;
+    // Reverts the expanded state to the one saved by the last collapse-all, expand-all or outline.
+    pub fn restore_expansion(&mut self) -> orfail::Result<()> {
+        self.outline = false;
+        let Some(saved) = self.saved_expansion.take() else {
+            return Ok(());
+        };
+        self.root_node.copy_expanded_state(&saved);
+        self.move_cursor_out_of_collapsed().or_fail()
+    }
 
-    fn head_line_tokens(&self) -> impl Iterator<Item = Token> {
-        let path = Token::with_style(
-            self.path().display().to_string(),
-            TerminalStyle::new().underline(),
-        );
-        let tokens = match self {
-            FileDiff::Update {
-                old_mode, new_mode, ..
-            } => {
-                let mode = if let Some(old_mode) = old_mode {
-                    format!(", {old_mode} -> {new_mode} mode")
-                } else {
-                    "".to_string()
-                };
-                vec![
-                    Token::new("modified "),
-                    path,
-                    Token::new(format!(
-                        " ({} chunks, -{} +{} lines{})",
-                        self.children().len(),
-                        self.removed_lines(),
-                        self.added_lines(),
-                        mode
-                    )),
-                ]
-            }
-            FileDiff::New { content, .. } => {
-                vec![
-                    Token::new("added "),
-                    path,
-                    if matches!(content, ContentDiff::Binary) {
-                        Token::new(" (binary)")
-                    } else {
-                        Token::new(format!(" (+{} lines)", self.added_lines()))
-                    },
-                ]
+    // Expands all files with their chunks collapsed, so that only the chunk headers
+    // (with their function contexts) are shown, or reverts that.
+    pub fn toggle_outline(&mut self) -> orfail::Result<()> {
+        if self.outline {
+            return self.restore_expansion().or_fail();
+        }
+        self.saved_expansion = Some(self.root_node.clone());
+        self.outline = true;
+        for phase_node in &mut self.root_node.children {
+            phase_node.expanded = true;
+            for file_node in &mut phase_node.children {
+                file_node.expanded = true;
+                for chunk_node in &mut file_node.children {
+                    chunk_node.expanded = false;
+                }
             }
-            FileDiff::Rename {
-                old_path, content, ..
-            } => {
-                let old_path = Token::with_style(
-                    old_path.display().to_string(),
-                    TerminalStyle::new().underline(),
-                );
-
-                let summary = if content.is_some() {
-                    Token::new(format!(
-                        " ({} chunks, -{} +{} lines)",
-                        self.children().len(),
-                        self.removed_lines(),
-                        self.added_lines(),
-                    ))
-                } else {
-                    Token::new("")
-                };
+        }
+        self.move_cursor_out_of_collapsed().or_fail()
+    }
 
-                vec![
-                    Token::new("renamed "),
-                    old_path,
-                    Token::new(" -> "),
-                    path,
-                    summary,
-                ]
-            }
-            FileDiff::Delete { content, .. } => {
-                vec![
-                    Token::new("deleted "),
-                    path,
-                    if matches!(content, ContentDiff::Binary) {
-                        Token::new(" (binary)")
-                    } else {
-                        Token::new(format!(" (-{} lines)", self.removed_lines()))
-                    },
-                ]
-            }
-            FileDiff::Chmod {
-                old_mode, new_mode, ..
-            } => {
-                vec![
-                    Token::new("mode changed "),
-                    path,
-                    Token::new(format!(" {} -> {}", old_mode, new_mode)),
-                ]
+    fn move_cursor_out_of_collapsed(&mut self) -> orfail::Result<()> {
+        let mut cursor = self.cursor.clone();
+        while let Some(parent) = cursor.parent() {
+            if !self.root_node.get_node(&parent).or_fail()?.expanded {
+                self.cursor = parent.clone();
             }
-        };
-        tokens.into_iter()
+            cursor = parent;
+        }
+        self.update_focus();
+        Ok(())
     }
 
-    fn can_alter(&self) -> bool {
-        true
+    pub fn cursor_path(&self) -> &[usize] {
+        &self.cursor.path.0
     }
 
-    fn children(&self) -> &[Self::Child] {
-        self.chunks()
+    // Increases each time the diffs are loaded or reloaded.
+    pub fn load_count(&self) -> usize {
+        self.load_count
     }
-}
-
-impl DiffTreeNodeContent for ChunkDiff {
-    type Child = LineDiff;
 
-    fn head_line_tokens(&self) -> impl Iterator<Item = Token> {
-        std::iter::once(Token::new(self.head_line()))
+    fn cursor_file(&self) -> Option<Cursor> {
+        (!self.combined && self.cursor.path.len() >= 3).then(|| Cursor {
+            path: NodePath(self.cursor.path.0[..3].to_vec()),
+        })
     }
 
-    fn can_alter(&self) -> bool {
-        true
+    pub fn can_toggle_file_chunks(&self) -> bool {
+        self.cursor_file()
+            .and_then(|cursor| self.root_node.get_node(&cursor).ok())
+            .is_some_and(|node| !node.children.is_empty())
     }
 
-    fn children(&self) -> &[Self::Child] {
-        &self.lines
+    // Collapses the chunks of the file under the cursor, or expands them if all are collapsed.
+    pub fn toggle_file_chunks(&mut self) -> orfail::Result<()> {
+        let Some(file_cursor) = self.cursor_file() else {
+            return Ok(());
+        };
+        let file_node = self.root_node.get_node_mut(&file_cursor).or_fail()?;
+        let expanded = !file_node.children.iter().any(|chunk| chunk.expanded);
+        file_node.expanded = true;
+        for chunk_node in &mut file_node.children {
+            chunk_node.expanded = expanded;
+        }
+        if !expanded && self.cursor.path.len() > 4 {
+            self.cursor = file_cursor;
+        }
+        self.update_focus();
+        Ok(())
     }
-}
 
-impl DiffTreeNodeContent for LineDiff {
-    type Child = Self;
-
-    fn head_line_tokens(&self) -> impl Iterator<Item = Token> {
-        let style = TerminalStyle::new();
-        let style = match self {
-            LineDiff::Old(_) => style.dim(),
-            LineDiff::New(_) => style.bold(),
-            LineDiff::Both(_) => style,
-            LineDiff::NoNewlineAtEndOfFile => style,
+    fn is_deleted_file(&self, cursor: &Cursor) -> bool {
+        let (Some(phase), Some(file)) = (cursor.path.get(1), cursor.path.get(2)) else {
+            return false;
         };
-        std::iter::once(Token::with_style(self.to_string(), style))
+        cursor.path.len() == 3
+            && self
+                .children_and_diffs()
+                .nth(phase)
+                .and_then(|(_, diff)| diff.diff.files.get(file))
+                .is_some_and(|f| matches!(f, FileDiff::Delete { .. }))
     }
 
-    fn can_alter(&self) -> bool {
-        !matches!(self, Self::Both(_))
+    pub fn toggle_path_style(&mut self) {
+        self.path_style = self.path_style.next();
     }
 
-    fn children(&self) -> &[Self::Child] {
-        &[]
+    pub fn toggle_mode_labels(&mut self) {
+        self.mode_labels = !self.mode_labels;
     }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct NodePath(Vec<usize>);
 
-impl NodePath {
-    fn root() -> Self {
-        Self(vec![0])
+    pub fn toggle_focus(&mut self) {
+        self.focus = !self.focus;
+        self.update_focus();
     }
 
-    fn join(&self, index: usize) -> Self {
-        let mut child = self.clone();
-        child.0.push(index);
-        child
+    // Folds the lines of the chunk under the cursor except for those around the cursor line.
+    fn update_focus(&mut self) {
+        let focus_lines = self.focus.then_some(self.focus_lines);
+        let cursor = &self.cursor;
+        for phase_node in &mut self.root_node.children {
+            for file_node in &mut phase_node.children {
+                for chunk_node in &mut file_node.children {
+                    chunk_node.visible_lines =
+                        focus_lines.and_then(|n| chunk_node.focused_lines(cursor, n));
+                }
+            }
+        }
     }
 
-    fn starts_with(&self, other: &Self) -> bool {
-        self.0.starts_with(&other.0)
+    // Rebuilds the nodes for the current diffs, keeping the expanded state and the cursor position.
+    fn rebuild_nodes(&mut self) -> orfail::Result<()> {
+        let old_root_node = self.root_node.clone();
+        let cursor_keys = self.root_node.node_keys(&self.cursor).or_fail()?;
+
+        self.build_file_nodes();
+        self.root_node.copy_expanded_state(&old_root_node);
+        self.cursor = self.root_node.find_cursor(&cursor_keys);
+        self.update_focus();
+        Ok(())
     }
 
-    fn len(&self) -> usize {
-        self.0.len()
+    // Returns the numbers of unstaged files and chunks.
+    pub fn unstaged_summary(&self) -> (usize, usize) {
+        let files = &self.unstaged_diff.diff.files;
+        let chunks = files.iter().map(|f| f.chunks().len()).sum();
+        (files.len(), chunks)
     }
 
-    fn get(&self, i: usize) -> Option<usize> {
-        self.0.get(i).copied()
+    // The files hidden by the glob filter are also counted.
+    pub fn change_summary(&self) -> ChangeSummary {
+        let (unstaged_diff, staged_diff) = &self.unfiltered_diffs;
+        let files = || unstaged_diff.files.iter().chain(&staged_diff.files);
+        ChangeSummary {
+            staged_files: staged_diff.files.len(),
+            unstaged_files: unstaged_diff.files.len(),
+            added: files().map(|f| f.added_lines()).sum(),
+            removed: files().map(|f| f.removed_lines()).sum(),
+        }
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct Cursor {
-    path: NodePath,
-}
+    pub fn stage_all(&mut self) -> orfail::Result<bool> {
+        let diff = self.unstaged_diff.diff.clone();
+        if !self.apply(Operation::Stage, &diff).or_fail()? {
+            return Ok(false);
+        }
+        self.reload().or_fail()?;
+        Ok(true)
+    }
 
-impl Cursor {
-    fn root() -> Self {
-        Self {
-            path: NodePath::root().join(0),
+    pub fn discard_all(&mut self) -> orfail::Result<bool> {
+        let diff = self.unstaged_diff.diff.clone();
+        if !self.apply(Operation::Discard, &diff).or_fail()? {
+            return Ok(false);
         }
+        self.reload().or_fail()?;
+        Ok(true)
     }
 
-    fn join(&self, index: usize) -> Self {
-        Self {
-            path: self.path.join(index),
+    // Applies the diff with `git apply`, or only keeps its patch in dry-run mode.
+    // Returns `false` if nothing has been applied.
+    fn apply(&mut self, operation: Operation, diff: &Diff) -> orfail::Result<bool> {
+        if self.is_read_only() {
+            return Ok(false);
+        }
+        if self.dry_run {
+            let patch = diff.to_patch().or_fail()?;
+            self.dry_run_patch = Some((operation.dry_run_title(), patch));
+            return Ok(false);
+        }
+        match operation {
+            Operation::Stage => {
+                git::stage(diff).or_fail()?;
+                self.staged_conflict_markers |= diff.adds_conflict_markers();
+            }
+            Operation::Unstage => git::unstage(diff).or_fail()?,
+            Operation::Discard => {
+                let patch = git::discard(diff).or_fail()?;
+                self.push_trash(patch);
+            }
         }
+        Ok(true)
     }
 
-    fn parent(&self) -> Option<Self> {
-        (self.path.len() > 2).then(|| {
-            let mut path = self.path.clone();
-            path.0.pop();
-            Self { path }
-        })
+    // Returns the title and patch of the operation skipped in dry-run mode, if any.
+    pub fn take_dry_run_patch(&mut self) -> Option<(&'static str, String)> {
+        self.dry_run_patch.take()
     }
 
-    fn first_child(&self) -> Self {
-        let path = self.path.join(0);
-        Self { path }
+    // Returns `true` once if conflict markers have been staged since the last call.
+    pub fn take_staged_conflict_markers(&mut self) -> bool {
+        std::mem::take(&mut self.staged_conflict_markers)
     }
 
-    fn next_sibling(&self) -> Self {
-        let mut path = self.path.clone();
-        *path.0.last_mut().expect("infallible") += 1;
-        Self { path }
+    pub fn can_undiscard(&self) -> bool {
+        !self.trash.is_empty()
     }
 
-    fn prev_sibling(&self) -> Option<Self> {
-        let mut path = self.path.clone();
-        if path.0.last().copied() == Some(0) {
+    // Re-applies the most recently discarded changes to the working tree (without reloading).
+    // If that fails (e.g., the surrounding lines have changed), the patch is kept in the trash.
+    pub fn undiscard(&mut self) -> orfail::Result<()> {
+        let patch = self.trash.pop_back().or_fail()?;
+        if let Err(e) = git::apply(&patch) {
+            self.trash.push_back(patch);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn push_trash(&mut self, patch: String) {
+        if self.trash.len() == Self::TRASH_CAPACITY {
+            self.trash.pop_front();
+        }
+        self.trash.push_back(patch);
+    }
+
+    pub fn unstaged_paths(&self) -> Vec<PathBuf> {
+        let files = &self.unstaged_diff.diff.files;
+        files.iter().map(|f| f.path().clone()).collect()
+    }
+
+    // Paths of the unstaged files affected by staging or discarding the node under the cursor.
+    pub fn cursor_unstaged_paths(&self) -> Vec<PathBuf> {
+        let files = &self.unstaged_diff.diff.files;
+        match (self.cursor.path.get(1), self.cursor.path.get(2)) {
+            (Some(0), None) => self.unstaged_paths(),
+            (Some(0), Some(i)) => files.get(i).map(|f| f.path().clone()).into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn stage(&mut self) -> orfail::Result<bool> {
+        if !self.can_stage_or_discard() {
+            return Ok(false);
+        }
+        let chunk_position = self.cursor_chunk_position();
+        let cursor = self.operation_cursor(0).or_fail()?;
+        let diff = self.root_node.children[0]
+            .get_diff(&cursor, &self.unstaged_diff.diff, false)
+            .or_fail()?;
+        if !self.apply(Operation::Stage, &diff).or_fail()? {
+            return Ok(false);
+        }
+        self.reload().or_fail()?;
+        if let Some(position) = chunk_position {
+            self.move_cursor_after_staging(&position).or_fail()?;
+        }
+        Ok(true)
+    }
+
+    // Stages the chunks of the unstaged file under the cursor one by one, reloading the diffs in
+    // between so that each chunk is applied to the index updated by the previous ones.
+    //
+    // Unlike staging the whole file, only the line changes are staged: a mode change is not.
+    // Staging the first chunk of a renamed file adds the rename to the index, as with staging
+    // the chunk alone. New and deleted files are staged as a whole, so that a deletion
+    // removes the file from the index instead of emptying it.
+    pub fn stage_file_hunks(&mut self) -> orfail::Result<bool> {
+        let Some(file) = self.cursor_unstaged_file() else {
+            return Ok(false);
+        };
+        if matches!(file, FileDiff::New { .. } | FileDiff::Delete { .. }) {
+            let diff = file.to_diff();
+            if !self.apply(Operation::Stage, &diff).or_fail()? {
+                return Ok(false);
+            }
+            self.reload().or_fail()?;
+            return Ok(true);
+        }
+        if self.dry_run {
+            let diff = Diff {
+                files: file
+                    .chunks()
+                    .iter()
+                    .flat_map(|chunk| file.chunk_diff(chunk, false).files)
+                    .collect(),
+            };
+            self.apply(Operation::Stage, &diff).or_fail()?;
+            return Ok(false);
+        }
+
+        let path = file.path().clone();
+        let mut staged = false;
+        for _ in 0..file.chunks().len() {
+            let Some(file) = self
+                .unstaged_diff
+                .diff
+                .files
+                .iter()
+                .find(|f| *f.path() == path)
+            else {
+                break;
+            };
+            let Some(chunk) = file.chunks().first() else {
+                break;
+            };
+            let diff = file.chunk_diff(chunk, false);
+            self.apply(Operation::Stage, &diff).or_fail()?;
+            self.reload().or_fail()?;
+            staged = true;
+        }
+        Ok(staged)
+    }
+
+    // Returns the position of the unstaged chunk under (or containing the line under) the cursor.
+    fn cursor_chunk_position(&self) -> Option<ChunkPosition> {
+        if !matches!(self.cursor.path.len(), 4 | 5) || self.cursor.path.get(1) != Some(0) {
             return None;
         }
-        *path.0.last_mut().expect("infallible") -= 1;
-        Some(Self { path })
+        let chunk_cursor = Cursor {
+            path: NodePath(self.cursor.path.0[..4].to_vec()),
+        };
+        let chunk_node = self.root_node.get_node(&chunk_cursor).ok()?;
+        if chunk_node.staged_context.is_some() {
+            return None;
+        }
+        let file_index = self.cursor.path.get(2)?;
+        let file = self.unstaged_diff.diff.files.get(file_index)?;
+        let chunk = file.chunks().get(chunk_node.content_index)?;
+        let next_line = self
+            .cursor
+            .path
+            .get(4)
+            .and_then(|i| chunk_node.children.get(i + 1))
+            .filter(|node| node.folded_lines == 0)
+            .and_then(|node| {
+                let position = chunk.new_file_position(node.content_index)?;
+                Some((position, chunk.lines[node.content_index].clone()))
+            });
+        Some(ChunkPosition {
+            path: file.path().clone(),
+            file_index,
+            chunk_index: chunk_node.content_index,
+            chunks: file.chunks().len(),
+            line: self.cursor.path.len() == 5,
+            next_line,
+        })
     }
 
-    fn render(&self, canvas: &mut Canvas, path: &NodePath) {
-        let mut text = String::with_capacity(path.len() * 2);
-        let selected = *path == self.path;
+    // Moves the cursor after staging at `position` so that the next change is selected.
+    // A line cursor moves to the line that followed it if the line remains; otherwise,
+    // it is left as it is (usually now on the next line) while its chunk remains.
+    fn move_cursor_after_staging(&mut self, position: &ChunkPosition) -> orfail::Result<()> {
+        if let Some(cursor) = self.find_line_after_staging(position) {
+            self.move_cursor(Some(cursor)).or_fail()?;
+            return Ok(());
+        }
+        if position.line {
+            let chunks = self
+                .unstaged_diff
+                .diff
+                .files
+                .iter()
+                .find(|f| *f.path() == position.path)
+                .map_or(0, |f| f.chunks().len());
+            if chunks >= position.chunks {
+                return Ok(());
+            }
+        }
+        self.move_cursor_to_chunk(position).or_fail()?;
+        Ok(())
+    }
 
-        if selected {
-            text.push('-');
-        } else {
-            text.push(' ');
+    // Returns the cursor of the unstaged line that followed the staged one.
+    // The chunk may have been split or had its context trimmed, so all chunks of the file are searched.
+    fn find_line_after_staging(&self, position: &ChunkPosition) -> Option<Cursor> {
+        let (new_file_position, line) = position.next_line.as_ref()?;
+        let files = &self.unstaged_diff.diff.files;
+        let file_index = files.iter().position(|f| *f.path() == position.path)?;
+        let file = &files[file_index];
+        let file_node = self.root_node.children[0].children.get(file_index)?;
+        file_node
+            .children
+            .iter()
+            .filter(|c| c.staged_context.is_none())
+            .find_map(|chunk_node| {
+                let chunk = file.chunks().get(chunk_node.content_index)?;
+                chunk_node
+                    .children
+                    .iter()
+                    .filter(|node| node.folded_lines == 0)
+                    .find(|node| {
+                        chunk.lines.get(node.content_index) == Some(line)
+                            && chunk.new_file_position(node.content_index)
+                                == Some(*new_file_position)
+                    })
+            })
+            .map(|node| Cursor {
+                path: node.path.clone(),
+            })
+    }
+
+    // Moves the cursor to the unstaged chunk at the same position (or the next one if it has gone).
+    // If the file has no chunks there, the first chunk of the next file is chosen instead.
+    fn move_cursor_to_chunk(&mut self, position: &ChunkPosition) -> orfail::Result<bool> {
+        let files = &self.unstaged_diff.diff.files;
+        let phase_node = &self.root_node.children[0];
+        let same_file = files.iter().position(|f| *f.path() == position.path);
+        let chunk_node = |file_index: usize, chunk_index: usize| {
+            phase_node
+                .children
+                .get(file_index)?
+                .children
+                .iter()
+                .find(|c| c.staged_context.is_none() && c.content_index == chunk_index)
+        };
+        let node = same_file
+            .and_then(|i| chunk_node(i, position.chunk_index))
+            .or_else(|| {
+                // The next file is at the same index if the file has been fully staged.
+                let next_file_index = same_file.map_or(position.file_index, |i| i + 1);
+                chunk_node(next_file_index, 0)
+            });
+        let cursor = node.map(|node| Cursor {
+            path: node.path.clone(),
+        });
+        self.move_cursor(cursor).or_fail()
+    }
+
+    pub fn discard(&mut self) -> orfail::Result<bool> {
+        let Some(diff) = self.discard_diff().or_fail()? else {
+            return Ok(false);
+        };
+        if !self.apply(Operation::Discard, &diff).or_fail()? {
+            return Ok(false);
         }
+        self.reload().or_fail()?;
+        Ok(true)
+    }
 
-        for i in 2..path.len() {
-            if i == self.path.len() && path.starts_with(&self.path) {
-                text.push_str(" :")
-            } else if selected {
-                text.push_str("--")
+    fn discard_diff(&self) -> orfail::Result<Option<Diff>> {
+        if !self.can_stage_or_discard() {
+            return Ok(None);
+        }
+        let cursor = self.operation_cursor(0).or_fail()?;
+        let diff = self.root_node.children[0]
+            .get_diff(&cursor, &self.unstaged_diff.diff, true)
+            .or_fail()?;
+        Ok(Some(diff))
+    }
+
+    // Returns the patch of the changes that `discard()` would revert, to review them beforehand.
+    pub fn discard_patch(&self) -> orfail::Result<Option<String>> {
+        let Some(diff) = self.discard_diff().or_fail()? else {
+            return Ok(None);
+        };
+        diff.to_patch().map(Some).or_fail()
+    }
+
+    pub fn discard_all_patch(&self) -> orfail::Result<String> {
+        self.unstaged_diff.diff.to_patch().or_fail()
+    }
+
+    pub fn unstage(&mut self) -> orfail::Result<bool> {
+        if !self.can_unstage() {
+            return Ok(false);
+        }
+        let cursor = self.operation_cursor(1).or_fail()?;
+        let diff = self.root_node.children[1]
+            .get_diff(&cursor, &self.staged_diff.diff, true)
+            .or_fail()?;
+        if !self.apply(Operation::Unstage, &diff).or_fail()? {
+            return Ok(false);
+        }
+        self.reload().or_fail()?;
+        Ok(true)
+    }
+
+    fn expand_if_possible(&mut self, terminal_size: TerminalSize) -> orfail::Result<()> {
+        if !self.cursor_right().or_fail()? {
+            return Ok(());
+        }
+
+        loop {
+            // Deleted files are left collapsed unless their content is requested.
+            if self.show_deleted_content || !self.is_deleted_file(&self.cursor) {
+                self.root_node.toggle(&self.cursor).or_fail()?;
+                if self.rows() > terminal_size.rows {
+                    self.root_node.toggle(&self.cursor).or_fail()?;
+                    break;
+                }
+            }
+            let next_cursor = self.root_node.cursor_down(&self.cursor);
+            if !self.move_cursor(next_cursor).or_fail()? {
+                break;
+            }
+        }
+
+        self.cursor = Cursor::root();
+        Ok(())
+    }
+
+    // Expands all ancestors of the cursor so that the cursor node is visible.
+    fn expand_parent(&mut self) -> orfail::Result<()> {
+        let mut cursor = self.cursor.clone();
+        while let Some(parent) = cursor.parent() {
+            self.root_node.get_node_mut(&parent).or_fail()?.expanded = true;
+            cursor = parent;
+        }
+        Ok(())
+    }
+
+    fn rows(&self) -> usize {
+        let root_node_offset = 1;
+        self.root_node.rows() - root_node_offset + self.separator_rows()
+    }
+
+    pub fn reload(&mut self) -> orfail::Result<()> {
+        let (unstaged_diff, staged_diff, untracked_groups) = git::unstaged_and_staged_diffs(
+            self.include_ignored,
+            self.staged_against_worktree,
+            &self.expanded_untracked_groups,
+        )
+        .or_fail()?;
+        self.untracked_groups = untracked_groups;
+        self.has_staged_changes = git::has_staged_changes().or_fail()?;
+        self.update_diffs(unstaged_diff, staged_diff).or_fail()
+    }
+
+    // Reloads the diffs on a periodic refresh.
+    // Unlike `reload()`, the cursor follows its file even if files before it have come or gone.
+    pub fn refresh(&mut self) -> orfail::Result<()> {
+        let (unstaged_diff, staged_diff, untracked_groups) = git::unstaged_and_staged_diffs(
+            self.include_ignored,
+            self.staged_against_worktree,
+            &self.expanded_untracked_groups,
+        )
+        .or_fail()?;
+        self.untracked_groups = untracked_groups;
+        self.has_staged_changes = git::has_staged_changes().or_fail()?;
+        self.update_diffs_keeping_cursor_file(unstaged_diff, staged_diff)
+            .or_fail()
+    }
+
+    fn update_diffs_keeping_cursor_file(
+        &mut self,
+        unstaged_diff: Diff,
+        staged_diff: Diff,
+    ) -> orfail::Result<()> {
+        let old_cursor = self.cursor.clone();
+        let old_file =
+            old_cursor
+                .path
+                .get(1)
+                .zip(old_cursor.path.get(2))
+                .and_then(|(phase, file)| {
+                    let (_, diff) = self.children_and_diffs().nth(phase)?;
+                    Some((phase, diff.diff.files.get(file)?.path().clone()))
+                });
+        self.update_diffs(unstaged_diff, staged_diff).or_fail()?;
+
+        let Some((phase, path)) = old_file else {
+            return Ok(());
+        };
+        let Some(file) = self
+            .children_and_diffs()
+            .nth(phase)
+            .and_then(|(_, diff)| diff.diff.files.iter().position(|f| *f.path() == path))
+        else {
+            return Ok(());
+        };
+        let mut cursor = old_cursor;
+        cursor.path.0[2] = file;
+        if !self.root_node.is_valid_cursor(&cursor) {
+            cursor.path.0.truncate(3);
+        }
+        if cursor != self.cursor {
+            self.move_cursor(Some(cursor)).or_fail()?;
+        }
+        Ok(())
+    }
+
+    fn update_diffs(
+        &mut self,
+        mut unstaged_diff: Diff,
+        mut staged_diff: Diff,
+    ) -> orfail::Result<()> {
+        let old = self.clone();
+        self.load_count += 1;
+        self.unfiltered_diffs = (unstaged_diff.clone(), staged_diff.clone());
+        if let Some(glob) = &self.glob_filter {
+            for diff in [&mut unstaged_diff, &mut staged_diff] {
+                diff.files.retain(|f| glob_matches_path(glob, f.path()));
+            }
+        }
+        self.unstaged_diff.set_diff(unstaged_diff, self.file_order);
+        self.staged_diff.set_diff(staged_diff, self.file_order);
+        if self.mark_reload_changes {
+            self.unstaged_diff
+                .mark_reload_changes(&old.unstaged_diff.diff);
+            self.staged_diff.mark_reload_changes(&old.staged_diff.diff);
+        }
+        if self.show_mtimes {
+            self.unstaged_diff.read_mtimes();
+            self.staged_diff.read_mtimes();
+        }
+        if self.show_binary_sizes {
+            self.unstaged_diff.read_binary_sizes();
+            self.staged_diff.read_binary_sizes();
+        }
+        if let Some(path) = &self.reconciled_file
+            && !self.has_intersecting_chunks(path)
+        {
+            // Nothing is left to reconcile.
+            self.reconciled_file = None;
+        }
+        self.build_file_nodes();
+        // The saved nodes may no longer correspond to the files.
+        let diffs_changed = self.unstaged_diff.diff != old.unstaged_diff.diff
+            || self.staged_diff.diff != old.staged_diff.diff;
+        if diffs_changed {
+            self.saved_expansion = None;
+        }
+        if self.staged_diff.diff != old.staged_diff.diff {
+            self.blamed_authors.clear();
+        }
+        for (node, diff) in self.children_and_diffs_mut() {
+            node.restore_expanded_state(
+                &diff.diff,
+                &old.children_and_diffs()
+                    .map(|x| (x.0, &x.1.diff))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        // The outline cannot be reverted any more, but the files that have just appeared
+        // are shown in it as well.
+        if diffs_changed && std::mem::take(&mut self.outline) {
+            let old_paths = old
+                .children_and_diffs()
+                .map(|(_, diff)| diff.diff.files.iter().map(|f| f.path()).collect())
+                .collect::<Vec<HashSet<_>>>();
+            for ((node, diff), old_paths) in self.children_and_diffs_mut().zip(&old_paths) {
+                for (file_node, file) in node.children.iter_mut().zip(&diff.diff.files) {
+                    if old_paths.contains(file.path()) {
+                        continue;
+                    }
+                    file_node.expanded = true;
+                    for chunk_node in &mut file_node.children {
+                        chunk_node.expanded = false;
+                    }
+                }
+            }
+        }
+
+        while !self.root_node.is_valid_cursor(&self.cursor) {
+            if let Some(sibling_cursor) = self.cursor.prev_sibling() {
+                self.cursor = sibling_cursor;
+            } else if let Some(parent_cursor) = self.cursor.parent() {
+                self.cursor = parent_cursor;
             } else {
-                text.push_str("  ")
+                self.cursor = Cursor::root();
+                break;
             }
         }
 
-        if selected {
-            text.push_str(">| ");
-        } else if path.len() == self.path.len() {
-            text.push_str(" | ");
-        } else {
-            text.push_str("   ");
+        self.expand_parent().or_fail()?;
+        self.update_focus();
+        if self.combined {
+            self.sync_combined_cursor().or_fail()?;
         }
+        if self.sidebar {
+            self.show_files_in_sidebar().or_fail()?;
+        }
+
+        Ok(())
+    }
+
+    fn build_file_nodes(&mut self) {
+        let staged_files = &self.staged_diff.diff.files;
+        for (phase, (node, diff)) in self
+            .root_node
+            .children
+            .iter_mut()
+            .zip([&self.unstaged_diff, &self.staged_diff])
+            .enumerate()
+        {
+            let children = diff
+                .diff
+                .files
+                .iter()
+                .enumerate()
+                .map(|(i, file)| {
+                    // Staged changes are shown as context only within unstaged files.
+                    let reconciled = diff.phase == DiffPhase::Unstaged
+                        && self.reconciled_file.as_ref() == Some(file.path());
+                    let staged_file = staged_files
+                        .iter()
+                        .enumerate()
+                        .find(|(_, f)| f.path() == file.path())
+                        .filter(|_| {
+                            (self.show_staged_context || reconciled)
+                                && diff.phase == DiffPhase::Unstaged
+                                && !self.staged_against_worktree
+                        });
+                    let line_filter = |staged_context: bool, chunk: usize| {
+                        let key = (phase, file.path().clone(), staged_context, chunk);
+                        LineFilter {
+                            compact: self
+                                .chunk_compact
+                                .get(&key)
+                                .copied()
+                                .unwrap_or(self.compact),
+                            fold_context_lines: self
+                                .fold_context_lines
+                                .filter(|_| !self.unfolded_chunks.contains(&key)),
+                        }
+                    };
+                    DiffTreeNode::new_file_diff_node(
+                        node.path.join(i),
+                        file,
+                        staged_file,
+                        reconciled,
+                        line_filter,
+                    )
+                })
+                .collect();
+            node.children = children;
+        }
+    }
+
+    fn children_and_diffs(&self) -> impl '_ + Iterator<Item = (&DiffTreeNode, &PhasedDiff)> {
+        self.root_node
+            .children
+            .iter()
+            .zip([&self.unstaged_diff, &self.staged_diff])
+    }
 
-        canvas.draw(Token::new(text));
+    fn children_and_diffs_mut(
+        &mut self,
+    ) -> impl '_ + Iterator<Item = (&mut DiffTreeNode, &mut PhasedDiff)> {
+        self.root_node
+            .children
+            .iter_mut()
+            .zip([&mut self.unstaged_diff, &mut self.staged_diff])
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum DiffPhase {
-    Unstaged,
-    Staged,
+/// Numbers of changed files and lines, e.g., for status line integrations.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeSummary {
+    /// Number of files with staged changes.
+    pub staged_files: usize,
+    /// Number of files with unstaged changes (including untracked files).
+    pub unstaged_files: usize,
+    /// Number of added lines in both the staged and unstaged changes.
+    pub added: usize,
+    /// Number of removed lines in both the staged and unstaged changes.
+    pub removed: usize,
+}
+
+impl nojson::DisplayJson for ChangeSummary {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.object(|f| {
+            f.member("staged_files", self.staged_files)?;
+            f.member("unstaged_files", self.unstaged_files)?;
+            f.member("added", self.added)?;
+            f.member("removed", self.removed)
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
-struct PhasedDiff {
-    phase: DiffPhase,
-    diff: Diff,
+struct DiffTreeNode {
+    path: NodePath,
+    // Index of the corresponding item in the parent content's children.
+    // This differs from the last element of `path` only when some lines are hidden.
+    content_index: usize,
+    // Index of the staged file if this node shows its changes as read-only context
+    // within the corresponding unstaged file.
+    staged_context: Option<usize>,
+    // Range of the children shown in focus mode (the others are folded).
+    visible_lines: Option<Range<usize>>,
+    // Number of context lines folded into this line node (zero unless it is a placeholder).
+    folded_lines: usize,
+    expanded: bool,
+    children: Vec<Self>,
+}
+
+// Key of a chunk that is kept across rebuilds: the phase, the file path,
+// whether the chunk is staged context, and the chunk index.
+type ChunkKey = (usize, PathBuf, bool, usize);
+
+// Which lines of a chunk have nodes.
+#[derive(Debug, Clone, Copy)]
+struct LineFilter {
+    // Whether to hide all context lines.
+    compact: bool,
+    // Runs of more context lines than this are folded into a placeholder.
+    fold_context_lines: Option<usize>,
+}
+
+impl DiffTreeNode {
+    // Number of context lines next to changes that are not folded.
+    const FOLD_MARGIN_LINES: usize = 3;
+
+    fn new_root_node() -> Self {
+        let root_path = NodePath::root();
+        Self {
+            path: root_path.clone(),
+            content_index: 0,
+            staged_context: None,
+            visible_lines: None,
+            folded_lines: 0,
+            expanded: true,
+            children: vec![
+                Self::new_diff_node(root_path.join(0)),
+                Self::new_diff_node(root_path.join(1)),
+            ],
+        }
+    }
+
+    fn new_diff_node(path: NodePath) -> Self {
+        Self {
+            content_index: path.last(),
+            staged_context: None,
+            visible_lines: None,
+            folded_lines: 0,
+            path,
+            expanded: true,
+            children: Vec::new(),
+        }
+    }
+
+    fn new_file_diff_node(
+        path: NodePath,
+        diff: &FileDiff,
+        staged_file: Option<(usize, &FileDiff)>,
+        only_intersecting: bool,
+        line_filter: impl Fn(bool, usize) -> LineFilter,
+    ) -> Self {
+        let mut chunks = diff
+            .chunks()
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.old_start_line_number, None, i, c))
+            .collect::<Vec<_>>();
+        if let Some((file_index, staged_file)) = staged_file {
+            if only_intersecting {
+                let staged_chunks = staged_file.chunks();
+                chunks.retain(|(.., c)| staged_chunks.iter().any(|s| s.is_intersect(c)));
+            }
+            // Both the new side of staged chunks and the old side of unstaged chunks
+            // refer to the index, so their line numbers can be compared directly.
+            chunks.extend(
+                staged_file
+                    .chunks()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (c.new_start_line_number, Some(file_index), i, c))
+                    .filter(|(.., c)| {
+                        !only_intersecting || diff.chunks().iter().any(|u| c.is_intersect(u))
+                    }),
+            );
+            chunks.sort_by_key(|(line_number, staged, ..)| (*line_number, staged.is_none()));
+        }
+
+        let children = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, (_, staged_context, content_index, c))| {
+                DiffTreeNode::new_chunk_diff_node(
+                    path.join(i),
+                    content_index,
+                    staged_context,
+                    c,
+                    line_filter(staged_context.is_some(), content_index),
+                )
+            })
+            .collect();
+        Self {
+            content_index: path.last(),
+            staged_context: None,
+            visible_lines: None,
+            folded_lines: 0,
+            path,
+            expanded: false,
+            children,
+        }
+    }
+
+    fn new_chunk_diff_node(
+        path: NodePath,
+        content_index: usize,
+        staged_context: Option<usize>,
+        diff: &ChunkDiff,
+        line_filter: LineFilter,
+    ) -> Self {
+        Self {
+            children: Self::new_line_diff_nodes(&path, staged_context, diff, line_filter),
+            content_index,
+            staged_context,
+            visible_lines: None,
+            folded_lines: 0,
+            path,
+            expanded: true,
+        }
+    }
+
+    fn new_line_diff_nodes(
+        path: &NodePath,
+        staged_context: Option<usize>,
+        diff: &ChunkDiff,
+        line_filter: LineFilter,
+    ) -> Vec<Self> {
+        // Pairs of a line index and the number of lines folded into it.
+        let mut lines = Vec::new();
+        let mut start = 0;
+        while start < diff.lines.len() {
+            let is_context = |line: &LineDiff| matches!(line, LineDiff::Both(_));
+            if !is_context(&diff.lines[start]) {
+                lines.push((start, 0));
+                start += 1;
+                continue;
+            }
+            let end = diff.lines[start..]
+                .iter()
+                .position(|line| !is_context(line))
+                .map_or(diff.lines.len(), |n| start + n);
+            if !line_filter.compact {
+                // Some lines next to the changes are kept visible.
+                let margin = |changed: bool| if changed { Self::FOLD_MARGIN_LINES } else { 0 };
+                let fold_start = start + margin(start > 0);
+                let fold_end = end.saturating_sub(margin(end < diff.lines.len()));
+                let fold = line_filter
+                    .fold_context_lines
+                    .filter(|&n| end - start > n && fold_end > fold_start + 1);
+                for i in start..end {
+                    match fold {
+                        Some(_) if i == fold_start => lines.push((i, fold_end - fold_start)),
+                        Some(_) if (fold_start..fold_end).contains(&i) => {}
+                        _ => lines.push((i, 0)),
+                    }
+                }
+            }
+            start = end;
+        }
+
+        lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, (line_index, folded_lines))| Self {
+                path: path.join(i),
+                content_index: line_index,
+                staged_context,
+                visible_lines: None,
+                folded_lines,
+                expanded: false,
+                children: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn focused_lines(&self, cursor: &Cursor, focus_lines: usize) -> Option<Range<usize>> {
+        if !cursor.path.starts_with(&self.path) {
+            return None;
+        }
+        let i = cursor.path.get(self.path.len())?;
+        let len = self.children.len();
+        let mut start = i.saturating_sub(focus_lines);
+        let mut end = (i + focus_lines + 1).min(len);
+
+        // Folding a single line saves nothing because the marker takes a row too.
+        if start == 1 {
+            start = 0;
+        }
+        if end + 1 == len {
+            end = len;
+        }
+        (start != 0 || end != len).then_some(start..end)
+    }
+
+    fn visible_children(&self) -> &[Self] {
+        match &self.visible_lines {
+            Some(range) => &self.children[range.clone()],
+            None => &self.children,
+        }
+    }
+
+    fn fold_marker_rows(&self) -> usize {
+        self.visible_lines.as_ref().map_or(0, |range| {
+            usize::from(range.start > 0) + usize::from(range.end < self.children.len())
+        })
+    }
+
+    fn render_fold_marker(&self, canvas: &mut Canvas, ctx: &RenderContext, i: usize, lines: usize) {
+        ctx.cursor.render(canvas, &self.path.join(i), true);
+        canvas.drawln(Token::with_style(
+            format!("{} {lines} more lines", ctx.charset.ellipsis()),
+            TerminalStyle::new().dim(),
+        ));
+    }
+
+    fn key(&self) -> (Option<usize>, usize) {
+        (self.staged_context, self.content_index)
+    }
+
+    fn node_keys(&self, cursor: &Cursor) -> orfail::Result<Vec<(Option<usize>, usize)>> {
+        let mut node = self;
+        let mut keys = Vec::new();
+        while let Some((_, child)) = node.get_maybe_child(cursor).or_fail()? {
+            keys.push(child.key());
+            node = child;
+        }
+        Ok(keys)
+    }
+
+    fn find_cursor(&self, keys: &[(Option<usize>, usize)]) -> Cursor {
+        let mut node = self;
+        let mut path = self.path.clone();
+        for key in keys {
+            // If the exact node has gone (e.g., a hidden line), the nearest following sibling is chosen.
+            let i = node
+                .children
+                .iter()
+                .position(|c| c.key() == *key)
+                .or_else(|| {
+                    let mut siblings = node.children.iter().filter(|c| c.staged_context == key.0);
+                    siblings
+                        .clone()
+                        .find(|c| c.content_index >= key.1)
+                        .or_else(|| siblings.next_back())
+                        .map(|c| c.path.last())
+                });
+            let Some(i) = i else {
+                break;
+            };
+            node = &node.children[i];
+            path = node.path.clone();
+        }
+        if path.len() < 2 {
+            return Cursor::root();
+        }
+        Cursor { path }
+    }
+
+    // Writes this node and its descendants as an indented outline, one node per line.
+    fn dump(&self, out: &mut String, cursor: &Cursor) {
+        use std::fmt::Write;
+
+        let indent = "  ".repeat(self.path.len() - 1);
+        let _ = write!(
+            out,
+            "{indent}{:?} content_index={} children={}",
+            self.path.0,
+            self.content_index,
+            self.children.len()
+        );
+        if self.expanded {
+            out.push_str(" expanded");
+        }
+        if let Some(i) = self.staged_context {
+            let _ = write!(out, " staged_context={i}");
+        }
+        if let Some(range) = &self.visible_lines {
+            let _ = write!(out, " visible_lines={range:?}");
+        }
+        if self.folded_lines > 0 {
+            let _ = write!(out, " folded_lines={}", self.folded_lines);
+        }
+        if self.path == cursor.path {
+            out.push_str(" <- cursor");
+        }
+        out.push('\n');
+        for child in &self.children {
+            child.dump(out, cursor);
+        }
+    }
+
+    fn set_expanded_recursively(&mut self, expanded: bool) {
+        self.expanded = expanded;
+        for child in &mut self.children {
+            child.set_expanded_recursively(expanded);
+        }
+    }
+
+    fn copy_expanded_state(&mut self, old: &Self) {
+        self.expanded = old.expanded;
+        for child in &mut self.children {
+            if let Some(old_child) = old.children.iter().find(|c| c.key() == child.key()) {
+                child.copy_expanded_state(old_child);
+            }
+        }
+    }
+
+    fn restore_expanded_state(&mut self, diff: &Diff, old: &[(&Self, &Diff)]) {
+        if old.is_empty() {
+            return;
+        }
+
+        self.expanded = old.iter().any(|x| x.0.expanded);
+
+        for (c, d) in self.children.iter_mut().zip(diff.files.iter()) {
+            let expanded = old
+                .iter()
+                .flat_map(|x| x.0.children.iter().zip(x.1.files.iter()))
+                .filter(|x| x.1.path() == d.path())
+                .any(|x| x.0.expanded);
+            c.expanded = expanded;
+        }
+    }
+
+    // The head lines of phases and files already show their counts, so only chunks get them here.
+    fn shows_child_counts(&self, ctx: &RenderContext) -> bool {
+        ctx.show_child_counts && self.path.len() > 3
+    }
+
+    fn collapsed_marker(&self, ctx: &RenderContext) -> Option<String> {
+        if self.children.is_empty() {
+            return None;
+        }
+        if !self.shows_child_counts(ctx) {
+            return Some(ctx.charset.ellipsis().to_owned());
+        }
+        Some(format!("({} lines)", self.children.len()))
+    }
+
+    fn render<T>(&self, canvas: &mut Canvas, ctx: &RenderContext, content: &T)
+    where
+        T: DiffTreeNodeContent,
+    {
+        let alterable = self.staged_context.is_none() && content.can_alter();
+        ctx.cursor.render(canvas, &self.path, alterable);
+        let dim = self.staged_context.is_some() || ctx.is_author_mismatch(&self.path);
+        for token in content.head_line_tokens(ctx) {
+            if dim {
+                canvas.draw(Token::with_style(token.text(), TerminalStyle::new().dim()));
+            } else {
+                canvas.draw(token);
+            }
+        }
+        if let Some(change) = ctx.reload_change(&self.path) {
+            canvas.draw(Token::with_style(
+                change.label(),
+                TerminalStyle::new().italic(),
+            ));
+        }
+        if let Some(mtime) = ctx.mtime(&self.path) {
+            let elapsed = ctx.now.duration_since(mtime).unwrap_or_default();
+            canvas.draw(Token::with_style(
+                format!(" ({})", format_elapsed(elapsed)),
+                TerminalStyle::new().dim(),
+            ));
+        }
+        if let Some(size) = ctx.binary_size(&self.path) {
+            canvas.draw(Token::with_style(
+                format!(" ({})", format_size(size)),
+                TerminalStyle::new().dim(),
+            ));
+        }
+        if !self.expanded {
+            let mut has_summary = false;
+            for token in content.collapsed_summary_tokens() {
+                canvas.draw(token);
+                has_summary = true;
+            }
+            if let Some(marker) = self.collapsed_marker(ctx) {
+                // Unlike the ellipsis, the counts need to be separated from the head line.
+                if self.shows_child_counts(ctx) && !has_summary {
+                    canvas.draw(Token::new(" "));
+                }
+                canvas.draw(Token::new(marker));
+            }
+        }
+        canvas.newline();
+
+        if self.expanded {
+            if let Some(range) = self.visible_lines.clone().filter(|r| r.start > 0) {
+                self.render_fold_marker(canvas, ctx, 0, range.start);
+            }
+            for child in self.visible_children() {
+                let drawn = match child.staged_context {
+                    _ if child.folded_lines > 0 => child.render_folded_lines(canvas, ctx),
+                    Some(file_index) if self.staged_context.is_none() => {
+                        let Some(chunk) = ctx
+                            .staged_diff
+                            .files
+                            .get(file_index)
+                            .and_then(|f| f.chunks().get(child.content_index))
+                        else {
+                            break;
+                        };
+                        child.render_if_need(canvas, ctx, &StagedChunk(chunk))
+                    }
+                    _ => {
+                        let Some(child_content) = content.children().get(child.content_index)
+                        else {
+                            break;
+                        };
+                        child.render_if_need(canvas, ctx, child_content)
+                    }
+                };
+                if !drawn {
+                    return;
+                }
+            }
+            if let Some(range) = self.visible_lines.clone() {
+                let hidden = self.children.len() - range.end;
+                if hidden > 0 {
+                    self.render_fold_marker(canvas, ctx, range.end, hidden);
+                }
+            }
+        }
+    }
+
+    fn render_folded_lines(&self, canvas: &mut Canvas, ctx: &RenderContext) -> bool {
+        if canvas.is_frame_exceeded() {
+            return false;
+        }
+        let ellipsis = ctx.charset.ellipsis();
+        ctx.cursor.render(canvas, &self.path, false);
+        canvas.drawln(Token::with_style(
+            format!(
+                "{ellipsis} {} unchanged lines {ellipsis}",
+                self.folded_lines
+            ),
+            TerminalStyle::new().dim(),
+        ));
+        true
+    }
+
+    fn render_if_need<T>(&self, canvas: &mut Canvas, ctx: &RenderContext, content: &T) -> bool
+    where
+        T: DiffTreeNodeContent,
+    {
+        if canvas.is_frame_exceeded() {
+            return false;
+        }
+
+        // Only the rows above the frame are counted, so that the subtrees of the nodes
+        // within the frame are not traversed just to find that they cannot be skipped.
+        let mut canvas_cursor = canvas.cursor();
+        let rows_above_frame = canvas
+            .frame_row_range()
+            .start
+            .saturating_sub(canvas_cursor.row);
+        let drawn_rows = self.rows_up_to(rows_above_frame + 1);
+        if drawn_rows <= rows_above_frame {
+            canvas_cursor.row += drawn_rows;
+            canvas.set_cursor(canvas_cursor);
+        } else {
+            self.render(canvas, ctx, content);
+        }
+        true
+    }
+
+    // The tree is traversed with an explicit stack (here and in the methods below)
+    // so that deep trees cannot overflow the call stack.
+    fn rows(&self) -> usize {
+        let mut rows = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            rows += 1;
+            if node.expanded {
+                rows += node.fold_marker_rows();
+                stack.extend(node.visible_children());
+            }
+        }
+        rows
+    }
+
+    // Same as `rows()` but stops counting at `limit`.
+    fn rows_up_to(&self, limit: usize) -> usize {
+        let mut rows = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            rows += 1;
+            if rows >= limit {
+                return limit;
+            }
+            if node.expanded {
+                rows += node.fold_marker_rows();
+                stack.extend(node.visible_children());
+            }
+        }
+        rows.min(limit)
+    }
+
+    fn cursor_row(&self, cursor: &Cursor) -> usize {
+        let order = |node: &Self| cursor.path.0[..node.path.len()].cmp(&node.path.0);
+        let mut row = 0;
+        let mut node = self;
+        loop {
+            match order(node) {
+                Ordering::Less => return row,
+                Ordering::Equal if cursor.path.len() == node.path.len() => return row,
+                Ordering::Equal => {}
+                Ordering::Greater => return row + node.rows(),
+            }
+
+            let hidden_above = node.visible_lines.as_ref().is_some_and(|r| r.start > 0);
+            row += 1 + usize::from(hidden_above);
+
+            // Children above the cursor path count fully, and those below it do not count.
+            let mut next = None;
+            for child in node.visible_children() {
+                match order(child) {
+                    Ordering::Less => break,
+                    Ordering::Equal => {
+                        next = Some(child);
+                        break;
+                    }
+                    Ordering::Greater => row += child.rows(),
+                }
+            }
+            let Some(child) = next else {
+                return row;
+            };
+            node = child;
+        }
+    }
+
+    fn check_cursor(&self, cursor: &Cursor) -> orfail::Result<()> {
+        cursor.path.starts_with(&self.path).or_fail_with(|()| {
+            format!(
+                "invalid cursor: path={:?}, cursor={:?}",
+                self.path, cursor.path
+            )
+        })?;
+        Ok(())
+    }
+
+    fn can_alter<T>(&self, cursor: &Cursor, content: &T) -> orfail::Result<bool>
+    where
+        T: DiffTreeNodeContent,
+    {
+        self.check_cursor(cursor).or_fail()?;
+
+        if let Some(i) = cursor.path.get(self.path.len()) {
+            let child_node = self.children.get(i).or_fail()?;
+            if child_node.staged_context.is_some() {
+                return Ok(false);
+            }
+            let child_content = content.children().get(child_node.content_index).or_fail()?;
+            child_node.can_alter(cursor, child_content).or_fail()
+        } else {
+            Ok(content.can_alter())
+        }
+    }
+
+    fn is_valid_cursor(&self, cursor: &Cursor) -> bool {
+        self.get_node(cursor).is_ok()
+    }
+
+    fn toggle(&mut self, cursor: &Cursor) -> orfail::Result<()> {
+        let node = self.get_node_mut(cursor).or_fail()?;
+        node.expanded = !node.expanded;
+        Ok(())
+    }
+
+    fn get_node(&self, cursor: &Cursor) -> orfail::Result<&Self> {
+        let mut node = self;
+        while let Some((_, child)) = node.get_maybe_child(cursor).or_fail()? {
+            node = child;
+        }
+        Ok(node)
+    }
+
+    fn get_node_mut(&mut self, cursor: &Cursor) -> orfail::Result<&mut Self> {
+        let mut node = self;
+        loop {
+            cursor.path.starts_with(&node.path).or_fail()?;
+            let Some(i) = cursor.path.get(node.path.len()) else {
+                return Ok(node);
+            };
+            node = node.children.get_mut(i).or_fail()?;
+        }
+    }
+
+    fn get_maybe_child(&self, cursor: &Cursor) -> orfail::Result<Option<(usize, &Self)>> {
+        cursor.path.starts_with(&self.path).or_fail()?;
+
+        if let Some(i) = cursor.path.get(self.path.len()) {
+            let child = self.children.get(i).or_fail()?;
+            Ok(Some((i, child)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_diff(&self, cursor: &Cursor, diff: &Diff, reverse: bool) -> orfail::Result<Diff> {
+        let Some((i, node)) = self.get_maybe_child(cursor).or_fail()? else {
+            return Ok(diff.clone());
+        };
+        let file = diff.files.get(i).or_fail()?;
+
+        let Some((_, node)) = node.get_maybe_child(cursor).or_fail()? else {
+            return Ok(file.to_diff());
+        };
+        node.staged_context.is_none().or_fail()?;
+        let chunk = file.chunks().get(node.content_index).or_fail()?;
+
+        let Some((_, node)) = node.get_maybe_child(cursor).or_fail()? else {
+            return Ok(file.chunk_diff(chunk, reverse));
+        };
+
+        let line_chunk = chunk
+            .get_line_chunk(node.content_index, reverse)
+            .or_fail()?;
+        Ok(file.chunk_diff(&line_chunk, reverse))
+    }
+
+    fn cursor_right(&self, cursor: &Cursor) -> Option<Cursor> {
+        let mut cursor = cursor.clone();
+
+        while cursor.path.len() >= self.path.len() {
+            let child_cursor = cursor.first_child();
+            if self.is_valid_cursor(&child_cursor) {
+                return Some(child_cursor);
+            }
+
+            let sibling_cursor = cursor.next_sibling();
+            if self.is_valid_cursor(&sibling_cursor) {
+                cursor = sibling_cursor;
+            } else {
+                break;
+            }
+        }
+
+        None
+    }
+
+    fn cursor_down(&self, cursor: &Cursor) -> Option<Cursor> {
+        let sibling_cursor = cursor.next_sibling();
+        if self.is_valid_cursor(&sibling_cursor) {
+            return Some(sibling_cursor);
+        }
+
+        let mut base_cursor = cursor.clone();
+        loop {
+            base_cursor = base_cursor.parent()?;
+
+            let mut next_cursor = base_cursor.next_sibling();
+            while next_cursor.path.len() < cursor.path.len() {
+                next_cursor = next_cursor.first_child();
+            }
+
+            if self.is_valid_cursor(&next_cursor) {
+                return Some(next_cursor);
+            }
+        }
+    }
+
+    fn cursor_up(&self, cursor: &Cursor) -> Option<Cursor> {
+        if let Some(sibling_cursor) = cursor.prev_sibling() {
+            return Some(sibling_cursor);
+        }
+
+        let mut base_cursor = cursor.clone();
+        loop {
+            base_cursor = base_cursor.parent()?;
+
+            let Some(mut next_cursor) = base_cursor.prev_sibling() else {
+                continue;
+            };
+            while next_cursor.path.len() < cursor.path.len() {
+                let index = self
+                    .get_node(&next_cursor)
+                    .ok()
+                    .map(|n| n.children.len().saturating_sub(1))
+                    .unwrap_or_default();
+                next_cursor = next_cursor.join(index);
+            }
+            if self.is_valid_cursor(&next_cursor) {
+                return Some(next_cursor);
+            }
+        }
+    }
+}
+
+pub trait DiffTreeNodeContent {
+    type Child: DiffTreeNodeContent;
+
+    fn head_line_tokens(&self, ctx: &RenderContext) -> impl Iterator<Item = Token>;
+    fn can_alter(&self) -> bool;
+    fn children(&self) -> &[Self::Child];
+
+    // Additional tokens drawn after the head line while the node is collapsed.
+    fn collapsed_summary_tokens(&self) -> impl Iterator<Item = Token> {
+        std::iter::empty()
+    }
+}
+
+impl DiffTreeNodeContent for PhasedDiff {
+    type Child = FileDiff;
+
+    fn head_line_tokens(&self, ctx: &RenderContext) -> impl Iterator<Item = Token> {
+        let note = match (self.phase, ctx.staged_diff_base) {
+            (DiffPhase::Unstaged, _) if ctx.include_ignored => ", including ignored".to_owned(),
+            (DiffPhase::Staged, _) if ctx.staged_against_worktree => {
+                ", index vs worktree".to_owned()
+            }
+            (DiffPhase::Staged, Some(base)) => format!(", against {base}"),
+            _ => String::new(),
+        };
+        let gone = if ctx.mark_reload_changes && self.gone_files > 0 {
+            format!(", {} gone", self.gone_files)
+        } else {
+            String::new()
+        };
+        let order = if ctx.file_order == FileOrder::Git {
+            String::new()
+        } else {
+            format!(", sorted by {}", ctx.file_order.label())
+        };
+        let label = match (self.phase, ctx.shown_rev) {
+            (DiffPhase::Unstaged, Some(rev)) => format!("Changes in {rev}"),
+            (phase, _) => format!("{phase:?} changes"),
+        };
+        std::iter::once(Token::with_style(
+            format!(
+                "{label} ({} files{note}{gone}{order})",
+                self.diff.files.len()
+            ),
+            TerminalStyle::new()
+                .bold()
+                .fg_color(ctx.header_colors[self.phase as usize]),
+        ))
+    }
+
+    fn can_alter(&self) -> bool {
+        !self.diff.files.is_empty()
+    }
+
+    fn children(&self) -> &[Self::Child] {
+        &self.diff.files
+    }
+}
+
+impl DiffTreeNodeContent for FileDiff {
+    type Child = ChunkDiff;
+
+    fn head_line_tokens(&self, ctx: &RenderContext) -> impl Iterator<Item = Token> {
+        let path = Token::with_style(
+            ctx.display_path(self.path()),
+            TerminalStyle::new().underline(),
+        );
+        // The only line of a symlink is its target, which is clearer in the head line.
+        if let Some(targets) = self.symlink_targets() {
+            let (kind, targets) = match targets {
+                (None, Some(new)) => ("added", format!(" -> {new}")),
+                (Some(old), None) => ("deleted", format!(" -> {old}")),
+                (old, new) => (
+                    "modified",
+                    format!(
+                        ": {} -> {}",
+                        old.unwrap_or_default(),
+                        new.unwrap_or_default()
+                    ),
+                ),
+            };
+            let tokens = vec![
+                Token::new(format!("{kind} symlink ")),
+                path,
+                Token::new(targets),
+            ];
+            return tokens.into_iter();
+        }
+        let tokens = match self {
+            FileDiff::Update {
+                old_mode, new_mode, ..
+            } => {
+                let mode = if let Some(old_mode) = old_mode {
+                    format!(
+                        ", {} -> {} mode",
+                        old_mode.format(ctx.mode_labels),
+                        new_mode.format(ctx.mode_labels)
+                    )
+                } else {
+                    "".to_string()
+                };
+                vec![
+                    Token::new("modified "),
+                    path,
+                    Token::new(format!(
+                        " ({} chunks, -{} +{} lines{})",
+                        self.children().len(),
+                        self.removed_lines(),
+                        self.added_lines(),
+                        mode
+                    )),
+                ]
+            }
+            FileDiff::New { .. } if ctx.untracked_groups.contains_key(self.path()) => {
+                let n = ctx.untracked_groups[self.path()];
+                vec![Token::new(format!("{n} untracked files in ")), path]
+            }
+            FileDiff::New { content, .. } => {
+                vec![
+                    Token::new("added "),
+                    path,
+                    if matches!(content, ContentDiff::Binary) {
+                        Token::new(" (binary)")
+                    } else {
+                        Token::new(format!(" (+{} lines)", self.added_lines()))
+                    },
+                ]
+            }
+            FileDiff::Rename {
+                old_path, content, ..
+            } => {
+                let old_path =
+                    Token::with_style(ctx.display_path(old_path), TerminalStyle::new().underline());
+
+                let summary = if content.is_some() {
+                    Token::new(format!(
+                        " ({} chunks, -{} +{} lines)",
+                        self.children().len(),
+                        self.removed_lines(),
+                        self.added_lines(),
+                    ))
+                } else {
+                    Token::new("")
+                };
+
+                vec![
+                    Token::new("renamed "),
+                    old_path,
+                    Token::new(" -> "),
+                    path,
+                    summary,
+                ]
+            }
+            FileDiff::Delete { content, .. } => {
+                vec![
+                    Token::new("deleted "),
+                    path,
+                    if matches!(content, ContentDiff::Binary) {
+                        Token::new(" (binary)")
+                    } else {
+                        Token::new(format!(" (-{} lines)", self.removed_lines()))
+                    },
+                ]
+            }
+            FileDiff::Chmod {
+                old_mode, new_mode, ..
+            } => {
+                vec![
+                    Token::new("mode changed "),
+                    path,
+                    Token::new(format!(
+                        " {} -> {}",
+                        old_mode.format(ctx.mode_labels),
+                        new_mode.format(ctx.mode_labels)
+                    )),
+                ]
+            }
+        };
+        tokens.into_iter()
+    }
+
+    fn can_alter(&self) -> bool {
+        true
+    }
+
+    fn children(&self) -> &[Self::Child] {
+        self.chunks()
+    }
+
+    fn collapsed_summary_tokens(&self) -> impl Iterator<Item = Token> {
+        let (added, removed) = change_bar(self.added_lines(), self.removed_lines());
+        let style = TerminalStyle::new();
+        [
+            Token::new(if added + removed > 0 { " " } else { "" }),
+            Token::with_style("+".repeat(added), style.fg_color(TerminalColor::GREEN)),
+            Token::with_style("-".repeat(removed), style.fg_color(TerminalColor::RED)),
+            Token::new(if added + removed > 0 { " " } else { "" }),
+        ]
+        .into_iter()
+        .filter(|t| !t.text().is_empty())
+    }
+}
+
+// Returns the numbers of `+` and `-` marks in the change bar of a file.
+// Large changes are scaled down to fit in a fixed width.
+fn change_bar(added_lines: usize, removed_lines: usize) -> (usize, usize) {
+    const MAX_WIDTH: usize = 10;
+
+    let total = added_lines + removed_lines;
+    if total <= MAX_WIDTH {
+        return (added_lines, removed_lines);
+    }
+    let added = (added_lines * MAX_WIDTH + total / 2) / total;
+
+    // Keep at least one mark for each non-zero count.
+    let added = added.clamp(
+        usize::from(added_lines > 0),
+        MAX_WIDTH - usize::from(removed_lines > 0),
+    );
+    (added, MAX_WIDTH - added)
+}
+
+impl DiffTreeNodeContent for ChunkDiff {
+    type Child = LineDiff;
+
+    fn head_line_tokens(&self, _ctx: &RenderContext) -> impl Iterator<Item = Token> {
+        std::iter::once(Token::new(self.head_line()))
+    }
+
+    fn can_alter(&self) -> bool {
+        true
+    }
+
+    fn children(&self) -> &[Self::Child] {
+        &self.lines
+    }
+}
+
+// A staged chunk displayed as context within the corresponding unstaged file.
+struct StagedChunk<'a>(&'a ChunkDiff);
+
+impl DiffTreeNodeContent for StagedChunk<'_> {
+    type Child = LineDiff;
+
+    fn head_line_tokens(&self, _ctx: &RenderContext) -> impl Iterator<Item = Token> {
+        std::iter::once(Token::new(format!("(staged) {}", self.0.head_line())))
+    }
+
+    fn can_alter(&self) -> bool {
+        false
+    }
+
+    fn children(&self) -> &[Self::Child] {
+        &self.0.lines
+    }
+}
+
+impl DiffTreeNodeContent for LineDiff {
+    type Child = Self;
+
+    fn head_line_tokens(&self, _ctx: &RenderContext) -> impl Iterator<Item = Token> {
+        let style = TerminalStyle::new();
+        let style = match self {
+            // Highlighted so that a half-resolved conflict is not staged by accident.
+            _ if self.is_conflict_marker() => style
+                .bold()
+                .fg_color(TerminalColor::BRIGHT_WHITE)
+                .bg_color(TerminalColor::BRIGHT_RED),
+            LineDiff::Old(_) => style.dim(),
+            LineDiff::New(_) => style.bold(),
+            LineDiff::Both(_) => style,
+            LineDiff::NoNewlineAtEndOfFile => style,
+        };
+
+        // The marker is a separate token so that it always occupies the first column of the content.
+        let (marker, content) = match self {
+            LineDiff::Old(s) => ("-", s.as_str()),
+            LineDiff::New(s) => ("+", s.as_str()),
+            LineDiff::Both(s) => (" ", s.as_str()),
+            LineDiff::NoNewlineAtEndOfFile => ("\\", " No newline at end of file"),
+        };
+        [
+            Token::with_style(marker, style),
+            Token::with_style(content, style),
+        ]
+        .into_iter()
+    }
+
+    // The "\ No newline at end of file" marker belongs to the preceding line.
+    fn can_alter(&self) -> bool {
+        matches!(self, Self::Old(_) | Self::New(_))
+    }
+
+    fn children(&self) -> &[Self::Child] {
+        &[]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NodePath(Vec<usize>);
+
+impl NodePath {
+    fn root() -> Self {
+        Self(vec![0])
+    }
+
+    fn join(&self, index: usize) -> Self {
+        let mut child = self.clone();
+        child.0.push(index);
+        child
+    }
+
+    fn starts_with(&self, other: &Self) -> bool {
+        self.0.starts_with(&other.0)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn last(&self) -> usize {
+        self.0.last().copied().expect("infallible")
+    }
+
+    fn get(&self, i: usize) -> Option<usize> {
+        self.0.get(i).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cursor {
+    path: NodePath,
+}
+
+impl Cursor {
+    fn root() -> Self {
+        Self {
+            path: NodePath::root().join(0),
+        }
+    }
+
+    fn join(&self, index: usize) -> Self {
+        Self {
+            path: self.path.join(index),
+        }
+    }
+
+    fn parent(&self) -> Option<Self> {
+        (self.path.len() > 2).then(|| {
+            let mut path = self.path.clone();
+            path.0.pop();
+            Self { path }
+        })
+    }
+
+    fn first_child(&self) -> Self {
+        let path = self.path.join(0);
+        Self { path }
+    }
+
+    fn next_sibling(&self) -> Self {
+        let mut path = self.path.clone();
+        *path.0.last_mut().expect("infallible") += 1;
+        Self { path }
+    }
+
+    fn prev_sibling(&self) -> Option<Self> {
+        let mut path = self.path.clone();
+        if path.0.last().copied() == Some(0) {
+            return None;
+        }
+        *path.0.last_mut().expect("infallible") -= 1;
+        Some(Self { path })
+    }
+
+    // The cursor is dimmed if the selected node cannot be staged, discarded or unstaged.
+    fn render(&self, canvas: &mut Canvas, path: &NodePath, alterable: bool) {
+        let mut text = String::with_capacity(path.len() * 2);
+        let selected = *path == self.path;
+
+        if selected {
+            text.push('-');
+        } else {
+            text.push(' ');
+        }
+
+        for i in 2..path.len() {
+            if i == self.path.len() && path.starts_with(&self.path) {
+                text.push_str(" :")
+            } else if selected {
+                text.push_str("--")
+            } else {
+                text.push_str("  ")
+            }
+        }
+
+        if selected {
+            text.push_str(">| ");
+        } else if path.len() == self.path.len() {
+            text.push_str(" | ");
+        } else {
+            text.push_str("   ");
+        }
+
+        if selected && !alterable {
+            canvas.draw(Token::with_style(text, TerminalStyle::new().dim()));
+        } else {
+            canvas.draw(Token::new(text));
+        }
+    }
+}
+
+// Operations that modify the index or the working tree with `git apply`.
+#[derive(Debug, Clone, Copy)]
+enum Operation {
+    Stage,
+    Unstage,
+    Discard,
+}
+
+impl Operation {
+    fn dry_run_title(self) -> &'static str {
+        match self {
+            Self::Stage => "dry run: would stage",
+            Self::Unstage => "dry run: would unstage",
+            Self::Discard => "dry run: would discard",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffPhase {
+    Unstaged,
+    Staged,
+}
+
+#[derive(Debug)]
+pub struct RenderContext<'a> {
+    cursor: &'a Cursor,
+    staged_diff: &'a Diff,
+    path_style: PathStyle,
+    cwd_prefix: &'a Path,
+    duplicate_basenames: HashSet<&'a OsStr>,
+    show_child_counts: bool,
+    mode_labels: bool,
+    include_ignored: bool,
+    untracked_groups: &'a BTreeMap<PathBuf, usize>,
+    staged_against_worktree: bool,
+    staged_diff_base: Option<&'a str>,
+    shown_rev: Option<&'a str>,
+    author_mismatches: &'a HashSet<(usize, usize)>,
+    elide_path_components: usize,
+    charset: Charset,
+    mark_reload_changes: bool,
+    file_order: FileOrder,
+    now: SystemTime,
+    mtimes: [&'a [Option<SystemTime>]; 2],
+    binary_sizes: [&'a [Option<u64>]; 2],
+    header_colors: [TerminalColor; 2],
+    reload_changes: [&'a [Option<ReloadChange>]; 2],
+}
+
+impl RenderContext<'_> {
+    fn reload_change(&self, path: &NodePath) -> Option<ReloadChange> {
+        if !self.mark_reload_changes || path.len() != 3 {
+            return None;
+        }
+        let changes = self.reload_changes.get(path.get(1)?)?;
+        changes.get(path.get(2)?).copied().flatten()
+    }
+
+    // Returns whether the node is (in) a staged chunk that is dimmed by the author filter.
+    fn is_author_mismatch(&self, path: &NodePath) -> bool {
+        path.len() >= 4
+            && path.get(1) == Some(1)
+            && path
+                .get(2)
+                .zip(path.get(3))
+                .is_some_and(|key| self.author_mismatches.contains(&key))
+    }
+
+    fn mtime(&self, path: &NodePath) -> Option<SystemTime> {
+        if path.len() != 3 {
+            return None;
+        }
+        let mtimes = self.mtimes.get(path.get(1)?)?;
+        mtimes.get(path.get(2)?).copied().flatten()
+    }
+
+    fn binary_size(&self, path: &NodePath) -> Option<u64> {
+        if path.len() != 3 {
+            return None;
+        }
+        let sizes = self.binary_sizes.get(path.get(1)?)?;
+        sizes.get(path.get(2)?).copied().flatten()
+    }
+
+    fn display_path(&self, path: &Path) -> String {
+        match self.path_style {
+            PathStyle::Root => {
+                elide_leading_components(path, self.elide_path_components, self.charset)
+            }
+            PathStyle::Cwd => elide_leading_components(
+                &relative_path(path, self.cwd_prefix),
+                self.elide_path_components,
+                self.charset,
+            ),
+            PathStyle::Basename => match (path.file_name(), path.parent()) {
+                (Some(name), Some(parent)) if self.duplicate_basenames.contains(name) => {
+                    format!("{} ({})", name.display(), parent.display())
+                }
+                (Some(name), _) => name.display().to_string(),
+                (None, _) => path.display().to_string(),
+            },
+        }
+    }
+}
+
+// Replaces up to `n` leading components with an ellipsis, always keeping the file name.
+fn elide_leading_components(path: &Path, n: usize, charset: Charset) -> String {
+    let n = n.min(path.components().count().saturating_sub(1));
+    if n == 0 {
+        return path.display().to_string();
+    }
+    let rest = path.components().skip(n).collect::<PathBuf>();
+    format!("{}/{}", charset.ellipsis(), rest.display())
+}
+
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+// A file in the combined view with the indices of its unstaged and staged diffs.
+#[derive(Debug)]
+struct CombinedFile<'a> {
+    path: &'a PathBuf,
+    unstaged: Option<usize>,
+    staged: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct ChunkPosition {
+    path: PathBuf,
+    file_index: usize,
+    chunk_index: usize,
+    // Number of the chunks in the file.
+    chunks: usize,
+    // Whether the cursor was on a line of the chunk rather than the chunk itself.
+    line: bool,
+    // Line following the cursor line, identified by its position in the new file
+    // (which staging does not change) and its content.
+    next_line: Option<(usize, LineDiff)>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum PathStyle {
+    // Relative to the repository root.
+    #[default]
+    Root,
+
+    // Relative to the directory where mamediff was started.
+    Cwd,
+
+    // File name only (with its directory if another file has the same name).
+    Basename,
+}
+
+impl PathStyle {
+    fn next(self) -> Self {
+        match self {
+            Self::Root => Self::Cwd,
+            Self::Cwd => Self::Basename,
+            Self::Basename => Self::Root,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PhasedDiff {
+    phase: DiffPhase,
+    diff: Diff,
+    // Changes of each file since the previous reload (only tracked if enabled).
+    reload_changes: Vec<Option<ReloadChange>>,
+    // Number of files that have gone since the previous reload.
+    gone_files: usize,
+    // Modification times of the files in the working tree (only read if enabled).
+    mtimes: Vec<Option<SystemTime>>,
+    // Sizes of the binary files in bytes (only read if enabled).
+    binary_sizes: Vec<Option<u64>>,
+    // Position of each file in the output of `git` (used to restore the original order).
+    git_indices: Vec<usize>,
+}
+
+impl PhasedDiff {
+    fn new(phase: DiffPhase) -> Self {
+        Self {
+            phase,
+            diff: Diff::default(),
+            reload_changes: Vec::new(),
+            gone_files: 0,
+            mtimes: Vec::new(),
+            binary_sizes: Vec::new(),
+            git_indices: Vec::new(),
+        }
+    }
+
+    // The per-file states derived from the previous diff are reset, as they no longer match.
+    fn set_diff(&mut self, diff: Diff, order: FileOrder) {
+        self.git_indices = (0..diff.files.len()).collect();
+        self.diff = diff;
+        self.reload_changes.clear();
+        self.mtimes.clear();
+        self.binary_sizes.clear();
+        self.sort_files(order);
+    }
+
+    // Sorts the files and returns their previous indices in the new order.
+    // Ties are broken by the order in the output of `git`.
+    fn sort_files(&mut self, order: FileOrder) -> Vec<usize> {
+        let files = &self.diff.files;
+        let git_indices = &self.git_indices;
+        let mut indices = (0..files.len()).collect::<Vec<_>>();
+        match order {
+            FileOrder::Git => indices.sort_by_key(|&i| git_indices[i]),
+            FileOrder::Path => indices.sort_by_key(|&i| (files[i].path(), git_indices[i])),
+            FileOrder::Size => indices.sort_by_key(|&i| {
+                let lines = files[i].added_lines() + files[i].removed_lines();
+                (Reverse(lines), git_indices[i])
+            }),
+            FileOrder::Status => indices.sort_by_key(|&i| {
+                let rank = match files[i] {
+                    FileDiff::New { .. } => 0,
+                    FileDiff::Update { .. } | FileDiff::Chmod { .. } => 1,
+                    FileDiff::Delete { .. } => 2,
+                    FileDiff::Rename { .. } => 3,
+                };
+                (rank, git_indices[i])
+            }),
+        }
+
+        permute(&mut self.diff.files, &indices);
+        permute(&mut self.git_indices, &indices);
+        permute(&mut self.reload_changes, &indices);
+        permute(&mut self.mtimes, &indices);
+        permute(&mut self.binary_sizes, &indices);
+        indices
+    }
+
+    // Deleted files have no modification time.
+    fn read_mtimes(&mut self) {
+        self.mtimes = self
+            .diff
+            .files
+            .iter()
+            .map(|f| std::fs::metadata(f.path()).and_then(|m| m.modified()).ok())
+            .collect();
+    }
+
+    // The size of a file is read from the working tree if it is there in this phase;
+    // otherwise (e.g., deleted or staged), the size of the blob is read via `git`.
+    // The blob sizes are read together so that a reload runs `git` once for them.
+    fn read_binary_sizes(&mut self) {
+        let head = git::staged_diff_base().unwrap_or("HEAD");
+        let mut blobs = Vec::new();
+        self.binary_sizes = self
+            .diff
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                if !f.is_binary() {
+                    return None;
+                }
+                let deleted = matches!(f, FileDiff::Delete { .. });
+                match (self.phase, deleted) {
+                    (DiffPhase::Unstaged, false) => {
+                        return std::fs::metadata(f.path()).ok().map(|m| m.len());
+                    }
+                    (DiffPhase::Unstaged, true) | (DiffPhase::Staged, false) => {
+                        blobs.push((i, "", f.path().as_path()));
+                    }
+                    (DiffPhase::Staged, true) => blobs.push((i, head, f.path().as_path())),
+                }
+                None
+            })
+            .collect();
+        let objects = blobs
+            .iter()
+            .map(|(_, rev, path)| (*rev, *path))
+            .collect::<Vec<_>>();
+        for ((i, _, _), size) in blobs.iter().zip(git::blob_sizes(&objects)) {
+            self.binary_sizes[*i] = size;
+        }
+    }
+
+    fn mark_reload_changes(&mut self, old: &Diff) {
+        let old_files = old
+            .files
+            .iter()
+            .map(|f| (f.path(), f))
+            .collect::<HashMap<_, _>>();
+        self.reload_changes = self
+            .diff
+            .files
+            .iter()
+            .map(|file| match old_files.get(file.path()) {
+                None => Some(ReloadChange::New),
+                Some(old_file) if *old_file != file => Some(ReloadChange::Modified),
+                Some(_) => None,
+            })
+            .collect();
+
+        let paths = self
+            .diff
+            .files
+            .iter()
+            .map(|f| f.path())
+            .collect::<HashSet<_>>();
+        self.gone_files = old_files.keys().filter(|p| !paths.contains(*p)).count();
+    }
+}
+
+// Reorders `items` so that the i-th item becomes the `indices[i]`-th one of the original.
+// Empty (i.e., disabled) per-file states are left as they are.
+fn permute<T>(items: &mut Vec<T>, indices: &[usize]) {
+    if items.len() != indices.len() {
+        return;
+    }
+    let mut old = std::mem::take(items)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+    *items = indices.iter().filter_map(|&i| old[i].take()).collect();
+}
+
+// Order of the files within each phase.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FileOrder {
+    // As output by `git` (untracked files first, then the others by path).
+    #[default]
+    Git,
+    Path,
+    // Files with more added and removed lines first.
+    Size,
+    // New, modified, deleted and then renamed files.
+    Status,
+}
+
+impl FileOrder {
+    fn next(self) -> Self {
+        match self {
+            Self::Git => Self::Path,
+            Self::Path => Self::Size,
+            Self::Size => Self::Status,
+            Self::Status => Self::Git,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Git => "git order",
+            Self::Path => "path",
+            Self::Size => "change size",
+            Self::Status => "status",
+        }
+    }
+}
+
+// How the files are expanded on startup.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StartupExpand {
+    // Expands as many nodes as fit in the terminal.
+    #[default]
+    Fill,
+    FirstFile,
+}
+
+// Matches a path against a glob where `*` and `?` do not match `/` but `**` does.
+// A glob without `/` is matched against the file name only (e.g., `*.rs` matches `src/main.rs`).
+fn glob_matches_path(glob: &str, path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    let text = if glob.contains('/') {
+        &path[..]
+    } else {
+        path.rsplit('/').next().unwrap_or_default()
+    };
+    glob_matches(glob.as_bytes(), text.as_bytes())
+}
+
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    match glob {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_matches(rest, &text[i..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_matches(rest, &text[i..])),
+        [b'?', rest @ ..] => text
+            .split_first()
+            .is_some_and(|(&c, text)| c != b'/' && glob_matches(rest, text)),
+        [c, rest @ ..] => text
+            .split_first()
+            .is_some_and(|(t, text)| t == c && glob_matches(rest, text)),
+    }
+}
+
+// Formats the elapsed time roughly in the largest unit (e.g., "2m ago").
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+// Formats the size in the largest binary unit in which it is at least one (e.g., "2.0 KiB").
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReloadChange {
+    New,
+    Modified,
+}
+
+impl ReloadChange {
+    fn label(self) -> &'static str {
+        match self {
+            Self::New => " [new]",
+            Self::Modified => " [modified]",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::action::Action;
+
+    const UNSTAGED_DIFF: &str = r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,3 +1,3 @@
+ a
+-b
++B
+ c
+diff --git a/bar b/bar
+index e3bdb24..dd04db5 100644
+--- a/bar
++++ b/bar
+@@ -1,2 +1,2 @@
+-x
++X
+ y"#;
+
+    const STAGED_DIFF: &str = r#"diff --git a/baz b/baz
+index e3bdb24..dd04db5 100644
+--- a/baz
++++ b/baz
+@@ -1,2 +1,2 @@
+ p
+-q
++Q"#;
+
+    fn tree() -> orfail::Result<DiffTreeWidget> {
+        let unstaged_diff = Diff::from_str(UNSTAGED_DIFF).or_fail()?;
+        let staged_diff = Diff::from_str(STAGED_DIFF).or_fail()?;
+        DiffTreeWidget::with_diffs(unstaged_diff, staged_diff).or_fail()
+    }
+
+    fn cursor(path: &[usize]) -> Cursor {
+        Cursor {
+            path: NodePath(path.to_vec()),
+        }
+    }
+
+    // Returns the diff text of a modified file consisting of the given chunks.
+    fn file_diff(path: &str, chunks: &str) -> String {
+        format!(
+            "diff --git a/{path} b/{path}\nindex e3bdb24..dd04db5 100644\n--- a/{path}\n+++ b/{path}\n{chunks}"
+        )
+    }
+
+    // Returns a diff of the given files, each with one-line chunks at the given line numbers.
+    fn chunks_diff(files: &[(&str, &[usize])]) -> orfail::Result<Diff> {
+        let text = files
+            .iter()
+            .map(|(path, lines)| {
+                let chunks = lines
+                    .iter()
+                    .map(|i| format!("@@ -{i} +{i} @@\n-x{i}\n+X{i}\n"))
+                    .collect::<String>();
+                file_diff(path, &chunks)
+            })
+            .collect::<String>();
+        Diff::from_str(&text).or_fail()
+    }
+
+    fn render(tree: &DiffTreeWidget, frame_row_offset: usize, size: TerminalSize) -> Vec<String> {
+        let mut canvas = Canvas::new(frame_row_offset, size);
+        tree.render(&mut canvas);
+        canvas.frame_lines().iter().map(|l| l.text()).collect()
+    }
+
+    #[test]
+    fn render_snapshot() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+
+        let size = TerminalSize::rows_cols(6, 40);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (2 files)",
+                "--->| modified foo (1 chunks, -1 +1 line",
+                "    :   @@ -1,3 +1,3 @@",
+                "    :      a",
+                "    :     -b",
+                "    :     +B",
+            ]
+        );
+
+        // Rows above the frame are skipped.
+        assert_eq!(
+            render(&tree, 3, size),
+            [
+                "    :      a",
+                "    :     -b",
+                "    :     +B",
+                "    :      c",
+                "    | modified bar (1 chunks, -1 +1 line",
+                "    Staged changes (1 files)",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wrap_navigation() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 1, 0]);
+        assert!(!tree.can_cursor_down());
+        assert!(!tree.cursor_down().or_fail()?);
+
+        tree.wrap_navigation = true;
+        assert!(tree.can_cursor_down());
+        assert!(tree.cursor_down().or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+        assert_eq!(tree.cursor_row(), 1);
+
+        assert!(tree.cursor_up().or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 1, 0]));
+        Ok(())
+    }
+
+    #[test]
+    fn compact() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        tree.toggle_compact().or_fail()?;
+
+        // Context lines are hidden, and the cursor stays on the same line.
+        let chunk_node = tree.root_node.get_node(&cursor(&[0, 0, 0, 0])).or_fail()?;
+        let line_indices = chunk_node
+            .children
+            .iter()
+            .map(|c| c.content_index)
+            .collect::<Vec<_>>();
+        assert_eq!(line_indices, [1, 2]);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 0]));
+
+        // Line staging still refers to the line in the full chunk.
+        let diff = tree.root_node.children[0]
+            .get_diff(&tree.cursor, &tree.unstaged_diff.diff, false)
+            .or_fail()?;
+        assert_eq!(
+            diff.files[0].chunks()[0].to_string(),
+            "@@ -1,3 +1,2 @@\n a\n-b\n c\n"
+        );
+
+        tree.toggle_compact().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 1]));
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_context() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        let line_indices = |tree: &DiffTreeWidget, chunk: &[usize]| {
+            let chunk_node = tree.root_node.get_node(&cursor(chunk)).or_fail()?;
+            let indices = chunk_node.children.iter().map(|c| c.content_index);
+            Ok::<_, orfail::Failure>(indices.collect::<Vec<_>>())
+        };
+
+        // Only the chunk under the cursor is affected.
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        tree.toggle_chunk_context().or_fail()?;
+        assert_eq!(line_indices(&tree, &[0, 0, 0, 0]).or_fail()?, [1, 2]);
+        assert_eq!(line_indices(&tree, &[0, 0, 1, 0]).or_fail()?, [0, 1, 2]);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 0]));
+
+        // The override is kept when the global mode changes.
+        tree.toggle_compact().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1, 0]);
+        tree.toggle_chunk_context().or_fail()?;
+        assert_eq!(line_indices(&tree, &[0, 0, 0, 0]).or_fail()?, [1, 2]);
+        assert_eq!(line_indices(&tree, &[0, 0, 1, 0]).or_fail()?, [0, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_context_lines() -> orfail::Result<()> {
+        let context = (0..50).map(|i| format!(" c{i}\n")).collect::<String>();
+        let unstaged_diff = Diff::from_str(&format!(
+            r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,52 +1,52 @@
+-a
++A
+{context}-b
++B"#
+        ))
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.set_fold_context_lines(Some(10)).or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+
+        // The run of 50 context lines is folded except for 3 lines on each side.
+        let chunk_cursor = cursor(&[0, 0, 0, 0]);
+        let chunk_node = tree.root_node.get_node(&chunk_cursor).or_fail()?;
+        assert_eq!(chunk_node.children.len(), 11);
+        assert_eq!(chunk_node.rows(), 12);
+        let size = TerminalSize::rows_cols(16, 40);
+        assert_eq!(
+            render(&tree, 0, size)[5..14],
+            [
+                "    :      c0",
+                "    :      c1",
+                "    :      c2",
+                "    :     … 44 unchanged lines …",
+                "    :      c47",
+                "    :      c48",
+                "    :      c49",
+                "    :     -b",
+                "    :     +B",
+            ]
+        );
+
+        // The placeholder is counted as a single row.
+        tree.cursor = cursor(&[0, 0, 0, 0, 9]);
+        assert_eq!(tree.cursor_row(), 12);
+
+        // Toggling the placeholder unfolds the lines.
+        tree.cursor = cursor(&[0, 0, 0, 0, 5]);
+        assert!(!tree.can_stage_or_discard());
+        assert_eq!(tree.cursor_line_text(true), None);
+        assert_eq!(tree.cursor_line_ref(), None);
+        tree.toggle().or_fail()?;
+        let chunk_node = tree.root_node.get_node(&chunk_cursor).or_fail()?;
+        assert_eq!(chunk_node.children.len(), 54);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 5]));
+        assert_eq!(chunk_node.children[5].content_index, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn staged_context() -> orfail::Result<()> {
+        let unstaged_diff = Diff::from_str(UNSTAGED_DIFF).or_fail()?;
+        let staged_diff = Diff::from_str(&format!(
+            r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,1 +1,1 @@
+-z
++a
+{STAGED_DIFF}"#
+        ))
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, staged_diff).or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+        tree.toggle_staged_context().or_fail()?;
+
+        // Staged chunks of the same file are interleaved by their line numbers.
+        let size = TerminalSize::rows_cols(9, 40);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (2 files)",
+                "--->| modified foo (1 chunks, -1 +1 line",
+                "    :   (staged) @@ -1,1 +1,1 @@",
+                "    :     -z",
+                "    :     +a",
+                "    :   @@ -1,3 +1,3 @@",
+                "    :      a",
+                "    :     -b",
+                "    :     +B",
+            ]
+        );
+
+        // Staged chunks cannot be staged or discarded again.
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        assert!(!tree.can_stage_or_discard());
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        assert!(!tree.can_stage_or_discard());
+        tree.cursor = cursor(&[0, 0, 0, 1]);
+        assert!(tree.can_stage_or_discard());
+        let diff = tree.root_node.children[0]
+            .get_diff(&tree.cursor, &tree.unstaged_diff.diff, false)
+            .or_fail()?;
+        assert_eq!(diff.files[0].chunks()[0].head_line(), "@@ -1,3 +1,3 @@");
+
+        // The cursor follows the unstaged chunk when the view is toggled back.
+        tree.toggle_staged_context().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0]));
+        tree.toggle_staged_context().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 1]));
+
+        // A staged chunk under the cursor falls back to its file.
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        tree.toggle_staged_context().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_file() -> orfail::Result<()> {
+        let mut tree = DiffTreeWidget::with_diffs(
+            chunks_diff(&[("foo", &[10, 20])])?,
+            chunks_diff(&[("foo", &[10, 40])])?,
+        )
+        .or_fail()?;
+        tree.cursor = cursor(&[0, 0]);
+        assert!(!tree.can_toggle_reconcile_file());
+
+        // Only the overlapping chunks of both phases are shown within the unstaged file.
+        tree.cursor = cursor(&[0, 1, 0]);
+        assert!(tree.can_toggle_reconcile_file());
+        tree.toggle_reconcile_file().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+        let size = TerminalSize::rows_cols(8, 40);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (1 files)",
+                "--->| modified foo (2 chunks, -2 +2 line",
+                "    :   (staged) @@ -10,1 +10,1 @@",
+                "    :     -x10",
+                "    :     +X10",
+                "    :   @@ -10,1 +10,1 @@",
+                "    :     -x10",
+                "    :     +X10",
+            ]
+        );
+
+        // The unstaged part can be staged from there.
+        tree.cursor = cursor(&[0, 0, 0, 1]);
+        assert!(tree.can_stage_or_discard());
+
+        // But not the whole file, whose other chunks are hidden.
+        tree.cursor = cursor(&[0, 0, 0]);
+        assert!(!tree.can_stage_or_discard());
+        assert!(!tree.can_stage_file_hunks());
+        assert!(!tree.stage().or_fail()?);
+        assert!(!tree.discard().or_fail()?);
+
+        // The view ends when nothing is left to reconcile.
+        tree.update_diffs(
+            chunks_diff(&[("foo", &[20])])?,
+            chunks_diff(&[("foo", &[10, 40])])?,
+        )
+        .or_fail()?;
+        assert_eq!(tree.reconciled_file, None);
+        tree.cursor = cursor(&[0, 0, 0]);
+        assert!(!tree.can_toggle_reconcile_file());
+        Ok(())
+    }
+
+    #[test]
+    fn glob_filter() -> orfail::Result<()> {
+        let matches = |glob, path| glob_matches_path(glob, Path::new(path));
+        assert!(matches("*.rs", "src/main.rs"));
+        assert!(!matches("*.rs", "src/main.rsx"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(matches("src/**.rs", "src/bin/main.rs"));
+        assert!(matches("ma?n.*", "src/main.rs"));
+        assert!(!matches("src?main.rs", "src/main.rs"));
+
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1]);
+        tree.set_glob_filter(Some("ba?".to_owned())).or_fail()?;
+        let paths = |tree: &DiffTreeWidget| {
+            [&tree.unstaged_diff, &tree.staged_diff].map(|d| {
+                d.diff
+                    .files
+                    .iter()
+                    .map(|f| f.path().display().to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(paths(&tree), [vec!["bar"], vec!["baz"]]);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+
+        // The cursor moves to a shown node if its file is filtered out.
+        tree.set_glob_filter(Some("baz".to_owned())).or_fail()?;
+        assert_eq!(paths(&tree), [vec![], vec!["baz"]]);
+        assert_eq!(tree.cursor, cursor(&[0, 0]));
+
+        // What is staged does not depend on what is shown.
+        tree.set_glob_filter(Some("foo".to_owned())).or_fail()?;
+        assert_eq!(paths(&tree), [vec!["foo"], vec![]]);
+        assert!(
+            tree.staged_patch()
+                .or_fail()?
+                .contains("diff --git a/baz b/baz")
+        );
+        assert_eq!(tree.staged_files().collect::<Vec<_>>(), [Path::new("baz")]);
+        assert_eq!(tree.change_summary().staged_files, 1);
+
+        tree.set_glob_filter(None).or_fail()?;
+        assert_eq!(paths(&tree), [vec!["foo", "bar"], vec!["baz"]]);
+        Ok(())
+    }
+
+    #[test]
+    fn focus() -> orfail::Result<()> {
+        let unstaged_diff = Diff::from_str(
+            r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,8 +1,8 @@
+ 1
+ 2
+ 3
+-4
++x
+ 5
+ 6
+ 7"#,
+        )
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.focus_lines = 1;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0, 3]);
+        tree.toggle_focus();
+
+        let size = TerminalSize::rows_cols(10, 30);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (1 files)",
+                "      modified foo (1 chunks, ",
+                "        @@ -1,7 +1,7 @@",
+                "        | … 2 more lines",
+                "        |  3",
+                "------->| -4",
+                "        | +x",
+                "        | … 3 more lines",
+                "    Staged changes (0 files)",
+                "",
+            ]
+        );
+        assert_eq!(tree.cursor_row(), 5);
+
+        // The folded lines follow the cursor, and a single hidden line is not folded.
+        tree.cursor_up().or_fail()?;
+        tree.cursor_up().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 1]));
+        assert_eq!(tree.cursor_row(), 4);
+        assert_eq!(
+            render(&tree, 0, size)[3..8],
+            [
+                "        |  1",
+                "------->|  2",
+                "        |  3",
+                "        | … 5 more lines",
+                "    Staged changes (0 files)",
+            ]
+        );
+
+        // Leaving the chunk unfolds all of its lines.
+        tree.cursor_left();
+        assert_eq!(tree.rows(), 3 + 8 + 1);
+        Ok(())
+    }
+
+    // Returns the row of the cursor marker in the fully rendered tree.
+    fn rendered_cursor_row(tree: &DiffTreeWidget) -> Option<usize> {
+        let size = TerminalSize::rows_cols(tree.rows() + 1, 80);
+        render(tree, 0, size)
+            .iter()
+            .position(|line| line.starts_with('-'))
+    }
+
+    #[test]
+    fn cursor_row() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        assert_eq!(tree.cursor_row(), 0);
+        assert_eq!(rendered_cursor_row(&tree), Some(0));
+
+        // Collapsed files before the cursor take one row each.
+        tree.cursor = cursor(&[0, 1]);
+        assert_eq!(tree.cursor_row(), 3);
+        assert_eq!(rendered_cursor_row(&tree), Some(3));
+
+        // Expanding a file before the cursor shifts the cursor by its rows.
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 1]);
+        assert_eq!(tree.cursor_row(), 3 + 5);
+        assert_eq!(rendered_cursor_row(&tree), Some(3 + 5));
+
+        // Collapsing a chunk hides its lines.
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1]);
+        assert_eq!(tree.cursor_row(), 3);
+        assert_eq!(rendered_cursor_row(&tree), Some(3));
+
+        // Collapsing a phase hides everything in it.
+        tree.cursor = cursor(&[0, 0]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 1, 0]);
+        tree.expand_parent().or_fail()?;
+        assert_eq!(tree.cursor_row(), 2);
+        assert_eq!(rendered_cursor_row(&tree), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn sidebar() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.toggle_sidebar().or_fail()?;
+        assert_eq!(tree.cursor_path(), [0, 0, 0]);
+        let size = TerminalSize::rows_cols(6, 60);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                " Unstaged changes (2│--->| modified foo (1 chunks, -1 +1 lin",
+                ">  modified foo (1 c│    :   @@ -1,3 +1,3 @@",
+                "   modified bar (1 c│    :      a",
+                " Staged changes (1 f│    :     -b",
+                "   modified baz (1 c│    :     +B",
+                "                    │    :      c",
+            ]
+        );
+
+        // The cursor row is relative to the file in the right pane.
+        tree.cursor = cursor(&[0, 0, 1, 0, 1]);
+        assert_eq!(tree.cursor_row(), 3);
+        assert_eq!(
+            render(&tree, 0, size)[1..4],
+            [
+                "   modified foo (1 c│        @@ -1,2 +1,2 @@",
+                ">  modified bar (1 c│        | -x",
+                " Staged changes (1 f│------->| +X",
+            ]
+        );
+
+        // The cursor stays on the files, whose content is shown in the right pane.
+        tree.cursor = cursor(&[0, 0, 1]);
+        assert!(!tree.cursor_left());
+        assert_eq!(tree.cursor, cursor(&[0, 0, 1]));
+
+        // Files that appear on a reload are expanded, and the cursor moves to a file
+        // if its own has gone.
+        tree.update_diffs(
+            Diff::default(),
+            chunks_diff(&[("baz", &[1]), ("qux", &[1])])?,
+        )
+        .or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 1, 0]));
+        assert!(tree.root_node.get_node(&cursor(&[0, 1, 1]))?.expanded);
+
+        // Back in the tree, the files expanded for the sidebar stay expanded.
+        tree.update_diffs(
+            Diff::from_str(UNSTAGED_DIFF).or_fail()?,
+            Diff::from_str(STAGED_DIFF).or_fail()?,
+        )
+        .or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1, 0, 1]);
+        tree.toggle_sidebar().or_fail()?;
+        assert_eq!(tree.cursor_row(), 1 + 6 + 3);
+        Ok(())
+    }
+
+    #[test]
+    fn phase_separator() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.phase_separator = true;
+        let size = TerminalSize::rows_cols(6, 20);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "->| Unstaged changes",
+                "  :   modified foo (",
+                "  :   modified bar (",
+                "────────────────────",
+                "  | Staged changes (",
+                "      modified baz (",
+            ]
+        );
+
+        // The separator shifts the rows of the staged phase only.
+        tree.cursor = cursor(&[0, 0, 1]);
+        assert_eq!(tree.cursor_row(), 2);
+        assert_eq!(rendered_cursor_row(&tree), Some(2));
+        tree.cursor = cursor(&[0, 1, 0]);
+        assert_eq!(tree.cursor_row(), 5);
+        assert_eq!(rendered_cursor_row(&tree), Some(5));
+        assert_eq!(tree.rows(), 6);
+
+        // Scrolled past the separator.
+        assert_eq!(
+            render(&tree, 4, size)[..2],
+            ["    Staged changes (", "--->| modified baz ("]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_row_after_moving_into_collapsed_file() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0, 3]);
+
+        // Moving to the first line of the collapsed "bar" expands the file too.
+        assert!(tree.cursor_down().or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 1, 0, 0]));
+        assert_eq!(tree.cursor_row(), 9);
+        assert_eq!(rendered_cursor_row(&tree), Some(9));
+
+        assert!(tree.cursor_up().or_fail()?);
+        assert_eq!(tree.cursor_row(), 6);
+        assert_eq!(rendered_cursor_row(&tree), Some(6));
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_line_text() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        assert_eq!(tree.cursor_line_text(true), None);
+
+        tree.cursor = cursor(&[0, 0, 0, 0, 0]);
+        assert_eq!(tree.cursor_line_text(true), Some("a"));
+        assert_eq!(tree.cursor_line_text(false), Some("a"));
+
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        assert_eq!(tree.cursor_line_text(true), None);
+        assert_eq!(tree.cursor_line_text(false), Some("b"));
+
+        tree.cursor = cursor(&[0, 1, 0, 0, 2]);
+        assert_eq!(tree.cursor_line_text(true), Some("Q"));
+        assert_eq!(tree.cursor_line_text(false), None);
+        Ok(())
+    }
+
+    #[test]
+    fn child_counts() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.show_child_counts = true;
+        tree.root_node
+            .get_node_mut(&cursor(&[0, 0, 0]))
+            .or_fail()?
+            .expanded = true;
+        tree.root_node
+            .get_node_mut(&cursor(&[0, 0, 0, 0]))
+            .or_fail()?
+            .expanded = false;
+        tree.root_node
+            .get_node_mut(&cursor(&[0, 1]))
+            .or_fail()?
+            .expanded = false;
+
+        let size = TerminalSize::rows_cols(5, 60);
+        assert_eq!(
+            render(&tree, 0, size)[1..],
+            [
+                "  :   modified foo (1 chunks, -1 +1 lines)",
+                "  :     @@ -1,3 +1,3 @@ (4 lines)",
+                "  :   modified bar (1 chunks, -1 +1 lines) +- …",
+                "  | Staged changes (1 files)…",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn split_chunk() -> orfail::Result<()> {
+        let unstaged_diff = Diff::from_str(
+            r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,5 +1,5 @@
+ a
+-b
++B
+ c
+ d
+-e
++E
+"#,
+        )
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+
+        tree.cursor = cursor(&[0, 0, 0, 0, 3]);
+        assert!(!tree.can_split_chunk());
+        assert!(!tree.split_chunk().or_fail()?);
+
+        tree.cursor = cursor(&[0, 0, 0, 0, 4]);
+        assert!(tree.can_split_chunk());
+        assert!(tree.split_chunk().or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 1, 0]));
+
+        let chunks = tree.unstaged_diff.diff.files[0].chunks();
+        assert_eq!(chunks[0].head_line(), "@@ -1,3 +1,3 @@");
+        assert_eq!(chunks[1].head_line(), "@@ -4,2 +4,2 @@");
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_after_staging_chunk() -> orfail::Result<()> {
+        let mut tree = DiffTreeWidget::with_diffs(
+            chunks_diff(&[("foo", &[10, 20, 30]), ("bar", &[10])])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+
+        // Staging the middle chunk moves the cursor to the next chunk of the same file.
+        tree.cursor = cursor(&[0, 0, 0, 1]);
+        let position = tree.cursor_chunk_position().or_fail()?;
+        tree.update_diffs(
+            chunks_diff(&[("foo", &[10, 30]), ("bar", &[10])])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+        assert!(tree.move_cursor_to_chunk(&position).or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 1]));
+
+        // Staging the last chunk moves the cursor to the next file.
+        let position = tree.cursor_chunk_position().or_fail()?;
+        tree.update_diffs(
+            chunks_diff(&[("foo", &[10]), ("bar", &[10])])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+        assert!(tree.move_cursor_to_chunk(&position).or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 1, 0]));
+
+        // Also when the file has been fully staged.
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        let position = tree.cursor_chunk_position().or_fail()?;
+        tree.update_diffs(chunks_diff(&[("bar", &[10])])?, Diff::default())
+            .or_fail()?;
+        assert!(tree.move_cursor_to_chunk(&position).or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0]));
+        assert_eq!(tree.unstaged_diff.diff.files[0].path(), Path::new("bar"));
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_keeps_cursor_file() -> orfail::Result<()> {
+        let diff = |paths: &[&str]| {
+            let text = paths
+                .iter()
+                .map(|path| file_diff(path, "@@ -1 +1 @@\n-x\n+X\n"))
+                .collect::<String>();
+            Diff::from_str(&text).or_fail()
+        };
+        let mut tree = DiffTreeWidget::with_diffs(diff(&["b", "c"])?, Diff::default()).or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1, 0]);
+        tree.root_node
+            .get_node_mut(&cursor(&[0, 0, 1, 0]))?
+            .expanded = true;
+
+        // Nothing has changed.
+        tree.update_diffs_keeping_cursor_file(diff(&["b", "c"])?, Diff::default())
+            .or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 1, 0]));
+
+        // A file has been added before the cursor file.
+        tree.update_diffs_keeping_cursor_file(diff(&["a", "b", "c"])?, Diff::default())
+            .or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 2, 0]));
+        assert!(tree.root_node.get_node(&cursor(&[0, 0, 2, 0]))?.expanded);
+
+        // Files have been removed before the cursor file.
+        tree.update_diffs_keeping_cursor_file(diff(&["c"])?, Diff::default())
+            .or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0]));
+        Ok(())
+    }
+
+    #[test]
+    fn outline() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        tree.expand_parent().or_fail()?;
+
+        // The files show their chunk headers but no lines.
+        tree.toggle_outline().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0]));
+        let size = TerminalSize::rows_cols(7, 40);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (2 files)",
+                "      modified foo (1 chunks, -1 +1 line",
+                "----->| @@ -1,3 +1,3 @@…",
+                "      modified bar (1 chunks, -1 +1 line",
+                "      | @@ -1,2 +1,2 @@…",
+                "    Staged changes (1 files)",
+                "      modified baz (1 chunks, -1 +1 line",
+            ]
+        );
+
+        // Expanding a chunk drills into its lines.
+        tree.toggle().or_fail()?;
+        assert!(tree.cursor_right().or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 0]));
+
+        // Toggling again restores the expanded state (only the first file was expanded).
+        tree.toggle_outline().or_fail()?;
+        let phase_node = &tree.root_node.children[0];
+        assert!(phase_node.children[0].expanded);
+        assert!(!phase_node.children[1].expanded);
+
+        // A reload that changes the files ends the outline, as there is nothing to restore,
+        // but new files are shown in it while the others keep their state.
+        tree.toggle_outline().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        tree.toggle().or_fail()?;
+        let qux = file_diff("qux", "@@ -1 +1 @@\n-x\n+X\n");
+        let unstaged_diff = format!("{UNSTAGED_DIFF}\n{qux}");
+        tree.update_diffs(
+            Diff::from_str(&unstaged_diff).or_fail()?,
+            Diff::from_str(STAGED_DIFF).or_fail()?,
+        )
+        .or_fail()?;
+        assert!(!tree.outline);
+        assert!(!tree.can_restore_expansion());
+        let files = &tree.unstaged_diff.diff.files;
+        let file_node = |path: &str| -> orfail::Result<&DiffTreeNode> {
+            let i = files
+                .iter()
+                .position(|f| f.path() == Path::new(path))
+                .or_fail()?;
+            Ok(&tree.root_node.children[0].children[i])
+        };
+        assert!(file_node("foo").or_fail()?.children[0].expanded);
+        assert!(file_node("qux").or_fail()?.expanded);
+        assert!(!file_node("qux").or_fail()?.children[0].expanded);
+        Ok(())
+    }
+
+    #[test]
+    fn collapse_all_and_restore() -> orfail::Result<()> {
+        fn expanded_paths(node: &DiffTreeNode, paths: &mut Vec<NodePath>) {
+            if node.expanded {
+                paths.push(node.path.clone());
+            }
+            for child in &node.children {
+                expanded_paths(child, paths);
+            }
+        }
+        let expanded = |tree: &DiffTreeWidget| {
+            let mut paths = Vec::new();
+            expanded_paths(&tree.root_node, &mut paths);
+            paths
+        };
+
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        tree.expand_parent().or_fail()?;
+        let original = expanded(&tree);
+        assert!(!tree.can_restore_expansion());
+
+        tree.collapse_all().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+        assert_eq!(
+            expanded(&tree),
+            [
+                NodePath::root(),
+                NodePath::root().join(0),
+                NodePath::root().join(1)
+            ]
+        );
+
+        tree.restore_expansion().or_fail()?;
+        assert_eq!(expanded(&tree), original);
+        assert!(!tree.can_restore_expansion());
+
+        // Expanding all is also undoable.
+        tree.expand_all().or_fail()?;
+        assert_ne!(expanded(&tree), original);
+        tree.restore_expansion().or_fail()?;
+        assert_eq!(expanded(&tree), original);
+        Ok(())
+    }
+
+    #[test]
+    fn toggle_file_chunks() -> orfail::Result<()> {
+        let unstaged_diff = Diff::from_str(
+            r#"diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1,2 +1,2 @@
+-a
++A
+ b
+@@ -10,2 +10,2 @@
+ y
+-z
++Z
+diff --git a/bar b/bar
+index e3bdb24..dd04db5 100644
+--- a/bar
++++ b/bar
+@@ -1 +1 @@
+-x
++X"#,
+        )
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 1, 1]);
+        tree.expand_parent().or_fail()?;
+
+        let size = TerminalSize::rows_cols(12, 40);
+        tree.toggle_file_chunks().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (2 files)",
+                "--->| modified foo (2 chunks, -2 +2 line",
+                "    :   @@ -1,2 +1,2 @@…",
+                "    :   @@ -10,2 +10,2 @@…",
+                "    | modified bar (1 chunks, -1 +1 line",
+                "        @@ -1,1 +1,1 @@",
+                "          -x",
+                "          +X",
+                "    Staged changes (0 files)",
+                "",
+                "",
+                "",
+            ]
+        );
+
+        // Toggling again expands the chunks.
+        tree.toggle_file_chunks().or_fail()?;
+        assert_eq!(
+            render(&tree, 0, size)[3..6],
+            ["    :     -a", "    :     +A", "    :      b"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.dry_run = true;
+        tree.cursor = cursor(&[0, 0, 0]);
+
+        // No `git` command is run, so this works outside of a repository.
+        assert!(!tree.stage().or_fail()?);
+        let (title, patch) = tree.take_dry_run_patch().or_fail()?;
+        assert_eq!(title, "dry run: would stage");
+        assert!(patch.starts_with("diff --git a/foo b/foo\n"), "{patch}");
+        assert_eq!(
+            tree.unstaged_diff.diff,
+            Diff::from_str(UNSTAGED_DIFF).or_fail()?
+        );
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+
+        assert!(!tree.discard_all().or_fail()?);
+        let (title, _) = tree.take_dry_run_patch().or_fail()?;
+        assert_eq!(title, "dry run: would discard");
+        assert!(!tree.can_undiscard());
+        assert!(tree.take_dry_run_patch().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_patch() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        let patch = tree.cursor_patch().or_fail()?.or_fail()?;
+        assert!(patch.starts_with("diff --git a/foo b/foo\n"), "{patch}");
+        assert!(
+            patch.contains("@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n"),
+            "{patch}"
+        );
+
+        // The whole phase.
+        tree.cursor = cursor(&[0, 0]);
+        let patch = tree.cursor_patch().or_fail()?.or_fail()?;
+        assert!(patch.contains("diff --git a/bar b/bar\n"), "{patch}");
+        Ok(())
+    }
+
+    #[test]
+    fn toggle_expand_leaf_action() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        let json = r#"{"type": "toggle-expand", "leaf_action": {"type": "stage"}}"#;
+        let json = nojson::RawJson::parse(json).or_fail()?;
+        let action = Action::try_from(json.value()).or_fail()?;
+
+        // The chunk is toggled as usual.
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        tree.expand_parent().or_fail()?;
+        assert!(action.leaf_fallback(&tree).is_none());
+        assert!(action.is_applicable(&tree));
+
+        // On a line, the leaf action is run instead.
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        assert!(matches!(action.leaf_fallback(&tree), Some(Action::Stage)));
+        assert!(action.is_applicable(&tree));
+
+        // The leaf action is not applicable to a context line.
+        tree.cursor = cursor(&[0, 0, 0, 0, 0]);
+        assert!(!action.is_applicable(&tree));
+        Ok(())
+    }
+
+    #[test]
+    fn discard_patch() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1]);
+        assert_eq!(
+            tree.discard_patch().or_fail()?.or_fail()?,
+            "diff --git a/bar b/bar\nindex e3bdb24..dd04db5 100644\n--- a/bar\n+++ b/bar\n@@ -1,2 +1,2 @@\n-x\n+X\n y\n"
+        );
+
+        // Nothing can be discarded in the staged phase.
+        tree.cursor = cursor(&[0, 1, 0]);
+        assert_eq!(tree.discard_patch().or_fail()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn context_line_is_not_stageable() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        let cursor_style = |tree: &DiffTreeWidget| {
+            let mut canvas = Canvas::new(0, TerminalSize::rows_cols(20, 80));
+            tree.render(&mut canvas);
+            let row = tree.cursor_row();
+            canvas.frame_lines()[row].tokens()[0].style()
+        };
+
+        // " a" is a context line.
+        tree.cursor = cursor(&[0, 0, 0, 0, 0]);
+        tree.expand_parent().or_fail()?;
+        assert!(!tree.can_stage_or_discard());
+        assert!(!Action::Stage.is_applicable(&tree));
+        assert!(!Action::Discard { confirm: false }.is_applicable(&tree));
+        assert_eq!(cursor_style(&tree), TerminalStyle::new().dim());
+
+        // "-b" can be staged.
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        assert!(Action::Stage.is_applicable(&tree));
+        assert_eq!(cursor_style(&tree), TerminalStyle::new());
+        Ok(())
+    }
+
+    #[test]
+    fn cycle_file_order() -> orfail::Result<()> {
+        let diff = Diff::from_str(
+            r#"diff --git a/b b/b
+deleted file mode 100644
+index e3bdb24..0000000
+--- a/b
++++ /dev/null
+@@ -1 +0,0 @@
+-b
+diff --git a/c b/c
+index e3bdb24..dd04db5 100644
+--- a/c
++++ b/c
+@@ -1,2 +1,2 @@
+-x
+-y
++X
++Y
+diff --git a/a b/a
+new file mode 100644
+index 0000000..e3bdb24
+--- /dev/null
++++ b/a
+@@ -0,0 +1 @@
++a
+"#,
+        )
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(diff, Diff::default()).or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1, 0]);
+        tree.expand_parent().or_fail()?;
+
+        let paths = |tree: &DiffTreeWidget| {
+            let files = &tree.unstaged_diff.diff.files;
+            files
+                .iter()
+                .map(|f| f.path().display().to_string())
+                .collect::<Vec<_>>()
+        };
+        for (order, expected) in [
+            (FileOrder::Path, ["a", "b", "c"]),
+            (FileOrder::Size, ["c", "b", "a"]),
+            (FileOrder::Status, ["a", "c", "b"]),
+            (FileOrder::Git, ["b", "c", "a"]),
+        ] {
+            tree.cycle_file_order().or_fail()?;
+            assert_eq!(tree.file_order(), order);
+            assert_eq!(paths(&tree), expected);
+
+            // The cursor stays on the chunk of "c", and staging it targets "c".
+            let i = expected.iter().position(|p| *p == "c").or_fail()?;
+            assert_eq!(tree.cursor, cursor(&[0, 0, i, 0]));
+            let diff = tree.root_node.children[0]
+                .get_diff(&tree.cursor, &tree.unstaged_diff.diff, false)
+                .or_fail()?;
+            assert_eq!(diff.files[0].path(), Path::new("c"));
+            assert!(
+                diff.files[0].chunks()[0]
+                    .lines
+                    .contains(&LineDiff::New("X".to_owned()))
+            );
+        }
+
+        tree.cycle_file_order().or_fail()?;
+        assert_eq!(
+            render(&tree, 0, TerminalSize::rows_cols(1, 80)),
+            ["    Unstaged changes (3 files, sorted by path)"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mtimes() -> orfail::Result<()> {
+        assert_eq!(format_elapsed(Duration::from_secs(59)), "59s ago");
+        assert_eq!(format_elapsed(Duration::from_secs(150)), "2m ago");
+        assert_eq!(format_elapsed(Duration::from_secs(3 * 3600)), "3h ago");
+        assert_eq!(format_elapsed(Duration::from_secs(200_000)), "2d ago");
+
+        let mut tree = tree().or_fail()?;
+        tree.show_mtimes = true;
+        let mtime = SystemTime::now() - Duration::from_secs(150);
+        // The second file has been deleted, so it has no modification time.
+        tree.unstaged_diff.mtimes = vec![Some(mtime), None];
+
+        let size = TerminalSize::rows_cols(4, 80);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "->| Unstaged changes (2 files)",
+                "  :   modified foo (1 chunks, -1 +1 lines) (2m ago) +- …",
+                "  :   modified bar (1 chunks, -1 +1 lines) +- …",
+                "  | Staged changes (1 files)",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn binary_sizes() -> orfail::Result<()> {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(2048), "2.0 KiB");
+        assert_eq!(format_size(50 * 1024 * 1024 + 300 * 1024), "50.3 MiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+
+        let text = "diff --git a/ls b/ls\nindex baec60b..a53cdf4 100755\nBinary files a/ls and b/ls differ\n";
+        let mut tree = DiffTreeWidget::with_diffs(Diff::from_str(text).or_fail()?, Diff::default())
+            .or_fail()?;
+        tree.show_binary_sizes = true;
+        tree.unstaged_diff.binary_sizes = vec![Some(2048)];
+
+        let size = TerminalSize::rows_cols(2, 80);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "->| Unstaged changes (1 files)",
+                "  :   modified ls (0 chunks, -0 +0 lines) (2.0 KiB)",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn symlinks() -> orfail::Result<()> {
+        let text = r#"diff --git a/link b/link
+index 1a2b3c4..5d6e7f8 120000
+--- a/link
++++ b/link
+@@ -1 +1 @@
+-old-target
+\ No newline at end of file
++new-target
+\ No newline at end of file
+diff --git a/new-link b/new-link
+new file mode 120000
+index 0000000..684e22a
+--- /dev/null
++++ b/new-link
+@@ -0,0 +1 @@
++target
+\ No newline at end of file
+"#;
+        let tree = DiffTreeWidget::with_diffs(Diff::from_str(text).or_fail()?, Diff::default())
+            .or_fail()?;
+        let size = TerminalSize::rows_cols(3, 60);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "->| Unstaged changes (2 files)",
+                "  :   modified symlink link: old-target -> new-target +- …",
+                "  :   added symlink new-link -> target + …",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mode_labels() -> orfail::Result<()> {
+        let text = "diff --git a/run b/run\nold mode 100644\nnew mode 100755\n";
+        let mut tree = DiffTreeWidget::with_diffs(Diff::from_str(text).or_fail()?, Diff::default())
+            .or_fail()?;
+        let size = TerminalSize::rows_cols(2, 80);
+        assert_eq!(
+            render(&tree, 0, size)[1],
+            "  :   mode changed run 100644 -> 100755"
+        );
+
+        tree.toggle_mode_labels();
+        assert_eq!(
+            render(&tree, 0, size)[1],
+            "  :   mode changed run file -> executable"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn untracked_groups() -> orfail::Result<()> {
+        let group = FileDiff::New {
+            path: PathBuf::from("build/"),
+            hash: "0000000".to_owned(),
+            mode: crate::diff::Mode(0),
+            content: ContentDiff::Binary,
+        };
+        let diff = Diff { files: vec![group] };
+        let mut tree = DiffTreeWidget::with_diffs(diff, Diff::default()).or_fail()?;
+        tree.untracked_groups = [(PathBuf::from("build/"), 120)].into();
+        tree.cursor = cursor(&[0, 0, 0]);
+
+        let size = TerminalSize::rows_cols(2, 80);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (1 files)",
+                "--->| 120 untracked files in build/",
+            ]
+        );
+        assert!(tree.can_toggle());
+
+        tree.untracked_groups.clear();
+        assert!(!tree.can_toggle());
+        Ok(())
+    }
+
+    #[test]
+    fn expand_untracked_group() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git::tests::git_in(dir.path(), args);
+        git(&["init", "--quiet"]).or_fail()?;
+        std::fs::create_dir(dir.path().join("build")).or_fail()?;
+        for path in [
+            "a", "build/1", "build/2", "build/3", "build/4", "build/5", "build/6", "z",
+        ] {
+            std::fs::write(dir.path().join(path), "x\n").or_fail()?;
+        }
+        let paths = |tree: &DiffTreeWidget| {
+            let files = &tree.unstaged_diff.diff.files;
+            files
+                .iter()
+                .map(|f| f.path().display().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        git::tests::with_current_dir(dir.path(), || {
+            // The threshold is process-wide, so it is kept above the file counts of other tests.
+            git::set_untracked_group_threshold(std::num::NonZeroUsize::new(5).or_fail()?);
+            let mut tree = DiffTreeWidget::load().or_fail()?;
+            assert_eq!(paths(&tree), ["a", "build/", "z"]);
+            assert_eq!(tree.untracked_groups.get(Path::new("build/")), Some(&6));
+
+            // Expanding the group shows its files in place, with the cursor on the first one.
+            tree.cursor = cursor(&[0, 0, 1]);
+            assert!(tree.can_toggle());
+            tree.toggle().or_fail()?;
+            let expanded = [
+                "a", "build/1", "build/2", "build/3", "build/4", "build/5", "build/6", "z",
+            ];
+            assert_eq!(paths(&tree), expanded);
+            assert_eq!(tree.cursor.path.0, [0, 0, 1]);
+            assert!(tree.untracked_groups.is_empty());
+
+            // The group stays expanded across reloads.
+            tree.reload().or_fail()?;
+            assert_eq!(paths(&tree), expanded);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ruler() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        tree.expand_parent().or_fail()?;
+        tree.ruler_column = Some(2);
+
+        let size = TerminalSize::rows_cols(5, 40);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes (2 files)",
+                "      modified foo (1 chunks, -1 +1 line",
+                "----->| @@ -1,3 +1,3 @@",
+                "      :    a │",
+                "      :   -b │",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_content_cols() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0]);
+        tree.expand_parent().or_fail()?;
+        tree.max_content_cols = Some(20);
+
+        let size = TerminalSize::rows_cols(3, 80);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Unstaged changes",
+                "      modified foo (",
+                "----->| @@ -1,3 +1,3",
+            ]
+        );
+
+        tree.center_content = true;
+        assert_eq!(
+            render(&tree, 1, size)[0],
+            format!("{:30}      modified foo (", "")
+        );
+
+        // The limit has no effect if the terminal is narrower.
+        tree.max_content_cols = Some(100);
+        assert_eq!(
+            render(&tree, 1, size)[0],
+            "      modified foo (1 chunks, -1 +1 lines)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_marker_style() -> orfail::Result<()> {
+        let text = "diff --git a/foo b/foo\nindex e3bdb24..dd04db5 100644\n--- a/foo\n+++ b/foo\n@@ -1,2 +1,3 @@\n a\n+<<<<<<< HEAD\n+b\n";
+        let unstaged_diff = Diff::from_str(text).or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+
+        let mut canvas = Canvas::new(0, TerminalSize::rows_cols(10, 80));
+        tree.render(&mut canvas);
+        let content_style = |text: &str| {
+            canvas
+                .frame_lines()
+                .iter()
+                .flat_map(|l| l.tokens())
+                .find(|t| t.text() == text)
+                .map(|t| t.style())
+        };
+        let marker = content_style("<<<<<<< HEAD").or_fail()?;
+        assert_eq!(marker.bg_color, Some(TerminalColor::BRIGHT_RED));
+        assert_eq!(content_style("b"), Some(TerminalStyle::new().bold()));
+        Ok(())
+    }
+
+    #[test]
+    fn header_colors() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        let header_style = |tree: &DiffTreeWidget, phase: &str| {
+            let mut canvas = Canvas::new(0, TerminalSize::rows_cols(10, 80));
+            tree.render(&mut canvas);
+            canvas
+                .frame_lines()
+                .iter()
+                .flat_map(|l| l.tokens())
+                .find(|t| t.text().starts_with(phase))
+                .map(|t| t.style())
+        };
+
+        let unstaged = header_style(&tree, "Unstaged changes").or_fail()?;
+        let staged = header_style(&tree, "Staged changes").or_fail()?;
+        assert_ne!(unstaged, staged);
+        assert_eq!(
+            unstaged,
+            TerminalStyle::new()
+                .bold()
+                .fg_color(DiffTreeWidget::DEFAULT_UNSTAGED_HEADER_COLOR)
+        );
+
+        tree.header_colors = [TerminalColor::RED, TerminalColor::GREEN];
+        assert_eq!(
+            header_style(&tree, "Staged changes"),
+            Some(TerminalStyle::new().bold().fg_color(TerminalColor::GREEN))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reload_changes() -> orfail::Result<()> {
+        let diff = |files: &[(&str, &str)]| {
+            let text = files
+                .iter()
+                .map(|(path, line)| file_diff(path, &format!("@@ -1 +1 @@\n-x\n+{line}\n")))
+                .collect::<String>();
+            Diff::from_str(&text).or_fail()
+        };
+        let mut tree = DiffTreeWidget::with_diffs(
+            diff(&[("a", "A"), ("b", "B"), ("c", "C")])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+        tree.mark_reload_changes = true;
+        tree.update_diffs(
+            diff(&[("a", "A"), ("b", "BB"), ("d", "D")])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+
+        let size = TerminalSize::rows_cols(5, 80);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "->| Unstaged changes (3 files, 1 gone)",
+                "  :   modified a (1 chunks, -1 +1 lines) +- …",
+                "  :   modified b (1 chunks, -1 +1 lines) [modified] +- …",
+                "  :   modified d (1 chunks, -1 +1 lines) [new] +- …",
+                "  | Staged changes (0 files)"
+            ]
+        );
+
+        // The marks are cleared on the next reload if nothing has changed.
+        tree.update_diffs(
+            diff(&[("a", "A"), ("b", "BB"), ("d", "D")])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+        assert_eq!(
+            render(&tree, 0, size)[..2],
+            [
+                "->| Unstaged changes (3 files)",
+                "  :   modified a (1 chunks, -1 +1 lines) +- …",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combined_view() -> orfail::Result<()> {
+        let diff = |paths: &[&str]| {
+            let text = paths
+                .iter()
+                .map(|path| file_diff(path, "@@ -1 +1 @@\n-x\n+X\n"))
+                .collect::<String>();
+            Diff::from_str(&text).or_fail()
+        };
+        let mut tree =
+            DiffTreeWidget::with_diffs(diff(&["c", "a"])?, diff(&["b", "c"])?).or_fail()?;
+        tree.cursor = cursor(&[0, 1, 1]);
+        tree.toggle_combined_view().or_fail()?;
+
+        // The cursor stays on the same file ("c").
+        let size = TerminalSize::rows_cols(5, 80);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "    Changes (3 files)",
+                "    | [ U] modified a (1 chunks, -1 +1 lines)",
+                "    | [S ] modified b (1 chunks, -1 +1 lines)",
+                "--->| [SU] modified c (1 chunks, -1 +1 lines)",
+                ""
+            ]
+        );
+        assert_eq!(tree.cursor_row(), 3);
+        assert!(!tree.can_cursor_right());
+        assert!(!tree.can_toggle());
+        assert!(tree.can_stage_or_discard());
+        assert!(tree.can_unstage());
+        assert_eq!(tree.operation_cursor(0), Some(cursor(&[0, 0, 0])));
+        assert_eq!(tree.operation_cursor(1), Some(cursor(&[0, 1, 1])));
+        assert!(!tree.can_cursor_down());
+
+        // "b" is staged only.
+        assert!(tree.cursor_up().or_fail()?);
+        assert!(!tree.can_stage_or_discard());
+        assert!(tree.can_unstage());
+        assert_eq!(tree.cursor, cursor(&[0, 1, 0]));
+
+        // "a" is unstaged only.
+        assert!(tree.cursor_up().or_fail()?);
+        assert!(tree.can_stage_or_discard());
+        assert!(!tree.can_unstage());
+        assert_eq!(tree.cursor_unstaged_paths(), [PathBuf::from("a")]);
+        assert!(!tree.can_cursor_up());
+
+        // Staging "a" keeps the cursor on it.
+        tree.update_diffs(diff(&["c"])?, diff(&["a", "b", "c"])?)
+            .or_fail()?;
+        assert_eq!(tree.combined_index, 0);
+        assert_eq!(tree.cursor, cursor(&[0, 1, 0]));
+
+        tree.toggle_combined_view().or_fail()?;
+        assert!(tree.can_cursor_left());
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_after_staging_line() -> orfail::Result<()> {
+        let mut tree = DiffTreeWidget::with_diffs(
+            chunks_diff(&[("foo", &[10, 20]), ("bar", &[10])])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+
+        // The cursor moves to the line that followed it while the line remains.
+        tree.cursor = cursor(&[0, 0, 0, 0, 0]);
+        let position = tree.cursor_chunk_position().or_fail()?;
+        tree.update_diffs(
+            chunks_diff(&[("foo", &[10, 20]), ("bar", &[10])])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+        tree.move_cursor_after_staging(&position).or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 1]));
+
+        // Staging the last line of a chunk moves the cursor to the next chunk.
+        let position = tree.cursor_chunk_position().or_fail()?;
+        tree.update_diffs(
+            chunks_diff(&[("foo", &[20]), ("bar", &[10])])?,
+            Diff::default(),
+        )
+        .or_fail()?;
+        tree.move_cursor_after_staging(&position).or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0]));
+
+        // Staging the last line of the last chunk of a file moves the cursor to the next file.
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        let position = tree.cursor_chunk_position().or_fail()?;
+        tree.update_diffs(chunks_diff(&[("bar", &[10])])?, Diff::default())
+            .or_fail()?;
+        tree.move_cursor_after_staging(&position).or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0]));
+        assert_eq!(tree.unstaged_diff.diff.files[0].path(), Path::new("bar"));
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_after_staging_line_in_trimmed_chunk() -> orfail::Result<()> {
+        let header =
+            "diff --git a/foo b/foo\nindex e3bdb24..dd04db5 100644\n--- a/foo\n+++ b/foo\n";
+        let mut tree = DiffTreeWidget::with_diffs(
+            Diff::from_str(&format!(
+                "{header}@@ -1,4 +1,6 @@\n a\n b\n c\n+X\n d\n+Y\n"
+            ))
+            .or_fail()?,
+            Diff::default(),
+        )
+        .or_fail()?;
+
+        // Staging `+X` turns it into context, so the leading context of the chunk is trimmed.
+        tree.cursor = cursor(&[0, 0, 0, 0, 3]);
+        let position = tree.cursor_chunk_position().or_fail()?;
+        tree.update_diffs(
+            Diff::from_str(&format!("{header}@@ -3,3 +3,4 @@\n c\n X\n d\n+Y\n")).or_fail()?,
+            Diff::default(),
+        )
+        .or_fail()?;
+        tree.move_cursor_after_staging(&position).or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0, 0, 2]));
+        assert_eq!(tree.cursor_line_text(true), Some("d"));
+        Ok(())
+    }
+
+    #[test]
+    fn dump_tree() -> orfail::Result<()> {
+        let tree = tree().or_fail()?;
+        let dump = tree.dump_tree();
+        let lines = dump.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[..4],
+            [
+                "[0] content_index=0 children=2 expanded",
+                "  [0, 0] content_index=0 children=2 expanded <- cursor",
+                "    [0, 0, 0] content_index=0 children=1",
+                "      [0, 0, 0, 0] content_index=0 children=4 expanded",
+            ]
+        );
+        assert_eq!(lines.len(), 19);
+        Ok(())
+    }
+
+    #[test]
+    fn hunks() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        let hunks = tree.hunks();
+        assert_eq!(
+            hunks
+                .iter()
+                .map(|(_, label)| label.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "unstaged foo @@ -1,3 +1,3 @@",
+                "unstaged bar @@ -1,2 +1,2 @@",
+                "staged baz @@ -1,2 +1,2 @@",
+            ]
+        );
+
+        // Jumping to a chunk expands its file.
+        assert!(tree.jump_to(&hunks[2].0).or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 1, 0, 0]));
+        assert!(tree.root_node.children[1].children[0].expanded);
+        assert!(!tree.jump_to(&[0, 1, 5, 0]).or_fail()?);
+        Ok(())
+    }
+
+    #[test]
+    fn goto_file() -> orfail::Result<()> {
+        let text = (1..=5)
+            .map(|i| format!("diff --git a/f{i} b/f{i}\nold mode 100644\nnew mode 100755\n"))
+            .collect::<String>();
+        let mut tree =
+            DiffTreeWidget::with_diffs(Diff::from_str(&text).or_fail()?, Diff::default())
+                .or_fail()?;
+        tree.root_node.children[0].expanded = false;
+
+        // The phase is expanded to show the file.
+        assert!(tree.goto_file(3).or_fail()?);
+        assert_eq!(tree.cursor.path, NodePath(vec![0, 0, 2]));
+        assert!(tree.root_node.children[0].expanded);
+
+        // Numbers out of the phase's file count are ignored.
+        assert!(!tree.goto_file(0).or_fail()?);
+        assert!(!tree.goto_file(6).or_fail()?);
+        assert_eq!(tree.cursor.path, NodePath(vec![0, 0, 2]));
+        Ok(())
+    }
+
+    #[test]
+    fn select_file() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        assert!(tree.select_file(Path::new("bar")).or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 0, 1]));
+        assert!(tree.root_node.get_node(&tree.cursor).or_fail()?.expanded);
+
+        assert!(tree.select_file(Path::new("baz")).or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 1, 0]));
+        assert!(
+            tree.root_node
+                .get_node(&cursor(&[0, 1]))
+                .or_fail()?
+                .expanded
+        );
+
+        assert!(!tree.select_file(Path::new("qux")).or_fail()?);
+        assert_eq!(tree.cursor, cursor(&[0, 1, 0]));
+        Ok(())
+    }
+
+    #[test]
+    fn elided_path() -> orfail::Result<()> {
+        let path = "services/foo/bar/src/main.rs";
+        let unstaged_diff = Diff::from_str(&file_diff(path, "@@ -1 +1 @@\n-x\n+X\n")).or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.elide_path_components = 3;
+
+        let size = TerminalSize::rows_cols(2, 60);
+        assert_eq!(
+            render(&tree, 0, size)[1],
+            "  :   modified …/src/main.rs (1 chunks, -1 +1 lines) +- …"
+        );
+        assert_eq!(tree.unstaged_diff.diff.files[0].path(), Path::new(path));
+
+        // The file name is always kept.
+        assert_eq!(
+            elide_leading_components(Path::new(path), 10, Charset::Unicode),
+            "…/main.rs"
+        );
+        assert_eq!(
+            elide_leading_components(Path::new("main.rs"), 1, Charset::Unicode),
+            "main.rs"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expand_first_file_only() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.expand_if_possible(TerminalSize::rows_cols(20, 50))
+            .or_fail()?;
+        tree.expand_first_file_only().or_fail()?;
+
+        let size = TerminalSize::rows_cols(7, 50);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "->| Unstaged changes (2 files)",
+                "  :   modified foo (1 chunks, -1 +1 lines)",
+                "  :     @@ -1,3 +1,3 @@…",
+                "  :   modified bar (1 chunks, -1 +1 lines) +- …",
+                "  | Staged changes (1 files)",
+                "      modified baz (1 chunks, -1 +1 lines) +- …",
+                "",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_content() -> orfail::Result<()> {
+        let unstaged_diff = Diff::from_str(
+            r#"diff --git a/bar b/bar
+deleted file mode 100644
+index e3bdb24..0000000
+--- a/bar
++++ /dev/null
+@@ -1,2 +0,0 @@
+-1
+-2
+diff --git a/foo b/foo
+index e3bdb24..dd04db5 100644
+--- a/foo
++++ b/foo
+@@ -1 +1 @@
+-x
++X
+"#,
+        )
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.expand_if_possible(TerminalSize::rows_cols(20, 50))
+            .or_fail()?;
+
+        // Deleted files are collapsed by default, even if there is room to expand them.
+        let size = TerminalSize::rows_cols(4, 50);
+        assert_eq!(
+            render(&tree, 0, size),
+            [
+                "->| Unstaged changes (2 files)",
+                "  :   deleted bar (-2 lines) -- …",
+                "  :   modified foo (1 chunks, -1 +1 lines)",
+                "  :     @@ -1,1 +1,1 @@",
+            ]
+        );
+
+        tree.toggle_deleted_content().or_fail()?;
+        assert_eq!(
+            render(&tree, 0, size)[1..3],
+            ["  :   deleted bar (-2 lines)", "  :     @@ -1,2 +0,0 @@"]
+        );
+
+        // Hiding the content moves the cursor out of the deleted file.
+        tree.cursor = cursor(&[0, 0, 0, 0, 1]);
+        tree.toggle_deleted_content().or_fail()?;
+        assert_eq!(tree.cursor, cursor(&[0, 0, 0]));
+        assert!(!tree.root_node.get_node(&tree.cursor).or_fail()?.expanded);
+        Ok(())
+    }
+
+    #[test]
+    fn staged_diff_base() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 1, 0]);
+        assert!(tree.can_unstage());
+
+        tree.staged_diff_base = Some("main".to_owned());
+        assert!(!tree.can_unstage());
+        assert!(!tree.unstage().or_fail()?);
+
+        let size = TerminalSize::rows_cols(4, 60);
+        assert_eq!(
+            render(&tree, 0, size)[3],
+            "    Staged changes (1 files, against main)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unstage_against_worktree() -> orfail::Result<()> {
+        let dir = tempfile::tempdir().or_fail()?;
+        let git = |args: &[&str]| git::tests::git_in(dir.path(), args);
+        git(&["init", "--quiet"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "a\n").or_fail()?;
+        git(&["add", "foo"]).or_fail()?;
+        git(&["commit", "--quiet", "-m", "init"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "b\n").or_fail()?;
+        git(&["add", "foo"]).or_fail()?;
+        std::fs::write(dir.path().join("foo"), "c\n").or_fail()?;
+
+        // The staged phase shows the difference from the working tree ("c") to the index ("b").
+        let unstaged = git(&["diff", "--src-prefix=a/", "--dst-prefix=b/"]).or_fail()?;
+        let staged = git(&["diff", "-R", "--src-prefix=b/", "--dst-prefix=a/"]).or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(
+            Diff::from_str(&unstaged).or_fail()?,
+            Diff::from_str(&staged).or_fail()?,
+        )
+        .or_fail()?;
+        tree.staged_against_worktree = true;
+
+        // Reverting it would stage "c", so nothing can be unstaged.
+        for path in [&[0, 1][..], &[0, 1, 0], &[0, 1, 0, 0], &[0, 1, 0, 0, 1]] {
+            tree.cursor = cursor(path);
+            assert!(!tree.can_unstage(), "{path:?}");
+            assert!(!tree.unstage().or_fail()?, "{path:?}");
+        }
+        assert_eq!(git(&["show", ":foo"]).or_fail()?, "b\n");
+        Ok(())
+    }
+
+    #[test]
+    fn stage_file_hunks() -> orfail::Result<()> {
+        let lines = (1..=20).map(|i| format!("line{i}\n")).collect::<Vec<_>>();
+        let mut changed_lines = lines.clone();
+        changed_lines[0] = "LINE1\n".to_owned();
+        changed_lines[19] = "LINE20\n".to_owned();
+        let (old, new) = (lines.concat(), changed_lines.concat());
+
+        // (setup after adding `old` as "foo", expected index mode and content if not removed)
+        type Setup = fn(&Path, &str) -> orfail::Result<()>;
+        let cases: [(Setup, Option<(&str, &str)>); 4] = [
+            // For an updated file, staging all chunks is the same as staging the whole file.
+            (
+                |dir, new| std::fs::write(dir.join("foo"), new).or_fail(),
+                Some(("100644", &new)),
+            ),
+            // A mode change is not staged.
+            (
+                |dir, new| {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::write(dir.join("foo"), new).or_fail()?;
+                    let permissions = std::fs::Permissions::from_mode(0o755);
+                    std::fs::set_permissions(dir.join("foo"), permissions).or_fail()
+                },
+                Some(("100644", &new)),
+            ),
+            // A deleted file is removed, as with staging the whole file.
+            (
+                |dir, _| std::fs::remove_file(dir.join("foo")).or_fail(),
+                None,
+            ),
+            // A file added with `--intent-to-add` gets its whole content.
+            (
+                |dir, new| {
+                    git::tests::git_in(dir, &["rm", "--cached", "--quiet", "foo"]).or_fail()?;
+                    std::fs::write(dir.join("foo"), new).or_fail()?;
+                    git::tests::git_in(dir, &["add", "--intent-to-add", "foo"]).map(|_| ())
+                },
+                Some(("100644", &new)),
+            ),
+        ];
+        for (i, (setup, expected)) in cases.into_iter().enumerate() {
+            let dir = tempfile::tempdir().or_fail()?;
+            let git = |args: &[&str]| git::tests::git_in(dir.path(), args);
+            git(&["init", "--quiet"]).or_fail()?;
+            git(&["config", "core.fileMode", "true"]).or_fail()?;
+            std::fs::write(dir.path().join("foo"), &old).or_fail()?;
+            git(&["add", "foo"]).or_fail()?;
+            setup(dir.path(), &new).or_fail()?;
+
+            git::tests::with_current_dir(dir.path(), || {
+                let mut tree = DiffTreeWidget::load().or_fail()?;
+                tree.cursor = cursor(&[0, 0, 0]);
+                assert!(tree.can_stage_file_hunks(), "case {i}");
+                assert!(tree.stage_file_hunks().or_fail()?, "case {i}");
+                Ok(())
+            })
+            .or_fail()?;
+
+            let stage = git(&["ls-files", "--stage", "foo"]).or_fail()?;
+            let Some((expected_mode, expected)) = expected else {
+                assert_eq!(stage, "", "case {i}");
+                continue;
+            };
+            assert!(stage.starts_with(expected_mode), "case {i}: {stage}");
+            assert_eq!(git(&["show", ":foo"]).or_fail()?, expected, "case {i}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shown_rev() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0]);
+        assert!(tree.can_stage_or_discard());
+
+        tree.shown_rev = Some("abc1234".to_owned());
+        assert!(!tree.can_stage_or_discard());
+        assert!(!tree.can_stage_file_hunks());
+        assert!(!tree.can_restage_file());
+        assert!(!tree.stage().or_fail()?);
+        assert!(!tree.stage_all().or_fail()?);
+
+        let size = TerminalSize::rows_cols(1, 60);
+        assert_eq!(
+            render(&tree, 0, size)[0],
+            "    Changes in abc1234 (2 files)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn author_filter() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        assert!(!tree.can_filter_by_author());
+        tree.staged_diff_base = Some("main".to_owned());
+        assert!(tree.can_filter_by_author());
+
+        // Blamed authors are cached, so no `git blame` is run here.
+        tree.blamed_authors.insert(
+            PathBuf::from("baz"),
+            Some(vec!["alice <a@x>".to_owned(), "Bob <b@x>".to_owned()]),
+        );
+        tree.cursor = cursor(&[0, 1, 0]);
+        tree.toggle().or_fail()?;
+
+        // "+Q" (the second line) was last touched by Bob.
+        tree.set_author_filter(Some("bob".to_owned()));
+        tree.update_author_mismatches().or_fail()?;
+        assert!(tree.author_mismatches.is_empty());
+
+        tree.set_author_filter(Some("alice".to_owned()));
+        tree.update_author_mismatches().or_fail()?;
+        assert_eq!(tree.author_mismatches, [(0, 0)].into());
+        let mut canvas = Canvas::new(0, TerminalSize::rows_cols(20, 60));
+        tree.render(&mut canvas);
+        let chunk_row = &canvas.frame_lines()[5];
+        assert_eq!(chunk_row.text(), "    :   @@ -1,2 +1,2 @@");
+        assert_eq!(
+            chunk_row.tokens().last().map(|t| t.style()),
+            Some(TerminalStyle::new().dim())
+        );
+
+        // The chunks of a file that could not be blamed are not dimmed.
+        tree.blamed_authors.insert(PathBuf::from("baz"), None);
+        tree.update_author_mismatches().or_fail()?;
+        assert!(tree.author_mismatches.is_empty());
+
+        tree.set_author_filter(None);
+        tree.update_author_mismatches().or_fail()?;
+        assert!(tree.author_mismatches.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn line_marker_gutter() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        for file in [&[0, 0, 0], &[0, 0, 1], &[0, 1, 0]] {
+            tree.cursor = cursor(file);
+            tree.toggle().or_fail()?;
+        }
+
+        // Wherever the cursor is, the markers of all lines are in the same column.
+        let size = TerminalSize::rows_cols(20, 40);
+        for path in [
+            &[0, 0][..],
+            &[0, 0, 0, 0],
+            &[0, 0, 0, 0, 1],
+            &[0, 1, 0, 0, 2],
+        ] {
+            tree.cursor = cursor(path);
+            let lines = render(&tree, 0, size);
+            for marker in ["-b", "+B", "-x", "+X", "-q", "+Q"] {
+                let col = lines.iter().find_map(|l| l.find(marker));
+                assert_eq!(col, Some(10), "{marker} in {lines:?}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn change_summary() -> orfail::Result<()> {
+        let tree = tree().or_fail()?;
+        let summary = tree.change_summary();
+        assert_eq!(
+            summary,
+            ChangeSummary {
+                staged_files: 1,
+                unstaged_files: 2,
+                added: 3,
+                removed: 3,
+            }
+        );
+        assert_eq!(
+            nojson::Json(summary).to_string(),
+            r#"{"staged_files":1,"unstaged_files":2,"added":3,"removed":3}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn render_with_offset() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 1]);
+        tree.toggle().or_fail()?;
+
+        // Skipping the rows above the frame gives the same lines as rendering everything.
+        let rows = tree.rows();
+        let full = render(&tree, 0, TerminalSize::rows_cols(rows, 80));
+        let size = TerminalSize::rows_cols(3, 80);
+        for offset in 0..rows {
+            let end = (offset + 3).min(rows);
+            assert_eq!(
+                render(&tree, offset, size)[..end - offset],
+                full[offset..end]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn render_large_tree_with_offset() -> orfail::Result<()> {
+        // 100 files with 100 changed lines each (about 10k nodes when expanded).
+        let lines = (0..50).map(|i| format!("-x{i}\n")).collect::<String>()
+            + &(0..50).map(|i| format!("+y{i}\n")).collect::<String>();
+        let text = (0..100)
+            .map(|i| file_diff(&format!("f{i}"), &format!("@@ -1,50 +1,50 @@\n{lines}")))
+            .collect::<String>();
+        let diff = Diff::from_str(&text).or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(diff, Diff::default()).or_fail()?;
+        tree.expand_all().or_fail()?;
+
+        let rows = tree.rows();
+        assert_eq!(rows, 2 + 100 * (1 + 1 + 100));
+        let full = render(&tree, 0, TerminalSize::rows_cols(rows, 80));
+        let size = TerminalSize::rows_cols(40, 80);
+        for offset in [1, 101, 102, rows / 2, rows - 40] {
+            assert_eq!(render(&tree, offset, size), full[offset..offset + 40]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rows_up_to() {
+        let new_node = |path: Vec<usize>, children: Vec<DiffTreeNode>| DiffTreeNode {
+            path: NodePath(path),
+            content_index: 0,
+            staged_context: None,
+            visible_lines: None,
+            folded_lines: 0,
+            expanded: true,
+            children,
+        };
+        let files = (0..100)
+            .map(|i| {
+                let lines = (0..100).map(|j| new_node(vec![0, 0, i, j], Vec::new()));
+                new_node(vec![0, 0, i], lines.collect())
+            })
+            .collect();
+        let root = new_node(vec![0, 0], files);
+
+        let rows = root.rows();
+        assert_eq!(rows, 1 + 100 + 100 * 100);
+        for limit in [0, 1, 2, 101, 102, rows - 1, rows, rows + 1] {
+            assert_eq!(root.rows_up_to(limit), rows.min(limit));
+        }
+    }
+
+    #[test]
+    fn deep_tree() -> orfail::Result<()> {
+        let depth = 2000;
+        let new_node = |len: usize, children: Vec<DiffTreeNode>| DiffTreeNode {
+            path: NodePath(vec![0; len]),
+            content_index: 0,
+            staged_context: None,
+            visible_lines: None,
+            folded_lines: 0,
+            expanded: true,
+            children,
+        };
+        let mut root = new_node(depth, Vec::new());
+        for len in (1..depth).rev() {
+            root = new_node(len, vec![root]);
+        }
+        let deepest = cursor(&vec![0; depth]);
+
+        // A small stack is enough because the traversals are not recursive.
+        std::thread::scope(|s| {
+            std::thread::Builder::new()
+                .stack_size(64 * 1024)
+                .spawn_scoped(s, || -> orfail::Result<()> {
+                    assert_eq!(root.rows(), depth);
+                    assert_eq!(root.cursor_row(&deepest), depth - 1);
+                    assert_eq!(root.get_node(&deepest).or_fail()?.path.len(), depth);
+                    root.get_node_mut(&deepest).or_fail()?.expanded = false;
+                    Ok(())
+                })
+                .or_fail()?
+                .join()
+                .ok()
+                .or_fail()?
+        })
+    }
+
+    #[test]
+    fn cursor_unstaged_paths() -> orfail::Result<()> {
+        let mut tree = tree().or_fail()?;
+        assert_eq!(
+            tree.cursor_unstaged_paths(),
+            [PathBuf::from("foo"), PathBuf::from("bar")]
+        );
+        tree.cursor = cursor(&[0, 0, 1, 0]);
+        assert_eq!(tree.cursor_unstaged_paths(), [PathBuf::from("bar")]);
+        tree.cursor = cursor(&[0, 1, 0]);
+        assert!(tree.cursor_unstaged_paths().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn ascii_charset() -> orfail::Result<()> {
+        let unstaged_diff = Diff::from_str(&file_diff(
+            "src/foo/bar.rs",
+            "@@ -1,7 +1,7 @@\n 1\n 2\n 3\n-4\n+x\n 5\n 6\n 7\n",
+        ))
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.elide_path_components = 1;
+        tree.focus_lines = 1;
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+        tree.cursor = cursor(&[0, 0, 0, 0, 3]);
+        tree.toggle_focus();
+
+        let render_ascii = |tree: &DiffTreeWidget| {
+            let mut canvas = Canvas::new(0, TerminalSize::rows_cols(10, 60));
+            canvas.set_charset(Charset::Ascii);
+            tree.render(&mut canvas);
+            canvas
+                .frame_lines()
+                .iter()
+                .map(|l| l.text())
+                .collect::<Vec<_>>()
+        };
+        let lines = render_ascii(&tree);
+        assert_eq!(lines[3], "        | ... 2 more lines");
+        assert!(lines.iter().all(|l| l.is_ascii()), "{lines:?}");
+
+        tree.cursor = cursor(&[0, 0, 0]);
+        tree.toggle().or_fail()?;
+        let lines = render_ascii(&tree);
+        assert_eq!(
+            lines[1],
+            "--->| modified .../foo/bar.rs (1 chunks, -1 +1 lines) +- ..."
+        );
+        assert!(lines.iter().all(|l| l.is_ascii()), "{lines:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn path_style() -> orfail::Result<()> {
+        let chunk = "@@ -1 +1 @@\n-x\n+X\n";
+        let unstaged_diff = Diff::from_str(&format!(
+            "{}{}{}",
+            file_diff("src/x/mod.rs", chunk),
+            file_diff("src/y/mod.rs", chunk),
+            file_diff("src/x/lib.rs", chunk)
+        ))
+        .or_fail()?;
+        let mut tree = DiffTreeWidget::with_diffs(unstaged_diff, Diff::default()).or_fail()?;
+        tree.cwd_prefix = PathBuf::from("src/x");
+
+        let size = TerminalSize::rows_cols(4, 60);
+        let displayed_paths = |tree: &DiffTreeWidget| {
+            render(tree, 0, size)[1..]
+                .iter()
+                .map(|line| {
+                    let line = line.split_once("modified ").map_or("", |x| x.1);
+                    line.split_once(" (1 chunks").map_or("", |x| x.0).to_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            displayed_paths(&tree),
+            ["src/x/mod.rs", "src/y/mod.rs", "src/x/lib.rs"]
+        );
+
+        tree.toggle_path_style();
+        assert_eq!(displayed_paths(&tree), ["mod.rs", "../y/mod.rs", "lib.rs"]);
+
+        // Same-named files are disambiguated by their directories.
+        tree.toggle_path_style();
+        assert_eq!(
+            displayed_paths(&tree),
+            ["mod.rs (src/x)", "mod.rs (src/y)", "lib.rs"]
+        );
+
+        tree.toggle_path_style();
+        assert_eq!(tree.path_style, PathStyle::Root);
+        Ok(())
+    }
+
+    #[test]
+    fn change_bars() -> orfail::Result<()> {
+        assert_eq!(change_bar(0, 0), (0, 0));
+        assert_eq!(change_bar(3, 2), (3, 2));
+        assert_eq!(change_bar(100, 100), (5, 5));
+        assert_eq!(change_bar(300, 100), (8, 2));
+        assert_eq!(change_bar(1000, 1), (9, 1));
+        assert_eq!(change_bar(0, 1000), (0, 10));
+
+        // Collapsed files show the bar.
+        let tree = tree().or_fail()?;
+        let size = TerminalSize::rows_cols(3, 60);
+        assert_eq!(
+            render(&tree, 0, size)[1..],
+            [
+                "  :   modified foo (1 chunks, -1 +1 lines) +- …",
+                "  :   modified bar (1 chunks, -1 +1 lines) +- …",
+            ]
+        );
+        Ok(())
+    }
 }
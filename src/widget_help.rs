@@ -0,0 +1,124 @@
+use mame::action::{Binding, BindingConfig, BindingContextName};
+use tuinix::{KeyCode, KeyInput, TerminalSize, TerminalStyle};
+
+use crate::action::Action;
+use crate::canvas::{Canvas, Charset, Token};
+
+// Full-screen list of all key bindings, grouped by context.
+#[derive(Debug)]
+pub struct HelpWidget {
+    lines: Vec<Token>,
+    row_start: usize,
+}
+
+impl HelpWidget {
+    pub fn new(config: &BindingConfig<Action>, current_context: &BindingContextName) -> Self {
+        // The current context comes first, followed by the others in name order.
+        let mut contexts = config.all_bindings().collect::<Vec<_>>();
+        contexts.sort_by_key(|(name, _)| *name != current_context);
+
+        let keys = |binding: &Binding<Action>| {
+            binding
+                .triggers
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let keys_cols = contexts
+            .iter()
+            .flat_map(|(_, bindings)| bindings.iter())
+            .map(|b| mame::terminal::str_cols(&keys(b)))
+            .max()
+            .unwrap_or_default();
+
+        let bold = TerminalStyle::new().bold();
+        let mut lines = vec![
+            Token::with_style("Key bindings", bold),
+            Token::new("(Up/Down/PageUp/PageDown to scroll, any other key to close)"),
+        ];
+        for (name, bindings) in contexts {
+            lines.push(Token::new(""));
+            lines.push(Token::with_style(name.get(), bold));
+            for binding in bindings {
+                let description = match (&binding.label, &binding.action, &binding.context) {
+                    (Some(label), _, _) => label.trim_end().to_owned(),
+                    (None, Some(action), _) => action.type_name(),
+                    (None, None, Some(context)) => format!("-> {}", context.get()),
+                    (None, None, None) => String::new(),
+                };
+                let keys = keys(binding);
+                let padding = keys_cols - mame::terminal::str_cols(&keys);
+                lines.push(Token::new(format!(
+                    "  {keys}{:padding$}  {description}",
+                    ""
+                )));
+            }
+        }
+        Self {
+            lines,
+            row_start: 0,
+        }
+    }
+
+    // Scrolls the list if the key is a scroll key; otherwise, returns `false` to close the help.
+    pub fn handle_key(&mut self, key: KeyInput, size: TerminalSize) -> bool {
+        let max_row_start = self.lines.len().saturating_sub(size.rows);
+        self.row_start = match key.code {
+            KeyCode::Up => self.row_start.saturating_sub(1),
+            KeyCode::Down => self.row_start + 1,
+            KeyCode::PageUp => self.row_start.saturating_sub(size.rows),
+            KeyCode::PageDown => self.row_start + size.rows,
+            _ => return false,
+        }
+        .min(max_row_start);
+        true
+    }
+
+    pub fn render(
+        &self,
+        size: TerminalSize,
+        charset: Charset,
+    ) -> mame::terminal::UnicodeTerminalFrame {
+        let mut canvas = Canvas::new(self.row_start, size);
+        canvas.set_charset(charset);
+        for token in &self.lines {
+            if canvas.is_frame_exceeded() {
+                break;
+            }
+            canvas.drawln(token.clone());
+        }
+        canvas.into_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use orfail::OrFail;
+
+    use super::*;
+
+    #[test]
+    fn default_config() -> orfail::Result<()> {
+        let config = BindingConfig::<Action>::load_from_str(
+            "<DEFAULT>",
+            include_str!("../configs/default.jsonc"),
+        )
+        .or_fail()?;
+        let help = HelpWidget::new(&config, config.initial_context());
+        let lines = help.lines.iter().map(|t| t.text()).collect::<Vec<_>>();
+        assert_eq!(
+            lines[..6],
+            [
+                "Key bindings",
+                "(Up/Down/PageUp/PageDown to scroll, any other key to close)",
+                "",
+                "@main",
+                "  q, <ESCAPE>, C-c  (q)uit [ESC,C-c]",
+                "  r, C-l            (r)ecenter [C-l]",
+            ]
+        );
+        assert!(lines.contains(&"  U                 undiscard"));
+        Ok(())
+    }
+}
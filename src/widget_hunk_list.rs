@@ -0,0 +1,144 @@
+use tuinix::{KeyCode, KeyInput, TerminalSize, TerminalStyle};
+
+use crate::canvas::{Canvas, Charset, Token};
+
+// Full-screen numbered list of all chunks in both phases, for jumping to one of them.
+#[derive(Debug)]
+pub struct HunkListWidget {
+    // Tree path of each chunk and its label.
+    entries: Vec<(Vec<usize>, String)>,
+    selected: usize,
+    row_start: usize,
+}
+
+// What to do after a key is handled by the list.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HunkListInput {
+    Continue,
+    Close,
+    Jump(Vec<usize>),
+}
+
+impl HunkListWidget {
+    // The title line precedes the entries.
+    const HEADER_ROWS: usize = 1;
+
+    pub fn new(entries: Vec<(Vec<usize>, String)>) -> Self {
+        Self {
+            entries,
+            selected: 0,
+            row_start: 0,
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyInput, size: TerminalSize) -> HunkListInput {
+        let last = self.entries.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Char('j') | KeyCode::Down => self.selected = (self.selected + 1).min(last),
+            KeyCode::Char('k') | KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::PageDown => self.selected = (self.selected + size.rows).min(last),
+            KeyCode::PageUp => self.selected = self.selected.saturating_sub(size.rows),
+            KeyCode::Enter => {
+                return match self.entries.get(self.selected) {
+                    Some((path, _)) => HunkListInput::Jump(path.clone()),
+                    None => HunkListInput::Close,
+                };
+            }
+            KeyCode::Escape | KeyCode::Char('q') => return HunkListInput::Close,
+            _ => {}
+        }
+
+        // Keep the selected entry visible.
+        let rows = size.rows.saturating_sub(Self::HEADER_ROWS).max(1);
+        if self.selected < self.row_start {
+            self.row_start = self.selected;
+        } else if self.selected >= self.row_start + rows {
+            self.row_start = self.selected + 1 - rows;
+        }
+        HunkListInput::Continue
+    }
+
+    pub fn render(
+        &self,
+        size: TerminalSize,
+        charset: Charset,
+    ) -> mame::terminal::UnicodeTerminalFrame {
+        let mut canvas = Canvas::new(0, size);
+        canvas.set_charset(charset);
+        canvas.drawln(Token::with_style(
+            format!(
+                "Hunks: {} (j/k to move, Enter to jump, ESC to close)",
+                self.entries.len()
+            ),
+            TerminalStyle::new().bold(),
+        ));
+        let number_cols = self.entries.len().to_string().len();
+        for (i, (_, label)) in self.entries.iter().enumerate().skip(self.row_start) {
+            if canvas.is_frame_exceeded() {
+                break;
+            }
+            let text = format!("{:>number_cols$}. {label}", i + 1);
+            if i == self.selected {
+                canvas.drawln(Token::with_style(
+                    format!("-> {text}"),
+                    TerminalStyle::new().reverse(),
+                ));
+            } else {
+                canvas.drawln(Token::new(format!("   {text}")));
+            }
+        }
+        canvas.into_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyInput {
+        KeyInput {
+            ctrl: false,
+            alt: false,
+            code,
+        }
+    }
+
+    #[test]
+    fn select_and_jump() {
+        let entries = (0..5)
+            .map(|i| (vec![0, 0, i, 0], format!("unstaged file{i} @@ -1 +1 @@")))
+            .collect();
+        let mut list = HunkListWidget::new(entries);
+        let size = TerminalSize::rows_cols(3, 80);
+
+        // The list scrolls to keep the selected entry visible below the title line.
+        for _ in 0..3 {
+            assert_eq!(
+                list.handle_key(key(KeyCode::Char('j')), size),
+                HunkListInput::Continue
+            );
+        }
+        assert_eq!(list.selected, 3);
+        assert_eq!(list.row_start, 2);
+
+        list.handle_key(key(KeyCode::Down), size);
+        list.handle_key(key(KeyCode::Down), size);
+        assert_eq!(list.selected, 4);
+        list.handle_key(key(KeyCode::Up), size);
+        assert_eq!(
+            list.handle_key(key(KeyCode::Enter), size),
+            HunkListInput::Jump(vec![0, 0, 3, 0])
+        );
+        assert_eq!(
+            list.handle_key(key(KeyCode::Escape), size),
+            HunkListInput::Close
+        );
+
+        // An empty list is closed by Enter.
+        let mut list = HunkListWidget::new(Vec::new());
+        assert_eq!(
+            list.handle_key(key(KeyCode::Enter), size),
+            HunkListInput::Close
+        );
+    }
+}